@@ -1,11 +1,15 @@
-use std::{borrow::Cow, error::Error, io::{self}};
+use std::{borrow::Cow, error::Error, fs, io::{self, Read, Write}, path::Path, thread, time::Duration};
 use anyhow::anyhow;
 
 use clap::Parser;
 use espflash::{
     cli::{config::Config, *},
     elf::RomSegment,
+    flasher::Flasher,
+    logging::initialize_logger,
+    targets::Chip,
 };
+use log::LevelFilter;
 use miette::Result;
 use serde::Deserialize;
 use url::Url;
@@ -13,13 +17,25 @@ use url::Url;
 #[derive(Debug, Parser)]
 #[command(about, max_term_width = 100, propagate_version = true, version, arg_required_else_help = true)]
 pub struct MyCli {
-    /// url for (esp-web-tools) manifest file
+    /// url or local path of (esp-web-tools) manifest file, parts are loaded relative to it
     url: String,
 
     /// Don't erase device before flashing (default false, so erase)
     #[arg(long, required = false, default_value="false")]
     dont_erase: bool,
 
+    /// Read each part back from flash after writing and compare its CRC32
+    #[arg(long, required = false, default_value="false")]
+    verify: bool,
+
+    /// Flash even if the manifest's chipFamily doesn't match the connected chip
+    #[arg(long, required = false, default_value="false")]
+    force: bool,
+
+    /// Seconds allowed for downloading each file, per attempt
+    #[arg(long, default_value = "120")]
+    download_timeout: u64,
+
     /// Connection configuration
     #[clap(flatten)]
     pub connect_args: ConnectArgs,
@@ -50,6 +66,8 @@ struct ManifestBuildPart {
 }
 #[derive(Deserialize, Debug)]
 struct ManfestBuild {
+    #[serde(rename = "chipFamily")]
+    chip_family: Option<String>,
     parts: Vec<ManifestBuildPart>,
 }
 #[derive(Deserialize, Debug)]
@@ -61,6 +79,8 @@ struct Manifest {
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = MyCli::parse();
+    // espflash reports the serial port it picked through the log
+    initialize_logger(LevelFilter::Info);
 
     let mut connect_args = args.connect_args;
     if connect_args.baud.is_none() {
@@ -70,36 +90,55 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = Config::load()?;
     println!();
     println!("Loading manifest file {}",args.url);
-    let manifest_json = String::from_utf8(download_file(&args.url)?)?;
+    let download_timeout = Duration::from_secs(args.download_timeout);
+    let manifest_json = String::from_utf8(download_file(&args.url, download_timeout)?)?;
     let manifest = serde_json::from_str::<Manifest>(&manifest_json)?;
     println!("Found manifest for {} version {}", manifest.name, manifest.version);
-    let manifest_url = Url::parse(&args.url)?;
-    let parts_base_url = manifest_url.join("./")?;
 
     let mut segments = Vec::<RomSegment>::new();
-    let parts = &manifest.builds.get(0).ok_or(anyhow!("No builds in manifest"))?.parts;
-    for part in parts {
-        let bin_url = parts_base_url.join(&part.path)?;
+    let build = manifest.builds.get(0).ok_or(anyhow!("No builds in manifest"))?;
+    for part in &build.parts {
+        let bin_location = part_location(&args.url, &part.path)?;
         println!(" - Loading {}", part.path);
-        let bin = download_file(&bin_url.to_string())?;
+        let bin = download_file(&bin_location, download_timeout)?;
         segments.push(RomSegment {addr: part.offset, data: Cow::Owned(bin)})
     }
 
     println!(
 r#"
 --------------------------------------------------------------------------------
-Your device will now be {}flashed with {} vesion {}.
-Press Ctrl-C Now to cancel installation.
-
 Please connect your device via USB to your computer.
 Then press enter/return to continue.
+--------------------------------------------------------------------------------"#);
+
+    readln();
+    let mut flasher = connect(&connect_args, &config, false, false)?;
+    println!("\nConnected device:");
+    print_board_info(&mut flasher)?;
+
+    if let Some(chip_family) = &build.chip_family {
+        let chip = flasher.chip();
+        if !chip_matches(chip_family, chip) {
+            if !args.force {
+                return Err(format!(
+                    "Firmware is built for {chip_family} but the connected chip is {chip}, not flashing (--force to flash anyway)"
+                ).into());
+            }
+            println!("\nWARNING: firmware is built for {chip_family} but the connected chip is {chip}, flashing anyway (--force)");
+        }
+    }
+
+    println!(
+r#"
+--------------------------------------------------------------------------------
+Your device will now be {}flashed with {} vesion {}.
+Press Ctrl-C Now to cancel installation.
+Press enter/return to continue.
 --------------------------------------------------------------------------------"#, 
         if args.dont_erase { "" } else { "erased and then " },
         manifest.name, manifest.version);
 
     readln();
-    let mut flasher = connect(&connect_args, &config, false, false)?;
-    print_board_info(&mut flasher)?;
     if !args.dont_erase {
         println!("\nErasing device flash... this may take a couple of minutes with no progress indication");
         flasher.erase_flash().unwrap();
@@ -108,6 +147,24 @@ Then press enter/return to continue.
     println!("Erasing done, now flashing\n");
     flasher.write_bins_to_flash(&segments, Some(&mut EspflashProgress::default()))?;
 
+    if args.verify {
+        println!("\nVerifying flashed parts");
+        for (part, segment) in build.parts.iter().zip(&segments) {
+            print!(" - Verifying {}... ", part.path);
+            io::stdout().flush().ok();
+            let expected = crc32fast::hash(&segment.data);
+            let flashed = read_back_crc32(&mut flasher, segment)?;
+            if flashed != expected {
+                println!("FAILED");
+                return Err(format!(
+                    "Verification failed for {} at 0x{:x}: flash CRC32 {flashed:08x}, expected {expected:08x}. Device is NOT correctly flashed, please flash again",
+                    part.path, segment.addr
+                ).into());
+            }
+            println!("OK");
+        }
+    }
+
     println!(
 r#"
 
@@ -119,20 +176,101 @@ Follow setup instructions on the device to continue setup.
     Ok(())
 }
 
+// CLI defaults of espflash read-flash
+const READ_FLASH_BLOCK_SIZE: u32 = 0x1000;
+const READ_FLASH_MAX_IN_FLIGHT: u32 = 64;
+
+// espflash only reads flash into a file, so it goes through a temporary one
+fn read_back_crc32(flasher: &mut Flasher, segment: &RomSegment) -> Result<u32, Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("cli-flasher-verify-{:x}.bin", segment.addr));
+    let result = flasher
+        .read_flash(
+            segment.addr,
+            segment.data.len() as u32,
+            READ_FLASH_BLOCK_SIZE,
+            READ_FLASH_MAX_IN_FLIGHT,
+            path.clone(),
+        )
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|()| Ok(fs::read(&path)?));
+    fs::remove_file(&path).ok();
+    Ok(crc32fast::hash(&result?))
+}
+
 fn readln() -> String {
     let mut input = String::new();
     io::stdin().read_line(&mut input).unwrap();
     input.trim().to_string()
 }
 
-fn download_file(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    let client = reqwest::blocking::Client::new();
-    let response = client.get(url).send()?;
-    
+// Manifest chip families are esp-web-tools style ("ESP32-S3"), espflash's chip names "esp32s3"
+fn chip_matches(chip_family: &str, chip: Chip) -> bool {
+    chip_family.replace('-', "").eq_ignore_ascii_case(&chip.to_string())
+}
+
+fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+// Part paths in the manifest are relative to the manifest itself, whether it's a url or a local file
+fn part_location(manifest_location: &str, part_path: &str) -> Result<String, Box<dyn Error>> {
+    if is_url(manifest_location) {
+        let parts_base_url = Url::parse(manifest_location)?.join("./")?;
+        Ok(parts_base_url.join(part_path)?.to_string())
+    } else {
+        let parts_base_dir = Path::new(manifest_location).parent().unwrap_or(Path::new(""));
+        Ok(parts_base_dir.join(part_path).to_string_lossy().into_owned())
+    }
+}
+
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+// Doubled after every failed attempt
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// Anything that isn't an http(s) url is read from the local filesystem, for offline flashing
+fn download_file(url: &str, timeout: Duration) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !is_url(url) {
+        return fs::read(url).map_err(|e| format!("Failed reading {url}: {e}").into());
+    }
+    let client = reqwest::blocking::Client::builder().timeout(timeout).build()?;
+    let mut retry_delay = DOWNLOAD_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match download_attempt(&client, url) {
+            Ok(bytes) => return Ok(bytes),
+            Err(e) if attempt < DOWNLOAD_ATTEMPTS => {
+                println!("   Download failed ({e}), retrying in {}s", retry_delay.as_secs());
+                thread::sleep(retry_delay);
+                retry_delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(format!("Failed downloading {url}: {e}").into()),
+        }
+    }
+}
+
+fn download_attempt(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut response = client.get(url).send()?;
+
     if !response.status().is_success() {
         return Err(format!("HTTP error: {}", response.status()).into());
     }
-    
-    let bytes = response.bytes()?;
-    Ok(bytes.to_vec())
+
+    let total = response.content_length();
+    let mut bytes = Vec::with_capacity(total.unwrap_or(0) as usize);
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let len = response.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        bytes.extend_from_slice(&buf[..len]);
+        match total {
+            Some(total) => print!("\r   {} / {} bytes", bytes.len(), total),
+            None => print!("\r   {} bytes", bytes.len()),
+        }
+        io::stdout().flush().ok();
+    }
+    println!();
+    Ok(bytes)
 }
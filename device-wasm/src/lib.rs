@@ -41,24 +41,36 @@ pub fn fill_bytes(buf: &mut [u8]) -> Result<Object, JsValue> {
     crypto.get_random_values_with_u8_array(buf)
 }
 
+// salt and iterations are the device's web_app_salt and web_app_key_derivation_iterations, served by /api/key-params
 #[wasm_bindgen]
-pub fn derive_key(key: &str, salt: &str) -> Vec<u8> {
+pub fn derive_key(key: &str, salt: &str, iterations: u32) -> Vec<u8> {
     let salt = salt.as_bytes();
 
     let mut key_bytes = vec![0u8; 32]; // 32-byte key for AES-256
-    pbkdf2_hmac::<Sha256>(key.as_bytes(), salt, 10_000, &mut key_bytes);
+    pbkdf2_hmac::<Sha256>(key.as_bytes(), salt, iterations, &mut key_bytes);
 
     key_bytes
 }
 
 
+// Must match the device's GCM_FORMAT_PREFIX
+const FORMAT_PREFIX: &str = "v2:";
+
+// aad is the endpoint path, the device binds each request and response to it
 #[wasm_bindgen]
-pub fn decrypt(key_bytes: &[u8], encrypted: &str) -> Result<String, JsValue> {
+pub fn decrypt(key_bytes: &[u8], encrypted: &str, aad: &str) -> Result<String, JsValue> {
     // Derive key (32 bytes from a user-provided key)
     let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
 
     let cipher = Aes256Gcm::new(key);
 
+    let encrypted = encrypted
+        .strip_prefix(FORMAT_PREFIX)
+        .ok_or("Unsupported encryption format, device firmware may be outdated")?;
+    if encrypted.len() < 16 {
+        return Err("Improperly encrypted data".into());
+    }
+
     // Decode IV and ciphertext
     let iv_bytes = STANDARD_NO_PAD
         .decode(&encrypted[0..16])
@@ -70,7 +82,13 @@ pub fn decrypt(key_bytes: &[u8], encrypted: &str) -> Result<String, JsValue> {
         .map_err(|e| format!("Failed to decode ciphertext: {e}"))?;
 
     // Decrypt the data
-    let plaintext = cipher.decrypt(iv, Payload::from(&ciphertext[..]));
+    let plaintext = cipher.decrypt(
+        iv,
+        Payload {
+            msg: &ciphertext[..],
+            aad: aad.as_bytes(),
+        },
+    );
 
     let plaintext = plaintext.map_err(|e| format!("Decryption failed: {e}"))?;
 
@@ -78,7 +96,7 @@ pub fn decrypt(key_bytes: &[u8], encrypted: &str) -> Result<String, JsValue> {
 }
 
 #[wasm_bindgen]
-pub fn encrypt(key_bytes: &[u8], data: &str) -> Result<String, JsValue> {
+pub fn encrypt(key_bytes: &[u8], data: &str, aad: &str) -> Result<String, JsValue> {
     let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
 
     let cipher = Aes256Gcm::new(key);
@@ -90,11 +108,17 @@ pub fn encrypt(key_bytes: &[u8], data: &str) -> Result<String, JsValue> {
 
     // Encrypt the data
     let ciphertext = cipher
-        .encrypt(iv, Payload::from(data.as_bytes()))
+        .encrypt(
+            iv,
+            Payload {
+                msg: data.as_bytes(),
+                aad: aad.as_bytes(),
+            },
+        )
         .map_err(|e| format!("Encryption failed: {e}"))?;
 
     Ok(format!(
-        "{}{}",
+        "{FORMAT_PREFIX}{}{}",
         STANDARD_NO_PAD.encode(&iv_bytes),
         STANDARD_NO_PAD.encode(&ciphertext),
     ))
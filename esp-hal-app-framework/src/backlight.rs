@@ -5,6 +5,8 @@ pub struct BacklightConfig {
     pub dimming_timeout_secs: u64,
     pub dimming_percent: u8,
     pub blackout_timeout_secs: u64,
+    // Level when not dimmed, dimming_percent is relative to it
+    pub brightness_percent: u8,
 }
 
 pub trait BacklightDevice {
@@ -18,6 +20,7 @@ pub struct BacklightController {
     display_fully_dimmed: bool,
     display_partially_dimmed: bool,
     ignore_touch: bool,
+    brightness_percent: u8,
 }
 
 impl BacklightController {
@@ -27,17 +30,25 @@ impl BacklightController {
             display_fully_dimmed: false,
             display_partially_dimmed: false,
             ignore_touch: false,
+            // boards turn the backlight fully on at init
+            brightness_percent: 100,
         }
     }
 
+    // Also applies a changed brightness
     pub fn register_activity<D: BacklightDevice>(
         &mut self,
         backlight: &mut D,
+        brightness_percent: u8,
     ) -> Result<(), D::Error> {
         self.last_touch_time = Instant::now();
 
-        if self.display_partially_dimmed || self.display_fully_dimmed {
-            backlight.set_percent(100)?;
+        if self.display_partially_dimmed
+            || self.display_fully_dimmed
+            || self.brightness_percent != brightness_percent
+        {
+            backlight.set_percent(brightness_percent)?;
+            self.brightness_percent = brightness_percent;
             self.display_fully_dimmed = false;
             self.display_partially_dimmed = false;
         }
@@ -59,7 +70,9 @@ impl BacklightController {
         } else if !self.display_partially_dimmed
             && self.last_touch_time.elapsed().as_secs() > config.dimming_timeout_secs
         {
-            backlight.set_percent(config.dimming_percent)?;
+            let dimmed_percent =
+                (config.brightness_percent as u16 * config.dimming_percent as u16 / 100) as u8;
+            backlight.set_percent(dimmed_percent)?;
             self.display_partially_dimmed = true;
         }
 
@@ -0,0 +1,269 @@
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+};
+use core::fmt;
+
+use serde::Deserialize;
+
+// SDCard config file, overrides what's stored in flash. Values should be valid TOML (strings quoted), e.g.
+//   [wifi]
+//   ssid = "MyNetwork"
+//   [display]
+//   dimming_percent = 10
+// Files in the older format with unquoted strings (ssid = MyNetwork, password = 12345678) are still accepted,
+// such values are read as strings, but errors are then only reported against the TOML form.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ConfigFile {
+    pub wifi: WifiSection,
+    pub fixed: FixedKeySection,
+    pub device: DeviceSection,
+    pub display: DisplaySection,
+    // Everything else as "<section>_<key>" ("<key>" at the top level), for the app's own settings
+    #[serde(skip)]
+    pub extras: BTreeMap<String, String>,
+    // The file used the older format with unquoted strings
+    #[serde(skip)]
+    pub legacy_format: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct WifiSection {
+    pub ssid: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct FixedKeySection {
+    pub key: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DeviceSection {
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DisplaySection {
+    pub dimming_timeout: Option<u64>,
+    pub dimming_percent: Option<u8>,
+    pub blackout_timeout: Option<u64>,
+    pub brightness_percent: Option<u8>,
+}
+
+// Framework keys read as strings, in the older format their whole value is the string even if it looks like
+// a number or contains '#'
+const FRAMEWORK_STRING_KEYS: &[&str] = &["wifi_ssid", "wifi_password", "fixed_key", "device_name"];
+
+const FRAMEWORK_KEYS: &[&str] = &[
+    "wifi_ssid",
+    "wifi_password",
+    "fixed_key",
+    "device_name",
+    "display_dimming_timeout",
+    "display_dimming_percent",
+    "display_blackout_timeout",
+    "display_brightness_percent",
+];
+
+#[derive(Debug)]
+pub struct ConfigFileError {
+    // 1 based, None if the parser couldn't tell
+    pub line: Option<usize>,
+    pub section: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "config file error")?;
+        if let Some(line) = self.line {
+            write!(f, " at line {line}")?;
+        }
+        if let Some(section) = &self.section {
+            write!(f, " in [{section}]")?;
+        }
+        write!(f, ": {}", self.message.trim())
+    }
+}
+
+impl ConfigFile {
+    pub fn parse(toml_str: &str) -> Result<Self, ConfigFileError> {
+        match Self::parse_toml(toml_str) {
+            Ok(config_file) => Ok(config_file),
+            Err(e) => {
+                // The error of the file as written is more useful than of its quoted form
+                let quoted = quote_legacy_values(toml_str).ok_or(e)?;
+                let mut config_file = Self::parse_toml(&quoted)?;
+                config_file.legacy_format = true;
+                Ok(config_file)
+            }
+        }
+    }
+
+    fn parse_toml(toml_str: &str) -> Result<Self, ConfigFileError> {
+        let mut config_file =
+            toml::from_str::<ConfigFile>(toml_str).map_err(|e| config_file_error(toml_str, &e))?;
+        let table =
+            toml::from_str::<toml::Table>(toml_str).map_err(|e| config_file_error(toml_str, &e))?;
+        for (key, value) in table {
+            if let toml::Value::Table(section) = value {
+                for (section_key, value) in section {
+                    config_file.add_extra(format!("{key}_{section_key}"), value);
+                }
+            } else {
+                config_file.add_extra(key, value);
+            }
+        }
+        Ok(config_file)
+    }
+
+    fn add_extra(&mut self, key: String, value: toml::Value) {
+        if FRAMEWORK_KEYS.contains(&key.as_str()) {
+            return;
+        }
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            toml::Value::Datetime(value) => value.to_string(),
+            // nested tables/arrays aren't flattened, the app can parse the file itself for those
+            toml::Value::Array(_) | toml::Value::Table(_) => return,
+        };
+        self.extras.insert(key, value);
+    }
+}
+
+// The older format took everything after '=' as the value, with optional surrounding quotes.
+// Values of framework string keys that aren't TOML strings, and values that aren't valid TOML at all,
+// are quoted. None if there were none so the TOML error stands.
+fn quote_legacy_values(toml_str: &str) -> Option<String> {
+    let mut quoted_any = false;
+    let mut quoted = String::new();
+    let mut section = None;
+    for line in toml_str.lines() {
+        let trimmed = line.trim();
+        if let Some(header) = trimmed
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = Some(header.trim());
+        }
+        match trimmed.split_once('=') {
+            Some((key, value))
+                if !trimmed.starts_with('#')
+                    && !trimmed.starts_with('[')
+                    && needs_quoting(section, key.trim(), value.trim()) =>
+            {
+                let value = value.trim().trim_matches('"');
+                let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+                quoted.push_str(&format!("{} = \"{}\"", key.trim(), value));
+                quoted_any = true;
+            }
+            _ => quoted.push_str(line),
+        }
+        quoted.push('\n');
+    }
+    quoted_any.then_some(quoted)
+}
+
+fn needs_quoting(section: Option<&str>, key: &str, value: &str) -> bool {
+    let full_key = match section {
+        Some(section) => format!("{section}_{key}"),
+        None => key.to_string(),
+    };
+    match toml::from_str::<toml::Table>(&format!("v = {value}")) {
+        Ok(table) => {
+            FRAMEWORK_STRING_KEYS.contains(&full_key.as_str())
+                && !table.get("v").is_some_and(toml::Value::is_str)
+        }
+        Err(_) => true,
+    }
+}
+
+fn config_file_error(toml_str: &str, e: &toml::de::Error) -> ConfigFileError {
+    let offset = e.span().map(|span| span.start.min(toml_str.len()));
+    let before = offset.map(|offset| &toml_str[..offset]);
+    ConfigFileError {
+        line: before.map(|before| before.matches('\n').count() + 1),
+        // closest section header before the error
+        section: before.and_then(|before| {
+            before.lines().rev().find_map(|line| {
+                let line = line.trim();
+                line.strip_prefix('[')
+                    .and_then(|line| line.strip_suffix(']'))
+                    .map(|section| section.trim().to_string())
+            })
+        }),
+        message: e.message().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_format() {
+        let config_file = ConfigFile::parse(
+            "[wifi]\nssid = \"MyNetwork\"\npassword = \"12345678\"\n[display]\ndimming_percent = 10\n",
+        )
+        .unwrap();
+        assert!(!config_file.legacy_format);
+        assert_eq!(config_file.wifi.ssid.as_deref(), Some("MyNetwork"));
+        assert_eq!(config_file.wifi.password.as_deref(), Some("12345678"));
+        assert_eq!(config_file.display.dimming_percent, Some(10));
+    }
+
+    #[test]
+    fn legacy_unquoted_strings() {
+        let config_file =
+            ConfigFile::parse("[wifi]\nssid = My Network\npassword = pass word\n").unwrap();
+        assert!(config_file.legacy_format);
+        assert_eq!(config_file.wifi.ssid.as_deref(), Some("My Network"));
+        assert_eq!(config_file.wifi.password.as_deref(), Some("pass word"));
+    }
+
+    #[test]
+    fn legacy_numeric_values_of_string_keys() {
+        let config_file = ConfigFile::parse(
+            "[wifi]\nssid = 1234\npassword = 12345678\n[fixed]\nkey = 987654321\n[device]\nname = 42\n",
+        )
+        .unwrap();
+        assert!(config_file.legacy_format);
+        assert_eq!(config_file.wifi.ssid.as_deref(), Some("1234"));
+        assert_eq!(config_file.wifi.password.as_deref(), Some("12345678"));
+        assert_eq!(config_file.fixed.key.as_deref(), Some("987654321"));
+        assert_eq!(config_file.device.name.as_deref(), Some("42"));
+    }
+
+    #[test]
+    fn legacy_password_with_hash() {
+        let config_file = ConfigFile::parse("[wifi]\nssid = Home\npassword = 1234#abc\n").unwrap();
+        assert_eq!(config_file.wifi.password.as_deref(), Some("1234#abc"));
+    }
+
+    #[test]
+    fn legacy_numbers_stay_numbers() {
+        let config_file =
+            ConfigFile::parse("[wifi]\npassword = 12345678\n[display]\ndimming_percent = 10\n")
+                .unwrap();
+        assert_eq!(config_file.display.dimming_percent, Some(10));
+    }
+
+    #[test]
+    fn invalid_value_reports_toml_error() {
+        let e = ConfigFile::parse("[display]\ndimming_percent = 1000\n")
+            .err()
+            .unwrap();
+        assert_eq!(e.line, Some(2));
+        assert_eq!(e.section.as_deref(), Some("display"));
+    }
+}
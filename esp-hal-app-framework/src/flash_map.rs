@@ -5,6 +5,8 @@ use embedded_storage_async::nor_flash::MultiwriteNorFlash;
 use esp_partition_table::PartitionTable;
 use sequential_storage::{cache::NoCache, Error};
 
+const MAGIC_KEY: &str = "__map_name__";
+
 pub struct FlashMap<S: MultiwriteNorFlash> {
     nor_flash: S,
     addr_range: Range<u32>,
@@ -40,7 +42,6 @@ impl<S: MultiwriteNorFlash> FlashMap<S> {
             buffer: Vec::new(),
         };
         flash_map.init_flash_map(name).await?;
-        // const MAGIC_KEY: &str = "__map_name__";
         // let magic = flash_map.fetch(String::from(MAGIC_KEY)).await?;
         //
         // if magic.is_none() || magic.unwrap() != name {
@@ -52,7 +53,6 @@ impl<S: MultiwriteNorFlash> FlashMap<S> {
     }
 
     async fn init_flash_map(&mut self, name: &str) -> Result<(), Error<S::Error>> {
-        const MAGIC_KEY: &str = "__map_name__";
         let magic = self.fetch(String::from(MAGIC_KEY)).await?;
 
         if magic.is_none() || magic.unwrap() != name {
@@ -161,4 +161,33 @@ impl<S: MultiwriteNorFlash> FlashMap<S> {
         )
         .await
     }
+
+    // Distinct keys currently stored, excluding the map's own name key, at most max_keys of them.
+    // The map keeps superseded copies of an item until its page is reclaimed, so keys are deduplicated.
+    pub async fn keys(&mut self, max_keys: usize) -> Result<Vec<String>, Error<S::Error>> {
+        if self.buffer.len() < self.max_buf_size {
+            self.buffer.resize(self.max_buf_size, 0)
+        }
+
+        let mut keys: Vec<String> = Vec::new();
+        let mut cache = NoCache::new();
+        let mut iter = sequential_storage::map::fetch_all_items::<String, _, _>(
+            &mut self.nor_flash,
+            self.addr_range.clone(),
+            &mut cache,
+            &mut self.buffer,
+        )
+        .await?;
+        while let Some((key, _)) = iter.next::<&[u8]>(&mut self.buffer).await? {
+            if key == MAGIC_KEY || keys.contains(&key) {
+                continue;
+            }
+            if keys.len() == max_keys {
+                warn!("Flash map has more than {max_keys} keys, listing truncated");
+                break;
+            }
+            keys.push(key);
+        }
+        Ok(keys)
+    }
 }
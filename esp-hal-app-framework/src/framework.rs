@@ -1,21 +1,22 @@
 use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
     format,
     rc::Rc,
     string::{String, ToString},
     vec::Vec,
 };
-use core::{cell::RefCell, fmt, net::Ipv4Addr};
+use core::{any::Any, cell::RefCell, fmt, net::Ipv4Addr};
 use embassy_embedded_hal::adapter::BlockingAsync;
 use embassy_executor::Spawner;
 use embassy_futures::block_on;
 use embassy_net::Stack;
-#[allow(unused_imports)]
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-#[allow(unused_imports)]
-use embassy_sync::mutex::Mutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
 use embassy_sync::{
-    blocking_mutex::raw::NoopRawMutex,
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    channel::Channel,
     pubsub::{PubSubChannel, Publisher, Subscriber},
+    signal::Signal,
 };
 use embassy_time::{Duration, Timer};
 use embedded_hal_bus::spi::{ExclusiveDevice, NoDelay};
@@ -28,11 +29,21 @@ use esp_storage::FlashStorage;
 use serde::Serialize;
 
 use super::{
-    flash_map::FlashMap, framework_web_app::derive_key, ota::ota_task, terminal::Terminal,
+    flash_map::FlashMap,
+    framework_web_app::{ctr_decrypt, ctr_encrypt, derive_key},
+    ota::ota_task,
+    terminal::Terminal,
 };
 use crate::{
-    mdns::mdns_task, ntp::ntp_task, ota::OtaRequest, sdcard_store::SDCardStore,
-    web_server::WebServerCommand,
+    config_file::ConfigFile,
+    http_client::{self, HttpClientError},
+    mdns::mdns_task,
+    ntp::ntp_task,
+    ota::{OtaError, OtaRequest},
+    sdcard_store::SDCardStore,
+    texts::{FrameworkText, FrameworkTexts},
+    touch::{Gesture, GestureConfig},
+    web_server::{WebServerBindInterface, WebServerCommand},
 };
 use crate::{
     display_snapshot::{DisplaySnapshotBmp, DisplaySnapshotError},
@@ -50,7 +61,38 @@ pub type SDCardStoreType = SDCardStore<
 const WIFI_CONFIG_KEY: &str = "__wifi__";
 const FIXED_KEY_CONFIG_KEY: &str = "__fixed_key__";
 const DEVICE_NAME_CONFIG_KEY: &str = "__device_name__";
+const TLS_CONFIG_KEY: &str = "__tls__";
 const DISPLAY_CONFIG_KEY: &str = "__display_";
+const UTC_OFFSET_CONFIG_KEY: &str = "__utc_offset__";
+// Everything the framework keeps in flash, wiped by factory_reset
+const FRAMEWORK_CONFIG_KEYS: &[&str] = &[
+    WIFI_CONFIG_KEY,
+    FIXED_KEY_CONFIG_KEY,
+    DEVICE_NAME_CONFIG_KEY,
+    TLS_CONFIG_KEY,
+    DISPLAY_CONFIG_KEY,
+    UTC_OFFSET_CONFIG_KEY,
+    crate::ota::OTA_RESUME_KEY,
+];
+const WIFI_SSID_MAX_LEN: usize = 32;
+const WIFI_PASSWORD_MIN_LEN: usize = 8;
+const WIFI_PASSWORD_MAX_LEN: usize = 63;
+// A raw WPA2 PSK, given instead of the passphrase as its hex encoding
+const WIFI_PSK_HEX_LEN: usize = 64;
+pub const WIFI_TX_POWER_MIN_DBM: i8 = 2;
+pub const WIFI_TX_POWER_MAX_DBM: i8 = 20;
+// Below this PBKDF2 iteration count a captured request makes guessing the security key cheap
+pub const WEB_APP_KEY_DERIVATION_MIN_ITERATIONS: u32 = 10_000;
+pub const IMPROV_DEFAULT_CHIP: &str = "ESP32S3";
+pub const IMPROV_DEFAULT_DEVICE_NAME: &str = "WT32-SC01-Plus";
+pub const NTP_DEFAULT_SERVERS: &[&str] = &[
+    "pool.ntp.org",
+    "time.aws.com",
+    "time.windows.com",
+    "time.apple.com",
+    "cn.pool.ntp.org",
+    "time.google.com",
+];
 // const WEB_SERVER_COMMANDS_LISTENERS: usize = WEB_SERVER_NUM_LISTENERS + 1 + 1; // web_server listeners + potentially https captive if on https + 1 for use by app_config to monitor if required to behave accordingly
 
 // calculation is as above, but to avoid generics going into embassy tasks, use here a number large enough, at very little cost in memory
@@ -58,6 +100,18 @@ const DISPLAY_CONFIG_KEY: &str = "__display_";
 // Not nice, but good enough for now
 const WEB_SERVER_COMMANDS_LISTENERS: usize = 20;
 
+// Application events pub/sub sizing, shared by all app tasks
+const APP_EVENTS_CAPACITY: usize = 8;
+const APP_EVENTS_SUBSCRIBERS: usize = 8;
+const APP_EVENTS_PUBLISHERS: usize = 4;
+// How often a pending web app key rotation checks for in flight requests
+const WEB_KEY_ROTATION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Rotation doesn't wait longer for in flight requests, e.g. with a client that keeps sending
+const WEB_KEY_ROTATION_DEADLINE: Duration = Duration::from_secs(10);
+// Touch gestures pub/sub sizing, published only by the ui loop
+const TOUCH_GESTURES_CAPACITY: usize = 4;
+const TOUCH_GESTURES_SUBSCRIBERS: usize = 4;
+
 #[derive(Clone, Copy, Debug)]
 pub enum WebConfigMode {
     AP,
@@ -67,6 +121,30 @@ pub enum WebConfigMode {
 pub struct WifiConfig {
     pub ssid: Option<String>,
     pub password: Option<String>,
+    // absent in configs stored before these were introduced
+    #[serde(default)]
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+// Optional WiFi connection details, beyond ssid and password
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WifiCredentialOptions {
+    // WPA2-Enterprise (PEAP/TTLS) outer identity, defaults to username when not set
+    pub identity: Option<String>,
+    // WPA2-Enterprise username, when set the password is the user's and not a passphrase
+    pub username: Option<String>,
+    // SSID not broadcast, connect by scanning all channels
+    pub hidden: bool,
+}
+
+impl WifiCredentialOptions {
+    pub fn is_enterprise(&self) -> bool {
+        self.username.is_some()
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -79,11 +157,471 @@ pub struct DeviceNameConfig {
     pub name: Option<String>,
 }
 
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct UtcOffsetConfig {
+    pub minutes: i32,
+}
+
+// Per device TLS certificate/key (PEM), overrides the ones in settings when stored
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct TlsConfig {
+    pub certificate: String,
+    pub private_key: String,
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct DisplayConfig {
     pub dimming_timeout: Option<u64>,
     pub dimming_percent: Option<u8>,
     pub blackout_timeout: Option<u64>,
+    pub brightness_percent: Option<u8>,
+}
+
+// Stored configuration carried between devices by export_config/import_config,
+// sections left out are kept as they are on import
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+pub struct ConfigExport {
+    pub wifi: Option<WifiConfig>,
+    pub fixed_key: Option<String>,
+    pub device_name: Option<String>,
+    pub display: Option<DisplayConfig>,
+    pub utc_offset_minutes: Option<i32>,
+}
+
+#[derive(Debug)]
+pub enum WifiCredentialsError {
+    EmptySsid,
+    SsidTooLong(usize),
+    InvalidPasswordLength(usize),
+    EmptyEnterprisePassword,
+    EnterpriseConnectionTestUnsupported,
+    ConnectionTestUnavailable,
+    ConnectionFailed,
+    Storage(sequential_storage::Error<esp_storage::FlashStorageError>),
+}
+
+impl fmt::Display for WifiCredentialsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WifiCredentialsError::EmptySsid => write!(f, "WiFi SSID must not be empty"),
+            WifiCredentialsError::SsidTooLong(len) => write!(
+                f,
+                "WiFi SSID is {len} bytes long, maximum is {WIFI_SSID_MAX_LEN}"
+            ),
+            WifiCredentialsError::InvalidPasswordLength(len) => write!(
+                f,
+                "WiFi password is {len} bytes long, must be {WIFI_PASSWORD_MIN_LEN}-{WIFI_PASSWORD_MAX_LEN} ({WIFI_PSK_HEX_LEN} if a hex key, empty for open networks)"
+            ),
+            WifiCredentialsError::EmptyEnterprisePassword => {
+                write!(f, "WiFi password must not be empty when a username is set")
+            }
+            WifiCredentialsError::EnterpriseConnectionTestUnsupported => write!(
+                f,
+                "WiFi connection test isn't supported for enterprise networks, store without testing"
+            ),
+            WifiCredentialsError::ConnectionTestUnavailable => write!(
+                f,
+                "WiFi connection test is only available while in access point configuration mode"
+            ),
+            WifiCredentialsError::ConnectionFailed => {
+                write!(f, "Failed to connect to WiFi with the provided credentials")
+            }
+            WifiCredentialsError::Storage(e) => write!(f, "Failed storing WiFi credentials: {e:?}"),
+        }
+    }
+}
+
+pub fn validate_wifi_credentials(ssid: &str, password: &str) -> Result<(), WifiCredentialsError> {
+    if ssid.is_empty() {
+        return Err(WifiCredentialsError::EmptySsid);
+    }
+    if ssid.len() > WIFI_SSID_MAX_LEN {
+        return Err(WifiCredentialsError::SsidTooLong(ssid.len()));
+    }
+    // Empty password means an open network, otherwise WPA2 passphrase length rules apply
+    let is_psk_hex =
+        password.len() == WIFI_PSK_HEX_LEN && password.bytes().all(|b| b.is_ascii_hexdigit());
+    if !password.is_empty()
+        && !is_psk_hex
+        && !(WIFI_PASSWORD_MIN_LEN..=WIFI_PASSWORD_MAX_LEN).contains(&password.len())
+    {
+        return Err(WifiCredentialsError::InvalidPasswordLength(password.len()));
+    }
+    Ok(())
+}
+
+pub fn validate_wifi_credentials_with_options(
+    ssid: &str,
+    password: &str,
+    options: &WifiCredentialOptions,
+) -> Result<(), WifiCredentialsError> {
+    if !options.is_enterprise() {
+        return validate_wifi_credentials(ssid, password);
+    }
+    // Enterprise passwords are the user's, passphrase length rules don't apply
+    validate_wifi_credentials(ssid, "")?;
+    if password.is_empty() {
+        return Err(WifiCredentialsError::EmptyEnterprisePassword);
+    }
+    Ok(())
+}
+
+// Raw flash entries read at startup, before SDCard overrides are applied
+struct StoredConfig {
+    wifi: Option<String>,
+    fixed_key: Option<String>,
+    device_name: Option<String>,
+    tls: Option<String>,
+    display: Option<String>,
+    utc_offset: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum DisplaySettingsError {
+    DimmingPercentOutOfRange(u8),
+    BlackoutBeforeDimming(u64, u64),
+    BrightnessPercentOutOfRange(u8),
+    Storage(sequential_storage::Error<esp_storage::FlashStorageError>),
+}
+
+impl fmt::Display for DisplaySettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisplaySettingsError::DimmingPercentOutOfRange(percent) => {
+                write!(f, "Dimming percent is {percent}, must be 0 to 100")
+            }
+            DisplaySettingsError::BlackoutBeforeDimming(blackout, dimming) => write!(
+                f,
+                "Blackout timeout ({blackout}s) must not be shorter than dimming timeout ({dimming}s)"
+            ),
+            DisplaySettingsError::BrightnessPercentOutOfRange(percent) => {
+                write!(f, "Brightness percent is {percent}, must be 1 to 100")
+            }
+            DisplaySettingsError::Storage(e) => write!(f, "Failed storing display settings: {e:?}"),
+        }
+    }
+}
+
+pub fn validate_display_settings(
+    dimming_timeout: u64,
+    dimming_percent: u8,
+    blackout_timeout: u64,
+) -> Result<(), DisplaySettingsError> {
+    // Passed as is to the backlight PWM duty
+    if dimming_percent > 100 {
+        return Err(DisplaySettingsError::DimmingPercentOutOfRange(
+            dimming_percent,
+        ));
+    }
+    if blackout_timeout < dimming_timeout {
+        return Err(DisplaySettingsError::BlackoutBeforeDimming(
+            blackout_timeout,
+            dimming_timeout,
+        ));
+    }
+    Ok(())
+}
+
+// 0 would blank the display without the blackout handling of touches
+pub fn validate_display_brightness(brightness_percent: u8) -> Result<(), DisplaySettingsError> {
+    if !(1..=100).contains(&brightness_percent) {
+        return Err(DisplaySettingsError::BrightnessPercentOutOfRange(
+            brightness_percent,
+        ));
+    }
+    Ok(())
+}
+
+// Used as the mDNS hostname, so limited to a single DNS label
+const DEVICE_NAME_MAX_LEN: usize = 63;
+
+#[derive(Debug)]
+pub enum DeviceNameError {
+    TooLong(usize),
+    InvalidChar(char),
+    Storage(sequential_storage::Error<esp_storage::FlashStorageError>),
+}
+
+impl fmt::Display for DeviceNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceNameError::TooLong(len) => write!(
+                f,
+                "Device name is {len} characters, must be at most {DEVICE_NAME_MAX_LEN}"
+            ),
+            DeviceNameError::InvalidChar(c) => write!(
+                f,
+                "Device name can't contain '{c}', only letters, digits and '-' (not first or last)"
+            ),
+            DeviceNameError::Storage(e) => write!(f, "Failed storing device name: {e:?}"),
+        }
+    }
+}
+
+// Empty clears the name, so it's valid
+pub fn validate_device_name(name: &str) -> Result<(), DeviceNameError> {
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && *c != '-')
+    {
+        return Err(DeviceNameError::InvalidChar(c));
+    }
+    if name.starts_with('-') || name.ends_with('-') {
+        return Err(DeviceNameError::InvalidChar('-'));
+    }
+    // ASCII only by now, so bytes are characters
+    if name.len() > DEVICE_NAME_MAX_LEN {
+        return Err(DeviceNameError::TooLong(name.len()));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum UtcOffsetError {
+    OutOfRange(i32),
+    Storage(sequential_storage::Error<esp_storage::FlashStorageError>),
+}
+
+impl fmt::Display for UtcOffsetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UtcOffsetError::OutOfRange(minutes) => write!(
+                f,
+                "UTC offset is {minutes} minutes, must be {} to {}",
+                crate::ntp::UTC_OFFSET_MIN_MINUTES,
+                crate::ntp::UTC_OFFSET_MAX_MINUTES
+            ),
+            UtcOffsetError::Storage(e) => write!(f, "Failed storing UTC offset: {e:?}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigTransferError {
+    NoEncryptionKey,
+    Decryption(String),
+    Parse(serde_json::Error),
+    Wifi(WifiCredentialsError),
+    FixedKey(FixedKeyError),
+    DeviceName(DeviceNameError),
+    Display(DisplaySettingsError),
+    UtcOffset(UtcOffsetError),
+}
+
+impl fmt::Display for ConfigTransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigTransferError::NoEncryptionKey => {
+                write!(f, "Web app key isn't set, start the web app first")
+            }
+            ConfigTransferError::Decryption(e) => {
+                write!(
+                    f,
+                    "Failed decrypting configuration, was it exported with the same key? {e}"
+                )
+            }
+            ConfigTransferError::Parse(e) => write!(f, "Invalid configuration: {e}"),
+            ConfigTransferError::Wifi(e) => write!(f, "{e}"),
+            ConfigTransferError::FixedKey(e) => write!(f, "{e}"),
+            ConfigTransferError::DeviceName(e) => write!(f, "{e}"),
+            ConfigTransferError::Display(e) => write!(f, "{e}"),
+            ConfigTransferError::UtcOffset(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FixedKeyError {
+    TooShort(usize, usize),
+    TooSimple(usize, usize),
+    Storage(sequential_storage::Error<esp_storage::FlashStorageError>),
+}
+
+impl fmt::Display for FixedKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixedKeyError::TooShort(len, min) => write!(
+                f,
+                "Security key is {len} characters long, minimum is {min}"
+            ),
+            FixedKeyError::TooSimple(classes, min) => write!(
+                f,
+                "Security key uses {classes} of lowercase/uppercase/digits/symbols, at least {min} required"
+            ),
+            FixedKeyError::Storage(e) => write!(f, "Failed storing security key: {e:?}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsConfigError {
+    InvalidCertificate,
+    InvalidPrivateKey,
+    Storage(sequential_storage::Error<esp_storage::FlashStorageError>),
+}
+
+impl fmt::Display for TlsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsConfigError::InvalidCertificate => {
+                write!(f, "Certificate must be a valid PEM encoded certificate")
+            }
+            TlsConfigError::InvalidPrivateKey => {
+                write!(f, "Private key must be a valid PEM encoded private key")
+            }
+            TlsConfigError::Storage(e) => write!(f, "Failed storing TLS credentials: {e:?}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WifiTxPowerError {
+    OutOfRange(i8),
+    Driver(i32),
+}
+
+impl fmt::Display for WifiTxPowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WifiTxPowerError::OutOfRange(dbm) => write!(
+                f,
+                "WiFi TX power {dbm} dBm out of range, must be {WIFI_TX_POWER_MIN_DBM}-{WIFI_TX_POWER_MAX_DBM} dBm"
+            ),
+            WifiTxPowerError::Driver(code) => {
+                write!(f, "WiFi driver failed setting TX power (error {code})")
+            }
+        }
+    }
+}
+
+// min_length is in characters, min_char_classes is the number of lowercase/uppercase/digits/symbols groups used
+pub fn validate_fixed_key(
+    key: &str,
+    min_length: usize,
+    min_char_classes: usize,
+) -> Result<(), FixedKeyError> {
+    let len = key.chars().count();
+    if len < min_length {
+        return Err(FixedKeyError::TooShort(len, min_length));
+    }
+    let classes = [
+        key.chars().any(|c| c.is_lowercase()),
+        key.chars().any(|c| c.is_uppercase()),
+        key.chars().any(|c| c.is_ascii_digit()),
+        key.chars().any(|c| !c.is_alphanumeric()),
+    ]
+    .iter()
+    .filter(|used| **used)
+    .count();
+    if classes < min_char_classes {
+        return Err(FixedKeyError::TooSimple(classes, min_char_classes));
+    }
+    Ok(())
+}
+
+// Snapshot of what succeeded during boot, None when the step didn't complete (yet) or isn't enabled
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct InitStatus {
+    pub config_ok: Option<bool>,
+    pub wifi_credentials_ok: bool,
+    pub wifi_ok: Option<bool>,
+    pub time_ok: Option<bool>,
+    pub app_ok: Option<bool>,
+    pub reset_reason: ResetReason,
+}
+
+impl InitStatus {
+    pub fn is_ok(&self) -> bool {
+        matches!(self.config_ok, Some(true))
+            && self.wifi_credentials_ok
+            && [self.wifi_ok, self.time_ok, self.app_ok]
+                .iter()
+                .all(|step| !matches!(step, Some(false)))
+    }
+}
+
+// Where an effective config value came from, later sources override earlier ones
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum ConfigSource {
+    #[default]
+    Default,
+    Flash,
+    SdCard,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ConfigSources {
+    wifi: ConfigSource,
+    fixed_key: ConfigSource,
+    device_name: ConfigSource,
+    display_dimming_timeout: ConfigSource,
+    display_dimming_percent: ConfigSource,
+    display_blackout_timeout: ConfigSource,
+    display_brightness_percent: ConfigSource,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ConfigValue<T> {
+    pub value: T,
+    pub source: ConfigSource,
+}
+
+// Merged config the device is running with, secrets are only reported as set/not set
+#[derive(Debug, Serialize, Clone)]
+pub struct EffectiveConfig {
+    pub wifi_ssid: ConfigValue<Option<String>>,
+    pub wifi_password_set: ConfigValue<bool>,
+    pub fixed_key_set: ConfigValue<bool>,
+    pub device_name: ConfigValue<Option<String>>,
+    pub display_dimming_timeout: ConfigValue<u64>,
+    pub display_dimming_percent: ConfigValue<u8>,
+    pub display_blackout_timeout: ConfigValue<u64>,
+    pub display_brightness_percent: ConfigValue<u8>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum ResetReason {
+    PowerOn,
+    Software,
+    DeepSleepWakeup,
+    Watchdog,
+    Brownout,
+    Other,
+}
+
+impl ResetReason {
+    // Reads the reset reason of the boot core as reported by the ROM
+    fn read() -> Self {
+        use esp_hal::rtc_cntl::SocResetReason;
+        match esp_hal::rtc_cntl::reset_reason(esp_hal::system::Cpu::ProCpu) {
+            Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+            Some(SocResetReason::CoreSw | SocResetReason::Cpu0Sw) => ResetReason::Software,
+            Some(SocResetReason::CoreDeepSleep) => ResetReason::DeepSleepWakeup,
+            Some(
+                SocResetReason::CoreMwdt0
+                | SocResetReason::CoreMwdt1
+                | SocResetReason::CoreRtcWdt
+                | SocResetReason::Cpu0Mwdt0
+                | SocResetReason::Cpu0RtcWdt
+                | SocResetReason::SysRtcWdt,
+            ) => ResetReason::Watchdog,
+            Some(SocResetReason::SysBrownOut) => ResetReason::Brownout,
+            _ => ResetReason::Other,
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetReason::PowerOn => write!(f, "Power on"),
+            ResetReason::Software => write!(f, "Software reset (restart or panic)"),
+            ResetReason::DeepSleepWakeup => write!(f, "Wakeup from deep sleep"),
+            ResetReason::Watchdog => write!(f, "Watchdog"),
+            ResetReason::Brownout => write!(f, "Brownout"),
+            ResetReason::Other => write!(f, "Other"),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -91,8 +629,10 @@ pub enum OtaState {
     VersionAvailable(String, bool),
     Started,
     InProgress(String),
-    Failed(String),
+    Failed(OtaError, String),
     Completed(String),
+    // Booted into a new firmware that rolls back unless the app calls confirm_ota_boot
+    PendingConfirmation,
 }
 
 impl fmt::Display for OtaState {
@@ -106,23 +646,98 @@ impl fmt::Display for OtaState {
             ),
             OtaState::Started => write!(f, "Update started"),
             OtaState::InProgress(stage) => write!(f, "In progress: {}", stage),
-            OtaState::Failed(reason) => write!(f, "Update failed: {}", reason),
+            OtaState::Failed(_, reason) => write!(f, "Update failed: {}", reason),
             OtaState::Completed(ver) => write!(f, "Update completed: {}", ver),
+            OtaState::PendingConfirmation => write!(f, "Verifying new firmware"),
         }
     }
 }
 
+// Apps typically start from FrameworkSettings::default() and override what differs. The defaults
+// of the app specific values (app_*, ota_domain/path/certs, web_app_domain/salt, TLS) are empty.
 pub struct FrameworkSettings {
+    // Host of the OTA server, optionally with a scheme and port, e.g. "fw.example.com" (https on 443)
+    // or "http://10.0.0.5:8080" for an internal plaintext server, which doesn't use ota_certs
     pub ota_domain: &'static str,
     pub ota_path: &'static str,
     pub ota_toml_filename: &'static str,
     pub ota_certs: &'static str,
+    // CA chain (PEM, nul terminated) trusted by Framework::https_get/https_post
+    pub http_client_certs: &'static str,
+    // Host header sent to the OTA server, None uses ota_domain (e.g. when going through a proxy/CDN)
+    pub ota_host_header: Option<&'static str>,
+    // sent with both the metadata and firmware requests, e.g. &[("Authorization", "Bearer ...")]
+    pub ota_extra_headers: &'static [(&'static str, &'static str)],
+    // Time allowed for a single download read to make progress, a stalled OTA is aborted after it (default 15s)
+    pub ota_chunk_timeout: Duration,
 
     pub ap_addr: (u8, u8, u8, u8),
+    // Max WiFi TX power in dBm (WIFI_TX_POWER_MIN_DBM..=WIFI_TX_POWER_MAX_DBM), None keeps the driver default (max).
+    // Lower saves power/heat, but reduces range.
+    pub wifi_tx_power_dbm: Option<i8>,
+    // With stored credentials, keep the AP config portal up for this long after boot (AP+STA) to allow
+    // reconfiguring without erasing, 0 disables
+    pub config_portal_grace_secs: u64,
+    // After this many consecutive failed connects with the stored credentials, reopen the AP config
+    // portal (and improv) to collect new ones. None keeps retrying forever.
+    pub wifi_fallback_ap_after_failures: Option<u32>,
+    // While that fallback portal is up, retry the stored credentials this often (AP+STA) and return to station
+    // mode once they connect, e.g. after the router came back. 0 disables, not done for enterprise networks.
+    pub wifi_fallback_ap_retry_secs: u64,
+    // ISO 3166 alpha-2 country code (e.g. "US", "DE", "JP") for legal channels/TX power, "" keeps the driver default
+    // (channels 1-11 only, so APs on 12-14 aren't found)
+    pub wifi_country: &'static str,
+    // hostnames resolved to ap_addr while in AP mode (e.g. "setup.mydevice"), even when not captive
+    pub ap_dns_hostnames: &'static [&'static str],
 
     pub web_server_https: bool,
     pub web_server_port: u16,
+    // With web_server_https, also serve the same router over plaintext on this port (typically 80).
+    // Each listener then takes two sockets. Captive probes on it are redirected to the https one,
+    // and on port 80 it replaces the standalone http->https captive redirect task. None is https only.
+    pub web_server_http_port: Option<u16>,
     pub web_server_captive: bool,
+    // TTL of captive portal DNS answers. Android re-probes connectivity frequently and does fine with
+    // short values (~10-60s), iOS may show the captive sheet again when answers expire, so prefer
+    // longer values (~300s+) when mainly iOS clients are expected.
+    pub web_server_captive_dns_ttl_secs: u64,
+    // initial value of Framework::captive_redirect_all
+    pub web_server_captive_redirect_all: bool,
+    pub web_server_bind_interface: WebServerBindInterface,
+    pub web_server_log_requests: bool,
+    // exposes unauthenticated plaintext /metrics (Prometheus format)
+    pub web_server_metrics: bool,
+    // SD card log file offered as an attachment by /download/log.txt, None disables the route
+    pub web_server_log_download_path: Option<&'static str>,
+    // SD card directory served for GET requests no route handles, e.g. "/www". Names are 8.3,
+    // a path ending with / serves its index.htm. None serves nothing from the SD card.
+    pub web_server_sd_assets_dir: Option<&'static str>,
+    // Responses of the larger endpoints (/api/logs, /api/config-export) at least this long are gzipped
+    // when the client accepts it. The compressor takes ~230KB of heap while running. None disables.
+    pub web_server_gzip_min_size: Option<usize>,
+    // JSON request bodies declaring a larger Content-Length are refused with 413 before being read
+    pub web_server_max_body_size: usize,
+    // Overrides the read_request and write timeouts of the picoserve Config the app passes in,
+    // bounding how long a client can take to send a request or receive the response
+    pub web_server_request_timeout: Option<Duration>,
+    // Keep connections open between requests, so a page's assets reuse the connection it loaded on
+    // rather than waiting for a listener to accept again. None keeps the app's picoserve Config.
+    pub web_server_keep_alive: Option<bool>,
+    // How long a kept alive connection waits for its next request, holding its listener meanwhile.
+    // Keep it short (~1-5s) with few listeners. None keeps the app's picoserve Config.
+    pub web_server_keep_alive_timeout: Option<Duration>,
+    // Heap allocated per listener: tcp_rx + tcp_tx + http, so the total is
+    // web_server_num_listeners * (sum) while the web server runs, size them up only as needed.
+    // Defaults (see Default below): 2048, 2048, 16384. The http buffer must hold request headers + any
+    // non streamed body.
+    pub web_server_tcp_rx_buffer_size: usize,
+    pub web_server_tcp_tx_buffer_size: usize,
+    pub web_server_http_buffer_size: usize,
+    // rx/tx buffers of the standalone http->https captive redirect task, default 512
+    pub web_server_captive_buffer_size: usize,
+    // Number of WebAppRunner::run tasks the app spawns, each serving one connection at a time.
+    // Browsers load a page's assets over up to ~6 parallel connections, /config fetches 3 besides
+    // the page itself, so 3-4 listeners (with keep alive) avoid them queueing behind each other.
     #[allow(dead_code)]
     pub web_server_num_listeners: usize,
     pub web_server_tls_certificate: &'static str,
@@ -130,6 +745,8 @@ pub struct FrameworkSettings {
 
     pub web_app_domain: &'static str,
     pub web_app_security_key_length: usize,
+    // Salt and iterations are served by /api/key-params (and /captive/api/key-params), the web
+    // pages derive the key with them, so changing them needs no change to the pages
     pub web_app_salt: &'static str,
     pub web_app_key_derivation_iterations: u32,
 
@@ -137,8 +754,121 @@ pub struct FrameworkSettings {
     pub app_cargo_pkg_version: &'static str,
 
     pub default_fixed_security_key: Option<String>,
+    pub fixed_key_min_length: usize,
+    pub fixed_key_min_char_classes: usize,
+    // Subsystems, disabled ones are not spawned and don't consume stack resources
     pub mdns: bool,
+    // Extra TXT entries for the advertised web service, next to the automatic version/port/https ones
+    pub mdns_txt_records: &'static [(&'static str, &'static str)],
     pub ntp: bool,
+    // Tried in order until one answers, host names or IP addresses (skips DNS). NTP_DEFAULT_SERVERS
+    // for the public pools.
+    pub ntp_servers: &'static [&'static str],
+    // Time is queried again every interval to correct clock drift, None to sync only once at boot
+    pub ntp_resync_interval: Option<Duration>,
+    // Local time offset for InstantExt::to_local_date_time until one is stored with set_utc_offset
+    pub utc_offset_minutes: i32,
+    pub ota: bool,
+    // Download the firmware to the SD card and verify it before flashing, so a dropped connection
+    // never interrupts a flash in progress. Requires an SD card, updates fail without one.
+    pub ota_staged: bool,
+    // After an OTA reboot, the new firmware is rolled back to the previous one unless the app calls
+    // confirm_ota_boot within this time. None only rolls back if the device resets before confirming.
+    pub ota_confirm_timeout: Option<Duration>,
+    pub web_server: bool,
+    // Improv WiFi: max time to wait for the host tool to be ready before sending the initial (unsolicited)
+    // state, the web installer reopens the port after flashing and misses data sent earlier (default 2000)
+    pub improv_ready_delay_ms: u64,
+    // Improv WiFi: give up sending a packet after this, sending hangs if no one reads the port (default 1000)
+    pub improv_send_timeout_ms: u64,
+    // Improv WiFi: networks scan limits, host tools may give up on long scans (defaults 50, 10000)
+    pub improv_scan_max_results: usize,
+    pub improv_scan_timeout_ms: u64,
+    // Improv WiFi: chip family and device name reported to the host tool (esp-web-tools), see
+    // IMPROV_DEFAULT_CHIP and IMPROV_DEFAULT_DEVICE_NAME for the values used before these were settings
+    pub improv_chip: &'static str,
+    pub improv_device_name: &'static str,
+    // translations for framework generated texts, None for the defaults
+    pub texts: Option<FrameworkTexts>,
+    // recognize touch gestures for Framework::touch_gestures_subscriber, None to disable
+    pub touch_gestures: Option<GestureConfig>,
+}
+
+impl Default for FrameworkSettings {
+    fn default() -> Self {
+        Self {
+            ota_domain: "",
+            ota_path: "",
+            ota_toml_filename: "ota.toml",
+            ota_certs: "",
+            http_client_certs: "",
+            ota_host_header: None,
+            ota_extra_headers: &[],
+            ota_chunk_timeout: Duration::from_secs(15),
+
+            ap_addr: (192, 168, 2, 1),
+            wifi_tx_power_dbm: None,
+            config_portal_grace_secs: 0,
+            wifi_fallback_ap_after_failures: None,
+            wifi_fallback_ap_retry_secs: 300,
+            wifi_country: "",
+            ap_dns_hostnames: &[],
+
+            web_server_https: false,
+            web_server_port: 80,
+            web_server_http_port: None,
+            web_server_captive: true,
+            web_server_captive_dns_ttl_secs: 60,
+            web_server_captive_redirect_all: false,
+            web_server_bind_interface: WebServerBindInterface::default(),
+            web_server_log_requests: false,
+            web_server_metrics: false,
+            web_server_log_download_path: None,
+            web_server_sd_assets_dir: None,
+            web_server_gzip_min_size: None,
+            web_server_max_body_size: 8192,
+            web_server_request_timeout: None,
+            web_server_keep_alive: None,
+            web_server_keep_alive_timeout: None,
+            web_server_tcp_rx_buffer_size: 2048,
+            web_server_tcp_tx_buffer_size: 2048,
+            web_server_http_buffer_size: 16384,
+            web_server_captive_buffer_size: 512,
+            web_server_num_listeners: 3,
+            web_server_tls_certificate: "",
+            web_server_tls_private_key: "",
+
+            web_app_domain: "",
+            web_app_security_key_length: 8,
+            web_app_salt: "",
+            web_app_key_derivation_iterations: WEB_APP_KEY_DERIVATION_MIN_ITERATIONS,
+
+            app_cargo_pkg_name: "",
+            app_cargo_pkg_version: "",
+
+            default_fixed_security_key: None,
+            fixed_key_min_length: 8,
+            fixed_key_min_char_classes: 2,
+            mdns: true,
+            mdns_txt_records: &[],
+            ntp: true,
+            ntp_servers: NTP_DEFAULT_SERVERS,
+            ntp_resync_interval: Some(Duration::from_secs(3600)),
+            utc_offset_minutes: 0,
+            ota: false,
+            ota_staged: false,
+            ota_confirm_timeout: None,
+            web_server: true,
+            improv_ready_delay_ms: 2000,
+            improv_send_timeout_ms: 1000,
+            improv_scan_max_results: 50,
+            improv_scan_timeout_ms: 10000,
+            improv_chip: IMPROV_DEFAULT_CHIP,
+            improv_device_name: IMPROV_DEFAULT_DEVICE_NAME,
+            texts: None,
+            touch_gestures: None,
+        }
+    }
 }
 
 pub type WebServerCommands =
@@ -149,20 +879,118 @@ pub type WebServerPublisher =
 pub type WebServerSubscriber =
     Subscriber<'static, NoopRawMutex, WebServerCommand, 2, WEB_SERVER_COMMANDS_LISTENERS, 1>;
 
+// Requests from the web config to test WiFi credentials, handled by the wifi connection task
+pub type WifiCredentialsTestRequests =
+    Channel<NoopRawMutex, (String, String, WifiCredentialOptions), 1>;
+pub type WifiCredentialsTestResult = Signal<NoopRawMutex, bool>;
+// Whether the tested credentials got stored, the wifi task stops the access point only after they did
+pub type WifiCredentialsTestStored = Signal<NoopRawMutex, bool>;
+
+const WIFI_SCAN_WAIT_MARGIN: Duration = Duration::from_secs(5);
+
+// Requests from the app to scan for WiFi networks, handled by the wifi connection task
+pub type WifiScanRequests = Channel<NoopRawMutex, (), 1>;
+pub type WifiScanResult = Signal<NoopRawMutex, Vec<WifiNetwork>>;
+
+#[derive(Clone, Debug)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub rssi: i8,
+    pub auth_required: bool,
+}
+
+// Signals the mdns task to re-register after the device name changed
+pub type MdnsRestart = Signal<NoopRawMutex, ()>;
+
+// Application level event, broadcast to all app tasks subscribed through the framework
+// Subscribers match on topic and downcast the payload to the type the publisher used
+#[derive(Clone)]
+pub struct AppEvent {
+    pub topic: &'static str,
+    payload: Rc<dyn Any>,
+}
+
+impl AppEvent {
+    pub fn new<T: 'static>(topic: &'static str, payload: T) -> Self {
+        Self {
+            topic,
+            payload: Rc::new(payload),
+        }
+    }
+
+    pub fn payload<T: 'static>(&self) -> Option<&T> {
+        self.payload.downcast_ref::<T>()
+    }
+}
+
+pub type AppEvents = PubSubChannel<
+    NoopRawMutex,
+    AppEvent,
+    APP_EVENTS_CAPACITY,
+    APP_EVENTS_SUBSCRIBERS,
+    APP_EVENTS_PUBLISHERS,
+>;
+pub type AppEventsPublisher = Publisher<
+    'static,
+    NoopRawMutex,
+    AppEvent,
+    APP_EVENTS_CAPACITY,
+    APP_EVENTS_SUBSCRIBERS,
+    APP_EVENTS_PUBLISHERS,
+>;
+pub type AppEventsSubscriber = Subscriber<
+    'static,
+    NoopRawMutex,
+    AppEvent,
+    APP_EVENTS_CAPACITY,
+    APP_EVENTS_SUBSCRIBERS,
+    APP_EVENTS_PUBLISHERS,
+>;
+
+pub type TouchGestures =
+    PubSubChannel<NoopRawMutex, Gesture, TOUCH_GESTURES_CAPACITY, TOUCH_GESTURES_SUBSCRIBERS, 0>;
+pub type TouchGesturesSubscriber = Subscriber<
+    'static,
+    NoopRawMutex,
+    Gesture,
+    TOUCH_GESTURES_CAPACITY,
+    TOUCH_GESTURES_SUBSCRIBERS,
+    0,
+>;
+
 pub struct Framework {
     pub settings: FrameworkSettings,
     observers: Vec<alloc::rc::Weak<RefCell<dyn FrameworkObserver>>>,
+    // app callbacks returning the app's own flash keys, wiped along the framework's on factory reset
+    factory_reset_keys: Vec<Box<dyn Fn() -> Vec<String>>>,
     framework: Option<Rc<RefCell<Framework>>>,
-    flash_map: Rc<RefCell<FlashMap<BlockingAsync<FlashStorage>>>>,
+    // owned by an async mutex, so flash operations can yield while it stays locked
+    flash_map: Rc<Mutex<CriticalSectionRawMutex, FlashMap<BlockingAsync<FlashStorage>>>>,
+    // SDCard config file values the framework doesn't use, as "<section>_<key>"
+    pub config_extras: BTreeMap<String, String>,
     pub web_server_commands: &'static WebServerCommands,
+    pub wifi_credentials_test_requests: &'static WifiCredentialsTestRequests,
+    pub wifi_credentials_test_result: &'static WifiCredentialsTestResult,
+    pub wifi_credentials_test_stored: &'static WifiCredentialsTestStored,
+    pub wifi_credentials_test_available: bool,
+    pub wifi_scan_requests: &'static WifiScanRequests,
+    pub wifi_scan_result: &'static WifiScanResult,
+    pub mdns_restart: &'static MdnsRestart,
+    pub app_events: &'static AppEvents,
+    pub touch_gestures: &'static TouchGestures,
     pub wifi_ssid: Option<String>,
     pub wifi_password: Option<String>,
+    pub wifi_options: WifiCredentialOptions,
     pub fixed_key: Option<String>,
     pub device_name: Option<String>,
+    // stored per device TLS credentials, used by the web server instead of the settings ones
+    pub tls_config: Option<TlsConfig>,
 
     pub display_dimming_timeout: u64,
     pub display_dimming_percent: u8,
     pub display_blackout_timeout: u64,
+    pub display_brightness_percent: u8,
+    keep_awake_requests: usize,
     pub undim_display:
         &'static embassy_sync::signal::Signal<embassy_sync::blocking_mutex::raw::NoopRawMutex, ()>,
 
@@ -172,11 +1000,23 @@ pub struct Framework {
     pub encryption_key: &'static RefCell<Vec<u8>>,
 
     config_processed_ok: Option<bool>,
+    config_sources: ConfigSources,
     pub wifi_ok: Option<bool>,
+    // last sampled while connected as station
+    pub wifi_rssi: Option<i32>,
+    wifi_tx_power_dbm: Option<i8>,
+    // when false, only OS captive-detection paths are redirected to the captive page, others get a real 404
+    pub captive_redirect_all: bool,
+    reset_reason: ResetReason,
+    pub render_fps: u32,
     pub web_config_ip_url: String,
     pub web_config_name_url: String,
     pub web_config_key: String,
+    // interface web config was last started on, None when stopped
+    pub web_config_mode: Option<WebConfigMode>,
     pub ota_state: Option<OtaState>,
+    // an OtaRequest::Stage flashed a new firmware that waits for reboot_into_staged
+    pub ota_reboot_pending: bool,
     display_window: Option<Rc<McuWindow>>,
 
     #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
@@ -206,7 +1046,7 @@ pub struct Framework {
 impl Framework {
     pub fn new(
         settings: FrameworkSettings,
-        flash_map: Rc<RefCell<FlashMap<BlockingAsync<FlashStorage>>>>,
+        flash_map: FlashMap<BlockingAsync<FlashStorage>>,
         spawner: Spawner,
         stack: Stack<'static>,
         tls: TlsReference<'static>,
@@ -214,8 +1054,26 @@ impl Framework {
     ) -> Rc<RefCell<Self>> {
         Terminal::initialize();
 
+        crate::ntp::set_utc_offset_minutes(settings.utc_offset_minutes);
+
         let web_server_commands = crate::mk_static!(WebServerCommands, WebServerCommands::new());
 
+        let wifi_credentials_test_requests = crate::mk_static!(
+            WifiCredentialsTestRequests,
+            WifiCredentialsTestRequests::new()
+        );
+        let wifi_credentials_test_result =
+            crate::mk_static!(WifiCredentialsTestResult, WifiCredentialsTestResult::new());
+        let wifi_credentials_test_stored =
+            crate::mk_static!(WifiCredentialsTestStored, WifiCredentialsTestStored::new());
+        let wifi_scan_requests = crate::mk_static!(WifiScanRequests, WifiScanRequests::new());
+        let wifi_scan_result = crate::mk_static!(WifiScanResult, WifiScanResult::new());
+
+        let mdns_restart = crate::mk_static!(MdnsRestart, MdnsRestart::new());
+
+        let app_events = crate::mk_static!(AppEvents, AppEvents::new());
+        let touch_gestures = crate::mk_static!(TouchGestures, TouchGestures::new());
+
         let undim_display = crate::mk_static!(
             embassy_sync::signal::Signal<embassy_sync::blocking_mutex::raw::NoopRawMutex, ()>,
             embassy_sync::signal::Signal::<embassy_sync::blocking_mutex::raw::NoopRawMutex, ()>::new()
@@ -224,27 +1082,50 @@ impl Framework {
         let framework = Self {
             fixed_key: settings.default_fixed_security_key.clone(),
             device_name: None,
+            tls_config: None,
             observers: Vec::new(),
+            factory_reset_keys: Vec::new(),
             framework: None,
-            flash_map,
+            flash_map: Rc::new(Mutex::new(flash_map)),
+            config_extras: BTreeMap::new(),
             web_server_commands,
+            wifi_credentials_test_requests,
+            wifi_credentials_test_result,
+            wifi_credentials_test_stored,
+            wifi_credentials_test_available: false,
+            wifi_scan_requests,
+            wifi_scan_result,
+            mdns_restart,
+            app_events,
+            touch_gestures,
             wifi_ssid: None,
             wifi_password: None,
+            wifi_options: WifiCredentialOptions::default(),
             display_dimming_timeout: 60 * 2,
             display_dimming_percent: 10,
             display_blackout_timeout: 60 * 5,
+            display_brightness_percent: 100,
+            keep_awake_requests: 0,
             spawner,
             stack,
             tls,
             encryption_key: crate::mk_static!(RefCell<Vec<u8>>, RefCell::new(alloc::vec![])),
             undim_display,
             config_processed_ok: None,
+            config_sources: ConfigSources::default(),
             wifi_ok: None,
+            wifi_rssi: None,
+            wifi_tx_power_dbm: settings.wifi_tx_power_dbm,
+            captive_redirect_all: settings.web_server_captive_redirect_all,
+            reset_reason: ResetReason::read(),
+            render_fps: 0,
             web_config_ip_url: String::new(),
             web_config_name_url: String::new(),
             web_config_key: String::new(),
+            web_config_mode: None,
             settings,
             ota_state: None,
+            ota_reboot_pending: false,
             display_window: None,
             #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
             inner_file_store: None,
@@ -260,155 +1141,224 @@ impl Framework {
                 .ok();
         }
 
+        if framework.borrow().reset_reason == ResetReason::Brownout {
+            term_error!("Last reset was caused by a brownout, check power supply");
+        }
+
+        let iterations = framework
+            .borrow()
+            .settings
+            .web_app_key_derivation_iterations;
+        if iterations < WEB_APP_KEY_DERIVATION_MIN_ITERATIONS {
+            warn!(
+                "web_app_key_derivation_iterations is {iterations}, below the recommended minimum of {WEB_APP_KEY_DERIVATION_MIN_ITERATIONS}"
+            );
+        }
+
         framework.borrow_mut().framework = Some(framework.clone());
         framework
     }
 
     pub fn load_config_flash_then_toml(&mut self, toml_str: &str) -> Result<(), String> {
-        // Start by lading from flash, SDCard if exist will override
-        if let Ok(Some(wifi_store)) = block_on(
-            self.flash_map
-                .borrow_mut()
-                .fetch(String::from(WIFI_CONFIG_KEY)),
-        ) {
+        let stored = StoredConfig {
+            wifi: self.fetch(String::from(WIFI_CONFIG_KEY)).ok().flatten(),
+            fixed_key: self.fetch(String::from(FIXED_KEY_CONFIG_KEY)).ok().flatten(),
+            device_name: self.fetch(String::from(DEVICE_NAME_CONFIG_KEY)).ok().flatten(),
+            tls: self.fetch(String::from(TLS_CONFIG_KEY)).ok().flatten(),
+            display: self.fetch(String::from(DISPLAY_CONFIG_KEY)).ok().flatten(),
+            utc_offset: self.fetch(String::from(UTC_OFFSET_CONFIG_KEY)).ok().flatten(),
+        };
+        self.apply_config(stored, toml_str)
+    }
+
+    // Same as load_config_flash_then_toml, but yields while reading flash and doesn't hold a borrow meanwhile
+    pub async fn load_config_flash_then_toml_async(
+        framework: &Rc<RefCell<Self>>,
+        toml_str: &str,
+    ) -> Result<(), String> {
+        let stored = StoredConfig {
+            wifi: Self::fetch_async(framework, String::from(WIFI_CONFIG_KEY))
+                .await
+                .ok()
+                .flatten(),
+            fixed_key: Self::fetch_async(framework, String::from(FIXED_KEY_CONFIG_KEY))
+                .await
+                .ok()
+                .flatten(),
+            device_name: Self::fetch_async(framework, String::from(DEVICE_NAME_CONFIG_KEY))
+                .await
+                .ok()
+                .flatten(),
+            tls: Self::fetch_async(framework, String::from(TLS_CONFIG_KEY))
+                .await
+                .ok()
+                .flatten(),
+            display: Self::fetch_async(framework, String::from(DISPLAY_CONFIG_KEY))
+                .await
+                .ok()
+                .flatten(),
+            utc_offset: Self::fetch_async(framework, String::from(UTC_OFFSET_CONFIG_KEY))
+                .await
+                .ok()
+                .flatten(),
+        };
+        framework.borrow_mut().apply_config(stored, toml_str)
+    }
+
+    // Start with what's stored in flash, SDCard (toml_str) if exists overrides
+    fn apply_config(&mut self, stored: StoredConfig, toml_str: &str) -> Result<(), String> {
+        // Before any config parsing, a new firmware with a broken config file must still roll back
+        if self.settings.ota && crate::ota::boot_pending_confirmation() {
+            info!("Running new firmware, waiting for the app to confirm it");
+            self.ota_state = Some(OtaState::PendingConfirmation);
+            if let Some(timeout) = self.settings.ota_confirm_timeout {
+                self.spawner
+                    .spawn(crate::ota::ota_confirm_watchdog(
+                        timeout,
+                        self.framework.as_ref().unwrap().clone(),
+                    ))
+                    .ok();
+            }
+        }
+
+        if let Some(wifi_store) = stored.wifi {
             if let Ok(wifi_config) = serde_json::from_str::<WifiConfig>(&wifi_store) {
                 self.wifi_ssid = wifi_config.ssid.filter(|s| !s.is_empty());
                 self.wifi_password = wifi_config.password.filter(|s| !s.is_empty());
+                self.wifi_options = WifiCredentialOptions {
+                    identity: wifi_config.identity.filter(|s| !s.is_empty()),
+                    username: wifi_config.username.filter(|s| !s.is_empty()),
+                    hidden: wifi_config.hidden,
+                };
+                self.config_sources.wifi = ConfigSource::Flash;
             }
         }
 
-        if let Ok(Some(fixed_key_store)) = block_on(
-            self.flash_map
-                .borrow_mut()
-                .fetch(String::from(FIXED_KEY_CONFIG_KEY)),
-        ) {
+        if let Some(fixed_key_store) = stored.fixed_key {
             if let Ok(fixed_key_config) = serde_json::from_str::<FixedKeyConfig>(&fixed_key_store) {
                 self.fixed_key = fixed_key_config.key;
+                self.config_sources.fixed_key = ConfigSource::Flash;
             }
         }
 
-        if let Ok(Some(device_name_store)) = block_on(
-            self.flash_map
-                .borrow_mut()
-                .fetch(String::from(DEVICE_NAME_CONFIG_KEY)),
-        ) {
+        if let Some(device_name_store) = stored.device_name {
             if let Ok(device_name_config) =
                 serde_json::from_str::<DeviceNameConfig>(&device_name_store)
             {
                 self.device_name = device_name_config.name;
+                self.config_sources.device_name = ConfigSource::Flash;
             }
         }
 
-        if let Ok(Some(display_store)) = block_on(
-            self.flash_map
-                .borrow_mut()
-                .fetch(String::from(DISPLAY_CONFIG_KEY)),
-        ) {
-            if let Ok(display_config) = serde_json::from_str::<DisplayConfig>(&display_store) {
-                self.display_dimming_timeout = display_config
-                    .dimming_timeout
-                    .unwrap_or(self.display_dimming_timeout);
-                self.display_dimming_percent = display_config
-                    .dimming_percent
-                    .unwrap_or(self.display_dimming_percent);
-                self.display_blackout_timeout = display_config
-                    .blackout_timeout
-                    .unwrap_or(self.display_blackout_timeout);
+        if let Some(utc_offset_store) = stored.utc_offset {
+            if let Ok(utc_offset_config) =
+                serde_json::from_str::<UtcOffsetConfig>(&utc_offset_store)
+            {
+                crate::ntp::set_utc_offset_minutes(utc_offset_config.minutes);
             }
         }
 
-        let mut section = String::from("");
-
-        let mut parse_errors = false;
-
-        for (line_num, line) in toml_str.lines().enumerate() {
-            // Trim whitespace and ignore empty lines or comments
-            let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-            if line.starts_with("[") && line.ends_with("]") {
-                section = String::from(&line[1..line.len() - 1]);
-                continue;
+        if let Some(tls_store) = stored.tls {
+            if let Ok(tls_config) = serde_json::from_str::<TlsConfig>(&tls_store) {
+                self.tls_config = Some(tls_config);
             }
+        }
 
-            // Check if the line contains a key-value pair
-            if let Some((key, value)) = line.split_once('=') {
-                // Trim key and value to remove any surrounding whitespace
-                let key = key.trim();
-                let value = value.trim().trim_matches('"'); // Remove surrounding quotes if present
-
-                // Match the key and assign the value to the corresponding field
-                let expanded_key = format!("{}_{}", &section, &key);
-                match expanded_key.as_str() {
-                    "wifi_ssid" => {
-                        self.wifi_ssid = (!value.is_empty()).then(|| value.to_string());
-                        term_info!("Loaded WiFi credentials from SDCard (overriding Flash)");
-                    }
-                    "wifi_password" => self.wifi_password = (!value.is_empty()).then(|| value.to_string()),
-                    "fixed_key" => {
-                        self.fixed_key = Some(String::from(value));
-                    }
-                    "device_name" => {
-                        self.device_name = Some(String::from(value));
-                    }
-                    "display_dimming_timeout" => {
-                        if let Ok(display_dimming_timeout) = value.parse::<u64>() {
-                            self.display_dimming_timeout = display_dimming_timeout;
-                        } else {
-                            parse_errors = true;
-                            term_error!(
-                                "config file format error at display dimming_timeout at line {}",
-                                line_num
-                            );
-                        }
-                    }
-                    "display_dimming_percent" => {
-                        if let Ok(display_dimming_percent) = value.parse::<u8>() {
-                            self.display_dimming_percent = display_dimming_percent;
-                        } else {
-                            parse_errors = true;
-                            term_error!(
-                                "config file format error at display dimming_percent at line {}",
-                                line_num
-                            );
-                        }
-                    }
-                    "display_blackout_timeout" => {
-                        if let Ok(display_blackout_timeout) = value.parse::<u64>() {
-                            self.display_blackout_timeout = display_blackout_timeout;
-                        } else {
-                            parse_errors = true;
-                            term_error!(
-                                "config file format error at display blackout_timeout at line {}",
-                                line_num
-                            );
-                        }
-                    }
-                    _ => {
-                        // allow unknown rows because app_config might use them
-                    }
+        if let Some(display_store) = stored.display {
+            if let Ok(display_config) = serde_json::from_str::<DisplayConfig>(&display_store) {
+                if let Some(dimming_timeout) = display_config.dimming_timeout {
+                    self.display_dimming_timeout = dimming_timeout;
+                    self.config_sources.display_dimming_timeout = ConfigSource::Flash;
+                }
+                if let Some(dimming_percent) = display_config.dimming_percent {
+                    self.display_dimming_percent = dimming_percent;
+                    self.config_sources.display_dimming_percent = ConfigSource::Flash;
+                }
+                if let Some(blackout_timeout) = display_config.blackout_timeout {
+                    self.display_blackout_timeout = blackout_timeout;
+                    self.config_sources.display_blackout_timeout = ConfigSource::Flash;
+                }
+                if let Some(brightness_percent) = display_config.brightness_percent {
+                    self.display_brightness_percent = brightness_percent;
+                    self.config_sources.display_brightness_percent = ConfigSource::Flash;
                 }
-            } else {
-                // Error(warning) on general syntax error in line will be reported by app_config
             }
+        }
 
-            // TODO: add error handling with notification on missing mandatory selfs
-            if parse_errors {
+        let config_file = match ConfigFile::parse(toml_str) {
+            Ok(config_file) => config_file,
+            Err(e) => {
+                term_error!("{}", e);
                 self.config_processed_ok = Some(false);
-                return Err(String::from("Parse Error"));
+                return Err(format!("{e}"));
             }
+        };
+        if config_file.legacy_format {
+            term_info!("config file has unquoted values, please quote strings (ssid = \"x\")");
+        }
+
+        if let Some(ssid) = config_file.wifi.ssid {
+            self.wifi_ssid = (!ssid.is_empty()).then_some(ssid);
+            // options stored in flash belong to the flash network
+            self.wifi_options = WifiCredentialOptions::default();
+            self.config_sources.wifi = ConfigSource::SdCard;
+            term_info!("Loaded WiFi credentials from SDCard (overriding Flash)");
+        }
+        if let Some(password) = config_file.wifi.password {
+            self.wifi_password = (!password.is_empty()).then_some(password);
+            self.config_sources.wifi = ConfigSource::SdCard;
         }
+        if let Some(fixed_key) = config_file.fixed.key {
+            self.fixed_key = Some(fixed_key);
+            self.config_sources.fixed_key = ConfigSource::SdCard;
+        }
+        if let Some(device_name) = config_file.device.name {
+            self.device_name = Some(device_name);
+            self.config_sources.device_name = ConfigSource::SdCard;
+        }
+        if let Some(dimming_timeout) = config_file.display.dimming_timeout {
+            self.display_dimming_timeout = dimming_timeout;
+            self.config_sources.display_dimming_timeout = ConfigSource::SdCard;
+        }
+        if let Some(dimming_percent) = config_file.display.dimming_percent {
+            self.display_dimming_percent = dimming_percent;
+            self.config_sources.display_dimming_percent = ConfigSource::SdCard;
+        }
+        if let Some(blackout_timeout) = config_file.display.blackout_timeout {
+            self.display_blackout_timeout = blackout_timeout;
+            self.config_sources.display_blackout_timeout = ConfigSource::SdCard;
+        }
+        if let Some(brightness_percent) = config_file.display.brightness_percent {
+            self.display_brightness_percent = brightness_percent;
+            self.config_sources.display_brightness_percent = ConfigSource::SdCard;
+        }
+        // unknown keys are kept for the app's own settings
+        self.config_extras = config_file.extras;
+
+        // Checked on the combined values, since the SD card may override only some of them
+        if let Err(e) = validate_display_settings(
+            self.display_dimming_timeout,
+            self.display_dimming_percent,
+            self.display_blackout_timeout,
+        )
+        .and_then(|_| validate_display_brightness(self.display_brightness_percent))
+        {
+            term_error!("config file display settings error: {}", e);
+            self.config_processed_ok = Some(false);
+            return Err(String::from("Parse Error"));
+        }
+        // The ui loop picks up the loaded brightness on its next activity
+        self.undim_display();
         self.config_processed_ok = Some(true);
 
         if self.settings.mdns {
-            if self.device_name.is_some() {
-                self.spawner
-                    .spawn_heap(mdns_task(self.framework.as_ref().unwrap().clone()))
-                    .ok();
-            } else {
-                warn!("mDNS not activated - device name not configured");
+            if self.device_name.is_none() {
+                warn!("mDNS not activated until device name is configured");
             }
+            // Spawned anyway, waits for a device name to be set
+            self.spawner
+                .spawn_heap(mdns_task(self.framework.as_ref().unwrap().clone()))
+                .ok();
         }
 
         if self.settings.ntp {
@@ -472,6 +1422,11 @@ impl Framework {
         self.inner_file_store.clone().unwrap()
     }
 
+    #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+    pub fn has_file_store(&self) -> bool {
+        self.inner_file_store.is_some()
+    }
+
     pub fn set_display_window(&mut self, window: Rc<McuWindow>) {
         self.display_window = Some(window);
     }
@@ -550,9 +1505,14 @@ impl Framework {
     }
 
     pub fn initialization_ok(&self) -> bool {
-        matches!(self.config_processed_ok, Some(true))
-            && self.wifi_ssid.is_some()
-            && self.wifi_password.is_some()
+        matches!(self.config_processed_ok, Some(true)) && self.wifi_credentials_ok()
+    }
+
+    // Open networks have no password, enterprise ones can't connect without one
+    fn wifi_credentials_ok(&self) -> bool {
+        self.wifi_ssid.is_some()
+            && (!self.wifi_options.is_enterprise()
+                || self.wifi_password.as_ref().is_some_and(|p| !p.is_empty()))
     }
 
     #[allow(dead_code)]
@@ -560,7 +1520,61 @@ impl Framework {
         matches!(self.wifi_ok, Some(true))
     }
 
+    pub fn init_status(&self) -> InitStatus {
+        InitStatus {
+            config_ok: self.config_processed_ok,
+            wifi_credentials_ok: self.wifi_credentials_ok(),
+            wifi_ok: self.wifi_ok,
+            time_ok: if self.settings.ntp {
+                Some(crate::ntp::time_synced())
+            } else {
+                None
+            },
+            app_ok: None,
+            reset_reason: self.reset_reason,
+        }
+    }
+
     // General
+    // Values after merging defaults, flash and SD card config, with the source that won for each
+    pub fn effective_config(&self) -> EffectiveConfig {
+        let sources = &self.config_sources;
+        EffectiveConfig {
+            wifi_ssid: ConfigValue {
+                value: self.wifi_ssid.clone(),
+                source: sources.wifi,
+            },
+            wifi_password_set: ConfigValue {
+                value: self.wifi_password.is_some(),
+                source: sources.wifi,
+            },
+            fixed_key_set: ConfigValue {
+                value: self.fixed_key.is_some(),
+                source: sources.fixed_key,
+            },
+            device_name: ConfigValue {
+                value: self.device_name.clone(),
+                source: sources.device_name,
+            },
+            display_dimming_timeout: ConfigValue {
+                value: self.display_dimming_timeout,
+                source: sources.display_dimming_timeout,
+            },
+            display_dimming_percent: ConfigValue {
+                value: self.display_dimming_percent,
+                source: sources.display_dimming_percent,
+            },
+            display_blackout_timeout: ConfigValue {
+                value: self.display_blackout_timeout,
+                source: sources.display_blackout_timeout,
+            },
+            display_brightness_percent: ConfigValue {
+                value: self.display_brightness_percent,
+                source: sources.display_brightness_percent,
+            },
+        }
+    }
+
     pub fn reset_device_immediate(&self) {
         esp_hal::system::software_reset();
     }
@@ -610,86 +1624,392 @@ impl Framework {
         }
     }
 
+    // Factory Reset
+    pub fn register_factory_reset_keys(&mut self, keys: impl Fn() -> Vec<String> + 'static) {
+        self.factory_reset_keys.push(Box::new(keys));
+    }
+
+    // Removes all framework and registered app keys from flash, then resets the device
+    pub fn factory_reset(&mut self) {
+        info!("Factory reset, erasing stored configuration");
+        let mut keys: Vec<String> = FRAMEWORK_CONFIG_KEYS
+            .iter()
+            .map(|key| String::from(*key))
+            .collect();
+        for app_keys in self.factory_reset_keys.iter() {
+            keys.extend(app_keys());
+        }
+        for key in keys {
+            if let Err(e) = self.remove(key.clone()) {
+                error!("Factory reset failed to erase {key}: {e:?}");
+            }
+        }
+        self.reset_device_safer(None);
+    }
+
+    // Config Export/Import
+    // Flash stored configuration, encrypted with the web app key so only devices sharing the key
+    // (e.g. a common fixed key) can import it. The device name is left out unless asked for.
+    pub fn export_config(&self, include_device_name: bool) -> Result<String, ConfigTransferError> {
+        let key = self.encryption_key.borrow();
+        if key.is_empty() {
+            return Err(ConfigTransferError::NoEncryptionKey);
+        }
+        let fetch = |config_key: &str| self.fetch(String::from(config_key)).ok().flatten();
+        let config = ConfigExport {
+            wifi: fetch(WIFI_CONFIG_KEY).and_then(|store| serde_json::from_str(&store).ok()),
+            fixed_key: fetch(FIXED_KEY_CONFIG_KEY)
+                .and_then(|store| serde_json::from_str::<FixedKeyConfig>(&store).ok())
+                .and_then(|config| config.key),
+            device_name: fetch(DEVICE_NAME_CONFIG_KEY)
+                .filter(|_| include_device_name)
+                .and_then(|store| serde_json::from_str::<DeviceNameConfig>(&store).ok())
+                .and_then(|config| config.name),
+            display: fetch(DISPLAY_CONFIG_KEY).and_then(|store| serde_json::from_str(&store).ok()),
+            utc_offset_minutes: fetch(UTC_OFFSET_CONFIG_KEY)
+                .and_then(|store| serde_json::from_str::<UtcOffsetConfig>(&store).ok())
+                .map(|config| config.minutes),
+        };
+        Ok(ctr_encrypt(&key, &serde_json::to_string(&config).unwrap()))
+    }
+
+    // Applies and stores a blob from export_config. All sections are validated before any is stored,
+    // a storage error can still leave the sections stored before it.
+    pub fn import_config(&mut self, blob: &str) -> Result<(), ConfigTransferError> {
+        let decrypted = {
+            let key = self.encryption_key.borrow();
+            if key.is_empty() {
+                return Err(ConfigTransferError::NoEncryptionKey);
+            }
+            ctr_decrypt(&key, blob.as_bytes()).map_err(ConfigTransferError::Decryption)?
+        };
+        let config =
+            serde_json::from_str::<ConfigExport>(&decrypted).map_err(ConfigTransferError::Parse)?;
+
+        let wifi = config.wifi.map(|wifi| {
+            (
+                wifi.ssid.unwrap_or_default(),
+                wifi.password.unwrap_or_default(),
+                WifiCredentialOptions {
+                    identity: wifi.identity.filter(|s| !s.is_empty()),
+                    username: wifi.username.filter(|s| !s.is_empty()),
+                    hidden: wifi.hidden,
+                },
+            )
+        });
+        if let Some((ssid, password, options)) = &wifi {
+            validate_wifi_credentials_with_options(ssid, password, options)
+                .map_err(ConfigTransferError::Wifi)?;
+        }
+        if let Some(fixed_key) = config.fixed_key.as_deref().filter(|key| !key.is_empty()) {
+            validate_fixed_key(
+                fixed_key,
+                self.settings.fixed_key_min_length,
+                self.settings.fixed_key_min_char_classes,
+            )
+            .map_err(ConfigTransferError::FixedKey)?;
+        }
+        if let Some(device_name) = &config.device_name {
+            validate_device_name(device_name).map_err(ConfigTransferError::DeviceName)?;
+        }
+        let display = config.display.map(|display| {
+            (
+                display
+                    .dimming_timeout
+                    .unwrap_or(self.display_dimming_timeout),
+                display
+                    .dimming_percent
+                    .unwrap_or(self.display_dimming_percent),
+                display
+                    .blackout_timeout
+                    .unwrap_or(self.display_blackout_timeout),
+                display
+                    .brightness_percent
+                    .unwrap_or(self.display_brightness_percent),
+            )
+        });
+        if let Some((dimming_timeout, dimming_percent, blackout_timeout, brightness_percent)) =
+            display
+        {
+            validate_display_settings(dimming_timeout, dimming_percent, blackout_timeout)
+                .and_then(|_| validate_display_brightness(brightness_percent))
+                .map_err(ConfigTransferError::Display)?;
+        }
+        if let Some(minutes) = config.utc_offset_minutes {
+            if !(crate::ntp::UTC_OFFSET_MIN_MINUTES..=crate::ntp::UTC_OFFSET_MAX_MINUTES)
+                .contains(&minutes)
+            {
+                return Err(ConfigTransferError::UtcOffset(UtcOffsetError::OutOfRange(
+                    minutes,
+                )));
+            }
+        }
+
+        if let Some((ssid, password, options)) = wifi {
+            self.set_wifi_credentials_with_options(&ssid, &password, &options)
+                .map_err(ConfigTransferError::Wifi)?;
+        }
+        if let Some(fixed_key) = config.fixed_key {
+            self.set_fixed_key(&fixed_key)
+                .map_err(ConfigTransferError::FixedKey)?;
+        }
+        if let Some(device_name) = config.device_name {
+            self.set_device_name(&device_name)
+                .map_err(ConfigTransferError::DeviceName)?;
+        }
+        if let Some((dimming_timeout, dimming_percent, blackout_timeout, brightness_percent)) =
+            display
+        {
+            self.set_display_settings(dimming_timeout, dimming_percent, blackout_timeout)
+                .and_then(|_| self.set_backlight(brightness_percent))
+                .map_err(ConfigTransferError::Display)?;
+        }
+        if let Some(minutes) = config.utc_offset_minutes {
+            self.set_utc_offset(minutes)
+                .map_err(ConfigTransferError::UtcOffset)?;
+        }
+        Ok(())
+    }
+
     // Fixed Security Key
-    pub fn set_fixed_key(
-        &mut self,
-        key: &str,
-    ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
+    pub fn set_fixed_key(&mut self, key: &str) -> Result<(), FixedKeyError> {
         if key.is_empty() {
             self.fixed_key = None;
-            return embassy_futures::block_on(
-                self.flash_map
-                    .borrow_mut()
-                    .remove(String::from(FIXED_KEY_CONFIG_KEY)),
-            );
+            self.config_sources.fixed_key = ConfigSource::Default;
+            return self
+                .remove(String::from(FIXED_KEY_CONFIG_KEY))
+                .map_err(FixedKeyError::Storage);
         } else {
+            validate_fixed_key(
+                key,
+                self.settings.fixed_key_min_length,
+                self.settings.fixed_key_min_char_classes,
+            )?;
             self.fixed_key = Some(String::from(key));
+            self.config_sources.fixed_key = ConfigSource::Flash;
             let fixed_key_config = FixedKeyConfig {
                 key: Some(String::from(key)),
             };
             let fixed_key_store = serde_json::to_string(&fixed_key_config).unwrap();
             self.store(String::from(FIXED_KEY_CONFIG_KEY), fixed_key_store)
+                .map_err(FixedKeyError::Storage)
         }
     }
     pub fn erase_stored_fixed_key(&mut self) {
-        let _ = embassy_futures::block_on(
-            self.flash_map
-                .borrow_mut()
-                .remove(String::from(FIXED_KEY_CONFIG_KEY)),
-        );
+        let _ = self.remove(String::from(FIXED_KEY_CONFIG_KEY));
         self.fixed_key = self.settings.default_fixed_security_key.clone();
+        self.config_sources.fixed_key = ConfigSource::Default;
     }
 
     // Device Name
 
-    pub fn set_device_name(
-        &mut self,
-        name: &str,
-    ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
-        if name.is_empty() {
+    pub fn set_device_name(&mut self, name: &str) -> Result<(), DeviceNameError> {
+        validate_device_name(name)?;
+        let result = if name.is_empty() {
             self.device_name = None;
-            return embassy_futures::block_on(
-                self.flash_map
-                    .borrow_mut()
-                    .remove(String::from(DEVICE_NAME_CONFIG_KEY)),
-            );
+            self.config_sources.device_name = ConfigSource::Default;
+            self.remove(String::from(DEVICE_NAME_CONFIG_KEY))
         } else {
             self.device_name = Some(String::from(name));
+            self.config_sources.device_name = ConfigSource::Flash;
             let device_name_config = DeviceNameConfig {
                 name: Some(String::from(name)),
             };
             let device_name_store = serde_json::to_string(&device_name_config).unwrap();
             self.store(String::from(DEVICE_NAME_CONFIG_KEY), device_name_store)
+        };
+        // re-advertise under the new name (or stop advertising if name was cleared)
+        self.mdns_restart.signal(());
+        result.map_err(DeviceNameError::Storage)
+    }
+
+    // Local time offset from UTC, e.g. -300 for UTC-05:00, stored in flash
+    pub fn set_utc_offset(&mut self, minutes: i32) -> Result<(), UtcOffsetError> {
+        if !(crate::ntp::UTC_OFFSET_MIN_MINUTES..=crate::ntp::UTC_OFFSET_MAX_MINUTES)
+            .contains(&minutes)
+        {
+            return Err(UtcOffsetError::OutOfRange(minutes));
         }
+        crate::ntp::set_utc_offset_minutes(minutes);
+        let utc_offset_store = serde_json::to_string(&UtcOffsetConfig { minutes }).unwrap();
+        self.store(String::from(UTC_OFFSET_CONFIG_KEY), utc_offset_store)
+            .map_err(UtcOffsetError::Storage)
+    }
+    pub fn utc_offset_minutes(&self) -> i32 {
+        crate::ntp::utc_offset_minutes()
+    }
+
+    // TLS credentials, take effect on next restart. Generate unique ones per device with `xtask gen-cert`.
+    // Empty certificate and key remove the stored ones, reverting to the settings ones.
+    pub fn set_tls_credentials(
+        &mut self,
+        certificate: &str,
+        private_key: &str,
+    ) -> Result<(), TlsConfigError> {
+        if certificate.is_empty() && private_key.is_empty() {
+            self.tls_config = None;
+            return self
+                .remove(String::from(TLS_CONFIG_KEY))
+                .map_err(TlsConfigError::Storage);
+        }
+        let tls_config = TlsConfig {
+            certificate: certificate.trim_end_matches('\0').to_string(),
+            private_key: private_key.trim_end_matches('\0').to_string(),
+        };
+        // Parsed as the web server will at startup, where a bad one would fail every boot
+        crate::web_server::parse_tls_credentials(
+            &format!("{}\0", tls_config.certificate),
+            &format!("{}\0", tls_config.private_key),
+        )?;
+        let tls_store = serde_json::to_string(&tls_config).unwrap();
+        self.tls_config = Some(tls_config);
+        self.store(String::from(TLS_CONFIG_KEY), tls_store)
+            .map_err(TlsConfigError::Storage)
+    }
+
+    // Applied immediately if the radio is started, otherwise when it starts
+    pub fn set_wifi_tx_power(&mut self, dbm: i8) -> Result<(), WifiTxPowerError> {
+        if !(WIFI_TX_POWER_MIN_DBM..=WIFI_TX_POWER_MAX_DBM).contains(&dbm) {
+            return Err(WifiTxPowerError::OutOfRange(dbm));
+        }
+        self.wifi_tx_power_dbm = Some(dbm);
+        match crate::wifi::apply_wifi_tx_power(dbm) {
+            Ok(()) | Err(crate::wifi::ESP_ERR_WIFI_NOT_STARTED) => Ok(()),
+            Err(e) => Err(WifiTxPowerError::Driver(e)),
+        }
+    }
+
+    pub fn wifi_tx_power(&self) -> Option<i8> {
+        self.wifi_tx_power_dbm
     }
 
     // Wifi
     pub fn erase_stored_wifi_credentials(&mut self) {
-        let _ = embassy_futures::block_on(
-            self.flash_map
-                .borrow_mut()
-                .remove(String::from(WIFI_CONFIG_KEY)),
-        );
+        let _ = self.remove(String::from(WIFI_CONFIG_KEY));
         self.wifi_ssid = None;
         self.wifi_password = None;
+        self.wifi_options = WifiCredentialOptions::default();
+        self.config_sources.wifi = ConfigSource::Default;
     }
 
     pub fn set_wifi_credentials(
         &mut self,
         ssid: &str,
         password: &str,
-    ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
+    ) -> Result<(), WifiCredentialsError> {
+        self.set_wifi_credentials_with_options(ssid, password, &WifiCredentialOptions::default())
+    }
+
+    pub fn set_wifi_credentials_with_options(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        options: &WifiCredentialOptions,
+    ) -> Result<(), WifiCredentialsError> {
+        validate_wifi_credentials_with_options(ssid, password, options)?;
+
         self.wifi_ssid = (!ssid.is_empty()).then(|| ssid.to_string());
         self.wifi_password = (!password.is_empty()).then(|| password.to_string());
+        self.wifi_options = options.clone();
+        self.config_sources.wifi = ConfigSource::Flash;
 
         let wifi_config = WifiConfig {
             ssid: Some(String::from(ssid)),
             password: Some(String::from(password)),
+            identity: options.identity.clone(),
+            username: options.username.clone(),
+            hidden: options.hidden,
         };
 
         let wifi_store = serde_json::to_string(&wifi_config).unwrap();
 
         self.store(String::from(WIFI_CONFIG_KEY), wifi_store)
+            .map_err(WifiCredentialsError::Storage)
+    }
+
+    // not on self, since async across borrow on framework would most probably panic
+    // Credentials are persisted only if the wifi connection task managed to connect with them
+    pub async fn test_and_set_wifi_credentials(
+        framework: &Rc<RefCell<Self>>,
+        ssid: &str,
+        password: &str,
+        options: &WifiCredentialOptions,
+    ) -> Result<(), WifiCredentialsError> {
+        validate_wifi_credentials_with_options(ssid, password, options)?;
+        // The test keeps the access point up, which the enterprise station mode doesn't allow
+        if options.is_enterprise() {
+            return Err(WifiCredentialsError::EnterpriseConnectionTestUnsupported);
+        }
+
+        let (requests, result, stored) = {
+            let framework = framework.borrow();
+            if !framework.wifi_credentials_test_available {
+                return Err(WifiCredentialsError::ConnectionTestUnavailable);
+            }
+            (
+                framework.wifi_credentials_test_requests,
+                framework.wifi_credentials_test_result,
+                framework.wifi_credentials_test_stored,
+            )
+        };
+
+        result.reset();
+        stored.reset();
+        requests
+            .send((ssid.to_string(), password.to_string(), options.clone()))
+            .await;
+        if !result.wait().await {
+            return Err(WifiCredentialsError::ConnectionFailed);
+        }
+
+        // the wifi task waits for this before stopping the access point, and keeps it up if storing failed
+        let res = framework
+            .borrow_mut()
+            .set_wifi_credentials_with_options(ssid, password, options);
+        stored.signal(res.is_ok());
+        res
+    }
+
+    // not on self, the scan is done by the wifi connection task which owns the controller
+    // Returns an empty list if the scan failed or the wifi task didn't get to it in time
+    pub async fn scan_wifi(framework: &Rc<RefCell<Self>>) -> Vec<WifiNetwork> {
+        let (requests, result, scan_timeout) = {
+            let framework = framework.borrow();
+            (
+                framework.wifi_scan_requests,
+                framework.wifi_scan_result,
+                Duration::from_millis(framework.settings.improv_scan_timeout_ms),
+            )
+        };
+
+        result.reset();
+        let scan = async {
+            requests.send(()).await;
+            result.wait().await
+        };
+        // the wifi task may be busy connecting, in which case it serves the request only later
+        embassy_time::with_timeout(scan_timeout + WIFI_SCAN_WAIT_MARGIN, scan)
+            .await
+            .unwrap_or_default()
+    }
+
+    // HTTP(S) client for app use, not on self since the request is awaited.
+    // Follows redirects and returns the body of the final response, non 2xx statuses are errors.
+    pub async fn https_get(
+        framework: &Rc<RefCell<Self>>,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<Vec<u8>, HttpClientError> {
+        http_client::request(framework, edge_http::Method::Get, url, headers, None).await
+    }
+
+    pub async fn https_post(
+        framework: &Rc<RefCell<Self>>,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<Vec<u8>, HttpClientError> {
+        http_client::request(framework, edge_http::Method::Post, url, headers, Some(body)).await
     }
 
     // OTA
@@ -701,8 +2021,28 @@ impl Framework {
         info!("Checking Firmware Version Over the Air");
         self.submit_ota_request(OtaRequest::CheckVersion);
     }
+    // Downloads and flashes without restarting, call reboot_into_staged e.g. in a maintenance window
+    pub fn stage_firmware_ota(&self) {
+        info!("Staging Firmware Upgrade Over the Air");
+        self.submit_ota_request(OtaRequest::Stage);
+    }
+
+    // Restarts into the firmware flashed by an OtaRequest::Stage, returns false if there's none
+    pub fn reboot_into_staged(&self) -> bool {
+        if !self.ota_reboot_pending {
+            warn!("No staged firmware to reboot into");
+            return false;
+        }
+        info!("Rebooting into staged firmware");
+        self.reset_device_safer(Some(Duration::from_secs(5)));
+        true
+    }
 
     pub fn submit_ota_request(&self, ota_request: OtaRequest) {
+        if !self.settings.ota {
+            warn!("OTA request ignored - OTA disabled in settings");
+            return;
+        }
         if let Some(curr_ota_stae) = &self.ota_state {
             if matches!(curr_ota_stae, OtaState::Started | OtaState::InProgress(_)) {
                 return;
@@ -720,6 +2060,35 @@ impl Framework {
             .ok();
     }
 
+    // Called by the app once it verified a new firmware came up healthy (e.g. WiFi connected, display rendered),
+    // otherwise it's rolled back. Does nothing if the running firmware isn't pending confirmation.
+    pub fn confirm_ota_boot(&mut self) -> Result<(), OtaError> {
+        if !matches!(self.ota_state, Some(OtaState::PendingConfirmation)) {
+            return Ok(());
+        }
+        crate::ota::mark_boot_valid()?;
+        info!("New firmware confirmed");
+        self.ota_state = None;
+        Ok(())
+    }
+
+    // Offline update from firmware + metadata (ota.toml format) placed on the SD card,
+    // toml_path is e.g. "firmware.toml", the firmware filename inside it is relative to it
+    #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+    pub fn update_from_sd(&self, toml_path: &str) {
+        if let Some(curr_ota_stae) = &self.ota_state {
+            if matches!(curr_ota_stae, OtaState::Started | OtaState::InProgress(_)) {
+                return;
+            }
+        }
+        self.spawner
+            .spawn(crate::ota::sd_update_task(
+                toml_path.to_string(),
+                self.framework.as_ref().unwrap().clone(),
+            ))
+            .ok();
+    }
+
     // Web App
     pub fn derive_encryption_key(&self, security_key: &str) -> Vec<u8> {
         let salt: &[u8] = self.settings.web_app_salt.as_bytes();
@@ -728,7 +2097,8 @@ impl Framework {
         derive_key(security_key, salt, iterations)
     }
 
-    pub fn start_web_app(&mut self, stack: Stack<'static>, mode: WebConfigMode) {
+    // The fixed key if set, otherwise a new random one
+    fn new_web_config_key(&self) -> String {
         let mut buf_vec = alloc::vec![0; self.settings.web_app_security_key_length];
         let buf = buf_vec.as_mut_slice();
 
@@ -753,16 +2123,74 @@ impl Framework {
             let key = core::str::from_utf8(buf).unwrap();
             key_to_use = key;
         }
-        self.web_config_key = key_to_use.to_string();
+        key_to_use.to_string()
+    }
+
+    pub fn start_web_app(&mut self, stack: Stack<'static>, mode: WebConfigMode) {
+        if !self.settings.web_server {
+            return;
+        }
+        let key_to_use = self.new_web_config_key();
+        self.web_config_key = key_to_use.clone();
         self.encryption_key
-            .replace(self.derive_encryption_key(key_to_use));
+            .replace(self.derive_encryption_key(&key_to_use));
         self.web_server_commands
             .publisher()
             .unwrap()
-            .publish_immediate(WebServerCommand::Start(stack));
-        self.notify_web_config_started(key_to_use, mode);
+            .publish_immediate(WebServerCommand::Start(stack, mode));
+        self.web_config_mode = Some(mode);
+        if self.settings.web_server_bind_interface.allows(mode) {
+            self.notify_web_config_started(&key_to_use, mode);
+        } else {
+            // Other web apps may serve on this interface, but web config doesn't
+            self.notify_web_config_stopped();
+        }
     }
-    pub fn stop_web_app(&self) {
+    // Switches the running web app to a key derived from the current fixed key (a new random key
+    // if there's none), e.g. after set_fixed_key, without restarting. Requests being handled when
+    // called complete with the old key, the new one is used once none are in flight, or after
+    // WEB_KEY_ROTATION_DEADLINE regardless.
+    pub fn rotate_web_key(&mut self) {
+        let Some(mode) = self.web_config_mode else {
+            return;
+        };
+        let key_to_use = self.new_web_config_key();
+        let encryption_key = self.derive_encryption_key(&key_to_use);
+        let framework = self.framework.as_ref().unwrap().clone();
+        self.spawner
+            .spawn_heap(async move {
+                // including the request that asked for the rotation, if any
+                let deadline = embassy_time::Instant::now() + WEB_KEY_ROTATION_DEADLINE;
+                while crate::web_server::requests_in_flight() > 0 {
+                    if embassy_time::Instant::now() >= deadline {
+                        warn!("Web app key rotated with requests still in flight");
+                        break;
+                    }
+                    Timer::after(WEB_KEY_ROTATION_POLL_INTERVAL).await;
+                }
+                {
+                    let mut framework = framework.borrow_mut();
+                    // stopped meanwhile, a restart derives its own key
+                    if framework.web_config_mode.is_none() {
+                        return;
+                    }
+                    framework.encryption_key.replace(encryption_key);
+                    framework.web_config_key = key_to_use.clone();
+                }
+                // observers may access the framework themselves
+                let framework = framework.borrow();
+                if framework.settings.web_server_bind_interface.allows(mode) {
+                    framework.notify_web_config_started(&key_to_use, mode);
+                }
+                info!("Web app key rotated");
+            })
+            .ok();
+    }
+    pub fn stop_web_app(&mut self) {
+        if !self.settings.web_server {
+            return;
+        }
+        self.web_config_mode = None;
         self.web_server_commands
             .publisher()
             .unwrap()
@@ -770,25 +2198,107 @@ impl Framework {
         self.notify_web_config_stopped();
     }
 
+    // App Events
+    pub fn app_events_publisher(
+        &self,
+    ) -> Result<AppEventsPublisher, embassy_sync::pubsub::Error> {
+        self.app_events.publisher()
+    }
+    pub fn app_events_subscriber(
+        &self,
+    ) -> Result<AppEventsSubscriber, embassy_sync::pubsub::Error> {
+        self.app_events.subscriber()
+    }
+    // Fire and forget, doesn't take a publisher slot, oldest event is dropped if subscribers lag
+    pub fn publish_app_event<T: 'static>(&self, topic: &'static str, payload: T) {
+        self.app_events
+            .immediate_publisher()
+            .publish_immediate(AppEvent::new(topic, payload));
+    }
+
+    // Gestures recognized by the ui loop, requires settings.touch_gestures
+    pub fn touch_gestures_subscriber(
+        &self,
+    ) -> Result<TouchGesturesSubscriber, embassy_sync::pubsub::Error> {
+        self.touch_gestures.subscriber()
+    }
+
     // Flash Storage
+    // Async versions yield during flash operations (e.g. garbage collection) instead of stalling the executor.
+    // The blocking versions are for callers that can't await, they must not run while an async one is in progress.
+    pub async fn store_async(
+        framework: &Rc<RefCell<Self>>,
+        key: String,
+        value: String,
+    ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
+        let flash_map = framework.borrow().flash_map.clone();
+        let result = flash_map.lock().await.store(key, value).await;
+        result
+    }
+    pub async fn fetch_async(
+        framework: &Rc<RefCell<Self>>,
+        key: String,
+    ) -> Result<Option<String>, sequential_storage::Error<esp_storage::FlashStorageError>> {
+        let flash_map = framework.borrow().flash_map.clone();
+        let result = flash_map.lock().await.fetch(key).await;
+        result
+    }
+    pub async fn remove_async(
+        framework: &Rc<RefCell<Self>>,
+        key: String,
+    ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
+        let flash_map = framework.borrow().flash_map.clone();
+        let result = flash_map.lock().await.remove(key).await;
+        result
+    }
+    pub async fn keys_async(
+        framework: &Rc<RefCell<Self>>,
+        max_keys: usize,
+    ) -> Result<Vec<String>, sequential_storage::Error<esp_storage::FlashStorageError>> {
+        let flash_map = framework.borrow().flash_map.clone();
+        let result = flash_map.lock().await.keys(max_keys).await;
+        result
+    }
+    // Waiting for the lock would never end, the async holder can't make progress while this blocks.
+    // Reported as a storage I/O error instead, nothing was accessed so the caller may retry later.
+    fn blocking_flash_map(
+        &self,
+    ) -> Result<
+        MutexGuard<'_, CriticalSectionRawMutex, FlashMap<BlockingAsync<FlashStorage>>>,
+        sequential_storage::Error<esp_storage::FlashStorageError>,
+    > {
+        self.flash_map.try_lock().map_err(|_| {
+            warn!("Blocking flash access while an async one is in progress, use the async variant");
+            sequential_storage::Error::Storage {
+                value: esp_storage::FlashStorageError::IoError,
+            }
+        })
+    }
+
     pub fn store(
         &self,
         key: String,
         value: String,
     ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
-        block_on(self.flash_map.borrow_mut().store(key, value))
+        block_on(self.blocking_flash_map()?.store(key, value))
     }
     pub fn fetch(
         &self,
         key: String,
     ) -> Result<Option<String>, sequential_storage::Error<esp_storage::FlashStorageError>> {
-        block_on(self.flash_map.borrow_mut().fetch(key))
+        block_on(self.blocking_flash_map()?.fetch(key))
     }
     pub fn remove(
         &self,
         key: String,
     ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
-        block_on(self.flash_map.borrow_mut().remove(key))
+        block_on(self.blocking_flash_map()?.remove(key))
+    }
+    pub fn keys(
+        &self,
+        max_keys: usize,
+    ) -> Result<Vec<String>, sequential_storage::Error<esp_storage::FlashStorageError>> {
+        block_on(self.blocking_flash_map()?.keys(max_keys))
     }
 
     // Display
@@ -797,24 +2307,63 @@ impl Framework {
         dimming_timeout: u64,
         dimming_percent: u8,
         blackout_timeout: u64,
-    ) -> Result<(), sequential_storage::Error<esp_storage::FlashStorageError>> {
+    ) -> Result<(), DisplaySettingsError> {
+        validate_display_settings(dimming_timeout, dimming_percent, blackout_timeout)?;
         self.display_dimming_timeout = dimming_timeout;
         self.display_dimming_percent = dimming_percent;
         self.display_blackout_timeout = blackout_timeout;
-
+        self.config_sources.display_dimming_timeout = ConfigSource::Flash;
+        self.config_sources.display_dimming_percent = ConfigSource::Flash;
+        self.config_sources.display_blackout_timeout = ConfigSource::Flash;
+        self.store_display_config()
+    }
+    // User brightness, the level the display returns to when undimmed. Counts as activity.
+    pub fn set_backlight(&mut self, brightness_percent: u8) -> Result<(), DisplaySettingsError> {
+        validate_display_brightness(brightness_percent)?;
+        self.display_brightness_percent = brightness_percent;
+        self.config_sources.display_brightness_percent = ConfigSource::Flash;
+        self.undim_display();
+        self.store_display_config()
+    }
+    fn store_display_config(&self) -> Result<(), DisplaySettingsError> {
         let display_config = DisplayConfig {
-            dimming_timeout: Some(dimming_timeout),
-            dimming_percent: Some(dimming_percent),
-            blackout_timeout: Some(blackout_timeout),
+            dimming_timeout: Some(self.display_dimming_timeout),
+            dimming_percent: Some(self.display_dimming_percent),
+            blackout_timeout: Some(self.display_blackout_timeout),
+            brightness_percent: Some(self.display_brightness_percent),
         };
 
         let display_store = serde_json::to_string(&display_config).unwrap();
 
         self.store(String::from(DISPLAY_CONFIG_KEY), display_store)
+            .map_err(DisplaySettingsError::Storage)
+    }
+    // User facing text, translated if the app provided translations
+    pub fn text(&self, text: FrameworkText) -> &'static str {
+        crate::texts::lookup(self.settings.texts, text)
+    }
+    pub fn text_with(&self, text: FrameworkText, args: &[&dyn fmt::Display]) -> String {
+        crate::texts::fill(self.text(text), args)
     }
+
+    pub fn last_reset_reason(&self) -> ResetReason {
+        self.reset_reason
+    }
+
     pub fn undim_display(&self) {
         self.undim_display.signal(());
     }
+    // Keeps the display at full brightness until the returned guard is dropped
+    pub fn keep_awake(framework: &Rc<RefCell<Self>>) -> KeepAwakeGuard {
+        framework.borrow_mut().keep_awake_requests += 1;
+        framework.borrow().undim_display();
+        KeepAwakeGuard {
+            framework: framework.clone(),
+        }
+    }
+    pub fn is_kept_awake(&self) -> bool {
+        self.keep_awake_requests > 0
+    }
 
     // Observers support
     pub fn subscribe(&mut self, observer: alloc::rc::Weak<RefCell<dyn FrameworkObserver>>) {
@@ -856,11 +2405,11 @@ impl Framework {
             observer.borrow_mut().on_ota_status(text);
         }
     }
-    pub fn notify_ota_failed(&mut self, text: &str) {
-        self.ota_state = Some(OtaState::Failed(text.to_string()));
+    pub fn notify_ota_failed(&mut self, error: OtaError, text: &str) {
+        self.ota_state = Some(OtaState::Failed(error, text.to_string()));
         for weak_observer in self.observers.iter() {
             let observer = weak_observer.upgrade().unwrap();
-            observer.borrow_mut().on_ota_failed(text);
+            observer.borrow_mut().on_ota_failed(error, text);
         }
     }
     pub fn notify_ota_completed(&mut self, text: &str) {
@@ -882,14 +2431,23 @@ impl Framework {
             observer.borrow_mut().on_wifi_sta_disconnected();
         }
     }
+    // status is the app's own initialization result, reported along the framework's in InitStatus
     pub fn notify_initialization_completed(&self, status: bool) {
+        let init_status = InitStatus {
+            app_ok: Some(status),
+            ..self.init_status()
+        };
         debug!(
             "Notified on Initialization Completed {}",
             self.observers.len()
         );
         for weak_observer in self.observers.iter() {
             let observer = weak_observer.upgrade().unwrap();
-            observer.borrow_mut().on_initialization_completed(status);
+            observer.borrow_mut().on_reset_reason(self.reset_reason);
+            if self.reset_reason == ResetReason::Brownout {
+                observer.borrow_mut().on_brownout_warning();
+            }
+            observer.borrow_mut().on_initialization_completed(&init_status);
         }
     }
     pub fn notify_webapp_url_update(&self, ip_url: &str, name_url: Option<&str>, ssid: &str) {
@@ -902,13 +2460,30 @@ impl Framework {
     }
 }
 
+pub struct KeepAwakeGuard {
+    framework: Rc<RefCell<Framework>>,
+}
+
+impl Drop for KeepAwakeGuard {
+    fn drop(&mut self) {
+        let mut framework = self.framework.borrow_mut();
+        framework.keep_awake_requests = framework.keep_awake_requests.saturating_sub(1);
+    }
+}
+
 pub trait FrameworkObserver {
     fn on_webapp_url_update(&self, ip_url: &str, name_url: Option<&str>, ssid: &str);
-    fn on_initialization_completed(&self, status: bool);
+    fn on_initialization_completed(&self, status: &InitStatus);
+    // Reported once, right before on_initialization_completed
+    fn on_reset_reason(&self, _reason: ResetReason) {}
+    // Called after a reset caused by a brownout, right before on_initialization_completed.
+    // Not an early warning: esp-hal has no API for the brownout interrupt, nor for the detector threshold,
+    // which stays as the bootloader configured it (no FrameworkSettings for it).
+    fn on_brownout_warning(&self) {}
     fn on_ota_version_available(&mut self, version: &str, newer: bool);
     fn on_ota_start(&mut self);
     fn on_ota_status(&mut self, text: &str);
-    fn on_ota_failed(&mut self, text: &str);
+    fn on_ota_failed(&mut self, error: OtaError, text: &str);
     fn on_ota_completed(&mut self, text: &str);
     fn on_web_config_started(&self, key: &str, mode: WebConfigMode);
     fn on_web_config_stopped(&self);
@@ -916,6 +2491,67 @@ pub trait FrameworkObserver {
     fn on_wifi_sta_disconnected(&self);
 }
 
+// Borrow-free access to the shared framework.
+// A Ref/RefMut held across an .await stays alive while other tasks run, and any of them borrowing
+// the framework mutably (or immutably while a RefMut is held) panics with "already borrowed".
+// These methods borrow only for the duration of the call and return owned copies, so they are
+// safe to use anywhere in async code. Never keep the result of framework.borrow() across an .await.
+pub trait FrameworkAccess {
+    // Runs f with a short lived borrow, f must not await or call back into the framework borrow
+    fn with<R>(&self, f: impl FnOnce(&Framework) -> R) -> R;
+    fn with_mut<R>(&self, f: impl FnOnce(&mut Framework) -> R) -> R;
+
+    fn stack(&self) -> Stack<'static> {
+        self.with(|framework| framework.stack)
+    }
+    fn spawner(&self) -> Spawner {
+        self.with(|framework| framework.spawner)
+    }
+    fn device_name(&self) -> Option<String> {
+        self.with(|framework| framework.device_name.clone())
+    }
+    fn wifi_ssid(&self) -> Option<String> {
+        self.with(|framework| framework.wifi_ssid.clone())
+    }
+    fn wifi_password(&self) -> Option<String> {
+        self.with(|framework| framework.wifi_password.clone())
+    }
+    fn wifi_options(&self) -> WifiCredentialOptions {
+        self.with(|framework| framework.wifi_options.clone())
+    }
+    // (dimming_timeout, dimming_percent, blackout_timeout)
+    fn display_settings(&self) -> (u64, u8, u64) {
+        self.with(|framework| {
+            (
+                framework.display_dimming_timeout,
+                framework.display_dimming_percent,
+                framework.display_blackout_timeout,
+            )
+        })
+    }
+    fn app_name(&self) -> &'static str {
+        self.with(|framework| framework.settings.app_cargo_pkg_name)
+    }
+    fn app_version(&self) -> &'static str {
+        self.with(|framework| framework.settings.app_cargo_pkg_version)
+    }
+    fn ota_status(&self) -> Option<String> {
+        self.with(|framework| framework.ota_state.as_ref().map(|s| s.to_string()))
+    }
+    fn wifi_ok(&self) -> Option<bool> {
+        self.with(|framework| framework.wifi_ok)
+    }
+}
+
+impl FrameworkAccess for Rc<RefCell<Framework>> {
+    fn with<R>(&self, f: impl FnOnce(&Framework) -> R) -> R {
+        f(&self.borrow())
+    }
+    fn with_mut<R>(&self, f: impl FnOnce(&mut Framework) -> R) -> R {
+        f(&mut self.borrow_mut())
+    }
+}
+
 #[embassy_executor::task]
 pub async fn button_erase_wifi_key_and_restart_handler(
     boot_gpio: AnyPin<'static>,
@@ -18,17 +18,27 @@ use framework_macros::include_bytes_gz;
 use hmac::{Hmac, Mac};
 use pbkdf2::pbkdf2_hmac;
 use picoserve::{
-    extract::{FromRequest, State},
+    extract::{FromRequest, FromRequestParts, Query, State},
     io::Read,
     request::{RequestBody, RequestParts},
-    response::{IntoResponse, Redirect, StatusCode},
+    response::{Connection, IntoResponse, Redirect, ResponseWriter, StatusCode},
     routing::{get, get_service, post, PathRouter},
     AppWithStateBuilder, ResponseSent,
 };
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 
-use crate::{framework::Framework, ota::OtaRequest};
+use crate::{
+    framework::{
+        Framework, FrameworkAccess, FrameworkSettings, OtaState, WebConfigMode,
+        WifiCredentialOptions, WifiCredentialsError,
+    },
+    web_api::{
+        ConfigExportQueryDTO, ConfigImportDTO, DeviceNameDTO, DisplayConfigDTO, EndpointSchema,
+        FactoryResetDTO, FixedKeyConfigDTO, LogsQueryDTO, OtaRequestDTO, ResetDeviceDTO,
+        TestKeyDTO, TlsConfigDTO, WifiConfigDTO, API_ENDPOINTS, ENCRYPTION_SCHEMES,
+    },
+};
 
 #[derive(Clone, Copy)]
 pub struct Encryption(pub &'static RefCell<Vec<u8>>);
@@ -97,6 +107,22 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
             self.app_builder.build_app(),
         );
 
+        // Metrics (opt-in, unencrypted) /////////////////////////////////////////////////////////////////////////////////
+
+        let router = router.route(
+            "/metrics",
+            get(
+                move |State(FrameworkState(framework)): State<FrameworkState>| {
+                    let framework = framework.borrow();
+                    ready(if framework.settings.web_server_metrics {
+                        (StatusCode::OK, render_metrics(&framework))
+                    } else {
+                        (StatusCode::NOT_FOUND, String::new())
+                    })
+                },
+            ),
+        );
+
         // Captive portal parts ///////////////////////////////////////////////////////////////////////////////////////
 
         let router = router
@@ -117,6 +143,21 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                 )),
             );
 
+        // The pages derive the web config key from the security key with these, before any encrypted request
+        let router = router
+            .route(
+                "/api/key-params",
+                get(|State(FrameworkState(framework)): State<FrameworkState>| {
+                    ready(picoserve::response::Json(KeyParamsDTO::new(&framework)))
+                }),
+            )
+            .route(
+                "/captive/api/key-params",
+                get(|State(FrameworkState(framework)): State<FrameworkState>| {
+                    ready(picoserve::response::Json(KeyParamsDTO::new(&framework)))
+                }),
+            );
+
         let router = router.route(
             "/captive/api/test-key",
             post(
@@ -149,7 +190,7 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                                         Ok(_) => SetConfigResponseDTO { error_text: None }
                                             .ctr_encrypt(&key.borrow()),
                                         Err(e) => SetConfigResponseDTO {
-                                            error_text: Some(format!("{e:?}")),
+                                            error_text: Some(format!("{e}")),
                                         }
                                         .ctr_encrypt(&key.borrow()),
                                     }
@@ -169,21 +210,20 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
         let router = router.route(
             "/captive/api/wifi-config",
             post(
-                move |State(Encryption(key)): State<Encryption>,
-                    State(FrameworkState(framework)): State<FrameworkState>,
-                    body: String| {
-                    ready(match ctr_decrypt(&key.borrow(), body.as_bytes()) {
+                async move |State(Encryption(key)): State<Encryption>,
+                            State(FrameworkState(framework)): State<FrameworkState>,
+                            body: String| {
+                    // Not borrowed across the connection test, the key may be replaced meanwhile
+                    let decrypted = ctr_decrypt(&key.borrow(), body.as_bytes());
+                    match decrypted {
                         Ok(decrypted) => (StatusCode::OK, {
                             match serde_json::from_str::<WifiConfigDTO>(&decrypted) {
                                 Ok(wifi_config) => {
-                                    match framework.borrow_mut().set_wifi_credentials(
-                                        &wifi_config.ssid,
-                                        &wifi_config.password,
-                                    ) {
+                                    match apply_wifi_config(&framework, &wifi_config).await {
                                         Ok(_) => SetConfigResponseDTO { error_text: None }
                                             .ctr_encrypt(&key.borrow()),
                                         Err(e) => SetConfigResponseDTO {
-                                            error_text: Some(format!("{e:?}")),
+                                            error_text: Some(format!("{e}")),
                                         }
                                         .ctr_encrypt(&key.borrow()),
                                     }
@@ -195,23 +235,17 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                             }
                         }),
                         Err(e) => (StatusCode::FORBIDDEN, format!("Decryption Error: {e}")),
-                    })
+                    }
                 },
             )
             .get(async move |State(Encryption(key)): State<Encryption>, State(FrameworkState(framework)): State<FrameworkState>| {
                     WifiConfigDTO {
-                        ssid: framework
-                            .borrow()
-                            .wifi_ssid
-                            .as_ref()
-                            .unwrap_or(&String::from(""))
-                            .clone(),
-                        password: framework
-                            .borrow()
-                            .wifi_password
-                            .as_ref()
-                            .unwrap_or(&String::from(""))
-                            .clone(),
+                        ssid: framework.wifi_ssid().unwrap_or_default(),
+                        password: framework.wifi_password().unwrap_or_default(),
+                        identity: framework.wifi_options().identity,
+                        username: framework.wifi_options().username,
+                        hidden: framework.wifi_options().hidden,
+                        test_connect: false,
                     }
                     .ctr_encrypt(&key.borrow())
             }),
@@ -232,7 +266,7 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                                         Ok(_) => SetConfigResponseDTO { error_text: None }
                                             .ctr_encrypt(&key.borrow()),
                                         Err(e) => SetConfigResponseDTO {
-                                            error_text: Some(format!("{e:?}")),
+                                            error_text: Some(format!("{e}")),
                                         }
                                         .ctr_encrypt(&key.borrow()),
                                     }
@@ -249,12 +283,7 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
             )
             .get(async move |State(Encryption(key)): State<Encryption>, State(FrameworkState(framework)): State<FrameworkState>| {
                     DeviceNameDTO {
-                        name: framework
-                            .borrow()
-                            .device_name
-                            .as_ref()
-                            .unwrap_or(&String::from(""))
-                            .clone(),
+                        name: framework.device_name().unwrap_or_default(),
                     }
                     .ctr_encrypt(&key.borrow())
             }),
@@ -313,31 +342,19 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
         let router = router.route(
             "/api/wifi-config",
             post(
-                move |State(Encryption(key)): State<Encryption>,
-                      State(FrameworkState(framework)): State<FrameworkState>,
-                      WifiConfigDTO { ssid, password }| {
-                    // NOTE: ready is used here, I'm not fully clear why it's required but it is.
-                    // It has to do with the method not being async and th need to borrow together.
-                    // If I do async then I get issue with borrowing moved data.
-                    // If I don't do async no the result (which is not future) then I have issue with borrow.
-                    // Could be that if key will not be borrowed, or if like with picoserve Json there will be
-                    //   an impl of future to the result (then need something other than String),
-                    // it will be solved.
-                    // So if need async here, need to search for proper solution
-                    ready(
-                        match framework
-                            .borrow_mut()
-                            .set_wifi_credentials(&ssid, &password)
-                        {
-                            Ok(_) => {
-                                SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow())
-                            }
-                            Err(e) => SetConfigResponseDTO {
-                                error_text: Some(format!("{e:?}")),
-                            }
-                            .encrypt(&key.borrow()),
-                        },
-                    )
+                async move |State(Encryption(key)): State<Encryption>,
+                            State(FrameworkState(framework)): State<FrameworkState>,
+                            wifi_config: WifiConfigDTO| {
+                    // Async since optionally waits for the connection test to complete
+                    // Order matter, state first, post data last
+                    match apply_wifi_config(&framework, &wifi_config).await {
+                        Ok(_) => SetConfigResponseDTO { error_text: None }
+                            .encrypt(&key.borrow(), "/api/wifi-config"),
+                        Err(e) => SetConfigResponseDTO {
+                            error_text: Some(format!("{e}")),
+                        }
+                        .encrypt(&key.borrow(), "/api/wifi-config"),
+                    }
                 },
             )
             .get(
@@ -345,20 +362,14 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                       State(FrameworkState(framework)): State<FrameworkState>| {
                     ready(
                         WifiConfigDTO {
-                            ssid: framework
-                                .borrow()
-                                .wifi_ssid
-                                .as_ref()
-                                .unwrap_or(&String::from(""))
-                                .clone(),
-                            password: framework
-                                .borrow()
-                                .wifi_password
-                                .as_ref()
-                                .unwrap_or(&String::from(""))
-                                .clone(),
+                            ssid: framework.wifi_ssid().unwrap_or_default(),
+                            password: framework.wifi_password().unwrap_or_default(),
+                            identity: framework.wifi_options().identity,
+                            username: framework.wifi_options().username,
+                            hidden: framework.wifi_options().hidden,
+                            test_connect: false,
                         }
-                        .encrypt(&key.borrow()),
+                        .encrypt(&key.borrow(), "/api/wifi-config"),
                     )
                 },
             ),
@@ -371,11 +382,12 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                       State(FrameworkState(framework)): State<FrameworkState>,
                       DeviceNameDTO { name }| {
                     ready(match framework.borrow_mut().set_device_name(&name) {
-                        Ok(_) => SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow()),
+                        Ok(_) => SetConfigResponseDTO { error_text: None }
+                            .encrypt(&key.borrow(), "/api/device-name-config"),
                         Err(e) => SetConfigResponseDTO {
-                            error_text: Some(format!("{e:?}")),
+                            error_text: Some(format!("{e}")),
                         }
-                        .encrypt(&key.borrow()),
+                        .encrypt(&key.borrow(), "/api/device-name-config"),
                     })
                 },
             )
@@ -384,14 +396,9 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                       State(FrameworkState(framework)): State<FrameworkState>| {
                     ready(
                         DeviceNameDTO {
-                            name: framework
-                                .borrow()
-                                .device_name
-                                .as_ref()
-                                .unwrap_or(&String::from(""))
-                                .clone(),
+                            name: framework.device_name().unwrap_or_default(),
                         }
-                        .encrypt(&key.borrow()),
+                        .encrypt(&key.borrow(), "/api/device-name-config"),
                     )
                 },
             ),
@@ -404,7 +411,73 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                       State(FrameworkState(framework)): State<FrameworkState>,
                       ResetDeviceDTO {}| {
                     framework.borrow_mut().reset_device_safer(None);
-                    ready(SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow()))
+                    ready(
+                        SetConfigResponseDTO { error_text: None }
+                            .encrypt(&key.borrow(), "/api/reset-device"),
+                    )
+                },
+            ),
+        );
+
+        let router = router.route(
+            "/api/factory-reset",
+            post(
+                move |State(Encryption(key)): State<Encryption>,
+                      State(FrameworkState(framework)): State<FrameworkState>,
+                      FactoryResetDTO {}| {
+                    framework.borrow_mut().factory_reset();
+                    ready(
+                        SetConfigResponseDTO { error_text: None }
+                            .encrypt(&key.borrow(), "/api/factory-reset"),
+                    )
+                },
+            ),
+        );
+
+        let router = router.route(
+            "/api/config-export",
+            get(
+                move |State(Encryption(key)): State<Encryption>,
+                      State(FrameworkState(framework)): State<FrameworkState>,
+                      accepts_gzip: AcceptsGzip,
+                      Query(ConfigExportQueryDTO {
+                          include_device_name,
+                      }): Query<ConfigExportQueryDTO>| {
+                    let result = framework
+                        .borrow()
+                        .export_config(include_device_name.unwrap_or(false));
+                    ready(MaybeGzip::new(
+                        &framework,
+                        accepts_gzip,
+                        match result {
+                            Ok(blob) => ConfigBlobDTO {
+                                blob: Some(blob),
+                                error_text: None,
+                            },
+                            Err(e) => ConfigBlobDTO {
+                                blob: None,
+                                error_text: Some(format!("{e}")),
+                            },
+                        }
+                        .encrypt(&key.borrow(), "/api/config-export"),
+                    ))
+                },
+            ),
+        );
+
+        let router = router.route(
+            "/api/config-import",
+            post(
+                move |State(Encryption(key)): State<Encryption>,
+                      State(FrameworkState(framework)): State<FrameworkState>,
+                      ConfigImportDTO { blob }| {
+                    let result = framework.borrow_mut().import_config(&blob);
+                    ready(
+                        SetConfigResponseDTO {
+                            error_text: result.err().map(|e| format!("{e}")),
+                        }
+                        .encrypt(&key.borrow(), "/api/config-import"),
+                    )
                 },
             ),
         );
@@ -425,13 +498,12 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                             dimming_percent,
                             blackout_timeout,
                         ) {
-                            Ok(_) => {
-                                SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow())
-                            }
+                            Ok(_) => SetConfigResponseDTO { error_text: None }
+                                .encrypt(&key.borrow(), "/api/display-config"),
                             Err(e) => SetConfigResponseDTO {
-                                error_text: Some(format!("{e:?}")),
+                                error_text: Some(format!("{e}")),
                             }
-                            .encrypt(&key.borrow()),
+                            .encrypt(&key.borrow(), "/api/display-config"),
                         },
                     )
                 },
@@ -439,14 +511,15 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
             .get(
                 move |State(Encryption(key)): State<Encryption>,
                       State(FrameworkState(framework)): State<FrameworkState>| {
-                    let framework = framework.borrow();
+                    let (dimming_timeout, dimming_percent, blackout_timeout) =
+                        framework.display_settings();
                     ready(
                         DisplayConfigDTO {
-                            dimming_timeout: framework.display_dimming_timeout,
-                            dimming_percent: framework.display_dimming_percent,
-                            blackout_timeout: framework.display_blackout_timeout,
+                            dimming_timeout,
+                            dimming_percent,
+                            blackout_timeout,
                         }
-                        .encrypt(&key.borrow()),
+                        .encrypt(&key.borrow(), "/api/display-config"),
                     )
                 },
             ),
@@ -458,7 +531,7 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                 async move |State(Encryption(key)): State<Encryption>,
                             TestKeyDTO { test: _test }| {
                     // Order matter, state first, post data last
-                    TestKeyResponseDTO { error_text: None }.encrypt(&key.borrow())
+                    TestKeyResponseDTO { error_text: None }.encrypt(&key.borrow(), "/api/test-key")
                 },
             ),
         );
@@ -470,12 +543,66 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                       State(FrameworkState(framework)): State<FrameworkState>,
                       FixedKeyConfigDTO { key: fixed_key }| {
                     ready(match framework.borrow_mut().set_fixed_key(&fixed_key) {
-                        Ok(_) => SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow()),
+                        Ok(_) => SetConfigResponseDTO { error_text: None }
+                            .encrypt(&key.borrow(), "/api/fixed-key-config"),
                         Err(e) => SetConfigResponseDTO {
-                            error_text: Some(format!("{e:?}")),
+                            error_text: Some(format!("{e}")),
                         }
-                        .encrypt(&key.borrow()),
+                        .encrypt(&key.borrow(), "/api/fixed-key-config"),
+                    })
+                },
+            ),
+        );
+
+        let router = router.route(
+            "/api/tls-config",
+            post(
+                move |State(Encryption(key)): State<Encryption>,
+                      State(FrameworkState(framework)): State<FrameworkState>,
+                      TlsConfigDTO {
+                    certificate,
+                    private_key,
+                }| {
+                    ready(
+                        match framework
+                            .borrow_mut()
+                            .set_tls_credentials(&certificate, &private_key)
+                        {
+                            Ok(_) => SetConfigResponseDTO { error_text: None }
+                                .encrypt(&key.borrow(), "/api/tls-config"),
+                            Err(e) => SetConfigResponseDTO {
+                                error_text: Some(format!("{e}")),
+                            }
+                            .encrypt(&key.borrow(), "/api/tls-config"),
+                        },
+                    )
+                },
+            ),
+        );
+
+        #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+        let router = router.route(
+            "/download/log.txt",
+            post(
+                async move |State(FrameworkState(framework)): State<FrameworkState>,
+                            DownloadRequestDTO {}| {
+                    // Encrypted (empty) request body proves the caller knows the key, the page saves the
+                    // response as a file. Streamed since the log may be much larger than memory.
+                    let path = framework.borrow().settings.web_server_log_download_path;
+                    let Some(path) = path.filter(|_| framework.borrow().has_file_store()) else {
+                        return Err((StatusCode::NOT_FOUND, "Log download not available"));
+                    };
+                    let file_store = framework.borrow().file_store();
+                    let Ok(length) = file_store.lock().await.file_length(path).await else {
+                        return Err((StatusCode::NOT_FOUND, "Log file not found"));
+                    };
+                    Ok(picoserve::response::Response::ok(SdFileContent {
+                        framework: framework.clone(),
+                        path: path.to_string(),
+                        content_type: "text/plain; charset=utf-8",
+                        length,
                     })
+                    .with_header("Content-Disposition", "attachment; filename=\"log.txt\""))
                 },
             ),
         );
@@ -488,32 +615,79 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
                       OtaRequestDTO { request }| {
                     ready({
                         framework.borrow().submit_ota_request(request);
-                        SetConfigResponseDTO { error_text: None }.encrypt(&key.borrow())
+                        SetConfigResponseDTO { error_text: None }
+                            .encrypt(&key.borrow(), "/api/ota-request")
                     })
                 },
             ),
         );
 
         let router = router.route(
-            "/api/ota-config",
+            "/api/device-info",
             get(
                 move |State(Encryption(key)): State<Encryption>,
                       State(FrameworkState(framework)): State<FrameworkState>| {
                     let framework = framework.borrow();
+                    ready(
+                        DeviceInfoDTO {
+                            app_name: framework.settings.app_cargo_pkg_name.to_string(),
+                            app_version: framework.settings.app_cargo_pkg_version.to_string(),
+                            device_name: framework.device_name.clone(),
+                            uptime_secs: embassy_time::Instant::now().as_secs(),
+                            reset_reason: framework.last_reset_reason().to_string(),
+                        }
+                        .encrypt(&key.borrow(), "/api/device-info"),
+                    )
+                },
+            ),
+        );
+
+        let router = router.route(
+            "/api/logs",
+            get(
+                move |State(Encryption(key)): State<Encryption>,
+                      State(FrameworkState(framework)): State<FrameworkState>,
+                      accepts_gzip: AcceptsGzip,
+                      Query(LogsQueryDTO { since }): Query<LogsQueryDTO>| {
+                    let (lines, next_seq) =
+                        crate::terminal::term().recent_since(since.unwrap_or(0));
+                    ready(MaybeGzip::new(
+                        &framework,
+                        accepts_gzip,
+                        LogsDTO { lines, next_seq }.encrypt(&key.borrow(), "/api/logs"),
+                    ))
+                },
+            ),
+        );
+
+        let router = router.route(
+            "/api/ota-config",
+            get(
+                move |State(Encryption(key)): State<Encryption>,
+                      State(FrameworkState(framework)): State<FrameworkState>| {
                     ready(
                         OtaStatusDTO {
-                            status: framework
-                                .ota_state
-                                .as_ref()
-                                .map_or(String::new(), |s| s.to_string()),
-                            curr_ver: framework.settings.app_cargo_pkg_version.to_string(),
+                            status: framework.ota_status().unwrap_or_default(),
+                            curr_ver: framework.app_version().to_string(),
                         }
-                        .encrypt(&key.borrow()),
+                        .encrypt(&key.borrow(), "/api/ota-config"),
                     )
                 },
             ),
         );
 
+        // API description (unencrypted, shapes only) /////////////////////////////////////////////////////////////////
+
+        let router = router.route(
+            "/api/schema",
+            get(|| {
+                ready(picoserve::response::Json(ApiSchemaDTO {
+                    encryption: ENCRYPTION_SCHEMES,
+                    endpoints: API_ENDPOINTS,
+                }))
+            }),
+        );
+
         router
     }
 }
@@ -522,23 +696,54 @@ pub struct CustomNotFound {
     pub web_server_captive: bool,
 }
 
+// Paths probed by OS captive portal detection, still redirected when captive_redirect_all is off
+const CAPTIVE_DETECTION_PATHS: &[&str] = &[
+    "/generate_204",        // Android
+    "/gen_204",             // Android
+    "/hotspot-detect.html", // Apple
+    "/library/test/success.html",
+    "/connecttest.txt", // Windows
+    "/ncsi.txt",        // Windows
+    "/redirect",        // Windows
+    "/success.txt",     // Firefox
+    "/canonical.html",  // Firefox
+    "/mobile/status.php",         // Android
+    "/check_network_status.txt",  // Android
+    "/kindle-wifi/wifistub.html", // Kindle
+];
+
 impl<MoreState> picoserve::routing::PathRouterService<WebAppState<MoreState>> for CustomNotFound {
     async fn call_path_router_service<
         R: picoserve::io::Read,
         W: picoserve::response::ResponseWriter<Error = R::Error>,
     >(
         &self,
-        _state: &WebAppState<MoreState>,
+        state: &WebAppState<MoreState>,
         _path_parameters: (),
         path: picoserve::request::Path<'_>,
         request: picoserve::request::Request<'_, R>,
         response_writer: W,
     ) -> Result<picoserve::ResponseSent, W::Error> {
-        debug!("Redirecting request from '{}' to: '/'", path);
-        if self.web_server_captive {
-            // TODO: Theoretically, this should be only when in AP mode
-            // TODO: probably best to handle only captive urls, for now everything. Could be confusing in case of not captive/AP
-            Redirect::to("/captive")
+        #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+        if request.parts.method() == "GET" {
+            if let Some(content) = sd_asset(&state.framework.0, &format!("{path}")).await {
+                return picoserve::response::Response::ok(content)
+                    .write_to(request.body_connection.finalize().await?, response_writer)
+                    .await;
+            }
+        }
+        let redirect = {
+            let framework = state.framework.0.borrow();
+            // Only clients of the device's own access point are behind the captive portal
+            self.web_server_captive
+                && matches!(framework.web_config_mode, Some(WebConfigMode::AP))
+                && (framework.captive_redirect_all
+                    || CAPTIVE_DETECTION_PATHS.contains(&format!("{path}").as_str()))
+        };
+        if redirect {
+            let location = captive_location(&state.framework.0.borrow().settings);
+            debug!("Redirecting request from '{}' to: '{}'", path, location);
+            Redirect::to(location)
                 .write_to(request.body_connection.finalize().await?, response_writer)
                 .await
         } else {
@@ -549,10 +754,58 @@ impl<MoreState> picoserve::routing::PathRouterService<WebAppState<MoreState>> fo
     }
 }
 
+// When served over both http and https, probes arriving on the plaintext listener are sent to the
+// https one so the captive page (and its key exchange) always runs over TLS
+fn captive_location(settings: &FrameworkSettings) -> String {
+    if settings.web_server_https && settings.web_server_http_port.is_some() {
+        let port = if settings.web_server_port == 443 {
+            String::new()
+        } else {
+            format!(":{}", settings.web_server_port)
+        };
+        format!("https://{}{port}/captive", settings.web_app_domain)
+    } else {
+        "/captive".to_string()
+    }
+}
+
+fn render_metrics(framework: &Framework) -> String {
+    let ota_state = match framework.ota_state {
+        None => "idle",
+        Some(OtaState::VersionAvailable(..)) => "version_available",
+        Some(OtaState::Started) => "started",
+        Some(OtaState::InProgress(_)) => "in_progress",
+        Some(OtaState::Failed(..)) => "failed",
+        Some(OtaState::Completed(_)) => "completed",
+        Some(OtaState::PendingConfirmation) => "pending_confirmation",
+    };
+    let mut metrics = format!(
+        "# TYPE uptime_seconds counter\n\
+         uptime_seconds {}\n\
+         # TYPE heap_free_bytes gauge\n\
+         heap_free_bytes {}\n\
+         # TYPE heap_used_bytes gauge\n\
+         heap_used_bytes {}\n\
+         # TYPE render_fps gauge\n\
+         render_fps {}\n\
+         # TYPE ota_state gauge\n\
+         ota_state{{state=\"{ota_state}\"}} 1\n",
+        embassy_time::Instant::now().as_secs(),
+        esp_alloc::HEAP.free(),
+        esp_alloc::HEAP.used(),
+        framework.render_fps,
+    );
+    if let Some(rssi) = framework.wifi_rssi {
+        metrics.push_str(&format!("# TYPE wifi_rssi_dbm gauge\nwifi_rssi_dbm {rssi}\n"));
+    }
+    metrics
+}
+
 // Macro has to be used prior to usage, it is for encryption reasons (encryption code comes later)
 #[macro_export]
 macro_rules! encrypted_input {
-    ($type:ident) => {
+    // aad is the endpoint path, so a payload captured from one endpoint doesn't decrypt on another
+    ($type:ident, $aad:expr) => {
         impl<'r, MoreState> FromRequest<'r, WebAppState<MoreState>> for $type {
             type Rejection = EncryptedRejection;
 
@@ -561,12 +814,16 @@ macro_rules! encrypted_input {
                 _request_parts: RequestParts<'r>,
                 request_body: RequestBody<'r, R>,
             ) -> Result<Self, Self::Rejection> {
+                let max_body_size = state.framework.0.borrow().settings.web_server_max_body_size;
+                if request_body.content_length() > max_body_size {
+                    return Err(EncryptedRejection::PayloadTooLarge(max_body_size));
+                }
                 let encrypted_data = request_body
                     .read_all()
                     .await
                     .map_err(|_| EncryptedRejection::IoError)?;
                 let key = state.encryption.0;
-                let decrypted_data = decrypt(&key.borrow(), encrypted_data)
+                let decrypted_data = decrypt(&key.borrow(), encrypted_data, $aad.as_bytes())
                     .map_err(|e| EncryptedRejection::DecryptionError(e))?;
 
                 (serde_json::from_str(&decrypted_data) as Result<$type, _>)
@@ -587,6 +844,10 @@ macro_rules! not_encrypted_input {
                 _request_parts: RequestParts<'r>,
                 request_body: RequestBody<'r, R>,
             ) -> Result<Self, Self::Rejection> {
+                let max_body_size = state.framework.0.borrow().settings.web_server_max_body_size;
+                if request_body.content_length() > max_body_size {
+                    return Err(EncryptedRejection::PayloadTooLarge(max_body_size));
+                }
                 let raw_input = request_body
                     .read_all()
                     .await
@@ -599,32 +860,52 @@ macro_rules! not_encrypted_input {
     };
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct WifiConfigDTO {
-    ssid: String,
-    password: String,
-}
-encrypted_input!(WifiConfigDTO);
+encrypted_input!(WifiConfigDTO, "/api/wifi-config");
 impl EncryptableCTR for WifiConfigDTO {}
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct DeviceNameDTO {
-    name: String,
+async fn apply_wifi_config(
+    framework: &Rc<RefCell<Framework>>,
+    wifi_config: &WifiConfigDTO,
+) -> Result<(), WifiCredentialsError> {
+    let options = WifiCredentialOptions {
+        identity: wifi_config.identity.clone().filter(|s| !s.is_empty()),
+        username: wifi_config.username.clone().filter(|s| !s.is_empty()),
+        hidden: wifi_config.hidden,
+    };
+    if wifi_config.test_connect {
+        Framework::test_and_set_wifi_credentials(
+            framework,
+            &wifi_config.ssid,
+            &wifi_config.password,
+            &options,
+        )
+        .await
+    } else {
+        framework.borrow_mut().set_wifi_credentials_with_options(
+            &wifi_config.ssid,
+            &wifi_config.password,
+            &options,
+        )
+    }
 }
-encrypted_input!(DeviceNameDTO);
+
+encrypted_input!(DeviceNameDTO, "/api/device-name-config");
 impl EncryptableCTR for DeviceNameDTO {}
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct ResetDeviceDTO {}
-encrypted_input!(ResetDeviceDTO);
+encrypted_input!(ResetDeviceDTO, "/api/reset-device");
+
+encrypted_input!(FactoryResetDTO, "/api/factory-reset");
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct DisplayConfigDTO {
-    dimming_timeout: u64,
-    dimming_percent: u8,
-    blackout_timeout: u64,
+// blob is Framework::export_config's output, opaque to the web app
+#[derive(Serialize)]
+struct ConfigBlobDTO {
+    blob: Option<String>,
+    error_text: Option<String>,
 }
-encrypted_input!(DisplayConfigDTO);
+
+encrypted_input!(ConfigImportDTO, "/api/config-import");
+
+encrypted_input!(DisplayConfigDTO, "/api/display-config");
 
 #[derive(serde::Serialize)]
 pub struct SetConfigResponseDTO {
@@ -632,28 +913,245 @@ pub struct SetConfigResponseDTO {
 }
 impl EncryptableCTR for SetConfigResponseDTO {}
 
+encrypted_input!(TestKeyDTO, "/api/test-key");
+
+encrypted_input!(FixedKeyConfigDTO, "/api/fixed-key-config");
+
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
 #[derive(Deserialize)]
-struct TestKeyDTO {
-    test: String,
+struct DownloadRequestDTO {}
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+encrypted_input!(DownloadRequestDTO, "/download/log.txt");
+
+// Streams a file from the SD card in chunks, the file store is locked only per chunk
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+struct SdFileContent {
+    framework: Rc<RefCell<Framework>>,
+    path: String,
+    content_type: &'static str,
+    length: u32,
 }
-encrypted_input!(TestKeyDTO);
 
-#[derive(Deserialize)]
-struct FixedKeyConfigDTO {
-    key: String,
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+impl picoserve::response::Content for SdFileContent {
+    fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    fn content_length(&self) -> usize {
+        self.length as usize
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, mut writer: W) -> Result<(), W::Error> {
+        let mut buf = alloc::vec![0u8; 1024];
+        let mut offset = 0;
+        while offset < self.length {
+            let chunk_len = buf.len().min((self.length - offset) as usize);
+            let file_store = self.framework.borrow().file_store();
+            let len = match file_store
+                .lock()
+                .await
+                .read_file_bytes_at(&self.path, offset, &mut buf[..chunk_len])
+                .await
+            {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("Failed reading {} for download: {e:?}", self.path);
+                    0
+                }
+            };
+            if len == 0 {
+                // Shorter than announced, client sees a truncated download
+                break;
+            }
+            writer.write_all(&buf[..len]).await?;
+            offset += len as u32;
+        }
+        Ok(())
+    }
+}
+
+// File under web_server_sd_assets_dir for a request path, None if not configured, not found or
+// the path tries to leave the directory
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+async fn sd_asset(framework: &Rc<RefCell<Framework>>, path: &str) -> Option<SdFileContent> {
+    let dir = framework.borrow().settings.web_server_sd_assets_dir?;
+    if !framework.borrow().has_file_store() {
+        return None;
+    }
+    let mut relative = path.trim_start_matches('/').to_string();
+    if relative.is_empty() || relative.ends_with('/') {
+        relative.push_str("index.htm");
+    }
+    if relative.contains('\\')
+        || relative
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        warn!("Refusing SD asset path '{path}'");
+        return None;
+    }
+    let full_path = format!("{}/{relative}", dir.trim_end_matches('/'));
+    let file_store = framework.borrow().file_store();
+    let length = file_store.lock().await.file_length(&full_path).await.ok()?;
+    Some(SdFileContent {
+        framework: framework.clone(),
+        content_type: content_type_for(&full_path),
+        path: full_path,
+        length,
+    })
+}
+
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+fn content_type_for(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "htm" | "html" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+// Whether the request's Accept-Encoding lists gzip
+pub struct AcceptsGzip(pub bool);
+
+impl<'r, State> FromRequestParts<'r, State> for AcceptsGzip {
+    type Rejection = core::convert::Infallible;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        let accepts_gzip = request_parts
+            .headers()
+            .get("Accept-Encoding")
+            .and_then(|value| value.as_str().ok())
+            .is_some_and(|value| {
+                value.split(',').any(|encoding| {
+                    let encoding = encoding.split(';').next().unwrap_or_default();
+                    encoding.trim().eq_ignore_ascii_case("gzip")
+                })
+            });
+        Ok(AcceptsGzip(accepts_gzip))
+    }
+}
+
+// Text response, gzipped when the client accepts it and it reaches web_server_gzip_min_size
+pub struct MaybeGzip {
+    body: String,
+    gzip: bool,
 }
-encrypted_input!(FixedKeyConfigDTO);
+
+impl MaybeGzip {
+    pub fn new(
+        framework: &Rc<RefCell<Framework>>,
+        accepts_gzip: AcceptsGzip,
+        body: String,
+    ) -> Self {
+        let min_size = framework.borrow().settings.web_server_gzip_min_size;
+        let gzip = accepts_gzip.0 && min_size.is_some_and(|min_size| body.len() >= min_size);
+        Self { body, gzip }
+    }
+}
+
+impl IntoResponse for MaybeGzip {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        if !self.gzip {
+            return self.body.write_to(connection, response_writer).await;
+        }
+        picoserve::response::Response::ok(GzipContent(gzip(self.body.as_bytes())))
+            .with_header("Content-Encoding", "gzip")
+            .with_header("Vary", "Accept-Encoding")
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+struct GzipContent(Vec<u8>);
+
+impl picoserve::response::Content for GzipContent {
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, mut writer: W) -> Result<(), W::Error> {
+        writer.write_all(&self.0).await
+    }
+}
+
+// RFC 1952 member: fixed header (no file name or time, unknown OS), deflate data, crc32 and size
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gzipped = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    gzipped.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(data, 6));
+    gzipped.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+    gzipped.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    gzipped
+}
+
+encrypted_input!(TlsConfigDTO, "/api/tls-config");
 
 #[derive(Serialize)]
 struct TestKeyResponseDTO {
     error_text: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct OtaRequestDTO {
-    request: OtaRequest,
+encrypted_input!(OtaRequestDTO, "/api/ota-request");
+
+#[derive(Serialize)]
+struct DeviceInfoDTO {
+    app_name: String,
+    app_version: String,
+    device_name: Option<String>,
+    uptime_secs: u64,
+    reset_reason: String,
+}
+
+#[derive(Serialize)]
+struct LogsDTO {
+    lines: Vec<String>,
+    next_seq: u64,
+}
+
+// Not secret, the salt only keeps precomputed tables of common security keys from being reused across apps
+#[derive(Serialize)]
+struct KeyParamsDTO {
+    salt: &'static str,
+    iterations: u32,
+}
+
+impl KeyParamsDTO {
+    fn new(framework: &Rc<RefCell<Framework>>) -> Self {
+        let settings = &framework.borrow().settings;
+        Self {
+            salt: settings.web_app_salt,
+            iterations: settings.web_app_key_derivation_iterations,
+        }
+    }
 }
-encrypted_input!(OtaRequestDTO);
 
 #[derive(Serialize)]
 struct OtaStatusDTO {
@@ -661,17 +1159,34 @@ struct OtaStatusDTO {
     curr_ver: String,
 }
 
+// Machine readable description of the config API served at /api/schema, for third party provisioning tools.
+// The endpoints are described in web_api.rs, next to the request DTOs.
+#[derive(Serialize)]
+struct ApiSchemaDTO {
+    encryption: &'static [(&'static str, &'static str)],
+    endpoints: &'static [EndpointSchema],
+}
+
 /////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 // AES-GCM Encryption ///////////////////////////////////////////////////////////////////////////////////////////////////////////////
 /////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+// Base64 (no padding) lengths of the fixed size prefixes of encrypted payloads
+const GCM_IV_BASE64_LEN: usize = 16; // 12 bytes
+const CTR_HMAC_BASE64_LEN: usize = 43; // 32 bytes
+const CTR_IV_BASE64_LEN: usize = 22; // 16 bytes
+
 pub fn derive_key(key: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
     let mut key_bytes = vec![0u8; 32]; // 32-byte key for AES-256
     pbkdf2_hmac::<Sha256>(key.as_bytes(), salt, iterations, &mut key_bytes);
     key_bytes
 }
 
-pub fn encrypt_bytes(key_bytes: &[u8], data: &[u8]) -> String {
+// Prefixed to GCM payloads, ':' isn't a base64 character so unversioned (pre AAD) payloads are rejected
+const GCM_FORMAT_PREFIX: &str = "v2:";
+
+// aad (additional authenticated data) isn't sent, the receiver must pass the same one to decrypt
+pub fn encrypt_bytes(key_bytes: &[u8], data: &[u8], aad: &[u8]) -> String {
     // Derive key (32 bytes from a user-provided key)
 
     assert!(!key_bytes.is_empty());
@@ -687,10 +1202,10 @@ pub fn encrypt_bytes(key_bytes: &[u8], data: &[u8]) -> String {
 
     // Encrypt the data
     let ciphertext = cipher
-        .encrypt(iv, Payload::from(data))
+        .encrypt(iv, Payload { msg: data, aad })
         .expect("Encryption here should not fail"); // only memory issue?
     let res = format!(
-        "{}{}",
+        "{GCM_FORMAT_PREFIX}{}{}",
         STANDARD_NO_PAD.encode(iv),
         STANDARD_NO_PAD.encode(ciphertext)
     );
@@ -698,33 +1213,47 @@ pub fn encrypt_bytes(key_bytes: &[u8], data: &[u8]) -> String {
     res
 }
 
-pub fn encrypt(key_bytes: &[u8], data: &str) -> String {
-    encrypt_bytes(key_bytes, data.as_bytes())
+pub fn encrypt(key_bytes: &[u8], data: &str, aad: &[u8]) -> String {
+    encrypt_bytes(key_bytes, data.as_bytes(), aad)
 }
 
-pub fn decrypt(key_bytes: &[u8], encrypted: &[u8]) -> Result<String, String> {
+pub fn decrypt(key_bytes: &[u8], encrypted: &[u8], aad: &[u8]) -> Result<String, String> {
     //Derive key (32 bytes from a user-provided key)
     // let key_bytes = derive_key(key);
     let key = Key::<Aes256Gcm>::from_slice(key_bytes);
 
     let cipher = Aes256Gcm::new(key);
 
+    let Some(encrypted) = encrypted.strip_prefix(GCM_FORMAT_PREFIX.as_bytes()) else {
+        return Err("Unsupported encryption format, reload the web page".to_string());
+    };
+
     // Decode IV and ciphertext
-    if encrypted.len() < 16 {
+    if encrypted.len() < GCM_IV_BASE64_LEN {
         return Err("Improperly encrypted or not encrypted data".to_string());
     }
+    let (iv_base64, ciphertext_base64) = encrypted.split_at(GCM_IV_BASE64_LEN);
     let iv_bytes = STANDARD_NO_PAD
-        .decode(&encrypted[..16])
+        .decode(iv_base64)
         .map_err(|_| "Failed to decode IV".to_string())?;
+    if iv_bytes.len() != 12 {
+        return Err("Failed to decode IV".to_string());
+    }
     let iv = Nonce::from_slice(&iv_bytes);
 
     let ciphertext = STANDARD_NO_PAD
-        .decode(&encrypted[16..])
+        .decode(ciphertext_base64)
         .map_err(|_| "Failed to decode ciphertext".to_string())?;
 
     // Decrypt the data
     let plaintext = cipher
-        .decrypt(iv, Payload::from(&ciphertext[..])) // Use `&ciphertext[..]` here
+        .decrypt(
+            iv,
+            Payload {
+                msg: &ciphertext[..],
+                aad,
+            },
+        )
         .map_err(|e| format!("Decryption failed : {e}"))?;
 
     String::from_utf8(plaintext).map_err(|_| "Failed to convert plaintext to string".to_string())
@@ -732,22 +1261,25 @@ pub fn decrypt(key_bytes: &[u8], encrypted: &[u8]) -> Result<String, String> {
 
 pub trait Encryptable<T: Serialize> {
     // fn encrypt(&self, key: &[u8], rng: Rng) -> EncryptedData;
-    fn encrypt(&self, key: &[u8]) -> String;
+    // aad is the endpoint path, as in encrypted_input!
+    fn encrypt(&self, key: &[u8], aad: &str) -> String;
 }
 
 impl<T> Encryptable<T> for T
 where
     T: Serialize,
 {
-    fn encrypt(&self, key: &[u8]) -> String {
+    fn encrypt(&self, key: &[u8], aad: &str) -> String {
         let serialized = serde_json::to_string(self).expect("Serialization failed");
-        encrypt(key, &serialized)
+        encrypt(key, &serialized, aad.as_bytes())
     }
 }
 
 #[derive(Debug)]
 pub enum EncryptedRejection {
     IoError,
+    // the maximum allowed body size
+    PayloadTooLarge(usize),
     DecryptionError(String),
     DeserializationError(serde_json::Error),
 }
@@ -764,6 +1296,14 @@ impl IntoResponse for EncryptedRejection {
                     .write_to(connection, response_writer)
                     .await
             }
+            Self::PayloadTooLarge(max_body_size) => {
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format_args!("Request body larger than {max_body_size} bytes"),
+                )
+                    .write_to(connection, response_writer)
+                    .await
+            }
             Self::DeserializationError(error) => {
                 (
                     StatusCode::BAD_REQUEST,
@@ -790,7 +1330,7 @@ impl IntoResponse for EncryptedRejection {
 
 type Aes256Ctr32BE = ctr::Ctr32BE<aes::Aes256>; // The 32 and BE are important for compatibility with CryptoJS
 
-fn ctr_encrypt(key_bytes: &[u8], data: &str) -> String {
+pub(crate) fn ctr_encrypt(key_bytes: &[u8], data: &str) -> String {
     let mut key = [0u8; 32];
     key.copy_from_slice(key_bytes);
 
@@ -815,26 +1355,26 @@ fn ctr_encrypt(key_bytes: &[u8], data: &str) -> String {
     format!("{hmac_tag}{encrypted_content}")
 }
 
-fn ctr_decrypt(key_bytes: &[u8], encrypted: &[u8]) -> Result<String, String> {
+pub(crate) fn ctr_decrypt(key_bytes: &[u8], encrypted: &[u8]) -> Result<String, String> {
+    if encrypted.len() < CTR_HMAC_BASE64_LEN + CTR_IV_BASE64_LEN {
+        return Err("Improperly encrypted or not encrypted data".to_string());
+    }
+
     // start verifying the hmac tag
 
-    let hmac_base64 = core::str::from_utf8(&encrypted[..43])
-        .map_err(|e| format!("Failed UTF8 decoding hmac {e}"))?;
+    let (hmac_base64, encrypted_content) = encrypted.split_at(CTR_HMAC_BASE64_LEN);
+    let hmac_base64 =
+        core::str::from_utf8(hmac_base64).map_err(|e| format!("Failed UTF8 decoding hmac {e}"))?;
     let received_hmac = STANDARD_NO_PAD
         .decode(hmac_base64)
         .map_err(|e| format!("Failed BASE64 decoding hmac {e}"))?;
 
-    let encrypted_content = &encrypted[43..];
-
     let mut hmac =
         <Hmac<Sha256> as KeyInit>::new_from_slice(key_bytes).expect("Invalid key length");
     hmac.update(encrypted_content);
-    let calced_hmac = hmac.finalize().into_bytes();
-    let calced_hmac = calced_hmac.as_slice(); // sha 256: 32 bytes -> 43 base64 no padding
-
-    if received_hmac != calced_hmac {
-        return Err("Failed hmac validation".to_string());
-    }
+    // constant time compare, so response timing doesn't tell how many leading bytes matched
+    hmac.verify_slice(&received_hmac)
+        .map_err(|_| "Failed hmac validation".to_string())?;
 
     let encrypted = encrypted_content;
 
@@ -844,15 +1384,19 @@ fn ctr_decrypt(key_bytes: &[u8], encrypted: &[u8]) -> Result<String, String> {
     key.copy_from_slice(key_bytes);
 
     // Decode IV and ciphertext
+    let (iv_base64, data_base64) = encrypted.split_at(CTR_IV_BASE64_LEN);
     let iv_vec = STANDARD_NO_PAD
-        .decode(&encrypted[..22])
+        .decode(iv_base64)
         .map_err(|e| format!("Failed to decode IV: {e}"))?;
-    let iv: &[u8; 16] = iv_vec.as_slice().try_into().unwrap();
+    let iv: &[u8; 16] = iv_vec
+        .as_slice()
+        .try_into()
+        .map_err(|_| "Failed to decode IV".to_string())?;
 
     let mut cipher = Aes256Ctr32BE::new(&key.into(), iv.into());
 
     let mut dest = STANDARD_NO_PAD
-        .decode(&encrypted[22..])
+        .decode(data_base64)
         .map_err(|_| "Failed to decode data".to_string())?;
 
     for chunk in dest.chunks_mut(1) {
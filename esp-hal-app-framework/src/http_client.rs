@@ -0,0 +1,320 @@
+use core::{
+    cell::RefCell,
+    ffi::CStr,
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use alloc::{
+    boxed::Box,
+    ffi::CString,
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    vec::Vec,
+};
+use edge_http::{io::client::Connection, Method};
+use edge_nal::TcpConnect;
+use edge_nal_embassy::{Tcp, TcpBuffers};
+use embassy_net::{IpAddress, Stack};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read as _, Write as _};
+use esp_mbedtls::{Certificate, ClientSessionConfig, X509};
+
+use crate::framework::Framework;
+
+// Time allowed for the server to accept a request and respond with headers
+const HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+// Time allowed between body chunks
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(15);
+const HTTP_MAX_REDIRECTS: usize = 5;
+// Responses are collected in memory, larger ones fail rather than exhaust the heap
+const HTTP_MAX_RESPONSE_LEN: usize = 256 * 1024;
+// Only sent to the host of the original url, not to where it redirects
+const HTTP_SENSITIVE_HEADERS: &[&str] = &["Authorization", "Proxy-Authorization", "Cookie"];
+
+#[derive(Debug)]
+pub enum HttpClientError {
+    InvalidUrl,
+    DnsFailed,
+    ConnectFailed,
+    Timeout,
+    HttpStatus(u16),
+    TooManyRedirects,
+    InsecureRedirect,
+    ResponseTooLarge,
+    InvalidCertificates,
+}
+
+impl fmt::Display for HttpClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpClientError::InvalidUrl => {
+                write!(f, "Invalid URL, expected http(s)://host[:port]/path")
+            }
+            HttpClientError::DnsFailed => write!(f, "DNS resolution failed"),
+            HttpClientError::ConnectFailed => write!(f, "Connection to server failed"),
+            HttpClientError::Timeout => write!(f, "Server didn't respond in time"),
+            HttpClientError::HttpStatus(code) => write!(f, "Server returned HTTP status {code}"),
+            HttpClientError::TooManyRedirects => {
+                write!(f, "More than {HTTP_MAX_REDIRECTS} redirects")
+            }
+            HttpClientError::InsecureRedirect => write!(f, "Refused redirect from https to http"),
+            HttpClientError::ResponseTooLarge => {
+                write!(f, "Response larger than {HTTP_MAX_RESPONSE_LEN} bytes")
+            }
+            HttpClientError::InvalidCertificates => {
+                write!(f, "CA certificates aren't a valid nul terminated PEM chain")
+            }
+        }
+    }
+}
+
+struct Url<'a> {
+    https: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+fn parse_url(url: &str) -> Result<Url<'_>, HttpClientError> {
+    let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(HttpClientError::InvalidUrl);
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| HttpClientError::InvalidUrl)?),
+        None => (authority, if https { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        return Err(HttpClientError::InvalidUrl);
+    }
+    Ok(Url {
+        https,
+        host,
+        port,
+        path,
+    })
+}
+
+// The port is left out when it's the scheme's default, as browsers do
+pub(crate) fn host_header(host: &str, port: u16, https: bool) -> String {
+    if port == if https { 443 } else { 80 } {
+        host.to_string()
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+// Location may be absolute or relative to the current server
+fn redirect_target(current: &Url<'_>, location: &str) -> String {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        location.to_string()
+    } else {
+        let scheme = if current.https { "https" } else { "http" };
+        let separator = if location.starts_with('/') { "" } else { "/" };
+        format!(
+            "{scheme}://{}:{}{separator}{location}",
+            current.host, current.port
+        )
+    }
+}
+
+pub(crate) async fn resolve_ipv4(
+    stack: Stack<'_>,
+    host: &str,
+) -> Result<Ipv4Addr, HttpClientError> {
+    let ips = stack
+        .dns_query(host, embassy_net::dns::DnsQueryType::A)
+        .await
+        .map_err(|_| HttpClientError::DnsFailed)?;
+    match ips.first() {
+        Some(IpAddress::Ipv4(addr)) => Ok(*addr),
+        _ => Err(HttpClientError::DnsFailed),
+    }
+}
+
+// certs is a nul terminated PEM chain, server_name is checked against the server certificate
+pub(crate) fn client_session_config<'a>(
+    certs: &'a CStr,
+    server_name: &'a CStr,
+) -> Result<ClientSessionConfig<'a>, HttpClientError> {
+    let ca_chain = Certificate::new(X509::PEM(certs)).map_err(|_| {
+        error!("CA certificates couldn't be parsed");
+        HttpClientError::InvalidCertificates
+    })?;
+    Ok(ClientSessionConfig {
+        ca_chain: Some(ca_chain),
+        server_name: Some(server_name),
+        ..ClientSessionConfig::new()
+    })
+}
+
+enum Exchange {
+    Body(Vec<u8>),
+    Redirect(u16, String),
+}
+
+async fn exchange<T: TcpConnect>(
+    connector: &T,
+    addr: SocketAddr,
+    method: Method,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<Exchange, HttpClientError> {
+    let mut conn_buf = Box::new([0_u8; 4096]);
+    let mut conn: Box<Connection<_, 32>> =
+        Box::new(Connection::new(&mut *conn_buf, connector, addr));
+
+    match with_timeout(
+        HTTP_REQUEST_TIMEOUT,
+        conn.initiate_request(true, method, path, headers),
+    )
+    .await
+    {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("HTTP request to {path} failed: {e:?}");
+            return Err(HttpClientError::ConnectFailed);
+        }
+        Err(_) => return Err(HttpClientError::Timeout),
+    }
+    if let Some(body) = body {
+        conn.write_all(body).await.map_err(|e| {
+            error!("HTTP request body to {path} failed: {e:?}");
+            HttpClientError::ConnectFailed
+        })?;
+    }
+    match with_timeout(HTTP_REQUEST_TIMEOUT, conn.initiate_response()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("HTTP response from {path} failed: {e:?}");
+            return Err(HttpClientError::ConnectFailed);
+        }
+        Err(_) => return Err(HttpClientError::Timeout),
+    }
+
+    let headers = conn.headers().map_err(|_| HttpClientError::ConnectFailed)?;
+    let code = headers.code;
+    if (300..400).contains(&code) {
+        return match headers.headers.get("Location") {
+            Some(location) => Ok(Exchange::Redirect(code, location.to_string())),
+            None => Err(HttpClientError::HttpStatus(code)),
+        };
+    }
+    if !(200..300).contains(&code) {
+        return Err(HttpClientError::HttpStatus(code));
+    }
+
+    // The connection decodes chunked and Content-Length bodies alike, read returns 0 at the end
+    let mut response = Vec::new();
+    let mut buf = Box::new([0_u8; 1024]);
+    loop {
+        let len = match with_timeout(HTTP_READ_TIMEOUT, conn.read(&mut *buf)).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) => {
+                error!("HTTP response body from {path} failed: {e:?}");
+                return Err(HttpClientError::ConnectFailed);
+            }
+            Err(_) => return Err(HttpClientError::Timeout),
+        };
+        if len == 0 {
+            return Ok(Exchange::Body(response));
+        }
+        if response.len() + len > HTTP_MAX_RESPONSE_LEN {
+            return Err(HttpClientError::ResponseTooLarge);
+        }
+        response.extend_from_slice(&buf[..len]);
+    }
+}
+
+// Sends the request and returns the body of the final (2xx) response, following redirects
+pub(crate) async fn request(
+    framework: &Rc<RefCell<Framework>>,
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<Vec<u8>, HttpClientError> {
+    let (stack, tls, certs) = {
+        let framework = framework.borrow();
+        (
+            framework.stack,
+            framework.tls,
+            framework.settings.http_client_certs,
+        )
+    };
+    let original_host = parse_url(url)?.host.to_string();
+    let mut url = url.to_string();
+    let mut method = method;
+    let mut body = body;
+    for _ in 0..=HTTP_MAX_REDIRECTS {
+        let target = parse_url(&url)?;
+        let addr = SocketAddr::new(
+            core::net::IpAddr::V4(resolve_ipv4(stack, target.host).await?),
+            target.port,
+        );
+        let host = host_header(target.host, target.port, target.https);
+        let content_length = body.map(|body| format!("{}", body.len()));
+        let mut request_headers = alloc::vec![("Host", host.as_str())];
+        if let Some(content_length) = &content_length {
+            request_headers.push(("Content-Length", content_length));
+        }
+        let same_host = target.host.eq_ignore_ascii_case(&original_host);
+        request_headers.extend(headers.iter().copied().filter(|(name, _)| {
+            same_host
+                || !HTTP_SENSITIVE_HEADERS
+                    .iter()
+                    .any(|sensitive| sensitive.eq_ignore_ascii_case(name))
+        }));
+
+        let mut tcp_buffers = Box::new(TcpBuffers::<1, 1024, 4096>::new());
+        let tcp = Tcp::new(stack, &mut *tcp_buffers);
+        let result = if target.https {
+            let certs = CStr::from_bytes_with_nul(certs.as_bytes()).map_err(|_| {
+                error!("http_client_certs must be nul terminated");
+                HttpClientError::InvalidCertificates
+            })?;
+            let server_name = CString::new(target.host).map_err(|_| HttpClientError::InvalidUrl)?;
+            let session_config = client_session_config(certs, &server_name)?;
+            let tls_connector = Box::new(esp_mbedtls::TlsConnector::new(tls, tcp, &session_config));
+            exchange(
+                &*tls_connector,
+                addr,
+                method,
+                target.path,
+                &request_headers,
+                body,
+            )
+            .await
+        } else {
+            exchange(&tcp, addr, method, target.path, &request_headers, body).await
+        };
+
+        match result? {
+            Exchange::Body(response) => return Ok(response),
+            Exchange::Redirect(code, location) => {
+                info!("HTTP {code} redirect to {location}");
+                // 307/308 repeat the request as is, the others continue with a GET
+                if !matches!(code, 307 | 308) {
+                    method = Method::Get;
+                    body = None;
+                }
+                let next = redirect_target(&target, &location);
+                if target.https && next.starts_with("http://") {
+                    return Err(HttpClientError::InsecureRedirect);
+                }
+                url = next;
+            }
+        }
+    }
+    Err(HttpClientError::TooManyRedirects)
+}
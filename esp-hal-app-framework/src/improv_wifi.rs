@@ -16,6 +16,25 @@ pub enum ParseError {
     InvalidCommand(u8),
     InvalidState(u8),
     InvalidError(u8),
+    // Encoding only, lengths are single bytes on the wire
+    StringTooLong(usize),
+    DataTooLong(usize),
+}
+
+// Lengths are encoded as a single byte
+const MAX_LENGTH: usize = u8::MAX as usize;
+
+fn check_string_lengths<'a>(
+    strings: impl IntoIterator<Item = &'a String>,
+) -> Result<usize, ParseError> {
+    let mut total = 0;
+    for s in strings {
+        if s.len() > MAX_LENGTH {
+            return Err(ParseError::StringTooLong(s.len()));
+        }
+        total += 1 + s.len();
+    }
+    Ok(total)
 }
 
 // Parser helper =============================================
@@ -103,6 +122,8 @@ impl Writer {
     }
 
     fn write_string(&mut self, s: &str) {
+        // callers check lengths before writing, see ImprovWifiPacketData::check_lengths
+        debug_assert!(s.len() <= MAX_LENGTH);
         self.write_u8(s.len() as u8);
         self.write_slice(s.as_bytes());
     }
@@ -236,6 +257,7 @@ impl ImprovWifiPacket {
     }
 
     pub fn to_bytes(&self) -> Result<Vec<u8>, ParseError> {
+        self.data.check_lengths()?;
         let mut writer = Writer::new();
         
         // Write magic
@@ -302,6 +324,25 @@ impl ImprovWifiPacketData {
         }
     }
 
+    // Fails if any string or the data as a whole doesn't fit its length byte
+    fn check_lengths(&self) -> Result<(), ParseError> {
+        let data_length = match self {
+            ImprovWifiPacketData::CurrentState(_) | ImprovWifiPacketData::ErrorState(_) => 1,
+            ImprovWifiPacketData::RPC(RPCCommandStruct {
+                data: RPCCommand::SendWifiSettings(settings),
+                ..
+            }) => 2 + check_string_lengths([&settings.ssid, &settings.password])?,
+            ImprovWifiPacketData::RPC(_) => 2,
+            ImprovWifiPacketData::RPCResult(rpc_result) => {
+                2 + check_string_lengths(&rpc_result.strings)?
+            }
+        };
+        if data_length > MAX_LENGTH {
+            return Err(ParseError::DataTooLong(data_length));
+        }
+        Ok(())
+    }
+
     fn get_type_id(&self) -> u8 {
         match self {
             ImprovWifiPacketData::CurrentState(_) => 0x01,
@@ -314,7 +355,7 @@ impl ImprovWifiPacketData {
 
 // Current State =================================
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum CurrentStateOption {
     Ready,
     Provisioning,
@@ -422,7 +463,7 @@ impl RPCCommandStruct {
     }
 
     pub fn get_data_length(&self) -> u8 {
-        2 + self.data.get_data_length()
+        2u8.wrapping_add(self.data.get_data_length())
     }
 }
 
@@ -497,7 +538,9 @@ impl SendWifiSettingsStruct {
     }
 
     fn get_data_length(&self) -> u8 {
-        2 + self.ssid.len() as u8 + self.password.len() as u8
+        // wrapping, oversized data is rejected by check_lengths before encoding
+        2u8.wrapping_add(self.ssid.len() as u8)
+            .wrapping_add(self.password.len() as u8)
     }
 }
 
@@ -544,15 +587,16 @@ impl RPCResultStruct {
     }
 
     pub fn get_data_length(&self) -> u8 {
-        let len: u8 = 1/*command_responded byte */+1 /*data_length byte*/ + Self::get_strings_data_length(&self.strings);
-        len
+        // command_responded and data_length bytes, then the strings
+        2u8.wrapping_add(Self::get_strings_data_length(&self.strings))
     }
 
     fn get_strings_data_length(data: &[String]) -> u8 {
-        let value: u8 = data
-            .iter()
-            .fold(0, |acc, x| acc + 1/*string len byte*/ + x.len() as u8);
-        value
+        // wrapping, oversized data is rejected by check_lengths before encoding
+        data.iter().fold(0u8, |acc, x| {
+            acc.wrapping_add(1 /*string len byte*/)
+                .wrapping_add(x.len() as u8)
+        })
     }
 
     // builders
@@ -610,3 +654,265 @@ impl RPCResultStruct {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(packet: ImprovWifiPacket) -> ImprovWifiPacket {
+        let bytes = packet.to_bytes().unwrap();
+        let ((rest, _), parsed) = ImprovWifiPacket::from_bytes((&bytes, 0)).unwrap();
+        assert!(rest.is_empty());
+        // header fields are derived from the data when encoding, so re-encoding must be identical
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+        assert_eq!(parsed.data_type, bytes[7]);
+        assert_eq!(parsed.data_length as usize, bytes.len() - 7 - 2 - 2);
+        parsed
+    }
+
+    fn rpc_command(data: RPCCommand) -> ImprovWifiPacket {
+        ImprovWifiPacket {
+            data_type: 0,
+            data_length: 0,
+            checksum: 0,
+            always_ten: AlwaysTen {},
+            data: ImprovWifiPacketData::RPC(RPCCommandStruct {
+                command: 0,
+                data_length: 0,
+                data,
+            }),
+        }
+    }
+
+    fn parsed_rpc_result(packet: ImprovWifiPacket) -> RPCResultStruct {
+        match round_trip(packet).data {
+            ImprovWifiPacketData::RPCResult(result) => result,
+            other => panic!("expected an RPC result, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn current_state_round_trip() {
+        for state in [
+            CurrentStateOption::Ready,
+            CurrentStateOption::Provisioning,
+            CurrentStateOption::Provisioned,
+        ] {
+            let parsed = round_trip(ImprovWifiPacket::new_current_state(state));
+            assert_eq!(parsed.data, ImprovWifiPacketData::CurrentState(state));
+        }
+    }
+
+    #[test]
+    fn error_state_round_trip() {
+        for make_state in [
+            || ErrorStateOption::NoError,
+            || ErrorStateOption::InvalidRPCPacket,
+            || ErrorStateOption::UnknownRPCCommand,
+            || ErrorStateOption::UnableToConnect,
+            || ErrorStateOption::UnknownError,
+        ] {
+            let parsed = round_trip(ImprovWifiPacket::new_error_state(make_state()));
+            assert_eq!(parsed.data, ImprovWifiPacketData::ErrorState(make_state()));
+        }
+    }
+
+    #[test]
+    fn rpc_commands_round_trip() {
+        for make_command in [
+            || RPCCommand::RequestCurrentState,
+            || RPCCommand::RequestDeviceInformation,
+            || RPCCommand::RequestScannedWifiNetworks,
+            || {
+                RPCCommand::SendWifiSettings(SendWifiSettingsStruct {
+                    ssid: "MyNetwork".to_string(),
+                    password: "secret password".to_string(),
+                })
+            },
+        ] {
+            let expected = make_command();
+            let parsed = round_trip(rpc_command(make_command()));
+            let ImprovWifiPacketData::RPC(rpc) = parsed.data else {
+                panic!("expected an RPC command, got {:?}", parsed.data);
+            };
+            assert_eq!(rpc.command, expected.get_command_id());
+            assert_eq!(rpc.data_length, expected.get_data_length());
+            assert_eq!(rpc.data, expected);
+        }
+    }
+
+    #[test]
+    fn rpc_result_with_multiple_strings_round_trip() {
+        let result = parsed_rpc_result(ImprovWifiPacket::new_rpc_result(
+            RPCResultStruct::new_response_to_request_device_information(
+                "app", "1.2.3", "ESP32S3", "Board",
+            ),
+        ));
+        assert_eq!(result.command_responded, 0x03);
+        assert_eq!(result.strings, ["app", "1.2.3", "ESP32S3", "Board"]);
+        assert_eq!(result.strings_data_length, 4 + 3 + 5 + 7 + 5);
+
+        let result = parsed_rpc_result(ImprovWifiPacket::new_rpc_result(
+            RPCResultStruct::new_response_to_request_scanned_wifi_networks("Net", "-60", true),
+        ));
+        assert_eq!(result.command_responded, 0x04);
+        assert_eq!(result.strings, ["Net", "-60", "YES"]);
+    }
+
+    #[test]
+    fn rpc_result_edge_cases_round_trip() {
+        let result = parsed_rpc_result(ImprovWifiPacket::new_rpc_result(
+            RPCResultStruct::new_response_to_request_scanned_wifi_networks_end(),
+        ));
+        assert!(result.strings.is_empty());
+
+        let result = parsed_rpc_result(ImprovWifiPacket::new_rpc_result(
+            RPCResultStruct::new_response_to_send_wifi_settings("http://192.168.2.1"),
+        ));
+        assert_eq!(result.command_responded, 0x01);
+        assert_eq!(result.strings, ["http://192.168.2.1"]);
+
+        let result = parsed_rpc_result(ImprovWifiPacket::new_rpc_result(
+            RPCResultStruct::new_response_to_send_wifi_settings(""),
+        ));
+        assert_eq!(result.strings, [""]);
+    }
+
+    #[test]
+    fn encodes_known_bytes() {
+        // RequestCurrentState as sent by esp-web-tools
+        let packet = rpc_command(RPCCommand::RequestCurrentState);
+        assert_eq!(
+            packet.to_bytes().unwrap(),
+            b"IMPROV\x01\x03\x02\x02\x00\xe5\x0a"
+        );
+    }
+
+    #[test]
+    fn parses_consecutive_packets() {
+        let mut bytes = ImprovWifiPacket::new_current_state(CurrentStateOption::Ready)
+            .to_bytes()
+            .unwrap();
+        let second = ImprovWifiPacket::new_error_state(ErrorStateOption::UnableToConnect)
+            .to_bytes()
+            .unwrap();
+        bytes.extend_from_slice(&second);
+        let ((rest, _), first) = ImprovWifiPacket::from_bytes((&bytes, 0)).unwrap();
+        assert_eq!(
+            first.data,
+            ImprovWifiPacketData::CurrentState(CurrentStateOption::Ready)
+        );
+        assert_eq!(rest, second.as_slice());
+    }
+
+    #[test]
+    fn rejects_invalid_checksum() {
+        let mut bytes = rpc_command(RPCCommand::RequestDeviceInformation)
+            .to_bytes()
+            .unwrap();
+        let checksum_pos = bytes.len() - 2;
+        bytes[checksum_pos] = bytes[checksum_pos].wrapping_add(1);
+        assert_eq!(
+            ImprovWifiPacket::from_bytes((&bytes, 0)),
+            Err(ParseError::InvalidChecksum)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_magic() {
+        let mut bytes = ImprovWifiPacket::new_current_state(CurrentStateOption::Ready)
+            .to_bytes()
+            .unwrap();
+        bytes[0] = b'X';
+        assert_eq!(
+            ImprovWifiPacket::from_bytes((&bytes, 0)),
+            Err(ParseError::InvalidMagic)
+        );
+
+        // the version byte is part of the magic
+        let mut bytes = ImprovWifiPacket::new_current_state(CurrentStateOption::Ready)
+            .to_bytes()
+            .unwrap();
+        bytes[6] = 0x02;
+        assert_eq!(
+            ImprovWifiPacket::from_bytes((&bytes, 0)),
+            Err(ParseError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn every_truncation_is_incomplete() {
+        let bytes = rpc_command(RPCCommand::SendWifiSettings(SendWifiSettingsStruct {
+            ssid: "ssid".to_string(),
+            password: "password".to_string(),
+        }))
+        .to_bytes()
+        .unwrap();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                ImprovWifiPacket::from_bytes((&bytes[..len], 0)),
+                Err(ParseError::Incomplete),
+                "truncated to {len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_strings() {
+        let long = "x".repeat(256);
+        assert_eq!(
+            ImprovWifiPacket::new_rpc_result(RPCResultStruct::new_response_to_send_wifi_settings(
+                &long
+            ))
+            .to_bytes(),
+            Err(ParseError::StringTooLong(256))
+        );
+
+        // each string fits, but not all of them together
+        let half = "x".repeat(200);
+        assert_eq!(
+            ImprovWifiPacket::new_rpc_result(
+                RPCResultStruct::new_response_to_request_scanned_wifi_networks(&half, &half, true)
+            )
+            .to_bytes(),
+            Err(ParseError::DataTooLong(2 + 201 + 201 + 4))
+        );
+
+        let packet = rpc_command(RPCCommand::SendWifiSettings(SendWifiSettingsStruct {
+            ssid: "x".repeat(100),
+            password: "x".repeat(200),
+        }));
+        assert_eq!(
+            packet.to_bytes(),
+            Err(ParseError::DataTooLong(2 + 101 + 201))
+        );
+
+        // largest string that still fits the data length byte
+        let longest = "x".repeat(MAX_LENGTH - 3);
+        let result = parsed_rpc_result(ImprovWifiPacket::new_rpc_result(
+            RPCResultStruct::new_response_to_send_wifi_settings(&longest),
+        ));
+        assert_eq!(result.strings, [longest]);
+    }
+
+    #[test]
+    fn rejects_unknown_ids() {
+        let mut bytes = ImprovWifiPacket::new_current_state(CurrentStateOption::Ready)
+            .to_bytes()
+            .unwrap();
+        bytes[9] = 0x7f;
+        assert_eq!(
+            ImprovWifiPacket::from_bytes((&bytes, 0)),
+            Err(ParseError::InvalidState(0x7f))
+        );
+
+        let mut bytes = ImprovWifiPacket::new_current_state(CurrentStateOption::Ready)
+            .to_bytes()
+            .unwrap();
+        bytes[7] = 0x09;
+        assert_eq!(
+            ImprovWifiPacket::from_bytes((&bytes, 0)),
+            Err(ParseError::InvalidDataType(0x09))
+        );
+    }
+}
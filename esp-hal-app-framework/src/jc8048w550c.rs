@@ -27,7 +27,7 @@ use crate::{
     },
     sdcard_spi::create_sdcard_spi_device_dma,
     slint_ext::McuWindow,
-    touch::Touch,
+    touch::{Touch, TouchConfig},
     ui_loop::UiRenderBackend,
 };
 
@@ -559,7 +559,11 @@ where
             .await
             .expect("Failed to initialize GT9x touch controller");
 
-        let touch = Touch::new(Gt9xAdapter::new(touch_inner, self.touch_config));
+        // The adapter already debounces releases over missing_points_before_release polls
+        let touch = Touch::new(
+            Gt9xAdapter::new(touch_inner, self.touch_config),
+            TouchConfig::raw(),
+        );
         let line_buffer =
             mk_static!(AlignedLineBuffer, AlignedLineBuffer([slint::platform::software_renderer::Rgb565Pixel(0); DISP_W]));
         let render_backend = Jc8048w550cRenderBackend {
@@ -14,6 +14,7 @@ pub mod log_ext;
 pub mod terminal;
 
 pub mod backlight;
+pub mod config_file;
 pub mod display_snapshot;
 pub mod flash_map;
 pub mod framework;
@@ -25,10 +26,12 @@ pub mod jc8048w550c;
 
 #[macro_use]
 pub mod framework_web_app;
+pub mod http_client;
 pub mod improv_wifi;
 pub mod license;
 // pub mod sdcard;
 pub mod ota;
+pub mod ota_checks;
 pub mod sdcard_spi;
 pub mod sdcard_store;
 pub mod slint_ext;
@@ -40,6 +43,7 @@ pub mod gt9x_adapter;
 #[cfg(feature = "jc8048w550c")]
 #[path = "rgb-display.rs"]
 pub mod rgb_display;
+pub mod web_api;
 pub mod web_server;
 pub mod wifi;
 #[macro_use]
@@ -47,6 +51,7 @@ pub mod utils;
 pub mod settings;
 pub mod ntp;
 pub mod mdns;
+pub mod texts;
 
 extern crate alloc;
 
@@ -66,6 +71,7 @@ compile_error!("Only one log level feature can be enabled at a time");
 pub mod prelude {
     pub use crate::flash_map::FlashMap;
     pub use crate::framework::Framework;
+    pub use crate::framework::FrameworkAccess;
     pub use crate::framework::FrameworkSettings;
     pub use crate::license::LicenseManager;
     pub use crate::warn;
@@ -79,12 +85,39 @@ pub mod prelude {
     pub use term_error;
     pub use term_info;
     pub use trace;
-    pub const FRAMEWORK_STA_STACK_RESOURCES: usize = 5; // potentially https captive +  ota + captive dns + ? initial firmware check if doen't complete + mDNS
-    pub const FRAMEWORK_AP_STACK_RESOURCES: usize = 5;
+    pub use crate::{framework_ap_stack_resources, framework_sta_stack_resources};
+    // With all subsystems enabled, use the const fns to budget only for the enabled ones
+    pub const FRAMEWORK_STA_STACK_RESOURCES: usize =
+        framework_sta_stack_resources(true, true, true, true);
+    pub const FRAMEWORK_AP_STACK_RESOURCES: usize = framework_ap_stack_resources(true, true);
     pub use crate::utils::AwaitHeap;
     pub use crate::utils::SpawnerHeapExt;
 }
 
+// Sockets the framework's own tasks use on the STA stack, on top of the app's (e.g. web server listeners).
+// Parameters match the FrameworkSettings subsystems, standalone_captive is the http->https redirect task
+// (settings.web_server_captive with https or port other than 80, unless web_server_http_port is 80).
+pub const fn framework_sta_stack_resources(
+    mdns: bool,
+    ntp: bool,
+    ota: bool,
+    standalone_captive: bool,
+) -> usize {
+    1 // embassy-net DNS socket
+        + mdns as usize
+        + ntp as usize
+        + ota as usize * 2 // a version check may still be running when an update starts
+        + standalone_captive as usize
+}
+
+// Sockets the framework's own tasks use on the AP stack, on top of the app's (e.g. web server listeners)
+pub const fn framework_ap_stack_resources(captive: bool, standalone_captive: bool) -> usize {
+    1 // embassy-net DNS socket
+        + 1 // DHCP server
+        + captive as usize // captive portal DNS
+        + standalone_captive as usize
+}
+
 #[cfg(feature = "extern-random")]
 pub static mut RNG: once_cell::sync::OnceCell<esp_hal::rng::Rng> = once_cell::sync::OnceCell::new();
 #[cfg(feature = "extern-random")]
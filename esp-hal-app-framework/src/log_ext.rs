@@ -475,3 +475,96 @@ macro_rules! term_error {
         log::error!("[{}:{}] {}", $crate::file_name!(), ::core::line!(), &$__term_txt)
     }
 }
+
+// Logging backend //////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // human readable, colored like esp-println's logger
+    Text,
+    // one JSON object per line, for log aggregators
+    Json,
+}
+
+static LOG_FORMAT: core::sync::atomic::AtomicU8 =
+    core::sync::atomic::AtomicU8::new(LogFormat::Text as u8);
+
+pub fn set_log_format(format: LogFormat) {
+    LOG_FORMAT.store(format as u8, core::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn log_format() -> LogFormat {
+    match LOG_FORMAT.load(core::sync::atomic::Ordering::Relaxed) {
+        x if x == LogFormat::Json as u8 => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+#[derive(serde::Serialize)]
+struct JsonLogLine<'a> {
+    // ms since epoch, null until time is synced
+    ts: Option<i64>,
+    uptime_ms: u64,
+    level: &'a str,
+    target: &'a str,
+    // file:line added by the log_ext macros
+    location: Option<&'a str>,
+    msg: &'a str,
+}
+
+struct FrameworkLogger;
+
+static LOGGER: FrameworkLogger = FrameworkLogger;
+
+impl log::Log for FrameworkLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        match log_format() {
+            LogFormat::Text => {
+                let color = match record.level() {
+                    log::Level::Error => "\x1b[31m",
+                    log::Level::Warn => "\x1b[33m",
+                    log::Level::Info => "\x1b[32m",
+                    log::Level::Debug => "\x1b[34m",
+                    log::Level::Trace => "\x1b[35m",
+                };
+                esp_println::println!("{color}{} - {}\x1b[0m", record.level(), record.args());
+            }
+            LogFormat::Json => {
+                use crate::ntp::InstantExt;
+                let message = alloc::format!("{}", record.args());
+                let (location, msg) = match message
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.split_once("] "))
+                {
+                    Some((location, msg)) => (Some(location), msg),
+                    None => (None, message.as_str()),
+                };
+                let now = embassy_time::Instant::now();
+                let line = JsonLogLine {
+                    ts: now.to_date_time().map(|dt| dt.timestamp_millis()),
+                    uptime_ms: now.as_millis(),
+                    level: record.level().as_str(),
+                    target: record.target(),
+                    location,
+                    msg,
+                };
+                if let Ok(line) = serde_json::to_string(&line) {
+                    esp_println::println!("{line}");
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Use instead of esp_println::logger::init_logger to be able to switch formats at runtime with set_log_format
+pub fn init_logger(level: log::LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
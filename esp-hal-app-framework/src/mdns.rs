@@ -3,13 +3,21 @@ use core::{
     net::{Ipv4Addr, Ipv6Addr},
 };
 
-use alloc::{boxed::Box, rc::Rc};
-use edge_mdns::io::{Mdns, DEFAULT_SOCKET};
+use alloc::{boxed::Box, format, rc::Rc, vec::Vec};
+use edge_mdns::{
+    host::{Service, ServiceAnswers},
+    io::{Mdns, DEFAULT_SOCKET},
+};
 use edge_nal::UdpSplit;
+use embassy_futures::select::{select, Either};
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
 use rand_core::RngCore;
 
-use crate::prelude::Framework;
+use crate::prelude::{Framework, FrameworkAccess};
+
+// Delay before binding/running mDNS again after it failed
+const MDNS_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 #[derive(Clone, Copy, Default)]
 struct GetRandomRng;
@@ -36,48 +44,111 @@ impl RngCore for GetRandomRng {
 
 // #[embassy_executor::task]
 pub async fn mdns_task(framework: Rc<RefCell<Framework>>) {
-    if framework.borrow().device_name.is_none() {
-        return;
-    }
     info!("mdns_task started (not yet functional, need IP)");
-    let stack = framework.borrow().stack;
-    let (recv_buf, send_buf) = (
-        Box::new(edge_mdns::buf::VecBufAccess::<NoopRawMutex, 512>::new()),
-        Box::new(edge_mdns::buf::VecBufAccess::<NoopRawMutex, 512>::new()),
-    );
+    let stack = framework.stack();
+    let mdns_restart = framework.borrow().mdns_restart;
+    let (web_server, https, port, version, extra_txt_records) = {
+        let settings = &framework.borrow().settings;
+        (
+            settings.web_server,
+            settings.web_server_https,
+            settings.web_server_port,
+            settings.app_cargo_pkg_version,
+            settings.mdns_txt_records,
+        )
+    };
+    let port_txt = format!("{port}");
+    let mut txt_kvs = Vec::from([
+        ("version", version),
+        ("port", port_txt.as_str()),
+        ("https", if https { "true" } else { "false" }),
+    ]);
+    txt_kvs.extend_from_slice(extra_txt_records);
     let udp_buffers: Box<edge_nal_embassy::UdpBuffers<1, 512, 512, 1>> =
         Box::new(edge_nal_embassy::UdpBuffers::new());
-
     let udp = edge_nal_embassy::Udp::new(stack, &*udp_buffers);
-    let mut socket =
-        edge_mdns::io::bind(&udp, DEFAULT_SOCKET, Some(Ipv4Addr::UNSPECIFIED), Some(0))
-            .await
-            .unwrap();
-    let (recv, send) = socket.split();
-    let signal = Signal::<NoopRawMutex, ()>::new();
-    let mdns = Mdns::new(
-        Some(Ipv4Addr::UNSPECIFIED),
-        Some(0),
-        recv,
-        send,
-        *recv_buf,
-        *send_buf,
-        GetRandomRng,
-        &signal,
-    );
-    let device_name = framework.borrow().device_name.as_ref().unwrap().clone();
 
     Framework::wait_for_wifi(&framework).await;
-    let address = stack.config_v4().unwrap().address.address();
 
-    let host = edge_mdns::host::Host {
-        hostname: &device_name,
-        ipv4: address,
-        ipv6: Ipv6Addr::UNSPECIFIED,
-        ttl: edge_mdns::domain::base::Ttl::from_secs(60),
-    };
-    info!("mDNS active with HOST {}, IP: {}", host.hostname, host.ipv4);
-    mdns.run(edge_mdns::HostAnswersMdnsHandler::new(&host))
-        .await
-        .unwrap();
+    loop {
+        let Some(device_name) = framework.device_name() else {
+            info!("mDNS inactive - device name not configured");
+            mdns_restart.wait().await;
+            continue;
+        };
+
+        let (recv_buf, send_buf) = (
+            Box::new(edge_mdns::buf::VecBufAccess::<NoopRawMutex, 512>::new()),
+            Box::new(edge_mdns::buf::VecBufAccess::<NoopRawMutex, 512>::new()),
+        );
+        // the link may have dropped since the last (re)start, there's no address to announce until it's back
+        stack.wait_config_up().await;
+        let Some(config) = stack.config_v4() else {
+            Timer::after(MDNS_RETRY_DELAY).await;
+            continue;
+        };
+        let address = config.address.address();
+
+        let mut socket =
+            match edge_mdns::io::bind(&udp, DEFAULT_SOCKET, Some(Ipv4Addr::UNSPECIFIED), Some(0))
+                .await
+            {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("mDNS bind failed, retrying: {:?}", e);
+                    Timer::after(MDNS_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+        let (recv, send) = socket.split();
+        let signal = Signal::<NoopRawMutex, ()>::new();
+        let mdns = Mdns::new(
+            Some(Ipv4Addr::UNSPECIFIED),
+            Some(0),
+            recv,
+            send,
+            *recv_buf,
+            *send_buf,
+            GetRandomRng,
+            &signal,
+        );
+
+        let host = edge_mdns::host::Host {
+            hostname: &device_name,
+            ipv4: address,
+            ipv6: Ipv6Addr::UNSPECIFIED,
+            ttl: edge_mdns::domain::base::Ttl::from_secs(60),
+        };
+        let service = Service {
+            name: &device_name,
+            priority: 1,
+            weight: 5,
+            service: if https { "_https" } else { "_http" },
+            protocol: "_tcp",
+            port,
+            service_subtypes: &[],
+            txt_kvs: &txt_kvs,
+        };
+        info!("mDNS active with HOST {}, IP: {}", host.hostname, host.ipv4);
+        let run = async {
+            // The web service is only advertised when there's a web server to reach
+            if web_server {
+                info!("mDNS advertising {}.{}.local", service.service, service.protocol);
+                mdns.run(edge_mdns::HostAnswersMdnsHandler::new(ServiceAnswers::new(
+                    &host, &service,
+                )))
+                .await
+            } else {
+                mdns.run(edge_mdns::HostAnswersMdnsHandler::new(&host)).await
+            }
+        };
+        match select(run, mdns_restart.wait()).await {
+            Either::First(Ok(())) => return,
+            Either::First(Err(e)) => {
+                error!("mDNS failed, restarting: {:?}", e);
+                Timer::after(MDNS_RETRY_DELAY).await;
+            }
+            Either::Second(_) => info!("mDNS restarting, device name changed"),
+        }
+    }
 }
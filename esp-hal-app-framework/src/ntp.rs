@@ -1,11 +1,16 @@
 use core::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicI32, Ordering},
 };
 
 use alloc::{boxed::Box, rc::Rc};
-use chrono::{DateTime, Utc};
-use embassy_net::udp::{PacketMetadata, UdpSocket};
+use chrono::{DateTime, FixedOffset, Utc};
+use critical_section::Mutex;
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    Stack,
+};
 use embassy_time::{Duration, Instant, Timer};
 use smoltcp::wire::DnsQueryType;
 use sntpc::{get_time, NtpContext, NtpTimestampGenerator};
@@ -13,15 +18,6 @@ use sntpc_net_embassy::UdpSocketWrapper;
 
 use crate::prelude::Framework;
 
-const NTP_SERVERS: [&str; 6] = [
-    "pool.ntp.org",
-    "time.aws.com",
-    "time.windows.com",
-    "time.apple.com",
-    "cn.pool.ntp.org",
-    "time.google.com",
-];
-
 #[derive(Copy, Clone)]
 struct TimestampGen {
     instant: Instant,
@@ -56,15 +52,40 @@ impl NtpTimestampGenerator for TimestampGen {
 pub async fn ntp_task(framework: Rc<RefCell<Framework>>) {
     info!("ntp_task started (not yet functional, need IP)");
 
-    Framework::wait_for_wifi(&framework).await;
-
     let stack = framework.borrow().stack;
+    let (servers, resync_interval) = {
+        let settings = &framework.borrow().settings;
+        (settings.ntp_servers, settings.ntp_resync_interval)
+    };
+    if servers.is_empty() {
+        term_error!("No NTP servers configured");
+        return;
+    }
+    loop {
+        Framework::wait_for_wifi(&framework).await;
+        sync_time(stack, servers).await;
+        match resync_interval {
+            Some(resync_interval) => Timer::after(resync_interval).await,
+            None => break,
+        }
+    }
+    info!("ntp_task Exited");
+}
 
-    let mut resolved = false;
-    let mut ntp_address = None;
+// Cycles through the servers until one answers
+async fn sync_time(stack: Stack<'static>, servers: &[&str]) {
     term_info!("Requesting to get NTP Time");
-    'global_loop: for ntp_server in NTP_SERVERS.iter().cycle() {
+    'global_loop: for ntp_server in servers.iter().cycle() {
+        // an IP address is used as is
+        let mut ntp_address = ntp_server.parse::<IpAddr>().ok();
+        let mut resolved = ntp_address.is_some();
+        if let Some(ip) = ntp_address {
+            term_info!("Using NTP server at address: {ip}");
+        }
         for trial in 0..2 {
+            if resolved {
+                break;
+            }
             let ntp_addrs = match stack.dns_query(ntp_server, DnsQueryType::A).await {
                 Ok(v) => v,
                 Err(err) => {
@@ -79,14 +100,14 @@ pub async fn ntp_task(framework: Rc<RefCell<Framework>>) {
                 continue;
             } else {
                 resolved = true;
-                ntp_address = Some(ntp_addrs[0]);
+                ntp_address = Some(ntp_addrs[0].into());
                 term_info!("Using NTP server {ntp_server} at address: {}", ntp_addrs[0]);
                 break;
             }
         }
         if resolved {
             let addr: IpAddr = if let Some(ntp_address) = ntp_address {
-                ntp_address.into()
+                ntp_address
             } else {
                 error!("Failed to resolve any ntp server");
                 return;
@@ -147,35 +168,65 @@ pub async fn ntp_task(framework: Rc<RefCell<Framework>>) {
                     }
                 }
             }
-            // Note: Can't get NTP more than once with current implementation since relies on global once_cell
-            // Need to change to something that can be modified many time
         }
     }
-    info!("ntp_task Exited");
 }
 
-pub static mut TIME_OFFSET: once_cell::sync::OnceCell<Duration> = once_cell::sync::OnceCell::new();
+// Offset from Instant to the unix epoch, replaced on every NTP sync
+static TIME_OFFSET: Mutex<Cell<Option<Duration>>> = Mutex::new(Cell::new(None));
 
 pub fn set_time_offset(offset_duration_micros: Duration) {
-    unsafe {
-        #[allow(static_mut_refs)]
-        TIME_OFFSET.set(offset_duration_micros).unwrap();
+    critical_section::with(|cs| TIME_OFFSET.borrow(cs).set(Some(offset_duration_micros)));
+}
+
+fn time_offset() -> Option<Duration> {
+    critical_section::with(|cs| TIME_OFFSET.borrow(cs).get())
+}
+
+pub fn time_synced() -> bool {
+    time_offset().is_some()
+}
+
+// UTC-12:00 to UTC+14:00
+pub const UTC_OFFSET_MIN_MINUTES: i32 = -12 * 60;
+pub const UTC_OFFSET_MAX_MINUTES: i32 = 14 * 60;
+
+// Local time offset from UTC in minutes, negative west of Greenwich
+static UTC_OFFSET_MINUTES: AtomicI32 = AtomicI32::new(0);
+
+// Offsets outside UTC_OFFSET_MIN_MINUTES..=UTC_OFFSET_MAX_MINUTES are ignored
+pub fn set_utc_offset_minutes(minutes: i32) {
+    if (UTC_OFFSET_MIN_MINUTES..=UTC_OFFSET_MAX_MINUTES).contains(&minutes) {
+        UTC_OFFSET_MINUTES.store(minutes, Ordering::Relaxed);
+    } else {
+        error!("Ignoring out of range UTC offset of {minutes} minutes");
     }
 }
 
+pub fn utc_offset_minutes() -> i32 {
+    UTC_OFFSET_MINUTES.load(Ordering::Relaxed)
+}
+
 pub trait InstantExt {
     fn to_date_time(&self) -> Option<DateTime<Utc>>;
+    // Wall clock time with the configured UTC offset, date changes across midnight are handled by chrono
+    fn to_local_date_time(&self) -> Option<DateTime<FixedOffset>>;
 }
 
 impl InstantExt for Instant {
     fn to_date_time(&self) -> Option<DateTime<Utc>> {
-        #[allow(static_mut_refs)]
-        if let Some(offset_duration_micros) = unsafe { TIME_OFFSET.get() } {
-            let real_world_instant_now = Instant::now() + *offset_duration_micros;
+        if let Some(offset_duration_micros) = time_offset() {
+            let real_world_instant_now = Instant::now() + offset_duration_micros;
             let micros_since_epoch_now = real_world_instant_now.as_micros();
             DateTime::from_timestamp_micros(micros_since_epoch_now as i64)
         } else {
             None
         }
     }
+
+    fn to_local_date_time(&self) -> Option<DateTime<FixedOffset>> {
+        let offset = FixedOffset::east_opt(utc_offset_minutes() * 60)?;
+        self.to_date_time()
+            .map(|date_time| date_time.with_timezone(&offset))
+    }
 }
@@ -1,41 +1,107 @@
 use core::net::SocketAddr;
-use core::{cell::RefCell, ffi::CStr};
+use core::{cell::RefCell, ffi::CStr, fmt};
 
 use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::{String, ToString};
 use alloc::{ffi::CString, format};
 use edge_http::io::client::Connection;
+use edge_nal::TcpConnect;
 use edge_nal_embassy::{Tcp, TcpBuffers};
-use embassy_net::IpAddress;
-use embassy_time::Timer;
+use embassy_time::{with_timeout, Duration, Timer};
 use embedded_io_async::Read;
+use embedded_storage::ReadStorage as _;
 use esp_hal_ota::Ota;
-use esp_mbedtls::{Certificate, ClientSessionConfig, X509};
+use esp_partition_table::PartitionTable;
 use esp_storage::FlashStorage;
 use semver::Version;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use super::framework::Framework;
+use super::framework::{Framework, OtaState};
+use crate::http_client::{client_session_config, host_header, resolve_ipv4};
+pub use crate::ota_checks::OtaRequest;
+use crate::ota_checks::{check_upgrade_path, is_newer_version, parse_ota_server};
+
+// Time allowed for the server to accept a request and respond with headers
+const OTA_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+// Where staged updates are downloaded to before flashing
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+const OTA_STAGED_FILE: &str = "ota/firmware.bin";
+// Progress of an interrupted (direct) download, continued on the next attempt with a Range request
+pub(crate) const OTA_RESUME_KEY: &str = "__ota_resume__";
+// Resume progress is persisted every this many bytes, each save is a flash write
+const OTA_RESUME_SAVE_INTERVAL: u32 = 64 * 1024;
 
 enum Report<'a> {
     Status,
-    Failure,
+    Failure(OtaError),
     Complete,
     Success,
     Version(&'a str, bool),
 }
 
-#[derive(Clone, Copy, PartialEq, Deserialize)]
-pub enum OtaRequest {
-    CheckVersion,
-    Update,
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum OtaError {
+    DnsFailed,
+    ConnectFailed,
+    HttpStatus(u16),
+    MetadataInvalid,
+    CrcMismatch,
+    FlashError,
+    SignatureInvalid,
+    StagingFailed,
+    UpgradePathNotAllowed,
+    HashMismatch,
+}
+
+impl OtaError {
+    // Network related failures may succeed on a later attempt, the rest require a different firmware/metadata
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OtaError::DnsFailed | OtaError::ConnectFailed => true,
+            OtaError::HttpStatus(code) => *code >= 500,
+            OtaError::MetadataInvalid
+            | OtaError::CrcMismatch
+            | OtaError::FlashError
+            | OtaError::SignatureInvalid
+            | OtaError::StagingFailed
+            | OtaError::UpgradePathNotAllowed
+            | OtaError::HashMismatch => false,
+        }
+    }
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::DnsFailed => write!(f, "DNS resolution failed"),
+            OtaError::ConnectFailed => write!(f, "Connection to update server failed"),
+            OtaError::HttpStatus(code) => write!(f, "Update server returned HTTP status {code}"),
+            OtaError::MetadataInvalid => write!(f, "Firmware metadata is invalid"),
+            OtaError::CrcMismatch => write!(f, "Firmware CRC mismatch"),
+            OtaError::FlashError => write!(f, "Flash write failed"),
+            OtaError::SignatureInvalid => write!(f, "Firmware signature is invalid"),
+            OtaError::StagingFailed => write!(f, "Staging firmware on SD card failed"),
+            OtaError::UpgradePathNotAllowed => {
+                write!(f, "Firmware can't be installed over the current version")
+            }
+            OtaError::HashMismatch => write!(f, "Firmware SHA-256 mismatch"),
+        }
+    }
+}
+
+fn flash_error(e: &esp_hal_ota::OtaError) -> OtaError {
+    match e {
+        esp_hal_ota::OtaError::WrongCRC => OtaError::CrcMismatch,
+        _ => OtaError::FlashError,
+    }
 }
 
 pub trait OtaObserver {
     fn on_ota_start(&mut self);
     fn on_ota_status(&mut self, text: &str);
-    fn on_ota_failed(&mut self, text: &str);
+    fn on_ota_failed(&mut self, error: OtaError, text: &str);
     fn on_ota_completed(&mut self, text: &str);
     fn on_ota_version_available(&mut self, version: &str, newer: bool);
 }
@@ -58,9 +124,9 @@ impl OtaObserver for FrameworkOtaObserver {
         }
     }
 
-    fn on_ota_failed(&mut self, text: &str) {
+    fn on_ota_failed(&mut self, error: OtaError, text: &str) {
         if self.update {
-            self.framework.borrow_mut().notify_ota_failed(text);
+            self.framework.borrow_mut().notify_ota_failed(error, text);
         }
     }
 
@@ -89,7 +155,7 @@ pub async fn ota_task(
 ) {
     let mut framework_observer = FrameworkOtaObserver {
         framework: framework.clone(),
-        update: matches!(ota_request, OtaRequest::Update),
+        update: ota_request.installs(),
     };
 
     let curr_ver = framework
@@ -124,90 +190,113 @@ pub async fn run_ota(
     let stack = framework.borrow().stack;
     let tls = framework.borrow().tls;
 
-    if ota_request == OtaRequest::Update {
+    if ota_request.installs() {
         observer.on_ota_start();
     }
+    // Don't let the display dim mid-update, released when done
+    let _keep_awake = ota_request
+        .installs()
+        .then(|| Framework::keep_awake(&framework));
 
-    let mut report = move |report: Report, text: &str| match report {
-        Report::Status => {
-            // if ota_request == OtaRequest::Update {
-            observer.on_ota_status(text);
-            // }
-            info!("{text}");
-        }
-        Report::Failure => {
-            // if ota_request == OtaRequest::Update {
-            observer.on_ota_failed(text);
-            // }
-            warn!("{text}");
-        }
-        Report::Complete => {
-            // if ota_request == OtaRequest::Update {
-            observer.on_ota_completed(text);
-            // }
-            info!("{text}");
-        }
-        Report::Success => {
-            // if ota_request == OtaRequest::Update {
-            observer.on_ota_completed(text);
-            // }
-            info!("{text}");
-        }
-        Report::Version(version, newer) => {
-            observer.on_ota_version_available(version, newer);
-        }
-    };
-    report(Report::Status, "Resolving Dns");
-    let Ok(ips) = stack
-        .dns_query(ota_domain, embassy_net::dns::DnsQueryType::A)
-        .await
-    else {
+    let mut report = reporter(observer);
+    let Some(server) = parse_ota_server(ota_domain) else {
         report(
-            Report::Failure,
-            "Failed to resolve Dns, Internet accessible?",
+            Report::Failure(OtaError::MetadataInvalid),
+            &format!("Invalid OTA server {ota_domain}, expected [http(s)://]host[:port]"),
         );
         return;
     };
-
-    info!("Resolved DNS for {ota_domain} {:?}", ips);
-
-    if ips.is_empty() {
+    report(Report::Status, "Resolving Dns");
+    let Ok(ip) = resolve_ipv4(stack, server.host).await else {
         report(
-            Report::Status,
-            "Failed to resolve Dns for {ota_domain}, Internet accessible?",
+            Report::Failure(OtaError::DnsFailed),
+            &format!(
+                "Failed to resolve Dns for {}, Internet accessible?",
+                server.host
+            ),
         );
         return;
-    }
-
-    let cert = CStr::from_bytes_with_nul(cert.as_bytes()).unwrap();
-    let servername = CString::new(ota_domain).unwrap();
-    let certificates = ClientSessionConfig {
-        ca_chain: Some(Certificate::new(X509::PEM(cert)).unwrap()),
-        server_name: Some(servername.as_c_str()),
-        ..ClientSessionConfig::new()
     };
 
+    info!("Resolved DNS for {} {:?}", server.host, ip);
+    let addr = SocketAddr::new(core::net::IpAddr::V4(ip), server.port);
+    let host_header = host_header(server.host, server.port, server.https);
+
     let mut tcp_buffers_boxed = Box::new(TcpBuffers::<1, 1024, 16384>::new());
     let tcp_buffers = &mut *tcp_buffers_boxed;
     let tcp = Tcp::new(stack, tcp_buffers);
 
-    let tls_connector = Box::new(esp_mbedtls::TlsConnector::new(tls, tcp, &certificates));
+    if server.https {
+        let cert = CStr::from_bytes_with_nul(cert.as_bytes()).unwrap();
+        let servername = CString::new(server.host).unwrap();
+        let Ok(certificates) = client_session_config(cert, &servername) else {
+            report(
+                Report::Failure(OtaError::ConnectFailed),
+                "Invalid OTA server CA certificates",
+            );
+            return;
+        };
+        let tls_connector = Box::new(esp_mbedtls::TlsConnector::new(tls, tcp, &certificates));
+        run_ota_over(
+            &*tls_connector,
+            addr,
+            &host_header,
+            ota_path,
+            ota_toml_filename,
+            cur_version,
+            ota_request,
+            framework,
+            &mut report,
+        )
+        .await;
+    } else {
+        warn!("OTA over plaintext HTTP, the firmware is only verified by its metadata");
+        run_ota_over(
+            &tcp,
+            addr,
+            &host_header,
+            ota_path,
+            ota_toml_filename,
+            cur_version,
+            ota_request,
+            framework,
+            &mut report,
+        )
+        .await;
+    }
+}
 
-    let IpAddress::Ipv4(addr) = ips[0] else {
-        report(Report::Failure, "Unsupported reply from Dns");
-        return;
-    };
+#[allow(clippy::too_many_arguments)]
+async fn run_ota_over<T: TcpConnect>(
+    connector: &T,
+    addr: SocketAddr,
+    host_header: &str,
+    ota_path: &str,
+    ota_toml_filename: &str,
+    cur_version: &str,
+    ota_request: OtaRequest,
+    framework: Rc<RefCell<Framework>>,
+    mut report: impl FnMut(Report, &str),
+) {
+    let read_timeout = framework.borrow().settings.ota_chunk_timeout;
 
     let mut conn_buf_boxed = Box::new([0_u8; 4096]);
     let conn_buf = &mut *conn_buf_boxed;
     let mut data_buf_boxed = Box::new([0_u8; 4096]);
     let data_buf = &mut *data_buf_boxed;
 
-    let mut conn: Box<Connection<_, 32>> = Box::new(Connection::new(
-        &mut *conn_buf,
-        &*tls_connector,
-        SocketAddr::new(core::net::IpAddr::V4(addr), 443),
-    ));
+    let mut conn: Box<Connection<_, 32>> =
+        Box::new(Connection::new(&mut *conn_buf, connector, addr));
+
+    let mut request_headers = alloc::vec![(
+        "Host",
+        framework
+            .borrow()
+            .settings
+            .ota_host_header
+            .unwrap_or(host_header),
+    )];
+    request_headers.extend_from_slice(framework.borrow().settings.ota_extra_headers);
 
     'block: {
         // Get ota.toml
@@ -216,30 +305,61 @@ pub async fn run_ota(
 
         info!("Fetching OTA metadata from {toml_filename}");
         report(Report::Status, "Fetching firmware metadata");
-        if let Err(err) = conn
-            .initiate_request(
+        match with_timeout(
+            OTA_REQUEST_TIMEOUT,
+            conn.initiate_request(
                 true,
                 edge_http::Method::Get,
                 &toml_filename,
-                &[("Host", ota_domain)],
-            )
-            .await
+                &request_headers,
+            ),
+        )
+        .await
         {
-            report(Report::Failure, "Failed to initiate request for metadata");
-            error!("Error: {err:?}");
-            break 'block;
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Failed to initiate request for metadata",
+                );
+                error!("Error: {err:?}");
+                break 'block;
+            }
+            Err(_) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Timeout initiating request for metadata",
+                );
+                break 'block;
+            }
         }
 
-        if let Err(err) = conn.initiate_response().await {
-            report(Report::Failure, "Failed to fetch response for metadata");
-            error!("Error: {err:?}");
-            break 'block;
-        };
+        match with_timeout(OTA_REQUEST_TIMEOUT, conn.initiate_response()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Failed to fetch response for metadata",
+                );
+                error!("Error: {err:?}");
+                break 'block;
+            }
+            Err(_) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Timeout fetching response for metadata",
+                );
+                break 'block;
+            }
+        }
 
         let headers = match conn.headers() {
             Ok(headers) => headers,
             Err(err) => {
-                report(Report::Failure, "Failed to read resopnse headers");
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Failed to read resopnse headers",
+                );
                 info!("Error: {err}");
                 break 'block;
             }
@@ -247,62 +367,52 @@ pub async fn run_ota(
 
         let status_code = headers.code;
         if status_code != 200 {
-            report(Report::Failure, "Failed to fetch firmware metadata");
+            report(
+                Report::Failure(OtaError::HttpStatus(status_code)),
+                "Failed to fetch firmware metadata",
+            );
             break 'block;
         }
 
         // TODO - loop to read until buffer full or nothing to read
-        let Ok(len) = conn.read(&mut *data_buf).await else {
-            report(Report::Failure, "Failed to read response");
-            break 'block;
-        };
-
-        let toml = core::str::from_utf8(&data_buf[..len]).unwrap_or_default();
-        info!("Firmware metadata:\n{}", toml.trim());
-
-        let mut filename = None;
-        let mut crc32 = None;
-        let mut version = None;
-        let mut filesize = None;
-
-        for line in toml.lines() {
-            if let Some((key, value)) = line.split_once('=') {
-                match key.trim() {
-                    "filename" => filename = Some(value.trim().trim_matches('"')),
-                    "crc32" => {
-                        crc32 = Some(u32::from_str_radix(value.trim().trim_matches('"'), 16))
-                    }
-                    "filesize" => filesize = Some(value.trim().trim_matches('"').parse::<u32>()),
-                    "version" => version = Some(value.trim().trim_matches('"')),
-                    _ => (), // Ignore unknown keys
-                }
+        let len = match with_timeout(read_timeout, conn.read(&mut *data_buf)).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(_)) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Failed to read response",
+                );
+                break 'block;
             }
-        }
-        let (Some(filename), Some(Ok(crc32)), Some(version), Some(Ok(filesize))) =
-            (filename, crc32, version, filesize)
-        else {
-            report(Report::Failure, "Something is wrong with firmware metadata");
-            break 'block;
-        };
-
-        let new_semver = match Version::parse(version) {
-            Ok(v) => v,
             Err(_) => {
                 report(
-                    Report::Failure,
-                    "Version number in firmware metadata is invalid",
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Timeout reading response",
                 );
                 break 'block;
             }
         };
 
-        let newer = if let Ok(cur_semver) = Version::parse(cur_version) {
-            new_semver > cur_semver
-        } else {
-            false
+        let toml = core::str::from_utf8(&data_buf[..len]).unwrap_or_default();
+        info!("Firmware metadata:\n{}", toml.trim());
+
+        let FirmwareMetadata {
+            filename,
+            crc32,
+            version,
+            new_semver,
+            filesize,
+            min_from_version,
+            sha256,
+        } = match parse_firmware_metadata(toml) {
+            Ok(metadata) => metadata,
+            Err(text) => {
+                report(Report::Failure(OtaError::MetadataInvalid), text);
+                break 'block;
+            }
         };
 
-        if !newer {
+        if !is_newer_version(cur_version, &new_semver) {
             report(
                 Report::Complete,
                 &format!(
@@ -316,60 +426,282 @@ pub async fn run_ota(
             report(Report::Version(version, true), "Version is behind");
         }
 
+        if let Err(text) = check_upgrade_path(cur_version, min_from_version.as_ref(), &new_semver) {
+            report(Report::Failure(OtaError::UpgradePathNotAllowed), &text);
+            break 'block;
+        }
+
         if ota_request == OtaRequest::CheckVersion {
             break 'block;
         }
 
+        // Direct flashing sets up the writer before the request, so a download that can't be resumed is
+        // requested from the start
+        let mut ota = None;
+        let mut resume = None;
+        let mut sha256_hasher = sha256.map(|_| Sha256::new());
+        if !framework.borrow().settings.ota_staged {
+            let mut direct_ota = match Ota::new(FlashStorage::new()) {
+                Ok(v) => v,
+                Err(_) => {
+                    report(
+                        Report::Failure(OtaError::FlashError),
+                        "Error initializing flashing",
+                    );
+                    break 'block;
+                }
+            };
+            if let Err(e) = direct_ota.ota_begin(filesize, crc32) {
+                report(
+                    Report::Failure(flash_error(&e)),
+                    &format!("Failed to start OTA: {e:?}"),
+                );
+                break 'block;
+            }
+            resume = load_ota_resume(&framework, crc32, filesize).await;
+            if let Some(interrupted) = &resume {
+                report(Report::Status, "Resuming interrupted firmware download");
+                if let Err(text) = replay_flashed_prefix(
+                    &mut direct_ota,
+                    interrupted,
+                    &mut sha256_hasher,
+                    &mut data_buf[..],
+                ) {
+                    warn!("{text}, downloading firmware from the start");
+                    clear_ota_resume(&framework).await;
+                    resume = None;
+                    sha256_hasher = sha256.map(|_| Sha256::new());
+                    if let Err(e) = direct_ota.ota_begin(filesize, crc32) {
+                        report(
+                            Report::Failure(flash_error(&e)),
+                            &format!("Failed to start OTA: {e:?}"),
+                        );
+                        break 'block;
+                    }
+                }
+            }
+            ota = Some(direct_ota);
+        }
+
         // Fetch the bin file
 
         report(Report::Status, "Downloading firmware");
         let bin_filename = format!("{}{}", ota_path, filename);
-        if let Err(e) = conn
-            .initiate_request(
+        let range_header = resume
+            .as_ref()
+            .map(|interrupted| format!("bytes={}-", interrupted.offset));
+        let mut bin_request_headers = request_headers.clone();
+        if let Some(range_header) = &range_header {
+            bin_request_headers.push(("Range", range_header));
+        }
+        match with_timeout(
+            OTA_REQUEST_TIMEOUT,
+            conn.initiate_request(
                 true,
                 edge_http::Method::Get,
                 &bin_filename,
-                &[("Host", ota_domain)],
-            )
-            .await
+                &bin_request_headers,
+            ),
+        )
+        .await
         {
-            report(
-                Report::Failure,
-                &format!("Failed to initiate request for firmware {e:?}"),
-            );
-            break 'block;
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    &format!("Failed to initiate request for firmware {e:?}"),
+                );
+                break 'block;
+            }
+            Err(_) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Timeout initiating request for firmware",
+                );
+                break 'block;
+            }
         }
 
-        if let Err(e) = conn.initiate_response().await {
-            report(
-                Report::Failure,
-                &format!("Failed to fetch response for metadata {e:?}"),
-            );
-            break 'block;
+        match with_timeout(OTA_REQUEST_TIMEOUT, conn.initiate_response()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    &format!("Failed to fetch response for metadata {e:?}"),
+                );
+                break 'block;
+            }
+            Err(_) => {
+                report(
+                    Report::Failure(OtaError::ConnectFailed),
+                    "Timeout fetching response for firmware",
+                );
+                break 'block;
+            }
         }
 
         let status_code = conn.headers().unwrap().code;
         info!("Response code {}", status_code);
-        if status_code != 200 {
-            report(Report::Failure, "Failed downloading firmware");
-            break 'block;
+        let resume_offset = match (status_code, &resume) {
+            (206, Some(interrupted)) => interrupted.offset,
+            (200, _) => 0,
+            _ => {
+                report(
+                    Report::Failure(OtaError::HttpStatus(status_code)),
+                    "Failed downloading firmware",
+                );
+                break 'block;
+            }
+        };
+        if resume_offset == 0 && resume.is_some() {
+            // Server ignored the Range header, restart clean
+            info!("Server doesn't support resuming, downloading firmware from the start");
+            clear_ota_resume(&framework).await;
+            sha256_hasher = sha256.map(|_| Sha256::new());
+            if let Some(Err(e)) = ota.as_mut().map(|ota| ota.ota_begin(filesize, crc32)) {
+                report(
+                    Report::Failure(flash_error(&e)),
+                    &format!("Failed to start OTA: {e:?}"),
+                );
+                break 'block;
+            }
         }
 
-        // start OTA
+        if framework.borrow().settings.ota_staged {
+            #[cfg(not(any(feature = "wt32-sc01-plus", feature = "jc8048w550c")))]
+            {
+                report(
+                    Report::Failure(OtaError::StagingFailed),
+                    "Staged update requires an SD card",
+                );
+                break 'block;
+            }
 
-        let mut ota = match Ota::new(FlashStorage::new()) {
-            Ok(v) => v,
-            Err(_) => {
-                report(Report::Failure, "Error initializing flashing");
+            #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+            {
+                if !framework.borrow().has_file_store() {
+                    report(
+                        Report::Failure(OtaError::StagingFailed),
+                        "Staged update requires an SD card",
+                    );
+                    break 'block;
+                }
+                let file_store = framework.borrow().file_store();
+
+                // Network phase: download everything to the SD card, flash is not touched yet
+                if let Err(e) = file_store
+                    .lock()
+                    .await
+                    .create_write_file_bytes(OTA_STAGED_FILE, &[])
+                    .await
+                {
+                    report(
+                        Report::Failure(OtaError::StagingFailed),
+                        &format!("Failed to create staging file: {e:?}"),
+                    );
+                    break 'block;
+                }
+
+                let mut bytes_read = 0;
+                let start_time = embassy_time::Instant::now();
+                let mut reported_on_sec_since_start = 0;
+                while bytes_read < filesize {
+                    let bytes_to_read = data_buf
+                        .len()
+                        .min((filesize - bytes_read).try_into().unwrap());
+
+                    match with_timeout(
+                        read_timeout,
+                        conn.read_exact(&mut data_buf[..bytes_to_read]),
+                    )
+                    .await
+                    {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            report(
+                                Report::Failure(OtaError::ConnectFailed),
+                                &format!("Failed downloading firmware {e:?}"),
+                            );
+                            break 'block;
+                        }
+                        Err(_) => {
+                            report(
+                                Report::Failure(OtaError::ConnectFailed),
+                                "Timeout downloading firmware",
+                            );
+                            break 'block;
+                        }
+                    }
+                    if let Err(e) = file_store
+                        .lock()
+                        .await
+                        .append_bytes(OTA_STAGED_FILE, &data_buf[..bytes_to_read])
+                        .await
+                    {
+                        report(
+                            Report::Failure(OtaError::StagingFailed),
+                            &format!("Failed writing firmware to SD card: {e:?}"),
+                        );
+                        break 'block;
+                    }
+                    bytes_read += bytes_to_read as u32;
+
+                    let sec_since_start = start_time.elapsed().as_secs();
+                    if sec_since_start >= reported_on_sec_since_start {
+                        report(
+                            Report::Status,
+                            &format!(
+                                "Downloading {} version {}\n{sec_since_start} secs, {:.0}%{}",
+                                framework.borrow().settings.app_cargo_pkg_name,
+                                new_semver,
+                                bytes_read as f32 * 100.0 / filesize as f32,
+                                download_rate(bytes_read, filesize - bytes_read, start_time),
+                            ),
+                        );
+                        reported_on_sec_since_start = sec_since_start + 1;
+                    }
+                }
+
+                with_timeout(OTA_REQUEST_TIMEOUT, conn.close()).await.ok();
+
+                // Flash phase: verified again from the SD card, so a corrupted download never reaches flash
+                let flashed = flash_from_sdcard(
+                    &framework,
+                    OTA_STAGED_FILE,
+                    filesize,
+                    crc32,
+                    sha256.as_ref(),
+                    &new_semver,
+                    &mut data_buf[..],
+                    &mut report,
+                )
+                .await;
+                file_store
+                    .lock()
+                    .await
+                    .delete_file_if_exists(OTA_STAGED_FILE)
+                    .await
+                    .ok();
+                if flashed {
+                    restart_after_flash(&framework, &new_semver, ota_request, &mut report).await;
+                }
                 break 'block;
             }
+        }
+
+        // Direct OTA
+
+        let Some(mut ota) = ota else {
+            break 'block;
         };
-        ota.ota_begin(filesize, crc32).unwrap_or_else(|e| {
-            report(Report::Failure, &format!("Failed to start OTA: {e:?}"));
-        });
 
-        debug!("Starting firmware download");
-        let mut bytes_read = 0;
+        debug!("Starting firmware download at {resume_offset}");
+        let mut bytes_read = resume_offset;
+        let mut prefix_hasher = crc32fast::Hasher::new_with_initial_len(
+            resume.as_ref().map_or(0, |interrupted| interrupted.prefix_crc32),
+            resume_offset as u64,
+        );
+        let mut resume_saved_at = resume_offset;
         let start_time = embassy_time::Instant::now();
         let mut reported_on_sec_since_start = 0;
         let mut x = 0;
@@ -379,74 +711,670 @@ pub async fn run_ota(
                 .len()
                 .min((filesize - bytes_read).try_into().unwrap());
 
-            if conn
-                .read_exact(&mut data_buf[..bytes_to_read])
-                .await
-                .is_ok()
+            match with_timeout(
+                read_timeout,
+                conn.read_exact(&mut data_buf[..bytes_to_read]),
+            )
+            .await
             {
-                bytes_read += bytes_to_read as u32;
-
-                if bytes_to_read == 0 {
-                    error!("Binary File smaller than expected");
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    report(
+                        Report::Failure(OtaError::ConnectFailed),
+                        &format!("Failed downloading firmware {e:?}"),
+                    );
+                    break;
+                }
+                Err(_) => {
+                    report(
+                        Report::Failure(OtaError::ConnectFailed),
+                        "Timeout downloading firmware",
+                    );
                     break;
                 }
+            }
+            bytes_read += bytes_to_read as u32;
 
-                let res = ota.ota_write_chunk(&data_buf[..bytes_to_read]);
+            if bytes_to_read == 0 {
+                clear_ota_resume(&framework).await;
+                report(
+                    Report::Failure(OtaError::CrcMismatch),
+                    "Firmware ended before the flasher accepted it, not installed",
+                );
+                break;
+            }
 
-                match res {
-                    Ok(true) => {
-                        let res = ota.ota_flush(false, true);
-                        sec_since_start = start_time.elapsed().as_secs();
-                        debug!(
-                        "Finished: {x}: {sec_since_start} secs, {bytes_read} {bytes_read} {:.0}%",
-                        100.0
-                    );
-                        info!(
-                            "Download & Flash time: {}ms",
-                            start_time.elapsed().as_millis()
-                        );
-                        if let Err(e) = res {
-                            report(Report::Failure, &format!("Ota flush error: {e:?}"));
+            let res = ota.ota_write_chunk(&data_buf[..bytes_to_read]);
+            prefix_hasher.update(&data_buf[..bytes_to_read]);
+            if let Some(sha256_hasher) = &mut sha256_hasher {
+                sha256_hasher.update(&data_buf[..bytes_to_read]);
+            }
+
+            match res {
+                Ok(true) => {
+                    clear_ota_resume(&framework).await;
+                    // Not flushed on mismatch, so the new partition is never activated.
+                    // The flasher's own CRC check is off in ota_flush, the image is read back instead.
+                    match flashed_crc32(&mut ota, filesize, &mut data_buf[..]) {
+                        Ok(flashed) if flashed == crc32 => {}
+                        Ok(flashed) => {
+                            report(
+                                Report::Failure(OtaError::CrcMismatch),
+                                &format!(
+                                    "Flashed firmware CRC {flashed:08x} doesn't match {crc32:08x}, not installed"
+                                ),
+                            );
                             break;
                         }
-
-                        for countdown in 0..5 {
+                        Err(text) => {
+                            report(Report::Failure(OtaError::FlashError), text);
+                            break;
+                        }
+                    }
+                    if let (Some(expected), Some(sha256_hasher)) = (&sha256, &sha256_hasher) {
+                        if sha256_hasher.clone().finalize().as_slice() != expected {
                             report(
-                            Report::Success,
-                            &format!(
-                                "Firmware version {} flashed successfully\nRestarting {} in {} seconds",
-                                framework.borrow().settings.app_cargo_pkg_name,
-                                new_semver,
-                                5 - countdown
-                            ),
-                        );
-                            Timer::after_millis(1000).await;
+                                Report::Failure(OtaError::HashMismatch),
+                                "Firmware doesn't match its SHA-256, not installed",
+                            );
+                            break;
                         }
-                        esp_hal::system::software_reset();
                     }
-                    Err(e) => {
-                        report(Report::Failure, &format!("Flashing error: {e:?}"));
+                    let res = ota.ota_flush(false, true);
+                    sec_since_start = start_time.elapsed().as_secs();
+                    debug!(
+                    "Finished: {x}: {sec_since_start} secs, {bytes_read} {bytes_read} {:.0}%",
+                    100.0
+                );
+                    info!(
+                        "Download & Flash time: {}ms",
+                        start_time.elapsed().as_millis()
+                    );
+                    if let Err(e) = res {
+                        report(
+                            Report::Failure(flash_error(&e)),
+                            &format!("Ota flush error: {e:?}"),
+                        );
                         break;
                     }
-                    _ => {}
+
+                    restart_after_flash(&framework, &new_semver, ota_request, &mut report).await;
+                    break;
                 }
-                sec_since_start = start_time.elapsed().as_secs();
-                if sec_since_start >= reported_on_sec_since_start {
-                    let progress_percent = ota.get_ota_progress() * 100.0;
+                Err(e) => {
+                    clear_ota_resume(&framework).await;
                     report(
-                        Report::Status,
-                        &format!(
-                            "Downloading/Flashing {} version {}\n{sec_since_start} secs, {:.0}%",
-                            framework.borrow().settings.app_cargo_pkg_name,
-                            new_semver,
-                            progress_percent
+                        Report::Failure(flash_error(&e)),
+                        &format!("Flashing error: {e:?}"),
+                    );
+                    break;
+                }
+                _ => {}
+            }
+            if bytes_read - resume_saved_at >= OTA_RESUME_SAVE_INTERVAL {
+                save_ota_resume(
+                    &framework,
+                    &OtaResume {
+                        crc32,
+                        offset: bytes_read,
+                        prefix_crc32: prefix_hasher.clone().finalize(),
+                    },
+                )
+                .await;
+                resume_saved_at = bytes_read;
+            }
+            sec_since_start = start_time.elapsed().as_secs();
+            if sec_since_start >= reported_on_sec_since_start {
+                let progress_percent = ota.get_ota_progress() * 100.0;
+                report(
+                    Report::Status,
+                    &format!(
+                        "Downloading/Flashing {} version {}\n{sec_since_start} secs, {:.0}%{}",
+                        framework.borrow().settings.app_cargo_pkg_name,
+                        new_semver,
+                        progress_percent,
+                        download_rate(
+                            bytes_read - resume_offset,
+                            filesize - bytes_read,
+                            start_time
                         ),
+                    ),
+                );
+                reported_on_sec_since_start = sec_since_start + 1;
+            }
+            x += 1;
+        }
+    }
+    with_timeout(OTA_REQUEST_TIMEOUT, conn.close()).await.ok();
+}
+
+// Progress line suffix with throughput and time left, empty until there's data to estimate from
+fn download_rate(downloaded: u32, remaining: u32, start_time: embassy_time::Instant) -> String {
+    let elapsed_ms = start_time.elapsed().as_millis();
+    if downloaded == 0 || elapsed_ms == 0 {
+        return String::new();
+    }
+    let bytes_per_sec = (downloaded as u64 * 1000 / elapsed_ms).max(1);
+    let eta_secs = remaining as u64 / bytes_per_sec;
+    format!("\n{} KB/s, ~{eta_secs}s left", bytes_per_sec / 1024)
+}
+
+fn reporter(observer: &mut dyn OtaObserver) -> impl FnMut(Report, &str) + '_ {
+    move |report: Report, text: &str| match report {
+        Report::Status => {
+            observer.on_ota_status(text);
+            info!("{text}");
+        }
+        Report::Failure(error) => {
+            observer.on_ota_failed(error, text);
+            warn!("{text} ({error})");
+        }
+        Report::Complete | Report::Success => {
+            observer.on_ota_completed(text);
+            info!("{text}");
+        }
+        Report::Version(version, newer) => {
+            observer.on_ota_version_available(version, newer);
+        }
+    }
+}
+
+struct FirmwareMetadata<'a> {
+    filename: &'a str,
+    crc32: u32,
+    version: &'a str,
+    new_semver: Version,
+    filesize: u32,
+    // firmware that can't be installed directly over older versions (e.g. flash layout changes)
+    min_from_version: Option<Version>,
+    // verified in addition to crc32 when present, older metadata doesn't have it
+    sha256: Option<[u8; 32]>,
+}
+
+// Parses the ota.toml format, shared by online and SD card updates
+fn parse_firmware_metadata(toml: &str) -> Result<FirmwareMetadata<'_>, &'static str> {
+    let mut filename = None;
+    let mut crc32 = None;
+    let mut version = None;
+    let mut filesize = None;
+    let mut min_from_version = None;
+    let mut sha256 = None;
+
+    for line in toml.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            match key.trim() {
+                "filename" => filename = Some(value.trim().trim_matches('"')),
+                "crc32" => crc32 = Some(u32::from_str_radix(value.trim().trim_matches('"'), 16)),
+                "filesize" => filesize = Some(value.trim().trim_matches('"').parse::<u32>()),
+                "version" => version = Some(value.trim().trim_matches('"')),
+                "min_from_version" => min_from_version = Some(value.trim().trim_matches('"')),
+                "sha256" => sha256 = Some(value.trim().trim_matches('"')),
+                _ => (), // Ignore unknown keys
+            }
+        }
+    }
+    let (Some(filename), Some(Ok(crc32)), Some(version), Some(Ok(filesize))) =
+        (filename, crc32, version, filesize)
+    else {
+        return Err("Something is wrong with firmware metadata");
+    };
+
+    let new_semver =
+        Version::parse(version).map_err(|_| "Version number in firmware metadata is invalid")?;
+    let min_from_version = min_from_version
+        .map(Version::parse)
+        .transpose()
+        .map_err(|_| "Minimum from version in firmware metadata is invalid")?;
+    let sha256 = sha256
+        .map(|hex| parse_sha256(hex).ok_or("SHA-256 in firmware metadata is invalid"))
+        .transpose()?;
+
+    Ok(FirmwareMetadata {
+        filename,
+        crc32,
+        version,
+        new_semver,
+        filesize,
+        min_from_version,
+        sha256,
+    })
+}
+
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+// Whether the running firmware was just installed by an OTA and still waits for Framework::confirm_ota_boot
+pub(crate) fn boot_pending_confirmation() -> bool {
+    Ota::new(FlashStorage::new())
+        .ok()
+        .and_then(|mut ota| ota.get_ota_image_state().ok())
+        .is_some_and(|state| matches!(state, esp_hal_ota::OtaImgState::EspOtaImgPendingVerify))
+}
+
+pub(crate) fn mark_boot_valid() -> Result<(), OtaError> {
+    let mut ota = Ota::new(FlashStorage::new()).map_err(|_| OtaError::FlashError)?;
+    ota.ota_mark_app_valid().map_err(|e| flash_error(&e))
+}
+
+// Rolls back to the previous firmware if the app didn't confirm the new one in time
+#[embassy_executor::task]
+pub async fn ota_confirm_watchdog(timeout: Duration, framework: Rc<RefCell<Framework>>) {
+    Timer::after(timeout).await;
+    if !matches!(
+        framework.borrow().ota_state,
+        Some(OtaState::PendingConfirmation)
+    ) {
+        return;
+    }
+    error!("New firmware not confirmed within {}s, rolling back", timeout.as_secs());
+    match Ota::new(FlashStorage::new()) {
+        Ok(mut ota) => {
+            if let Err(e) = ota.ota_mark_app_invalid_rollback() {
+                error!("Failed marking new firmware invalid: {e:?}");
+            }
+        }
+        Err(e) => error!("Failed marking new firmware invalid: {e:?}"),
+    }
+    // Bootloader falls back to the previous firmware, also if marking failed, since it's still pending
+    Framework::reset_device_safer_async(framework, Some(Duration::from_secs(5))).await;
+}
+
+// Download progress of the firmware with the given crc32, flashed directly into the OTA partition
+#[derive(Serialize, Deserialize)]
+struct OtaResume {
+    crc32: u32,
+    offset: u32,
+    // crc32 of the first offset bytes, to verify what's in flash before continuing after it
+    prefix_crc32: u32,
+}
+
+// Returns the stored progress if it belongs to this firmware, a different firmware always starts from zero
+async fn load_ota_resume(
+    framework: &Rc<RefCell<Framework>>,
+    crc32: u32,
+    filesize: u32,
+) -> Option<OtaResume> {
+    let stored = Framework::fetch_async(framework, String::from(OTA_RESUME_KEY))
+        .await
+        .ok()
+        .flatten()?;
+    serde_json::from_str::<OtaResume>(&stored)
+        .ok()
+        .filter(|resume| resume.crc32 == crc32 && resume.offset > 0 && resume.offset < filesize)
+}
+
+async fn save_ota_resume(framework: &Rc<RefCell<Framework>>, resume: &OtaResume) {
+    if let Err(e) = Framework::store_async(
+        framework,
+        String::from(OTA_RESUME_KEY),
+        serde_json::to_string(resume).unwrap(),
+    )
+    .await
+    {
+        warn!("Failed saving OTA download progress: {e:?}");
+    }
+}
+
+async fn clear_ota_resume(framework: &Rc<RefCell<Framework>>) {
+    Framework::remove_async(framework, String::from(OTA_RESUME_KEY))
+        .await
+        .ok();
+}
+
+// Feeds the part flashed before the interruption through the OTA writer again, read back from the target
+// partition, so the writer's position and CRC continue from there without downloading it again
+fn replay_flashed_prefix(
+    ota: &mut Ota<FlashStorage>,
+    resume: &OtaResume,
+    sha256_hasher: &mut Option<Sha256>,
+    buf: &mut [u8],
+) -> Result<(), &'static str> {
+    let slot = ota
+        .get_next_ota_partition()
+        .ok_or("No OTA partition to resume into")?;
+    let partition_offset = ota_partition_offset(slot).ok_or("OTA partition not found")?;
+    let mut flash = FlashStorage::new();
+    let mut hasher = crc32fast::Hasher::new();
+    let mut offset = 0;
+    while offset < resume.offset {
+        let len = buf.len().min((resume.offset - offset) as usize);
+        flash
+            .read(partition_offset + offset, &mut buf[..len])
+            .map_err(|_| "Failed reading partially downloaded firmware")?;
+        hasher.update(&buf[..len]);
+        if let Some(sha256_hasher) = sha256_hasher {
+            sha256_hasher.update(&buf[..len]);
+        }
+        ota.ota_write_chunk(&buf[..len])
+            .map_err(|_| "Failed rewriting partially downloaded firmware")?;
+        offset += len as u32;
+    }
+    if hasher.finalize() != resume.prefix_crc32 {
+        return Err("Partially downloaded firmware in flash doesn't match");
+    }
+    Ok(())
+}
+
+// CRC of the image as written to the target partition, the same as xtask computes over the .bin
+fn flashed_crc32(
+    ota: &mut Ota<FlashStorage>,
+    filesize: u32,
+    buf: &mut [u8],
+) -> Result<u32, &'static str> {
+    let slot = ota
+        .get_next_ota_partition()
+        .ok_or("No OTA partition the firmware was written to")?;
+    let partition_offset = ota_partition_offset(slot).ok_or("OTA partition not found")?;
+    let mut flash = FlashStorage::new();
+    let mut hasher = crc32fast::Hasher::new();
+    let mut offset = 0;
+    while offset < filesize {
+        let len = buf.len().min((filesize - offset) as usize);
+        flash
+            .read(partition_offset + offset, &mut buf[..len])
+            .map_err(|_| "Failed reading back flashed firmware")?;
+        hasher.update(&buf[..len]);
+        offset += len as u32;
+    }
+    Ok(hasher.finalize())
+}
+
+fn ota_partition_offset(slot: usize) -> Option<u32> {
+    let name = format!("ota_{slot}");
+    let mut flash = FlashStorage::new();
+    let mut partition_offset = None;
+    PartitionTable::default()
+        .iter_storage(&mut flash, false)
+        .for_each(|partition| {
+            if let Ok(partition) = partition {
+                if partition.name() == name {
+                    partition_offset = Some(partition.offset);
+                }
+            }
+        });
+    partition_offset
+}
+
+async fn restart_after_flash(
+    framework: &Rc<RefCell<Framework>>,
+    new_semver: &Version,
+    ota_request: OtaRequest,
+    report: &mut impl FnMut(Report, &str),
+) {
+    if ota_request == OtaRequest::Stage {
+        framework.borrow_mut().ota_reboot_pending = true;
+        report(
+            Report::Success,
+            &format!(
+                "Firmware version {new_semver} flashed successfully\nIt runs after the next restart"
+            ),
+        );
+        return;
+    }
+    for countdown in 0..5 {
+        report(
+            Report::Success,
+            &format!(
+                "Firmware version {} flashed successfully\nRestarting {} in {} seconds",
+                framework.borrow().settings.app_cargo_pkg_name,
+                new_semver,
+                5 - countdown
+            ),
+        );
+        Timer::after_millis(1000).await;
+    }
+    Framework::reset_device_safer_async(framework.clone(), Some(Duration::from_secs(5))).await;
+}
+
+// Verifies the CRC (and SHA-256 if given) of a firmware file on the SD card, then flashes it,
+// returns true if flashed
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+#[allow(clippy::too_many_arguments)]
+async fn flash_from_sdcard(
+    framework: &Rc<RefCell<Framework>>,
+    path: &str,
+    filesize: u32,
+    crc32: u32,
+    sha256: Option<&[u8; 32]>,
+    new_semver: &Version,
+    data_buf: &mut [u8],
+    report: &mut impl FnMut(Report, &str),
+) -> bool {
+    let file_store = framework.borrow().file_store();
+
+    // Verify pass, flash is not touched if the file doesn't match the metadata
+    report(Report::Status, "Verifying firmware file");
+    let mut hasher = crc32fast::Hasher::new();
+    let mut sha256_hasher = sha256.map(|_| Sha256::new());
+    let res = file_store
+        .lock()
+        .await
+        .read_file_streaming(path, data_buf, async |chunk: &[u8]| {
+            hasher.update(chunk);
+            if let Some(sha256_hasher) = &mut sha256_hasher {
+                sha256_hasher.update(chunk);
+            }
+            true
+        })
+        .await;
+    let size = match res {
+        Ok(size) => size,
+        Err(e) => {
+            report(
+                Report::Failure(OtaError::StagingFailed),
+                &format!("Failed reading firmware from SD card: {e:?}"),
+            );
+            return false;
+        }
+    };
+    if size != filesize || hasher.finalize() != crc32 {
+        report(
+            Report::Failure(OtaError::CrcMismatch),
+            "Firmware file doesn't match its metadata, nothing was flashed",
+        );
+        return false;
+    }
+    if let (Some(expected), Some(sha256_hasher)) = (sha256, sha256_hasher) {
+        if sha256_hasher.finalize().as_slice() != expected {
+            report(
+                Report::Failure(OtaError::HashMismatch),
+                "Firmware file doesn't match its SHA-256, nothing was flashed",
+            );
+            return false;
+        }
+    }
+
+    let mut ota = match Ota::new(FlashStorage::new()) {
+        Ok(v) => v,
+        Err(_) => {
+            report(
+                Report::Failure(OtaError::FlashError),
+                "Error initializing flashing",
+            );
+            return false;
+        }
+    };
+    if let Err(e) = ota.ota_begin(filesize, crc32) {
+        report(
+            Report::Failure(flash_error(&e)),
+            &format!("Failed to start OTA: {e:?}"),
+        );
+        return false;
+    }
+
+    let mut complete = false;
+    let mut failed = false;
+    let mut reported_on_sec_since_start = 0;
+    let start_time = embassy_time::Instant::now();
+    let res = file_store
+        .lock()
+        .await
+        .read_file_streaming(path, data_buf, async |chunk: &[u8]| {
+            match ota.ota_write_chunk(chunk) {
+                Ok(true) => {
+                    complete = true;
+                    return false;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    report(
+                        Report::Failure(flash_error(&e)),
+                        &format!("Flashing error: {e:?}"),
                     );
-                    reported_on_sec_since_start = sec_since_start + 1;
+                    failed = true;
+                    return false;
                 }
-                x += 1;
             }
+
+            let sec_since_start = start_time.elapsed().as_secs();
+            if sec_since_start >= reported_on_sec_since_start {
+                report(
+                    Report::Status,
+                    &format!(
+                        "Flashing {} version {}\n{sec_since_start} secs, {:.0}%",
+                        framework.borrow().settings.app_cargo_pkg_name,
+                        new_semver,
+                        ota.get_ota_progress() * 100.0,
+                    ),
+                );
+                reported_on_sec_since_start = sec_since_start + 1;
+            }
+            true
+        })
+        .await;
+    if let Err(e) = res {
+        report(
+            Report::Failure(OtaError::StagingFailed),
+            &format!("Failed reading firmware from SD card: {e:?}"),
+        );
+        return false;
+    }
+    if failed {
+        return false;
+    }
+    if !complete {
+        report(
+            Report::Failure(OtaError::StagingFailed),
+            "Firmware file smaller than expected",
+        );
+        return false;
+    }
+
+    // The file was verified before flashing, this catches what went wrong writing it
+    match flashed_crc32(&mut ota, filesize, data_buf) {
+        Ok(flashed) if flashed == crc32 => {}
+        Ok(flashed) => {
+            report(
+                Report::Failure(OtaError::CrcMismatch),
+                &format!(
+                    "Flashed firmware CRC {flashed:08x} doesn't match {crc32:08x}, not installed"
+                ),
+            );
+            return false;
+        }
+        Err(text) => {
+            report(Report::Failure(OtaError::FlashError), text);
+            return false;
         }
     }
-    conn.close().await.ok();
+
+    if let Err(e) = ota.ota_flush(false, true) {
+        report(
+            Report::Failure(flash_error(&e)),
+            &format!("Ota flush error: {e:?}"),
+        );
+        return false;
+    }
+    true
+}
+
+// Offline update from a metadata file (ota.toml format) on the SD card, the firmware filename in it is
+// relative to the metadata file's directory
+#[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+#[embassy_executor::task]
+pub async fn sd_update_task(toml_path: String, framework: Rc<RefCell<Framework>>) {
+    let mut framework_observer = FrameworkOtaObserver {
+        framework: framework.clone(),
+        update: true,
+    };
+    framework_observer.on_ota_start();
+    let _keep_awake = Framework::keep_awake(&framework);
+    let mut report = reporter(&mut framework_observer);
+
+    if !framework.borrow().has_file_store() {
+        report(
+            Report::Failure(OtaError::StagingFailed),
+            "Update from SD card requires an SD card",
+        );
+        return;
+    }
+    let file_store = framework.borrow().file_store();
+
+    report(Report::Status, "Reading firmware metadata from SD card");
+    let toml = match file_store.lock().await.read_file_str(&toml_path).await {
+        Ok(toml) => toml,
+        Err(e) => {
+            report(
+                Report::Failure(OtaError::MetadataInvalid),
+                &format!("Failed reading {toml_path}: {e:?}"),
+            );
+            return;
+        }
+    };
+    let metadata = match parse_firmware_metadata(&toml) {
+        Ok(metadata) => metadata,
+        Err(text) => {
+            report(Report::Failure(OtaError::MetadataInvalid), text);
+            return;
+        }
+    };
+
+    let cur_version = framework.borrow().settings.app_cargo_pkg_version;
+    if let Err(text) = check_upgrade_path(
+        cur_version,
+        metadata.min_from_version.as_ref(),
+        &metadata.new_semver,
+    ) {
+        report(Report::Failure(OtaError::UpgradePathNotAllowed), &text);
+        return;
+    }
+
+    let bin_path = match toml_path.rsplit_once(['/', '\\']) {
+        Some((dir, _)) => format!("{dir}/{}", metadata.filename),
+        None => metadata.filename.to_string(),
+    };
+    info!(
+        "Updating from SD card to version {} using {bin_path}",
+        metadata.new_semver
+    );
+
+    let mut data_buf = alloc::vec![0_u8; 4096];
+    if flash_from_sdcard(
+        &framework,
+        &bin_path,
+        metadata.filesize,
+        metadata.crc32,
+        metadata.sha256.as_ref(),
+        &metadata.new_semver,
+        &mut data_buf,
+        &mut report,
+    )
+    .await
+    {
+        restart_after_flash(
+            &framework,
+            &metadata.new_semver,
+            OtaRequest::Update,
+            &mut report,
+        )
+        .await;
+    }
 }
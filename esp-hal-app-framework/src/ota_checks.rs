@@ -0,0 +1,168 @@
+// The parts of OTA that don't touch the network or flash, kept apart so framework-host-tests can run
+// their tests on the host
+use core::cmp::Ordering;
+
+use alloc::{format, string::String};
+use semver::Version;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+pub enum OtaRequest {
+    CheckVersion,
+    Update,
+    // Like Update but doesn't restart, the new firmware boots on Framework::reboot_into_staged
+    // (or any other restart, as the slot is already marked for boot)
+    Stage,
+}
+
+impl OtaRequest {
+    pub(crate) fn installs(&self) -> bool {
+        matches!(self, OtaRequest::Update | OtaRequest::Stage)
+    }
+}
+
+pub(crate) struct OtaServer<'a> {
+    pub(crate) https: bool,
+    pub(crate) host: &'a str,
+    pub(crate) port: u16,
+}
+
+// ota_domain is a host name, optionally with a scheme and/or port, https unless http:// is given
+pub(crate) fn parse_ota_server(ota_domain: &str) -> Option<OtaServer<'_>> {
+    let (https, authority) = if let Some(rest) = ota_domain.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        (
+            true,
+            ota_domain.strip_prefix("https://").unwrap_or(ota_domain),
+        )
+    };
+    let authority = authority.trim_end_matches('/');
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, if https { 443 } else { 80 }),
+    };
+    if host.is_empty() || host.contains('/') {
+        return None;
+    }
+    Some(OtaServer { https, host, port })
+}
+
+// SemVer precedence: a prerelease is older than its release (1.2.0-rc.1 < 1.2.0) and prereleases
+// compare by identifier. Build metadata doesn't count, while semver's Ord would order by it.
+// An unparsable current version never offers an update.
+pub(crate) fn is_newer_version(cur_version: &str, new_semver: &Version) -> bool {
+    let Ok(cur_semver) = Version::parse(cur_version) else {
+        return false;
+    };
+    let release = |v: &Version| (v.major, v.minor, v.patch);
+    match release(new_semver).cmp(&release(&cur_semver)) {
+        Ordering::Equal => match (new_semver.pre.is_empty(), cur_semver.pre.is_empty()) {
+            (true, true) => false,
+            (true, false) => true,
+            (false, true) => false,
+            (false, false) => new_semver.pre > cur_semver.pre,
+        },
+        ordering => ordering.is_gt(),
+    }
+}
+
+pub(crate) fn check_upgrade_path(
+    cur_version: &str,
+    min_from_version: Option<&Version>,
+    new_semver: &Version,
+) -> Result<(), String> {
+    let Some(min_from_version) = min_from_version else {
+        return Ok(());
+    };
+    match Version::parse(cur_version) {
+        Ok(cur_semver) if cur_semver >= *min_from_version => Ok(()),
+        _ => Err(format!(
+            "Firmware version {new_semver} can only be installed over version {min_from_version} or later, current version is {cur_version}. Update to an intermediate version first"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn newer(cur: &str, new: &str) -> bool {
+        is_newer_version(cur, &Version::parse(new).unwrap())
+    }
+
+    #[test]
+    fn equal_version_is_not_newer() {
+        assert!(!newer("1.2.3", "1.2.3"));
+        assert!(!newer("1.2.3-rc.1", "1.2.3-rc.1"));
+    }
+
+    #[test]
+    fn newer_version() {
+        assert!(newer("1.2.3", "1.2.4"));
+        assert!(newer("1.2.3", "1.3.0"));
+        assert!(newer("1.9.9", "2.0.0"));
+    }
+
+    #[test]
+    fn older_version() {
+        assert!(!newer("1.2.4", "1.2.3"));
+        assert!(!newer("2.0.0", "1.9.9"));
+    }
+
+    #[test]
+    fn prerelease_precedence() {
+        // a release is newer than its prereleases, not the other way around
+        assert!(newer("1.2.3-rc.1", "1.2.3"));
+        assert!(!newer("1.2.3", "1.2.3-rc.1"));
+        assert!(newer("1.2.3-rc.1", "1.2.3-rc.2"));
+        assert!(newer("1.2.3-alpha", "1.2.3-beta"));
+        assert!(!newer("1.2.3-rc.2", "1.2.3-rc.1"));
+        // a prerelease of a later version is still newer
+        assert!(newer("1.2.3", "1.2.4-rc.1"));
+    }
+
+    #[test]
+    fn build_metadata_is_ignored() {
+        assert!(!newer("1.2.3+a", "1.2.3+b"));
+        assert!(!newer("1.2.3", "1.2.3+build.5"));
+    }
+
+    #[test]
+    fn unparsable_current_version_is_never_behind() {
+        assert!(!newer("dev", "1.2.3"));
+    }
+
+    fn server(ota_domain: &str) -> Option<(bool, &str, u16)> {
+        parse_ota_server(ota_domain).map(|server| (server.https, server.host, server.port))
+    }
+
+    #[test]
+    fn ota_server_defaults_to_https() {
+        assert_eq!(
+            server("fw.example.com"),
+            Some((true, "fw.example.com", 443))
+        );
+        assert_eq!(
+            server("https://fw.example.com:8443"),
+            Some((true, "fw.example.com", 8443))
+        );
+    }
+
+    #[test]
+    fn ota_server_plaintext() {
+        assert_eq!(server("http://10.0.0.5"), Some((false, "10.0.0.5", 80)));
+        assert_eq!(
+            server("http://10.0.0.5:8080/"),
+            Some((false, "10.0.0.5", 8080))
+        );
+    }
+
+    #[test]
+    fn ota_server_invalid() {
+        assert_eq!(server(""), None);
+        assert_eq!(server("http://:80"), None);
+        assert_eq!(server("fw.example.com:port"), None);
+        assert_eq!(server("fw.example.com/firmware"), None);
+    }
+}
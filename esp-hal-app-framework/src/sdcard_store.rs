@@ -95,6 +95,10 @@ where
         #[snafu(source(from(embedded_sdmmc::asynchronous::Error<E>, DebugWrap)))]
         source: DebugWrap<embedded_sdmmc::asynchronous::Error<E>>,
     },
+    #[snafu(display(
+        "Failed to rename '{full_path}' to '{part}': target must be in the same directory"
+    ))]
+    RenameAcrossDirs { full_path: String, part: String },
 }
 
 impl<E> Error<E>
@@ -103,7 +107,9 @@ where
 {
     fn is_not_found(&self) -> bool {
         match self {
-            Error::Open { source, .. } | Error::ChangeDir { source, .. } => {
+            Error::Open { source, .. }
+            | Error::ChangeDir { source, .. }
+            | Error::Delete { source, .. } => {
                 matches!(&source.0, embedded_sdmmc::asynchronous::Error::NotFound)
             }
             _ => false,
@@ -381,6 +387,17 @@ impl<SPI: SpiDevice, const MAX_DIRS: usize, const MAX_FILES: usize>
         }
     }
 
+    pub async fn file_length(&mut self, path: &str) -> Result<u32, SDCardStoreError<SPI>> {
+        let file = self.open_file(path, Mode::ReadOnly).await?;
+        let file = file.to_file(self.volume_mgr());
+        let length = file.length();
+        file.close().await.context(CloseSnafu {
+            full_path: path.to_string(),
+            part: String::new(),
+        })?;
+        Ok(length)
+    }
+
     pub async fn dir_exists(&mut self, path: &str) -> Result<bool, SDCardStoreError<SPI>> {
         match self.open_dir(path, Mode::ReadOnly).await {
             Ok(dir) => {
@@ -423,11 +440,84 @@ impl<SPI: SpiDevice, const MAX_DIRS: usize, const MAX_FILES: usize>
         res
     }
 
+    // Reads up to buffer.len() bytes starting at offset, returns the number of bytes read (0 at end of file)
+    pub async fn read_file_bytes_at(
+        &mut self,
+        path: &str,
+        offset: u32,
+        buffer: &mut [u8],
+    ) -> Result<usize, SDCardStoreError<SPI>> {
+        let file = self
+            .open_file(path, embedded_sdmmc::asynchronous::Mode::ReadOnly)
+            .await?;
+        let file = file.to_file(&self.volume_mgr);
+
+        let res: Result<usize, SDCardStoreError<SPI>> = async {
+            file.seek_from_start(offset).context(SeekFileSnafu {
+                full_path: path,
+                offset,
+            })?;
+            let num_read = file.read(buffer).await.context(ReadFileSnafu {
+                full_path: path.to_string(),
+            })?;
+            Ok(num_read)
+        }
+        .await;
+
+        // async finally block
+        file.close().await.context(CloseSnafu {
+            full_path: path,
+            part: "",
+        })?;
+
+        res
+    }
+
     pub async fn read_file_bytes(&mut self, path: &str) -> Result<Vec<u8>, SDCardStoreError<SPI>> {
         self.inner_read_file_bytes(path, embedded_sdmmc::asynchronous::Mode::ReadOnly)
             .await
     }
 
+    // Reads the file chunk by chunk into buffer and hands each chunk to on_chunk, so large files
+    // don't need to fit in RAM. on_chunk returns false to stop early. Returns the number of bytes read.
+    pub async fn read_file_streaming(
+        &mut self,
+        path: &str,
+        buffer: &mut [u8],
+        mut on_chunk: impl AsyncFnMut(&[u8]) -> bool,
+    ) -> Result<u32, SDCardStoreError<SPI>> {
+        let file = self
+            .open_file(path, embedded_sdmmc::asynchronous::Mode::ReadOnly)
+            .await?;
+        let file = file.to_file(&self.volume_mgr);
+
+        let res: Result<u32, SDCardStoreError<SPI>> = async {
+            let mut total_read = 0_u32;
+            while !file.is_eof() {
+                let num_read = file.read(buffer).await.context(ReadFileSnafu {
+                    full_path: path.to_string(),
+                })?;
+                if num_read == 0 {
+                    break;
+                }
+                total_read += num_read as u32;
+                if !on_chunk(&buffer[..num_read]).await {
+                    break;
+                }
+            }
+            Ok(total_read)
+        }
+        .await;
+
+        // async finally block
+        file.close().await.context(CloseSnafu {
+            full_path: path,
+            part: "",
+        })?;
+
+        res
+    }
+
     pub async fn append_bytes(
         &mut self,
         path: &str,
@@ -617,6 +707,14 @@ impl<SPI: SpiDevice, const MAX_DIRS: usize, const MAX_FILES: usize>
         Ok(s)
     }
 
+    // Like delete_file, but a file that doesn't exist is not an error (best effort cleanup)
+    pub async fn delete_file_if_exists(&mut self, path: &str) -> Result<(), SDCardStoreError<SPI>> {
+        match self.delete_file(path).await {
+            Err(err) if err.is_not_found() => Ok(()),
+            res => res,
+        }
+    }
+
     pub async fn delete_file(&mut self, path: &str) -> Result<(), SDCardStoreError<SPI>> {
         let volume0 = self.take_volume().await?.to_volume(&self.volume_mgr);
 
@@ -680,6 +778,24 @@ impl<SPI: SpiDevice, const MAX_DIRS: usize, const MAX_FILES: usize>
         })
     }
 
+    // Renames a file or directory, both paths must be in the same directory (FAT has no move)
+    pub async fn rename(
+        &mut self,
+        old_path: &str,
+        new_path: &str,
+    ) -> Result<(), SDCardStoreError<SPI>> {
+        let (old_dir, old_name) = split_parent(old_path);
+        let (new_dir, new_name) = split_parent(new_path);
+        if old_dir.trim_matches(['/', '\\']) != new_dir.trim_matches(['/', '\\']) {
+            return RenameAcrossDirsSnafu {
+                full_path: old_path,
+                part: new_path,
+            }
+            .fail();
+        }
+        self.rename_entry_in_dir(old_dir, old_name, new_name).await
+    }
+
     pub async fn rename_entry_in_dir(
         &mut self,
         top_folder_path: &str,
@@ -763,3 +879,11 @@ impl<SPI: SpiDevice, const MAX_DIRS: usize, const MAX_FILES: usize>
         res
     }
 }
+
+// Splits "dir/sub/name" into ("dir/sub", "name"), a bare name has an empty parent (root)
+fn split_parent(path: &str) -> (&str, &str) {
+    match path.rfind(['/', '\\']) {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => ("", path),
+    }
+}
@@ -1,11 +1,17 @@
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 
-use alloc::vec::Vec;
+use alloc::{collections::VecDeque, string::String, vec::Vec};
 
 pub static mut TERM: once_cell::unsync::OnceCell<Terminal> = once_cell::unsync::OnceCell::new();
 
+// Lines kept for Terminal::recent, older ones are dropped
+pub const TERMINAL_SCROLLBACK_LINES: usize = 100;
+
 pub struct Terminal {
     observers: Vec<alloc::rc::Weak<RefCell<dyn TerminalObserver>>>,
+    scrollback: RefCell<VecDeque<String>>,
+    // lines added since boot, the sequence number of the next line
+    next_seq: Cell<u64>,
 }
 
 pub fn term() -> &'static Terminal {
@@ -26,6 +32,8 @@ impl Terminal {
     pub fn initialize() {
         let global_term = Self {
             observers: Vec::new(),
+            scrollback: RefCell::new(VecDeque::with_capacity(TERMINAL_SCROLLBACK_LINES)),
+            next_seq: Cell::new(0),
         };
         unsafe {
             #[allow(static_mut_refs)]
@@ -33,13 +41,49 @@ impl Terminal {
         }
     }
     pub fn add_text_new_line(&self, txt: &str) {
+        {
+            let mut scrollback = self.scrollback.borrow_mut();
+            if scrollback.len() == TERMINAL_SCROLLBACK_LINES {
+                scrollback.pop_front();
+            }
+            scrollback.push_back(String::from(txt));
+        }
+        self.next_seq.set(self.next_seq.get() + 1);
         self.notify_add_text("\n");
         self.notify_add_text(txt);
+        self.notify_new_line(txt);
     }
     pub fn add_text_same_line(&self, txt: &str) {
+        if let Some(line) = self.scrollback.borrow_mut().back_mut() {
+            line.push_str(txt);
+        }
         self.notify_add_text(txt);
     }
 
+    // Up to the last n lines, oldest first
+    pub fn recent(&self, n: usize) -> Vec<String> {
+        let scrollback = self.scrollback.borrow();
+        scrollback
+            .iter()
+            .skip(scrollback.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    // Lines with sequence number since or later still in the scrollback, and the sequence number to
+    // pass next time for polling. Text later added to the last line with add_text_same_line is missed.
+    pub fn recent_since(&self, since: u64) -> (Vec<String>, u64) {
+        let scrollback = self.scrollback.borrow();
+        let next_seq = self.next_seq.get();
+        let first_seq = next_seq - scrollback.len() as u64;
+        let lines = scrollback
+            .iter()
+            .skip(since.saturating_sub(first_seq) as usize)
+            .cloned()
+            .collect();
+        (lines, next_seq)
+    }
+
     pub fn subscribe(&mut self, observer: alloc::rc::Weak<RefCell<dyn TerminalObserver>>) {
         self.observers.push(observer);
     }
@@ -49,8 +93,17 @@ impl Terminal {
             observer.borrow_mut().on_add_text(text);
         }
     }
+    fn notify_new_line(&self, line: &str) {
+        for weak_observer in self.observers.iter() {
+            let observer = weak_observer.upgrade().unwrap();
+            observer.borrow_mut().on_new_line(line);
+        }
+    }
 }
 
 pub trait TerminalObserver {
     fn on_add_text(&mut self, text: &str);
+    // A line was appended to the scrollback, text added to it later with add_text_same_line
+    // is only reported through on_add_text
+    fn on_new_line(&mut self, _line: &str) {}
 }
@@ -0,0 +1,103 @@
+use alloc::string::String;
+use core::fmt::{self, Write as _};
+
+// User facing texts the framework shows on the terminal.
+// Apps can provide translations by setting FrameworkSettings::texts, returning None falls back to the default (English).
+// '{}' in a text is replaced by the text arguments, in order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameworkText {
+    WifiNotConfigured,
+    ProvideWifiCredentials,
+    ProvideWifiCredentialsAp,
+    ProvideWifiCredentialsUrl,
+    ProvideWifiCredentialsWebFlash,
+    ConnectingToWifi,
+    ConnectedToWifi,
+    WaitingForIp,
+    ReceivedIp,
+    StoredWifiCredentials,
+    WifiConnectError,
+    ConfigPortalGrace,
+    WifiReconnectIn,
+    WifiDisconnected,
+    WifiFallbackToAp,
+    WifiFallbackRetry,
+    WifiFallbackRetryOk,
+    ImprovStoppingAp,
+    ImprovCredentialsCheck,
+    ImprovCredentialsOk,
+    ImprovCredentialsIncorrect,
+    WebCredentialsCheck,
+    WebCredentialsOk,
+    WebCredentialsNotStored,
+    WebStoppingAp,
+    WebCredentialsIncorrect,
+}
+
+pub type FrameworkTexts = fn(FrameworkText) -> Option<&'static str>;
+
+impl FrameworkText {
+    pub fn default_text(&self) -> &'static str {
+        match self {
+            FrameworkText::WifiNotConfigured => "WiFi Credentions not Configured.",
+            FrameworkText::ProvideWifiCredentials => "Provide WiFi credentials using either:",
+            FrameworkText::ProvideWifiCredentialsAp => "- WiFi SSID: {}",
+            FrameworkText::ProvideWifiCredentialsUrl => "  URL: {} or {}",
+            FrameworkText::ProvideWifiCredentialsWebFlash => {
+                "- Continue web flash process in browser"
+            }
+            FrameworkText::ConnectingToWifi => "About to connect to WiFi SSID '{}'",
+            FrameworkText::ConnectedToWifi => "Connected to WiFi",
+            FrameworkText::WaitingForIp => "Waiting for an IP",
+            FrameworkText::ReceivedIp => "Received IP: {}",
+            FrameworkText::StoredWifiCredentials => "Stored credentials in flash",
+            FrameworkText::WifiConnectError => "Error while trying to connect to wifi: {}",
+            FrameworkText::ConfigPortalGrace => "Config portal on WiFi SSID '{}' open for {}s",
+            FrameworkText::WifiReconnectIn => "Retrying WiFi connection in {}s",
+            FrameworkText::WifiDisconnected => "WiFi disconnected, reconnecting...",
+            FrameworkText::WifiFallbackToAp => {
+                "Failed connecting to WiFi SSID '{}' {} times, reopening WiFi setup"
+            }
+            FrameworkText::WifiFallbackRetry => "Retrying stored WiFi SSID '{}'",
+            FrameworkText::WifiFallbackRetryOk => {
+                "Stored WiFi credentials work again, closing WiFi setup"
+            }
+            FrameworkText::ImprovStoppingAp => "ImprovWiFi setup: Stopping Access Point",
+            FrameworkText::ImprovCredentialsCheck => "ImprovWiFi: Credentials check - WiFi '{}'",
+            FrameworkText::ImprovCredentialsOk => "ImprovWiFi: Credentials Ok",
+            FrameworkText::ImprovCredentialsIncorrect => "ImprovWiFi: Credentials incorrect",
+            FrameworkText::WebCredentialsCheck => "Web Config: Credentials check - WiFi '{}'",
+            FrameworkText::WebCredentialsOk => "Web Config: Credentials Ok",
+            FrameworkText::WebCredentialsNotStored => {
+                "Web Config: Credentials not stored, keeping Access Point"
+            }
+            FrameworkText::WebStoppingAp => "Web Config: Stopping Access Point",
+            FrameworkText::WebCredentialsIncorrect => "Web Config: Credentials incorrect ({})",
+        }
+    }
+}
+
+pub fn lookup(texts: Option<FrameworkTexts>, text: FrameworkText) -> &'static str {
+    texts
+        .and_then(|texts| texts(text))
+        .unwrap_or(text.default_text())
+}
+
+// Replaces each '{}' in template with the next argument, extra placeholders are left as is
+pub fn fill(template: &str, args: &[&dyn fmt::Display]) -> String {
+    let mut result = String::new();
+    let mut args = args.iter();
+    let mut parts = template.split("{}").peekable();
+    while let Some(part) = parts.next() {
+        result.push_str(part);
+        if parts.peek().is_some() {
+            match args.next() {
+                Some(arg) => {
+                    let _ = write!(result, "{arg}");
+                }
+                None => result.push_str("{}"),
+            }
+        }
+    }
+    result
+}
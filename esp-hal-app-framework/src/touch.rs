@@ -1,3 +1,4 @@
+use embassy_time::{with_timeout, Duration, Instant};
 use embedded_hal::digital::InputPin;
 
 pub enum Error {
@@ -42,23 +43,145 @@ pub trait IrqTraits = InputPin + embedded_hal_async::digital::Wait;
 
 #[allow(async_fn_in_trait)]
 pub trait TouchAdapter {
+    // Must be safe to cancel while waiting for a touch, release debouncing times it out
     async fn next_event(&mut self) -> Result<TouchEvent, Error>;
 }
 
+const TOUCH_MAX_SMOOTHING_SAMPLES: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TouchConfig {
+    // Moves are reported as the average of this many latest samples (1 to 8), 1 for raw positions
+    pub smoothing_samples: usize,
+    // Moves within this distance (in pixels) of the last reported position are dropped
+    pub deadband: i32,
+    // A release followed by a press within this time is treated as a move, for panels that drop out mid drag
+    pub release_debounce: Duration,
+}
+
+impl TouchConfig {
+    // Positions and events exactly as the adapter reports them
+    pub const fn raw() -> Self {
+        Self {
+            smoothing_samples: 1,
+            deadband: 0,
+            release_debounce: Duration::from_ticks(0),
+        }
+    }
+}
+
+impl Default for TouchConfig {
+    fn default() -> Self {
+        Self {
+            smoothing_samples: 3,
+            deadband: 2,
+            release_debounce: Duration::from_millis(50),
+        }
+    }
+}
+
 pub struct Touch<A> {
     adapter: A,
+    config: TouchConfig,
+    samples: [TouchPosition; TOUCH_MAX_SMOOTHING_SAMPLES],
+    samples_len: usize,
+    next_sample: usize,
+    last_reported: Option<TouchPosition>,
 }
 
 impl<A> Touch<A>
 where
     A: TouchAdapter,
 {
-    pub fn new(adapter: A) -> Self {
-        Self { adapter }
+    pub fn new(adapter: A, config: TouchConfig) -> Self {
+        Self {
+            adapter,
+            config,
+            samples: [TouchPosition { x: 0, y: 0 }; TOUCH_MAX_SMOOTHING_SAMPLES],
+            samples_len: 0,
+            next_sample: 0,
+            last_reported: None,
+        }
     }
 
     pub async fn event_async(&mut self) -> Result<Option<TouchEvent>, Error> {
-        self.adapter.next_event().await.map(Some)
+        loop {
+            let event = match self.adapter.next_event().await? {
+                TouchEvent::TouchReleased(pos) if self.config.release_debounce.as_ticks() > 0 => {
+                    match with_timeout(self.config.release_debounce, self.adapter.next_event())
+                        .await
+                    {
+                        Ok(Ok(TouchEvent::TouchPressed(pos) | TouchEvent::TouchMoved(pos))) => {
+                            TouchEvent::TouchMoved(pos)
+                        }
+                        Ok(Ok(TouchEvent::TouchReleased(_))) | Err(_) => {
+                            TouchEvent::TouchReleased(pos)
+                        }
+                        Ok(Err(err)) => return Err(err),
+                    }
+                }
+                event => event,
+            };
+            if let Some(event) = self.filter(event) {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    fn filter(&mut self, event: TouchEvent) -> Option<TouchEvent> {
+        match event {
+            TouchEvent::TouchPressed(pos) => {
+                self.samples_len = 0;
+                self.add_sample(pos);
+                self.last_reported = Some(pos);
+                Some(event)
+            }
+            TouchEvent::TouchMoved(pos) => {
+                self.add_sample(pos);
+                let pos = self.smoothed_position();
+                if let Some(last) = self.last_reported {
+                    if self.config.deadband > 0
+                        && (pos.x - last.x).abs() <= self.config.deadband
+                        && (pos.y - last.y).abs() <= self.config.deadband
+                    {
+                        return None;
+                    }
+                }
+                self.last_reported = Some(pos);
+                Some(TouchEvent::TouchMoved(pos))
+            }
+            // When filtering, released where the last move was reported so the release doesn't jump
+            TouchEvent::TouchReleased(pos) => {
+                self.samples_len = 0;
+                let last_reported = self.last_reported.take();
+                if self.config.smoothing_samples <= 1 && self.config.deadband <= 0 {
+                    return Some(event);
+                }
+                Some(TouchEvent::TouchReleased(last_reported.unwrap_or(pos)))
+            }
+        }
+    }
+
+    fn add_sample(&mut self, pos: TouchPosition) {
+        let window = self
+            .config
+            .smoothing_samples
+            .clamp(1, TOUCH_MAX_SMOOTHING_SAMPLES);
+        if self.samples_len == 0 {
+            self.next_sample = 0;
+        }
+        self.samples[self.next_sample] = pos;
+        self.next_sample = (self.next_sample + 1) % window;
+        self.samples_len = (self.samples_len + 1).min(window);
+    }
+
+    fn smoothed_position(&self) -> TouchPosition {
+        let samples = &self.samples[..self.samples_len];
+        let len = samples.len().max(1) as i32;
+        TouchPosition {
+            x: samples.iter().map(|pos| pos.x).sum::<i32>() / len,
+            y: samples.iter().map(|pos| pos.y).sum::<i32>() / len,
+        }
     }
 
     // https://stackoverflow.com/questions/66607516/how-to-implement-streams-from-future-functions
@@ -72,3 +195,108 @@ where
         })
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Tap(TouchPosition),
+    LongPress(TouchPosition),
+    SwipeLeft,
+    SwipeRight,
+    SwipeUp,
+    SwipeDown,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GestureConfig {
+    // A press that moved less than this (in pixels) is a tap or long press
+    pub tap_max_distance: i32,
+    // Held at least this long without moving is a long press
+    pub long_press_min_duration: Duration,
+    // Swipes must cover at least this distance (in pixels) along the dominant axis
+    pub swipe_min_distance: i32,
+    // Slower drags are not swipes, e.g. scrolling a list
+    pub swipe_max_duration: Duration,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            tap_max_distance: 15,
+            long_press_min_duration: Duration::from_millis(600),
+            swipe_min_distance: 60,
+            swipe_max_duration: Duration::from_millis(800),
+        }
+    }
+}
+
+// Turns press/move/release sequences into gestures, reported on release
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    pressed: Option<(TouchPosition, Instant)>,
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            pressed: None,
+        }
+    }
+
+    pub fn process(&mut self, event: TouchEvent) -> Option<Gesture> {
+        match event {
+            TouchEvent::TouchPressed(pos) => {
+                self.pressed = Some((pos, Instant::now()));
+                None
+            }
+            TouchEvent::TouchMoved(_) => None,
+            TouchEvent::TouchReleased(pos) => {
+                let (start, pressed_at) = self.pressed.take()?;
+                self.classify(start, pos, pressed_at.elapsed())
+            }
+        }
+    }
+
+    fn classify(
+        &self,
+        start: TouchPosition,
+        end: TouchPosition,
+        duration: Duration,
+    ) -> Option<Gesture> {
+        let dx = end.x - start.x;
+        let dy = end.y - start.y;
+        if dx.abs() <= self.config.tap_max_distance && dy.abs() <= self.config.tap_max_distance {
+            return if duration >= self.config.long_press_min_duration {
+                Some(Gesture::LongPress(start))
+            } else {
+                Some(Gesture::Tap(start))
+            };
+        }
+        if duration > self.config.swipe_max_duration
+            || dx.abs().max(dy.abs()) < self.config.swipe_min_distance
+        {
+            return None;
+        }
+        Some(match (dx.abs() >= dy.abs(), dx > 0, dy > 0) {
+            (true, true, _) => Gesture::SwipeRight,
+            (true, false, _) => Gesture::SwipeLeft,
+            (false, _, true) => Gesture::SwipeDown,
+            (false, _, false) => Gesture::SwipeUp,
+        })
+    }
+
+    // For apps reading Touch directly rather than through the framework's ui loop
+    pub fn gestures<'a, S>(mut self, events: S) -> impl futures::Stream<Item = Gesture> + 'a
+    where
+        S: futures::Stream<Item = Result<Option<TouchEvent>, Error>> + 'a,
+    {
+        use futures::StreamExt;
+        events.filter_map(move |event| {
+            let gesture = match event {
+                Ok(Some(event)) => self.process(event),
+                _ => None,
+            };
+            core::future::ready(gesture)
+        })
+    }
+}
@@ -2,14 +2,14 @@ use alloc::{boxed::Box, rc::Rc};
 use core::cell::RefCell;
 
 use embassy_futures::select::{Either3, Either4, select3, select4};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use slint::platform::{WindowEvent, update_timers_and_animations};
 
 use crate::{
     backlight::{BacklightConfig, BacklightController, BacklightDevice},
     framework::Framework,
     slint_ext::McuWindow,
-    touch::{Touch, TouchAdapter, TouchEvent, TouchPosition},
+    touch::{GestureRecognizer, Touch, TouchAdapter, TouchEvent, TouchPosition},
 };
 
 pub trait UiRenderBackend {
@@ -42,6 +42,17 @@ pub async fn event_loop<T, R, B>(
 
     let undim_display = framework.borrow().undim_display;
     let mut backlight_controller = BacklightController::new();
+    // Brightness changes are signaled through undim_display, like other activity
+    let brightness_percent = || framework.borrow().display_brightness_percent;
+    backlight_controller
+        .register_activity(&mut backlight, brightness_percent())
+        .expect("Failed to set display backlight brightness");
+    // Gestures are recognized alongside the raw pointer events dispatched to slint
+    let mut gesture_recognizer = framework
+        .borrow()
+        .settings
+        .touch_gestures
+        .map(GestureRecognizer::new);
 
     // Helper function for coordinates transformation
     #[inline(always)]
@@ -58,6 +69,10 @@ pub async fn event_loop<T, R, B>(
     // Touch events will translate to left button mouse
     let button = slint::platform::PointerEventButton::Left;
 
+    // For render FPS reporting
+    let mut frames_rendered = 0u32;
+    let mut fps_window_start = Instant::now();
+
     // let mut loop_count = 0;
     loop {
         // loop_count += 1;
@@ -67,9 +82,18 @@ pub async fn event_loop<T, R, B>(
         slint::platform::update_timers_and_animations();
 
         window.draw_if_needed(|renderer| {
+            frames_rendered += 1;
             render_backend.render(renderer)
         });
 
+        let fps_window = fps_window_start.elapsed();
+        if fps_window >= Duration::from_secs(1) {
+            framework.borrow_mut().render_fps =
+                (frames_rendered as u64 * 1000 / fps_window.as_millis()) as u32;
+            frames_rendered = 0;
+            fps_window_start = Instant::now();
+        }
+
         let async_res;
 
         if window.has_active_animations() {
@@ -127,7 +151,7 @@ pub async fn event_loop<T, R, B>(
                     trace!("Undimming the display");
                 }
                 backlight_controller
-                    .register_activity(&mut backlight)
+                    .register_activity(&mut backlight, brightness_percent())
                     .expect("Failed to undim display backlight");
 
                 // Now address the case of touch
@@ -137,6 +161,17 @@ pub async fn event_loop<T, R, B>(
                         Err(_) => panic!("Touch event stream failed"),
                         Ok(event) => {
                             if let Some(event) = event {
+                                if let Some(recognizer) = gesture_recognizer.as_mut() {
+                                    if !backlight_controller.ignoring_touch() {
+                                        if let Some(gesture) = recognizer.process(event) {
+                                            framework
+                                                .borrow()
+                                                .touch_gestures
+                                                .immediate_publisher()
+                                                .publish_immediate(gesture);
+                                        }
+                                    }
+                                }
                                 match event {
                                     TouchEvent::TouchMoved(pos) => {
                                         if !backlight_controller.ignoring_touch() {
@@ -173,6 +208,12 @@ pub async fn event_loop<T, R, B>(
                     }
                 }
             }
+            Either4::Second(_) if framework.borrow().is_kept_awake() => {
+                // Critical operation in progress, treat as activity so dimming restarts counting once released
+                backlight_controller
+                    .register_activity(&mut backlight, brightness_percent())
+                    .expect("Failed to undim display backlight");
+            }
             Either4::Second(_) => {
                 let cfg = {
                     let framework = framework.borrow();
@@ -180,6 +221,7 @@ pub async fn event_loop<T, R, B>(
                         dimming_timeout_secs: framework.display_dimming_timeout,
                         dimming_percent: framework.display_dimming_percent,
                         blackout_timeout_secs: framework.display_blackout_timeout,
+                        brightness_percent: framework.display_brightness_percent,
                     }
                 };
 
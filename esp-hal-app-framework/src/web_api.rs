@@ -0,0 +1,367 @@
+// Request bodies (and query parameters) of the config API and its /api/schema description. No hardware
+// dependencies, so framework-host-tests checks on the host that the two agree.
+// API_ENDPOINTS is kept by hand next to the DTOs, update it when adding a route or changing a DTO.
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+use crate::ota_checks::OtaRequest;
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct WifiConfigDTO {
+    pub(crate) ssid: String,
+    pub(crate) password: String,
+    // WPA2-Enterprise, absent for WPA2-Personal/open networks
+    #[serde(default)]
+    pub(crate) identity: Option<String>,
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    #[serde(default)]
+    pub(crate) hidden: bool,
+    // when set, credentials are stored only after successfully connecting with them
+    #[serde(default, skip_serializing)]
+    pub(crate) test_connect: bool,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct DeviceNameDTO {
+    pub(crate) name: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ResetDeviceDTO {}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct FactoryResetDTO {}
+
+// Query parameters of /api/config-export, the device name is left out unless asked for
+#[derive(Deserialize)]
+pub(crate) struct ConfigExportQueryDTO {
+    pub(crate) include_device_name: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ConfigImportDTO {
+    pub(crate) blob: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct DisplayConfigDTO {
+    pub(crate) dimming_timeout: u64,
+    pub(crate) dimming_percent: u8,
+    pub(crate) blackout_timeout: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TestKeyDTO {
+    pub(crate) test: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct FixedKeyConfigDTO {
+    pub(crate) key: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TlsConfigDTO {
+    pub(crate) certificate: String,
+    pub(crate) private_key: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct OtaRequestDTO {
+    pub(crate) request: OtaRequest,
+}
+
+// Query parameters of /api/logs, since is the next_seq of the previous response
+#[derive(Deserialize)]
+pub(crate) struct LogsQueryDTO {
+    pub(crate) since: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EndpointSchema {
+    pub(crate) path: &'static str,
+    pub(crate) method: &'static str,
+    // one of the ENCRYPTION_SCHEMES keys
+    pub(crate) encryption: &'static str,
+    pub(crate) request: Option<&'static [FieldSchema]>,
+    pub(crate) response: Option<&'static [FieldSchema]>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FieldSchema {
+    pub(crate) name: &'static str,
+    // "string", "bool", "u8", "u64", a trailing '?' marks optional (may be null/absent), 'a|b' lists enum values
+    #[serde(rename = "type")]
+    pub(crate) ty: &'static str,
+}
+
+pub(crate) const ENCRYPTION_SCHEMES: &[(&str, &str)] = &[
+    ("gcm", "AES-256-GCM with the web config key and the endpoint path as AAD, body is \"v2:\" + the encrypted JSON"),
+    ("ctr", "AES-256-CTR + HMAC-SHA256 with the web config key, body is the encrypted JSON"),
+    ("none", "plain"),
+];
+
+const fn field(name: &'static str, ty: &'static str) -> FieldSchema {
+    FieldSchema { name, ty }
+}
+
+const SET_CONFIG_RESPONSE_SCHEMA: &[FieldSchema] = &[field("error_text", "string?")];
+const WIFI_CONFIG_SCHEMA: &[FieldSchema] = &[
+    field("ssid", "string"),
+    field("password", "string"),
+    field("identity", "string?"),
+    field("username", "string?"),
+    field("hidden", "bool?"),
+    field("test_connect", "bool?"),
+];
+const DEVICE_NAME_SCHEMA: &[FieldSchema] = &[field("name", "string")];
+const DISPLAY_CONFIG_SCHEMA: &[FieldSchema] = &[
+    field("dimming_timeout", "u64"),
+    field("dimming_percent", "u8"),
+    field("blackout_timeout", "u64"),
+];
+const FIXED_KEY_CONFIG_SCHEMA: &[FieldSchema] = &[field("key", "string")];
+const TEST_KEY_SCHEMA: &[FieldSchema] = &[field("test", "string")];
+
+const fn endpoint(
+    path: &'static str,
+    method: &'static str,
+    encryption: &'static str,
+    request: Option<&'static [FieldSchema]>,
+    response: Option<&'static [FieldSchema]>,
+) -> EndpointSchema {
+    EndpointSchema {
+        path,
+        method,
+        encryption,
+        request,
+        response,
+    }
+}
+
+const KEY_PARAMS_SCHEMA: &[FieldSchema] = &[field("salt", "string"), field("iterations", "u32")];
+
+pub(crate) const API_ENDPOINTS: &[EndpointSchema] = &[
+    endpoint("/captive/api/key-params", "GET", "none", None, Some(KEY_PARAMS_SCHEMA)),
+    endpoint("/captive/api/test-key", "POST", "ctr", Some(TEST_KEY_SCHEMA), None),
+    endpoint(
+        "/captive/api/fixed-key-config",
+        "POST",
+        "ctr",
+        Some(FIXED_KEY_CONFIG_SCHEMA),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint(
+        "/captive/api/wifi-config",
+        "POST",
+        "ctr",
+        Some(WIFI_CONFIG_SCHEMA),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint("/captive/api/wifi-config", "GET", "ctr", None, Some(WIFI_CONFIG_SCHEMA)),
+    endpoint(
+        "/captive/api/device-name-config",
+        "POST",
+        "ctr",
+        Some(DEVICE_NAME_SCHEMA),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint("/captive/api/device-name-config", "GET", "ctr", None, Some(DEVICE_NAME_SCHEMA)),
+    endpoint(
+        "/captive/api/reset-device",
+        "POST",
+        "ctr",
+        Some(&[]),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint(
+        "/api/wifi-config",
+        "POST",
+        "gcm",
+        Some(WIFI_CONFIG_SCHEMA),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint("/api/wifi-config", "GET", "gcm", None, Some(WIFI_CONFIG_SCHEMA)),
+    endpoint(
+        "/api/device-name-config",
+        "POST",
+        "gcm",
+        Some(DEVICE_NAME_SCHEMA),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint("/api/device-name-config", "GET", "gcm", None, Some(DEVICE_NAME_SCHEMA)),
+    endpoint(
+        "/api/reset-device",
+        "POST",
+        "gcm",
+        Some(&[]),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint(
+        "/api/factory-reset",
+        "POST",
+        "gcm",
+        Some(&[]),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint(
+        "/api/config-export",
+        "GET",
+        "gcm",
+        Some(&[field("include_device_name", "bool?")]),
+        Some(&[field("blob", "string?"), field("error_text", "string?")]),
+    ),
+    endpoint(
+        "/api/config-import",
+        "POST",
+        "gcm",
+        Some(&[field("blob", "string")]),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint(
+        "/api/display-config",
+        "POST",
+        "gcm",
+        Some(DISPLAY_CONFIG_SCHEMA),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint("/api/display-config", "GET", "gcm", None, Some(DISPLAY_CONFIG_SCHEMA)),
+    endpoint(
+        "/api/test-key",
+        "POST",
+        "gcm",
+        Some(TEST_KEY_SCHEMA),
+        Some(&[field("error_text", "string?")]),
+    ),
+    endpoint(
+        "/api/fixed-key-config",
+        "POST",
+        "gcm",
+        Some(FIXED_KEY_CONFIG_SCHEMA),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint(
+        "/api/tls-config",
+        "POST",
+        "gcm",
+        Some(&[field("certificate", "string"), field("private_key", "string")]),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    #[cfg(any(feature = "wt32-sc01-plus", feature = "jc8048w550c"))]
+    endpoint("/download/log.txt", "POST", "gcm", Some(&[]), None),
+    endpoint(
+        "/api/ota-request",
+        "POST",
+        "gcm",
+        Some(&[field("request", "CheckVersion|Update|Stage")]),
+        Some(SET_CONFIG_RESPONSE_SCHEMA),
+    ),
+    endpoint(
+        "/api/device-info",
+        "GET",
+        "gcm",
+        None,
+        Some(&[
+            field("app_name", "string"),
+            field("app_version", "string"),
+            field("device_name", "string?"),
+            field("uptime_secs", "u64"),
+            field("reset_reason", "string"),
+        ]),
+    ),
+    // since is a query parameter rather than a body
+    endpoint(
+        "/api/logs",
+        "GET",
+        "gcm",
+        Some(&[field("since", "u64?")]),
+        Some(&[field("lines", "string[]"), field("next_seq", "u64")]),
+    ),
+    endpoint(
+        "/api/ota-config",
+        "GET",
+        "gcm",
+        None,
+        Some(&[field("status", "string"), field("curr_ver", "string")]),
+    ),
+    endpoint("/api/key-params", "GET", "none", None, Some(KEY_PARAMS_SCHEMA)),
+    endpoint("/metrics", "GET", "none", None, None),
+    endpoint("/api/schema", "GET", "none", None, None),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+
+    fn request_schema(path: &str, method: &str) -> &'static [FieldSchema] {
+        API_ENDPOINTS
+            .iter()
+            .find(|endpoint| endpoint.path == path && endpoint.method == method)
+            .and_then(|endpoint| endpoint.request)
+            .unwrap_or_else(|| panic!("no request schema for {method} {path}"))
+    }
+
+    fn sample_value(ty: &str) -> Value {
+        match ty.trim_end_matches('?') {
+            "string" => json!("x"),
+            "string[]" => json!(["x"]),
+            "bool" => json!(true),
+            ty if ty.contains('|') => json!(ty.split('|').next().unwrap()),
+            _ => json!(1),
+        }
+    }
+
+    fn sample(fields: &[FieldSchema], without: Option<&str>) -> Value {
+        Value::Object(
+            fields
+                .iter()
+                .filter(|field| Some(field.name) != without)
+                .map(|field| (field.name.to_string(), sample_value(field.ty)))
+                .collect(),
+        )
+    }
+
+    // The documented fields are all the DTO needs, and the ones documented as required are required by it
+    fn check_request<T: DeserializeOwned>(path: &str, method: &str) {
+        let fields = request_schema(path, method);
+        if let Err(e) = serde_json::from_value::<T>(sample(fields, None)) {
+            panic!("{method} {path} schema doesn't match its DTO: {e}");
+        }
+        for field in fields.iter().filter(|field| !field.ty.ends_with('?')) {
+            assert!(
+                serde_json::from_value::<T>(sample(fields, Some(field.name))).is_err(),
+                "{method} {path} documents {} as required, the DTO doesn't require it",
+                field.name
+            );
+        }
+    }
+
+    #[test]
+    fn captive_request_schemas_match_dtos() {
+        check_request::<TestKeyDTO>("/captive/api/test-key", "POST");
+        check_request::<FixedKeyConfigDTO>("/captive/api/fixed-key-config", "POST");
+        check_request::<WifiConfigDTO>("/captive/api/wifi-config", "POST");
+        check_request::<DeviceNameDTO>("/captive/api/device-name-config", "POST");
+        check_request::<ResetDeviceDTO>("/captive/api/reset-device", "POST");
+    }
+
+    #[test]
+    fn api_request_schemas_match_dtos() {
+        check_request::<WifiConfigDTO>("/api/wifi-config", "POST");
+        check_request::<DeviceNameDTO>("/api/device-name-config", "POST");
+        check_request::<ResetDeviceDTO>("/api/reset-device", "POST");
+        check_request::<FactoryResetDTO>("/api/factory-reset", "POST");
+        check_request::<ConfigExportQueryDTO>("/api/config-export", "GET");
+        check_request::<ConfigImportDTO>("/api/config-import", "POST");
+        check_request::<DisplayConfigDTO>("/api/display-config", "POST");
+        check_request::<TestKeyDTO>("/api/test-key", "POST");
+        check_request::<FixedKeyConfigDTO>("/api/fixed-key-config", "POST");
+        check_request::<TlsConfigDTO>("/api/tls-config", "POST");
+        check_request::<OtaRequestDTO>("/api/ota-request", "POST");
+        check_request::<LogsQueryDTO>("/api/logs", "GET");
+    }
+}
@@ -1,17 +1,28 @@
-use core::{cell::RefCell, ffi::CStr};
+use core::{
+    cell::RefCell,
+    ffi::CStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use alloc::{
-    boxed::Box,
     format,
     rc::Rc,
     string::{String, ToString},
+    vec,
 };
-use embassy_futures::select::select;
+use embassy_futures::{join::join, select::select};
 use embassy_net::Stack;
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, pubsub::WaitResult};
+use embassy_time::Duration;
 use embedded_io_async::Write;
 use esp_mbedtls::TlsReference;
-use picoserve::{routing, AppRouter, AppWithStateBuilder, Config, LogDisplay, Router};
+use embassy_net::IpEndpoint;
+use picoserve::{
+    request::RequestParts,
+    response::{Body, Connection, HeadersIter, Response, ResponseWriter},
+    routing::{self, Layer, Next},
+    AppRouter, AppWithStateBuilder, Config, ResponseSent, Router,
+};
 
 use embassy_net::tcp::TcpSocket;
 use embassy_sync::mutex::Mutex;
@@ -21,7 +32,10 @@ use esp_mbedtls::{
 };
 
 use super::{
-    framework::{Framework, WebServerCommands, WebServerSubscriber},
+    framework::{
+        Framework, FrameworkSettings, TlsConfigError, WebConfigMode, WebServerCommands,
+        WebServerSubscriber,
+    },
     framework_web_app::{NestedAppWithWebAppStateBuilder, WebAppBuilder, WebAppState},
 };
 
@@ -36,6 +50,10 @@ pub struct WebAppRunner<
     framework: Rc<RefCell<Framework>>,
     generic_runner:
         GenericRunner<WebAppBuilder<MoreState, NestedMainAppBuilder>, WebAppState<MoreState>>,
+    // plaintext listener alongside the https one, see FrameworkSettings::web_server_http_port
+    http_runner: Option<
+        GenericRunner<WebAppBuilder<MoreState, NestedMainAppBuilder>, WebAppState<MoreState>>,
+    >,
 }
 
 impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>>
@@ -45,30 +63,76 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
         framework: Rc<RefCell<Framework>>,
         app_router: &'static AppRouter<WebAppBuilder<MoreState, NestedMainAppBuilder>>,
         app_state: &'static WebAppState<MoreState>,
-        config: Config,
+        mut config: Config,
     ) -> Self {
+        if let Some(timeout) = framework.borrow().settings.web_server_request_timeout {
+            config.timeouts.read_request = Some(timeout);
+            config.timeouts.write = Some(timeout);
+        }
+        // Stored per device credentials win over the ones built into the app, leaked once as the
+        // runner lives for the whole program and TLS needs them nul terminated and 'static
+        let stored_credentials = framework
+            .borrow()
+            .tls_config
+            .as_ref()
+            .and_then(|tls_config| {
+                let certificate = format!("{}\0", tls_config.certificate);
+                let private_key = format!("{}\0", tls_config.private_key);
+                if let Err(e) = parse_tls_credentials(&certificate, &private_key).map(|_| ()) {
+                    error!("Stored TLS credentials can't be used ({e}), using the built in ones");
+                    return None;
+                }
+                Some((&*certificate.leak(), &*private_key.leak()))
+            });
+        let (tls_certificate, tls_private_key) = stored_credentials.unwrap_or((
+            framework.borrow().settings.web_server_tls_certificate,
+            framework.borrow().settings.web_server_tls_private_key,
+        ));
         let web_server_config = WebServerConfig {
             web_app_name: "Web-Config",
             port: framework.borrow().settings.web_server_port,
+            bind_interface: framework.borrow().settings.web_server_bind_interface,
+            log_requests: framework.borrow().settings.web_server_log_requests,
+            tcp_rx_buffer_size: framework.borrow().settings.web_server_tcp_rx_buffer_size,
+            tcp_tx_buffer_size: framework.borrow().settings.web_server_tcp_tx_buffer_size,
+            http_buffer_size: framework.borrow().settings.web_server_http_buffer_size,
+            keep_alive: framework.borrow().settings.web_server_keep_alive,
+            keep_alive_timeout: framework.borrow().settings.web_server_keep_alive_timeout,
             tls: framework.borrow().settings.web_server_https,
-            tls_certificate: framework.borrow().settings.web_server_tls_certificate,
-            tls_private_key: framework.borrow().settings.web_server_tls_private_key,
+            tls_certificate,
+            tls_private_key,
         };
         let generic_runner = GenericRunner::<
             WebAppBuilder<MoreState, NestedMainAppBuilder>,
             WebAppState<MoreState>,
         >::new(
             framework.clone(),
-            web_server_config,
+            web_server_config.clone(),
             app_router,
             app_state,
             framework.borrow().web_server_commands,
             config.clone(),
         );
+        let http_runner = http_port(&framework.borrow().settings).map(|port| {
+            GenericRunner::new(
+                framework.clone(),
+                WebServerConfig {
+                    web_app_name: "Web-Config-HTTP",
+                    port,
+                    tls: false,
+                    ..web_server_config.clone()
+                },
+                app_router,
+                app_state,
+                framework.borrow().web_server_commands,
+                config.clone(),
+            )
+        });
 
         let myself = Self {
             framework: framework.clone(),
             generic_runner,
+            http_runner,
         };
 
         myself.start_captive_if_needed(); // TODO: why is it here and not in run()?
@@ -76,8 +140,14 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
         myself
     }
 
+    // Serves one connection at a time, run it from web_server_num_listeners tasks with distinct ids
     pub async fn run(&self, id: usize) {
-        self.generic_runner.run(id).await;
+        match &self.http_runner {
+            Some(http_runner) => {
+                join(self.generic_runner.run(id), http_runner.run(id)).await;
+            }
+            None => self.generic_runner.run(id).await,
+        }
     }
 
     fn start_captive_if_needed(&self) {
@@ -91,25 +161,43 @@ impl<MoreState, NestedMainAppBuilder: NestedAppWithWebAppStateBuilder<MoreState>
             need_standalone_captive = true;
         }
 
-        if !self.framework.borrow().settings.web_server_captive {
+        if !self.framework.borrow().settings.web_server_captive
+            || !self.framework.borrow().settings.web_server
+        {
+            need_standalone_captive = false;
+        }
+
+        // The plaintext listener already takes port 80 and redirects captive probes to https
+        if http_port(&self.framework.borrow().settings) == Some(80) {
             need_standalone_captive = false;
         }
 
         let spawner = self.framework.borrow().spawner;
         let web_server_commands = self.framework.borrow().web_server_commands;
         let web_app_domain = self.framework.borrow().settings.web_app_domain;
+        let bind_interface = self.framework.borrow().settings.web_server_bind_interface;
+        let buffer_size = self.framework.borrow().settings.web_server_captive_buffer_size;
 
         if need_standalone_captive {
             spawner
                 .spawn(standalone_captive_redirect_listen_and_serve_task(
                     web_server_commands.subscriber().unwrap(),
                     web_app_domain.to_string(),
+                    bind_interface,
+                    buffer_size,
                 ))
                 .unwrap();
         }
     }
 }
 
+// Port of the plaintext listener served next to the https one, if any
+fn http_port(settings: &FrameworkSettings) -> Option<u16> {
+    settings
+        .web_server_http_port
+        .filter(|_| settings.web_server_https)
+}
+
 //////////////////////////////////////////////////////////////////////////////////////////////////////////////
 // Generic Web Application Runner - To be used for generic web applications (on unconflicting ports with Web Config)
 //////////////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -140,18 +228,30 @@ where
         app_router: &'static AppRouter<GenericAppProps>,
         app_state: &'static GenericAppState,
         web_server_commands: &'static WebServerCommands,
-        config: Config,
+        mut config: Config,
     ) -> Self {
+        config = match web_server_config.keep_alive {
+            Some(true) => config.keep_connection_alive(),
+            Some(false) => config.close_connection_after_response(),
+            None => config,
+        };
+        if let Some(keep_alive_timeout) = web_server_config.keep_alive_timeout {
+            config.timeouts.persistent_start_read_request = Some(keep_alive_timeout);
+        }
         let tls_credentials = if web_server_config.tls {
-            let certificate =
-                CStr::from_bytes_with_nul(web_server_config.tls_certificate.as_bytes()).unwrap();
-            let private_key =
-                CStr::from_bytes_with_nul(web_server_config.tls_private_key.as_bytes()).unwrap();
-
-            Some(Credentials {
-                certificate: Certificate::new(X509::PEM(certificate)).unwrap(),
-                private_key: PrivateKey::new(X509::PEM(private_key), None).unwrap(),
-            })
+            match parse_tls_credentials(
+                web_server_config.tls_certificate,
+                web_server_config.tls_private_key,
+            ) {
+                Ok(credentials) => Some(credentials),
+                Err(e) => {
+                    error!(
+                        "{} Web Application: invalid TLS credentials ({e}), not serving",
+                        web_server_config.web_app_name
+                    );
+                    None
+                }
+            }
         } else {
             None
         };
@@ -170,6 +270,9 @@ where
     }
 
     pub async fn run(&self, id: usize) {
+        if self.web_server_config.tls && self.tls_credentials.is_none() {
+            return;
+        }
         web_task::<GenericAppProps, GenericAppState>(
             self.web_server_config.clone(),
             id,
@@ -184,16 +287,65 @@ where
     }
 }
 
+// PEM certificate and private key, each nul terminated, parsed as TLS will use them
+pub(crate) fn parse_tls_credentials<'a>(
+    certificate: &'a str,
+    private_key: &'a str,
+) -> Result<Credentials<'a>, TlsConfigError> {
+    let certificate = CStr::from_bytes_with_nul(certificate.as_bytes())
+        .ok()
+        .and_then(|certificate| Certificate::new(X509::PEM(certificate)).ok())
+        .ok_or(TlsConfigError::InvalidCertificate)?;
+    let private_key = CStr::from_bytes_with_nul(private_key.as_bytes())
+        .ok()
+        .and_then(|private_key| PrivateKey::new(X509::PEM(private_key), None).ok())
+        .ok_or(TlsConfigError::InvalidPrivateKey)?;
+    Ok(Credentials {
+        certificate,
+        private_key,
+    })
+}
+
 #[derive(Clone)]
 pub enum WebServerCommand {
-    Start(Stack<'static>),
+    Start(Stack<'static>, WebConfigMode),
     Stop,
 }
 
+// Which interface(s) a web server serves on, e.g. to keep the config API off the home network
+#[derive(Clone, Copy, Debug, Default)]
+pub enum WebServerBindInterface {
+    #[default]
+    All,
+    ApOnly,
+    StaOnly,
+}
+
+impl WebServerBindInterface {
+    pub fn allows(&self, mode: WebConfigMode) -> bool {
+        matches!(
+            (self, mode),
+            (WebServerBindInterface::All, _)
+                | (WebServerBindInterface::ApOnly, WebConfigMode::AP)
+                | (WebServerBindInterface::StaOnly, WebConfigMode::STA)
+        )
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct WebServerConfig {
     pub web_app_name: &'static str,
     pub port: u16,
+    pub bind_interface: WebServerBindInterface,
+    // per request logging (method, path, status, client), off by default as it's noisy
+    pub log_requests: bool,
+    pub tcp_rx_buffer_size: usize,
+    pub tcp_tx_buffer_size: usize,
+    pub http_buffer_size: usize,
+    // override the keep alive behavior and persistent_start_read_request timeout of the Config
+    // the runner is given, None keeps the Config's
+    pub keep_alive: Option<bool>,
+    pub keep_alive_timeout: Option<Duration>,
     pub tls: bool,
     pub tls_certificate: &'static str,
     pub tls_private_key: &'static str,
@@ -228,7 +380,18 @@ async fn web_task<GenericAppProps, GenericAppState>(
             Some(embassy_sync::pubsub::WaitResult::Message(WebServerCommand::Stop)) => {
                 command = None;
             }
-            Some(embassy_sync::pubsub::WaitResult::Message(WebServerCommand::Start(stack))) => {
+            Some(embassy_sync::pubsub::WaitResult::Message(WebServerCommand::Start(
+                stack,
+                mode,
+            ))) => {
+                if !web_server_config.bind_interface.allows(mode) {
+                    info!(
+                        "[{task_id}] {} Web Application: not serving on {:?} interface",
+                        web_server_config.web_app_name, mode
+                    );
+                    command = None;
+                    continue;
+                }
                 let res = select(
                     my_listen_and_serve(
                         web_server_config.clone(),
@@ -259,6 +422,8 @@ async fn web_task<GenericAppProps, GenericAppState>(
 async fn standalone_captive_redirect_listen_and_serve_task(
     mut web_server_commands: WebServerSubscriber,
     web_app_domain: String,
+    bind_interface: WebServerBindInterface,
+    buffer_size: usize,
 ) {
     debug!("/// Captive started");
     let mut command = None;
@@ -272,9 +437,20 @@ async fn standalone_captive_redirect_listen_and_serve_task(
             Some(embassy_sync::pubsub::WaitResult::Message(WebServerCommand::Stop)) => {
                 command = None;
             }
-            Some(embassy_sync::pubsub::WaitResult::Message(WebServerCommand::Start(stack))) => {
+            Some(embassy_sync::pubsub::WaitResult::Message(WebServerCommand::Start(
+                stack,
+                mode,
+            ))) => {
+                if !bind_interface.allows(mode) {
+                    command = None;
+                    continue;
+                }
                 let res = select(
-                    standalone_captive_redirect_listen_and_serve(stack, web_app_domain.clone()),
+                    standalone_captive_redirect_listen_and_serve(
+                        stack,
+                        web_app_domain.clone(),
+                        buffer_size,
+                    ),
                     web_server_commands.next_message_pure(),
                 )
                 .await;
@@ -293,10 +469,11 @@ async fn standalone_captive_redirect_listen_and_serve_task(
 async fn standalone_captive_redirect_listen_and_serve(
     stack: embassy_net::Stack<'static>,
     web_app_domain: String,
+    buffer_size: usize,
 ) {
     let port = 80;
-    let mut tcp_rx_buffer = Box::new([0; 512]);
-    let mut tcp_tx_buffer = Box::new([0; 512]);
+    let mut tcp_rx_buffer = vec![0u8; buffer_size].into_boxed_slice();
+    let mut tcp_tx_buffer = vec![0u8; buffer_size].into_boxed_slice();
     let mut socket =
         embassy_net::tcp::TcpSocket::new(stack, &mut *tcp_rx_buffer, &mut *tcp_tx_buffer);
 
@@ -337,7 +514,7 @@ async fn standalone_captive_redirect_listen_and_serve(
 #[allow(clippy::too_many_arguments)]
 async fn my_listen_and_serve<P: routing::PathRouter<GenericAppState>, GenericAppState>(
     web_server_config: WebServerConfig,
-    task_id: impl LogDisplay,
+    task_id: usize,
     app: &Router<P, GenericAppState>,
     config: &Config,
     stack: embassy_net::Stack<'static>,
@@ -346,9 +523,9 @@ async fn my_listen_and_serve<P: routing::PathRouter<GenericAppState>, GenericApp
     state: &GenericAppState,
 ) -> ! {
     let port = web_server_config.port;
-    let mut tcp_rx_buffer = Box::new([0u8; 2048]);
-    let mut tcp_tx_buffer = Box::new([0u8; 2048]);
-    let mut http_buffer = Box::new([0u8; 1024 * 16]);
+    let mut tcp_rx_buffer = vec![0u8; web_server_config.tcp_rx_buffer_size].into_boxed_slice();
+    let mut tcp_tx_buffer = vec![0u8; web_server_config.tcp_tx_buffer_size].into_boxed_slice();
+    let mut http_buffer = vec![0u8; web_server_config.http_buffer_size].into_boxed_slice();
 
     loop {
         let mut socket =
@@ -373,7 +550,11 @@ async fn my_listen_and_serve<P: routing::PathRouter<GenericAppState>, GenericApp
             let session = Session::new(tls, socket, &SessionConfig::Server(tls_config)).unwrap();
 
             let wrapper = SessionWrapper::new(session);
-            let app_with_state = app.shared().with_state(state);
+            let app_with_state = app
+                .shared()
+                .layer(RequestLogLayer::new(&web_server_config, task_id, remote_endpoint))
+                .layer(InFlightLayer)
+                .with_state(state);
 
             match picoserve::Server::new(&app_with_state, config, &mut *http_buffer)
                 .serve(wrapper)
@@ -389,7 +570,11 @@ async fn my_listen_and_serve<P: routing::PathRouter<GenericAppState>, GenericApp
             }
         } else {
             debug!("[{task_id}] Serving HTTP request");
-            let app_with_state = app.shared().with_state(state);
+            let app_with_state = app
+                .shared()
+                .layer(RequestLogLayer::new(&web_server_config, task_id, remote_endpoint))
+                .layer(InFlightLayer)
+                .with_state(state);
             match picoserve::Server::new(&app_with_state, config, &mut *http_buffer)
                 .serve(socket)
                 .await
@@ -411,6 +596,155 @@ async fn my_listen_and_serve<P: routing::PathRouter<GenericAppState>, GenericApp
     }
 }
 
+//////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// In flight requests layer //////////////////////////////////////////////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+static REQUESTS_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+// Requests being handled by any web server task, from routing until the response is written
+pub fn requests_in_flight() -> usize {
+    REQUESTS_IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+struct InFlightLayer;
+
+struct InFlightGuard;
+
+impl InFlightGuard {
+    fn new() -> Self {
+        REQUESTS_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        REQUESTS_IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<State, PathParameters> Layer<State, PathParameters> for InFlightLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: picoserve::io::Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        _request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        // dropped also when the connection is dropped mid request
+        let _guard = InFlightGuard::new();
+        next.run(state, path_parameters, response_writer).await
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// Request logging layer /////////////////////////////////////////////////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Created per connection since that's where the client endpoint is known
+struct RequestLogLayer {
+    enabled: bool,
+    web_app_name: &'static str,
+    task_id: usize,
+    remote_endpoint: Option<IpEndpoint>,
+}
+
+impl RequestLogLayer {
+    fn new(
+        web_server_config: &WebServerConfig,
+        task_id: usize,
+        remote_endpoint: Option<IpEndpoint>,
+    ) -> Self {
+        Self {
+            enabled: web_server_config.log_requests,
+            web_app_name: web_server_config.web_app_name,
+            task_id,
+            remote_endpoint,
+        }
+    }
+}
+
+impl<State, PathParameters> Layer<State, PathParameters> for RequestLogLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: picoserve::io::Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        if !self.enabled {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+        next.run(
+            state,
+            path_parameters,
+            RequestLogResponseWriter {
+                layer: self,
+                method: request_parts.method(),
+                path: request_parts.path(),
+                response_writer,
+            },
+        )
+        .await
+    }
+}
+
+struct RequestLogResponseWriter<'l, 'r, W> {
+    layer: &'l RequestLogLayer,
+    method: &'r str,
+    path: picoserve::request::Path<'r>,
+    response_writer: W,
+}
+
+impl<W: ResponseWriter> ResponseWriter for RequestLogResponseWriter<'_, '_, W> {
+    type Error = W::Error;
+
+    async fn write_response<
+        R: picoserve::io::Read<Error = Self::Error>,
+        H: HeadersIter,
+        B: Body,
+    >(
+        self,
+        connection: Connection<'_, R>,
+        response: Response<H, B>,
+    ) -> Result<ResponseSent, Self::Error> {
+        let status_code = response.status_code();
+        let result = self
+            .response_writer
+            .write_response(connection, response)
+            .await;
+        info!(
+            "[{}] {}: {} {} -> {} from {:?}",
+            self.layer.task_id,
+            self.layer.web_app_name,
+            self.method,
+            self.path,
+            status_code,
+            self.layer.remote_endpoint
+        );
+        result
+    }
+}
+
 //////////////////////////////////////////////////////////////////////////////////////////////////////////////
 // esp-mbedtls implementation for use with picoserve /////////////////////////////////////////////////////////
 //////////////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -8,22 +8,50 @@ use alloc::vec::Vec;
 use alloc::{format, rc::Rc};
 use alloc::{string::String, vec};
 use edge_dhcp::io::{self, DEFAULT_SERVER_PORT};
-use edge_nal::UdpBind;
+use edge_nal::{UdpBind, UdpReceive as _, UdpSend as _};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
 use embassy_net::{Runner, Stack};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
 use embassy_time::with_timeout;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read as _, Write as _};
 use esp_radio::wifi::{
-    AccessPointConfig, AccessPointInfo, ClientConfig, ModeConfig, ScanConfig, WifiDevice,
+    AccessPointConfig, AccessPointInfo, ClientConfig, EapClientConfig, ModeConfig, ScanConfig,
+    ScanMethod, WifiController, WifiDevice, WifiError, WifiEvent,
 };
 
 // use deku::DekuContainerRead as _;
 
 use crate::utils::SpawnerHeapExt;
 
-use super::framework::{Framework, WebConfigMode};
+use super::framework::{Framework, WebConfigMode, WifiCredentialOptions, WifiNetwork};
+use crate::texts::FrameworkText;
 use super::improv_wifi::*;
 
+// Reconnect delay doubles on each failed attempt, so devices don't hammer a rebooting AP
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// Up to this fraction of the delay is added at random, so devices don't retry in lockstep
+const RECONNECT_JITTER_DIVISOR: u64 = 4;
+// While connected, how often RSSI is refreshed and pending work (scans, grace portal) is handled
+const STA_CONNECTED_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// After a successful web config credentials test, how long to wait for the web request to store them
+const WEB_CREDENTIALS_STORE_TIMEOUT: Duration = Duration::from_secs(10);
+// Once stored, time for the web response to reach the client before the access point is stopped
+const WEB_CREDENTIALS_RESPONSE_DELAY: Duration = Duration::from_millis(1000);
+
+fn reconnect_delay_with_jitter(backoff: Duration) -> Duration {
+    let mut buf = [0u8; 4];
+    if let Err(e) = getrandom::getrandom(&mut buf) {
+        // the backoff alone still spaces the retries out
+        warn!("No random for reconnect jitter: {e}");
+        return backoff;
+    }
+    let jitter_range = backoff.as_millis() / RECONNECT_JITTER_DIVISOR + 1;
+    backoff + Duration::from_millis(u32::from_le_bytes(buf) as u64 % jitter_range)
+}
+
 #[embassy_executor::task]
 #[allow(clippy::too_many_arguments)]
 pub async fn connection_task(
@@ -73,6 +101,11 @@ pub async fn connection_task_inner(
     let ap_addr = framework.borrow().settings.ap_addr;
     let app_cargo_pkg_name = framework.borrow().settings.app_cargo_pkg_name;
     let app_cargo_pkg_version = framework.borrow().settings.app_cargo_pkg_version;
+    #[cfg(any(feature = "improv-jtag-serial", feature = "improv-uart"))]
+    let (improv_chip, improv_device_name) = {
+        let settings = &framework.borrow().settings;
+        (settings.improv_chip, settings.improv_device_name)
+    };
     let prefix = if framework.borrow().settings.web_server_https {
         "https"
     } else {
@@ -83,7 +116,14 @@ pub async fn connection_task_inner(
 
     #[cfg(any(feature = "improv-jtag-serial", feature = "improv-uart"))]
     let mut send_packet = async |packet: ImprovWifiPacket, flush: bool| {
-        let data = packet.to_bytes().unwrap();
+        let data = match packet.to_bytes() {
+            Ok(data) => data,
+            Err(e) => {
+                // e.g. a redirect URL too long for a single length byte
+                error!("Improv packet not sent, can't encode it: {:?}", e);
+                return;
+            }
+        };
         <_ as embedded_io_async::Write>::write(&mut tx, &data)
             .await
             .unwrap();
@@ -97,6 +137,8 @@ pub async fn connection_task_inner(
     };
 
     trace!("Connection task started");
+
+    apply_wifi_country(framework.borrow().settings.wifi_country);
     //  TODO: improve on this flow, handle case of not getting IP due to disconnect, or handle
     //  timeout of not getting IP
 
@@ -104,416 +146,1067 @@ pub async fn connection_task_inner(
     // only once these are availble will continue to actual wifi connectivity
     let mut ssid = String::new();
     let mut password = String::new();
+    let mut options = WifiCredentialOptions::default();
     let mut improv_wifi_bootstrap = false;
+    let mut web_wifi_bootstrap = false;
     let mut ap_active;
     let mut credentials_available = false;
 
     if framework.borrow().wifi_ssid.is_some() {
         ssid = String::from_str(framework.borrow().wifi_ssid.as_ref().unwrap()).unwrap_or_default();
-        password = String::from_str(framework.borrow().wifi_password.as_ref().unwrap())
-            .unwrap_or_default();
+        // Open networks are stored without a password
+        password = framework.borrow().wifi_password.clone().unwrap_or_default();
+        options = framework.borrow().wifi_options.clone();
         credentials_available = true;
     }
 
-    // Improv Wifi and AccessPoint
-    if !credentials_available {
-        let client_config = ModeConfig::AccessPoint(
-            AccessPointConfig::default().with_ssid(app_cargo_pkg_name.into()),
-        );
+    // Restarted from the top when stored credentials keep failing, to collect new ones
+    let fallback_ap_after = framework.borrow().settings.wifi_fallback_ap_after_failures;
+    let fallback_retry_interval =
+        Duration::from_secs(framework.borrow().settings.wifi_fallback_ap_retry_secs);
+    // When the stored credentials are next retried while the fallback AP is up, None if they aren't
+    let mut stored_credentials_retry_at: Option<Instant> = None;
+    'provisioning: loop {
+        // Improv Wifi and AccessPoint
+        if !credentials_available {
+            let ap_config = AccessPointConfig::default().with_ssid(app_cargo_pkg_name.into());
+            let client_config = ModeConfig::AccessPoint(ap_config.clone());
 
-        controller.set_config(&client_config).unwrap();
-        controller.start_async().await.unwrap();
-        // spawner.spawn(crate::framework::wifi::ap_net_task(ap_runner)).ok();
-        spawner
-            .spawn_heap(dhcp_server(ap_stack, framework.clone()))
-            .ok();
-        if framework.borrow().settings.web_server_captive {
-            spawner
-                .spawn_heap(captive_portal(ap_stack, framework.clone()))
-                .ok();
-        }
-        Timer::after(Duration::from_millis(1000)).await; // why wait (in original example)
-        {
-            // Important: Don't remove: block to drop framework_borrow
-            let mut framework_borrow = framework.borrow_mut();
-            framework_borrow.start_web_app(ap_stack, WebConfigMode::AP);
-            drop(framework_borrow); // adding explicit drop, just in case
-        }
-        framework.borrow_mut().report_wifi(
-            Some(Ipv4Addr::new(ap_addr.0, ap_addr.1, ap_addr.2, ap_addr.3)),
-            true,
-            app_cargo_pkg_name,
-        );
-
-        term_info!("WiFi Credentions not Configured.");
-        term_info!("Provide WiFi credentials using either:");
-        term_info!("- WiFi SSID: {}", app_cargo_pkg_name);
-        term_info!(
-            "  URL: {}://{}.{}.{}.{} or {}://config",
-            { prefix },
-            ap_addr.0,
-            ap_addr.1,
-            ap_addr.2,
-            ap_addr.3,
-            { prefix },
-        );
-        term_info!("- Continue web flash process in browser");
-        // run Improv Wifi to get ssid/password
-
-        ap_active = true;
-        improv_wifi_bootstrap = true;
-
-        // using  async closures which is unstable and seems like quite recent,
-        // if there are issues, move to the function below with additional param
-
-        // async fn send_packet(tx: &mut esp_hal::usb_serial_jtag::UsbSerialJtagTx<'static, esp_hal::Async>, packet: ImprovWifiPacket) {
-        //     let data = packet.to_bytes().unwrap();
-        //     embedded_io_async::Write::write(tx, &data).await.unwrap();
-        //     embedded_io_async::Write::flush(tx).await.unwrap();
-        // }
-
-        #[cfg(any(feature = "improv-jtag-serial", feature = "improv-uart"))]
-        {
-            // When using esp-flash web installer we miss the request for status that comes right after
-            //   installation completes, therefore we send status w/o being asked.
-            // Also, if we send too early, data doesn't arrive properly, therefore the wait before.
-            // If there is no one on the other side of the serial this will hang, but it doesn't matter much,
-            //   since if there's no one on the other side no point in this anyway, but need to be aware of that
-            //   in case of future code changes
-            // Howevere, some edge cases were seen where I suspect were caused by this code hanging, so I added a timeout.
-
-            Timer::after(Duration::from_millis(2000)).await;
-            let response = ImprovWifiPacket::new_current_state(CurrentStateOption::Ready);
-            let _ = with_timeout(Duration::from_millis(1000), send_packet(response, false)).await;
-
-            let mut buffer = Vec::with_capacity(100);
-            let mut temp_buf = [0u8; 40];
-
-            'improv_loop: loop {
-                let r = rx.read(&mut temp_buf).await;
-
-                match r {
-                    Ok(len) => {
-                        if len == 0 {
-                            // need to display something to use and exit,
-                            // no point continuing to wifi section
-                            return;
-                        } // Append the new data to our growing buffer
-
-                        buffer.extend_from_slice(&temp_buf[..len]);
-
-                        // Try to parse packets from the buffer as long as data is available
-                        'process_data: while !buffer.is_empty() {
-                            // Attempt to parse a packet from the buffer
-                            match ImprovWifiPacket::from_bytes((buffer.as_ref(), 0)) {
-                                Ok((rest, packet)) => {
-                                    // Update the buffer by removing the parsed data (do it now to save time after send later)
-                                    let parsed_len = buffer.len() - rest.0.len();
-                                    buffer.drain(..parsed_len);
-                                    // Successfully parsed a packet
-                                    match packet.data {
-                                        ImprovWifiPacketData::RPC(RPCCommandStruct {
-                                            data: RPCCommand::RequestCurrentState,
-                                            ..
-                                        }) => {
-                                            // TODO: check wifi state and respond accordingly
-                                            let response = ImprovWifiPacket::new_current_state(
-                                                CurrentStateOption::Ready,
-                                            );
-                                            send_packet(response, false).await;
-                                        }
-                                        ImprovWifiPacketData::RPC(RPCCommandStruct {
-                                            data: RPCCommand::RequestDeviceInformation,
-                                            ..
-                                        }) => {
-                                            let response = ImprovWifiPacket::new_rpc_result(RPCResultStruct::new_response_to_request_device_information(
-                                            app_cargo_pkg_name,
-                                            app_cargo_pkg_version,
-                                            "ESP32S3",
-                                            "WT32-SC01-Plus",
-                                        ));
-                                            send_packet(response, false).await;
-                                        }
-                                        ImprovWifiPacketData::RPC(RPCCommandStruct {
-                                            data: RPCCommand::RequestScannedWifiNetworks,
-                                            ..
-                                        }) => {
-                                            let cfg = ScanConfig::default().with_max(50);
-                                            info!("Scanning for available WiFi networks");
-                                            let scan_res =
-                                                controller.scan_with_config_async(cfg).await;
-
-                                            if let Ok(scan_results) = scan_res {
-                                                let mut seen = hashbrown::HashSet::new();
-                                                let unique_aps: Vec<AccessPointInfo> = scan_results
-                                                    .into_iter()
-                                                    .filter(|item| seen.insert(item.ssid.clone()))
-                                                    .collect();
-                                                for ap_info in unique_aps {
-                                                    let response =
-                                                    ImprovWifiPacket::new_rpc_result(RPCResultStruct::new_response_to_request_scanned_wifi_networks(
-                                                        &ap_info.ssid,
-                                                        &format!("{}", ap_info.signal_strength),
-                                                        ap_info.auth_method.is_some(),
-                                                    ));
-                                                    send_packet(response, true).await;
-                                                }
-                                            } else {
-                                                term_error!(
-                                                    "Error scanning wifi networks {:?}",
-                                                    scan_res
+            controller.set_config(&client_config).unwrap();
+            controller.start_async().await.unwrap();
+            apply_configured_wifi_tx_power(&framework);
+            // spawner.spawn(crate::framework::wifi::ap_net_task(ap_runner)).ok();
+            let ap_services = ApServices::spawn(spawner, ap_stack, &framework);
+            Timer::after(Duration::from_millis(1000)).await; // why wait (in original example)
+            {
+                // Important: Don't remove: block to drop framework_borrow
+                let mut framework_borrow = framework.borrow_mut();
+                framework_borrow.start_web_app(ap_stack, WebConfigMode::AP);
+                drop(framework_borrow); // adding explicit drop, just in case
+            }
+            framework.borrow_mut().report_wifi(
+                Some(Ipv4Addr::new(ap_addr.0, ap_addr.1, ap_addr.2, ap_addr.3)),
+                true,
+                app_cargo_pkg_name,
+            );
+
+            {
+                let framework = framework.borrow();
+                term_info!(framework.text(FrameworkText::WifiNotConfigured));
+                term_info!(framework.text(FrameworkText::ProvideWifiCredentials));
+                term_info!(framework.text_with(
+                    FrameworkText::ProvideWifiCredentialsAp,
+                    &[&app_cargo_pkg_name]
+                ));
+                term_info!(framework.text_with(
+                    FrameworkText::ProvideWifiCredentialsUrl,
+                    &[
+                        &format!(
+                            "{prefix}://{}.{}.{}.{}",
+                            ap_addr.0, ap_addr.1, ap_addr.2, ap_addr.3
+                        ),
+                        &format!("{prefix}://config"),
+                    ]
+                ));
+                term_info!(framework.text(FrameworkText::ProvideWifiCredentialsWebFlash));
+            }
+            // run Improv Wifi to get ssid/password
+
+            ap_active = true;
+            improv_wifi_bootstrap = true;
+            framework.borrow_mut().wifi_credentials_test_available = true;
+            let wifi_credentials_test_requests = framework.borrow().wifi_credentials_test_requests;
+            let wifi_scan_requests = framework.borrow().wifi_scan_requests;
+
+            // using  async closures which is unstable and seems like quite recent,
+            // if there are issues, move to the function below with additional param
+
+            // async fn send_packet(tx: &mut esp_hal::usb_serial_jtag::UsbSerialJtagTx<'static, esp_hal::Async>, packet: ImprovWifiPacket) {
+            //     let data = packet.to_bytes().unwrap();
+            //     embedded_io_async::Write::write(tx, &data).await.unwrap();
+            //     embedded_io_async::Write::flush(tx).await.unwrap();
+            // }
+
+            #[cfg(any(feature = "improv-jtag-serial", feature = "improv-uart"))]
+            {
+                // When using esp-flash web installer we miss the request for status that comes right after
+                //   installation completes, therefore we send status w/o being asked.
+                // If we send too early (host tool didn't reopen the port yet), data doesn't arrive properly,
+                //   therefore wait up to improv_ready_delay_ms before. If the host sends anything meanwhile
+                //   it is listening already and will ask for the state itself, so no need to wait or send it.
+                // If there is no one on the other side of the serial sending may hang, therefore the
+                //   improv_send_timeout_ms timeout.
+                let (ready_delay, send_timeout) = {
+                    let settings = &framework.borrow().settings;
+                    (
+                        Duration::from_millis(settings.improv_ready_delay_ms),
+                        Duration::from_millis(settings.improv_send_timeout_ms),
+                    )
+                };
+
+                let mut buffer = Vec::with_capacity(100);
+                let mut temp_buf = [0u8; 40];
+
+                let mut pending_read = None;
+                match select(rx.read(&mut temp_buf), Timer::after(ready_delay)).await {
+                    Either::First(r) => pending_read = Some(r),
+                    Either::Second(_) => {
+                        let response =
+                            ImprovWifiPacket::new_current_state(improv_current_state(&framework));
+                        let _ = with_timeout(send_timeout, send_packet(response, false)).await;
+                    }
+                }
+
+                'improv_loop: loop {
+                    let r = match pending_read.take() {
+                        Some(r) => r,
+                        None => match select4(
+                            rx.read(&mut temp_buf),
+                            wifi_credentials_test_requests.receive(),
+                            wifi_scan_requests.receive(),
+                            retry_due(stored_credentials_retry_at.filter(|_| ap_active)),
+                        )
+                        .await
+                        {
+                            Either4::First(r) => r,
+                            Either4::Third(_) => {
+                                serve_wifi_scan(&mut controller, &framework).await;
+                                continue 'improv_loop;
+                            }
+                            Either4::Fourth(_) => {
+                                if retry_stored_credentials(
+                                    &mut controller,
+                                    &framework,
+                                    &ap_config,
+                                    &ap_services,
+                                    &ssid,
+                                    &password,
+                                    &options,
+                                )
+                                .await
+                                {
+                                    stored_credentials_retry_at = None;
+                                    improv_wifi_bootstrap = false;
+                                    break 'improv_loop;
+                                }
+                                stored_credentials_retry_at =
+                                    Some(Instant::now() + fallback_retry_interval);
+                                continue 'improv_loop;
+                            }
+                            Either4::Second((test_ssid, test_password, test_options)) => {
+                                if web_wifi_credentials_test(
+                                    &mut controller,
+                                    &framework,
+                                    &ap_config,
+                                    &ap_services,
+                                    &test_ssid,
+                                    &test_password,
+                                    &test_options,
+                                )
+                                .await
+                                {
+                                    ssid = test_ssid;
+                                    password = test_password;
+                                    options = test_options;
+                                    improv_wifi_bootstrap = false;
+                                    web_wifi_bootstrap = true;
+                                    break 'improv_loop;
+                                }
+                                continue 'improv_loop;
+                            }
+                        },
+                    };
+
+                    match r {
+                        Ok(len) => {
+                            if len == 0 {
+                                // need to display something to use and exit,
+                                // no point continuing to wifi section
+                                return;
+                            } // Append the new data to our growing buffer
+
+                            buffer.extend_from_slice(&temp_buf[..len]);
+
+                            // Try to parse packets from the buffer as long as data is available
+                            'process_data: while !buffer.is_empty() {
+                                // Attempt to parse a packet from the buffer
+                                match ImprovWifiPacket::from_bytes((buffer.as_ref(), 0)) {
+                                    Ok((rest, packet)) => {
+                                        // Update the buffer by removing the parsed data (do it now to save time after send later)
+                                        let parsed_len = buffer.len() - rest.0.len();
+                                        buffer.drain(..parsed_len);
+                                        // Successfully parsed a packet
+                                        match packet.data {
+                                            ImprovWifiPacketData::RPC(RPCCommandStruct {
+                                                data: RPCCommand::RequestCurrentState,
+                                                ..
+                                            }) => {
+                                                let response = ImprovWifiPacket::new_current_state(
+                                                    improv_current_state(&framework),
                                                 );
+                                                send_packet(response, false).await;
                                             }
-                                            let response =
-                                            ImprovWifiPacket::new_rpc_result(RPCResultStruct::new_response_to_request_scanned_wifi_networks_end());
-                                            send_packet(response, true).await;
-                                        }
-
-                                        ImprovWifiPacketData::RPC(RPCCommandStruct {
-                                            data:
-                                                RPCCommand::SendWifiSettings(SendWifiSettingsStruct {
-                                                    ssid: improv_ssid,
-                                                    password: improv_password,
-                                                }),
-                                            ..
-                                        }) => {
-                                            let response = ImprovWifiPacket::new_current_state(
-                                                CurrentStateOption::Provisioning,
-                                            );
-                                            send_packet(response, true).await;
-                                            // If Acess Point is active stop it from now on,
-                                            // For now to activate back need to restart device
-                                            if ap_active {
-                                                term_info!(
-                                                    "ImprovWiFi setup: Stopping Acess Point"
+                                            ImprovWifiPacketData::RPC(RPCCommandStruct {
+                                                data: RPCCommand::RequestDeviceInformation,
+                                                ..
+                                            }) => {
+                                                let response = ImprovWifiPacket::new_rpc_result(RPCResultStruct::new_response_to_request_device_information(
+                                                app_cargo_pkg_name,
+                                                app_cargo_pkg_version,
+                                                improv_chip,
+                                                improv_device_name,
+                                            ));
+                                                send_packet(response, false).await;
+                                            }
+                                            ImprovWifiPacketData::RPC(RPCCommandStruct {
+                                                data: RPCCommand::RequestScannedWifiNetworks,
+                                                ..
+                                            }) => {
+                                                let (scan_max, scan_timeout) = {
+                                                    let settings = &framework.borrow().settings;
+                                                    (
+                                                        settings.improv_scan_max_results,
+                                                        Duration::from_millis(
+                                                            settings.improv_scan_timeout_ms,
+                                                        ),
+                                                    )
+                                                };
+                                                let cfg = ScanConfig::default().with_max(scan_max);
+                                                info!("Scanning for available WiFi networks");
+                                                let scan = with_timeout(
+                                                    scan_timeout,
+                                                    controller.scan_with_config_async(cfg),
                                                 );
-                                                framework.borrow().stop_web_app(); // disable because it was started for Access Point mode configuration
-                                                let _ = controller.disconnect_async().await;
-                                                let _ = controller.stop_async().await;
-                                                ap_active = false;
+                                                // Keep answering state requests while scanning so the host doesn't consider
+                                                // the device hung, anything else is processed after the scan
+                                                let mut scan_pending = Vec::new();
+                                                let keep_responsive = answer_current_state_requests(
+                                                    &mut rx,
+                                                    &mut scan_pending,
+                                                    improv_current_state(&framework),
+                                                    &mut send_packet,
+                                                );
+                                                let scan_res =
+                                                    match select(scan, keep_responsive).await {
+                                                        Either::First(scan_res) => scan_res,
+                                                        Either::Second(never) => never,
+                                                    };
+                                                buffer.extend_from_slice(&scan_pending);
+
+                                                if let Ok(Ok(scan_results)) = scan_res {
+                                                    for ap_info in
+                                                        unique_access_points(scan_results)
+                                                    {
+                                                        let response =
+                                                        ImprovWifiPacket::new_rpc_result(RPCResultStruct::new_response_to_request_scanned_wifi_networks(
+                                                            &ap_info.ssid,
+                                                            &format!("{}", ap_info.signal_strength),
+                                                            ap_info.auth_method.is_some(),
+                                                        ));
+                                                        send_packet(response, true).await;
+                                                    }
+                                                } else {
+                                                    term_error!(
+                                                        "Error scanning wifi networks {:?}",
+                                                        scan_res
+                                                    );
+                                                }
+                                                let response =
+                                                ImprovWifiPacket::new_rpc_result(RPCResultStruct::new_response_to_request_scanned_wifi_networks_end());
+                                                send_packet(response, true).await;
                                             }
-                                            let client_config = ModeConfig::Client(
-                                                ClientConfig::default()
-                                                    .with_ssid(improv_ssid.clone())
-                                                    .with_password(improv_password.clone()),
-                                            );
-                                            term_info!(
-                                                "ImprovWiFi: Credentials check - WiFi '{}'",
-                                                <&str>::from(&improv_ssid)
-                                            );
-                                            controller.set_config(&client_config).unwrap();
-                                            let _ = controller.start_async().await;
-                                            let connect_res = controller.connect_async().await;
-                                            let _ = controller.stop_async().await;
-                                            if connect_res.is_ok() {
-                                                ssid = String::from_str(<&str>::from(&improv_ssid))
-                                                    .unwrap();
-                                                password = improv_password.clone();
-                                                term_info!("ImprovWifi: Credentials Ok");
-                                                break 'improv_loop;
-                                            } else {
-                                                let response = ImprovWifiPacket::new_error_state(
-                                                    ErrorStateOption::UnableToConnect,
+
+                                            ImprovWifiPacketData::RPC(RPCCommandStruct {
+                                                data:
+                                                    RPCCommand::SendWifiSettings(
+                                                        SendWifiSettingsStruct {
+                                                            ssid: improv_ssid,
+                                                            password: improv_password,
+                                                        },
+                                                    ),
+                                                ..
+                                            }) => {
+                                                let response = ImprovWifiPacket::new_current_state(
+                                                    CurrentStateOption::Provisioning,
                                                 );
                                                 send_packet(response, true).await;
-                                                term_info!("ImprovWiFi: Credentials incorrect");
+                                                // If Acess Point is active stop it from now on,
+                                                // For now to activate back need to restart device
+                                                if ap_active {
+                                                    term_info!(framework
+                                                        .borrow()
+                                                        .text(FrameworkText::ImprovStoppingAp));
+                                                    framework.borrow_mut().stop_web_app(); // disable because it was started for Access Point mode configuration
+                                                    ap_services.stop();
+                                                    let _ = controller.disconnect_async().await;
+                                                    let _ = controller.stop_async().await;
+                                                    ap_active = false;
+                                                    framework
+                                                        .borrow_mut()
+                                                        .wifi_credentials_test_available = false;
+                                                }
+                                                let client_config = ModeConfig::Client(
+                                                    ClientConfig::default()
+                                                        .with_ssid(improv_ssid.clone())
+                                                        .with_password(improv_password.clone()),
+                                                );
+                                                term_info!(framework.borrow().text_with(
+                                                    FrameworkText::ImprovCredentialsCheck,
+                                                    &[&<&str>::from(&improv_ssid)]
+                                                ));
+                                                // Keep reporting the attempt in flight, other requests wait until it's done
+                                                let mut connect_pending = Vec::new();
+                                                let connect_res = match select(
+                                                    try_wifi_credentials(
+                                                        &mut controller,
+                                                        &client_config,
+                                                    ),
+                                                    answer_current_state_requests(
+                                                        &mut rx,
+                                                        &mut connect_pending,
+                                                        CurrentStateOption::Provisioning,
+                                                        &mut send_packet,
+                                                    ),
+                                                )
+                                                .await
+                                                {
+                                                    Either::First(connect_res) => connect_res,
+                                                    Either::Second(never) => never,
+                                                };
+                                                buffer.extend_from_slice(&connect_pending);
+                                                let _ = controller.stop_async().await;
+                                                if connect_res.is_ok() {
+                                                    ssid = String::from_str(<&str>::from(
+                                                        &improv_ssid,
+                                                    ))
+                                                    .unwrap();
+                                                    password = improv_password.clone();
+                                                    // Improv only carries ssid and password, options of the old network don't apply
+                                                    options = WifiCredentialOptions::default();
+                                                    term_info!(framework
+                                                        .borrow()
+                                                        .text(FrameworkText::ImprovCredentialsOk));
+                                                    break 'improv_loop;
+                                                } else {
+                                                    let response =
+                                                        ImprovWifiPacket::new_error_state(
+                                                            ErrorStateOption::UnableToConnect,
+                                                        );
+                                                    send_packet(response, true).await;
+                                                    term_info!(framework.borrow().text(
+                                                        FrameworkText::ImprovCredentialsIncorrect
+                                                    ));
+                                                }
                                             }
+                                            _ => (),
                                         }
-                                        _ => (),
-                                    }
 
-                                    if buffer.is_empty() {
-                                        break 'process_data; // skips one empty iteration over no data to speed things up
+                                        if buffer.is_empty() {
+                                            break 'process_data; // skips one empty iteration over no data to speed things up
+                                        }
                                     }
-                                }
-                                Err(ParseError::Incomplete) => {
-                                    // debug!("Incomplete Deku data, will get more");
-                                    break 'process_data;
-                                }
-                                Err(_e) => {
-                                    term_error!("recv_err: {:x?}", buffer);
-                                    // let response = ImprovWifiPacket::new_error_state(ErrorStateOption::InvalidRPCPacket);
-                                    // send_packet(response).await;
-
-                                    // esp-web-tools doen't deal well with error messages.
-                                    // usually errors take place at the beginning of interaction and a few bytes are missed when it wants to send RequestCurrentState
-                                    // So let's send it, shouldn't hurt, and would probably help
-
-                                    // check that byte before last, checksum is 0xe6
-                                    if buffer.len() > 1 && buffer[buffer.len() - 2] == 0xe6 {
-                                        let response = ImprovWifiPacket::new_rpc_result(
-                                        RPCResultStruct::new_response_to_request_device_information(
-                                            app_cargo_pkg_name,
-                                            app_cargo_pkg_version,
-                                            "ESP32S3",
-                                            "WT32-SC01-Plus",
-                                        ),
-                                    );
-                                        send_packet(response, false).await;
+                                    Err(ParseError::Incomplete) => {
+                                        // debug!("Incomplete Deku data, will get more");
+                                        break 'process_data;
                                     }
-                                    if let Some(pos) = buffer.iter().position(|&x| x == 10) {
-                                        buffer.drain(..=pos); // Remove everything up to and including the first 10
-                                    } else {
-                                        buffer.clear(); // If no 10 is found, clear the vector
+                                    Err(_e) => {
+                                        term_error!("recv_err: {:x?}", buffer);
+                                        // let response = ImprovWifiPacket::new_error_state(ErrorStateOption::InvalidRPCPacket);
+                                        // send_packet(response).await;
+
+                                        // esp-web-tools doen't deal well with error messages.
+                                        // usually errors take place at the beginning of interaction and a few bytes are missed when it wants to send RequestCurrentState
+                                        // So let's send it, shouldn't hurt, and would probably help
+
+                                        // check that byte before last, checksum is 0xe6
+                                        if buffer.len() > 1 && buffer[buffer.len() - 2] == 0xe6 {
+                                            let response = ImprovWifiPacket::new_rpc_result(
+                                            RPCResultStruct::new_response_to_request_device_information(
+                                                app_cargo_pkg_name,
+                                                app_cargo_pkg_version,
+                                                improv_chip,
+                                                improv_device_name,
+                                            ),
+                                        );
+                                            send_packet(response, false).await;
+                                        }
+                                        if let Some(pos) = buffer.iter().position(|&x| x == 10) {
+                                            buffer.drain(..=pos); // Remove everything up to and including the first 10
+                                        } else {
+                                            buffer.clear(); // If no 10 is found, clear the vector
+                                        }
                                     }
                                 }
                             }
                         }
-                    }
-                    Err(e) => {
-                        term_error!("Error reading serial: {:?}", e);
+                        Err(e) => {
+                            term_error!("Error reading serial: {:?}", e);
+                        }
                     }
                 }
             }
-        }
-    }
-    // Now WiFi credtneials available
-
-    term_info!("About to connect to WiFi SSID '{}'", ssid);
-    // trace!("About to connect Wifi using '{}', '{}'", password, ssid);
 
-    let mut first_connect = true;
-    let mut is_connected = false;
-    loop {
-        #[allow(clippy::single_match)]
-        // TODO: Things are not working here as it should and code is also (in addition) incorrect.
-        //       wifi_state() is always Invalid.
-        //       and this loop is always 'stuck' in the connect_async() when connected.
-        //       https://github.com/esp-rs/esp-hal/discussions/4261
-        match esp_radio::wifi::sta_state() {
-            esp_radio::wifi::WifiStaState::Connected => {
-                // wait until we're no longer connected
-                // controller.wait_for_event(esp_wifi::wifi::WifiEvent::StaDisconnected).await;
-                loop {
-                    // trace!("Scanning");
-                    // let cfg = esp_wifi::wifi::ScanConfig{
-                    //     ssid:Some("DEV"),
-                    //     bssid: None,
-                    //     channel: None,
-                    //     show_hidden: false,
-                    //     scan_type: esp_wifi::wifi::ScanTypeConfig::Passive(core::time::Duration::from_secs(5))
-                    // };
-                    // let res = controller.scan_with_config::<1>(cfg).await;
-                    // dbg!(res);
-                    Timer::after(Duration::from_millis(1000)).await // why wait (in original example)
+            #[cfg(not(any(feature = "improv-jtag-serial", feature = "improv-uart")))]
+            loop {
+                let (test_ssid, test_password, test_options) = match select3(
+                    wifi_credentials_test_requests.receive(),
+                    wifi_scan_requests.receive(),
+                    retry_due(stored_credentials_retry_at),
+                )
+                .await
+                {
+                    Either3::First(request) => request,
+                    Either3::Second(_) => {
+                        serve_wifi_scan(&mut controller, &framework).await;
+                        continue;
+                    }
+                    Either3::Third(_) => {
+                        if retry_stored_credentials(
+                            &mut controller,
+                            &framework,
+                            &ap_config,
+                            &ap_services,
+                            &ssid,
+                            &password,
+                            &options,
+                        )
+                        .await
+                        {
+                            stored_credentials_retry_at = None;
+                            improv_wifi_bootstrap = false;
+                            break;
+                        }
+                        stored_credentials_retry_at =
+                            Some(Instant::now() + fallback_retry_interval);
+                        continue;
+                    }
+                };
+                if web_wifi_credentials_test(
+                    &mut controller,
+                    &framework,
+                    &ap_config,
+                    &ap_services,
+                    &test_ssid,
+                    &test_password,
+                    &test_options,
+                )
+                .await
+                {
+                    ssid = test_ssid;
+                    password = test_password;
+                    options = test_options;
+                    improv_wifi_bootstrap = false;
+                    web_wifi_bootstrap = true;
+                    break;
                 }
             }
-            _ => {
-                // if !first_connect {
-                //     term_error!("WiFi disconnected, reconnecting...");
-                // }
-            }
         }
+        // Now WiFi credtneials available
 
-        if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = ModeConfig::Client(
-                ClientConfig::default()
-                    .with_ssid(ssid.clone())
-                    .with_password(password.clone()),
-            );
-            controller.set_config(&client_config).unwrap();
-            trace!("Starting wifi");
+        // Config portal grace window: with stored credentials, keep the AP + web config up for a while after boot
+        // so the device can be reconfigured without erasing. Runs alongside the station connection (AP+STA).
+        let grace_secs = framework.borrow().settings.config_portal_grace_secs;
+        let mut grace_portal = None;
+        // Not for enterprise networks, the enterprise station mode can't be combined with an AP
+        if credentials_available && grace_secs > 0 && !options.is_enterprise() {
+            let ap_config = AccessPointConfig::default().with_ssid(app_cargo_pkg_name.into());
+            let client_config = client_config(&ssid, &password, &options);
+            controller
+                .set_config(&ModeConfig::ApSta(client_config, ap_config))
+                .unwrap();
             controller.start_async().await.unwrap();
-            trace!("Wifi started!");
+            apply_configured_wifi_tx_power(&framework);
+            let ap_services = ApServices::spawn(spawner, ap_stack, &framework);
+            framework
+                .borrow_mut()
+                .start_web_app(ap_stack, WebConfigMode::AP);
+            term_info!(framework.borrow().text_with(
+                FrameworkText::ConfigPortalGrace,
+                &[&app_cargo_pkg_name, &grace_secs]
+            ));
+            grace_portal = Some((
+                ap_services,
+                embassy_time::Instant::now() + Duration::from_secs(grace_secs),
+            ));
         }
 
-        match controller.connect_async().await {
-            Ok(_) => {
-                term_info!("Connected to WiFi");
+        term_info!(framework
+            .borrow()
+            .text_with(FrameworkText::ConnectingToWifi, &[&ssid]));
+        // trace!("About to connect Wifi using '{}', '{}'", password, ssid);
+
+        // Station state machine:
+        //   Connecting   --connect_async Ok----------> WaitingForIp
+        //   Connecting   --connect_async Err---------> Backoff (notify nothing, never was connected)
+        //   Backoff      --delay (doubling)----------> Connecting
+        //   WaitingForIp --IPv4 configured-----------> Connected (notify_wifi_sta_connected)
+        //   WaitingForIp --link lost-----------------> Connecting
+        //   Connected    --StaDisconnected event-----> Connecting (notify_wifi_sta_disconnected)
+        // Connected is left on the StaDisconnected event rather than by polling sta_state(), which
+        // always reported Invalid (https://github.com/esp-rs/esp-hal/discussions/4261).
+        let mut is_connected = false;
+        let mut reconnect_backoff = RECONNECT_BACKOFF_MIN;
+        let mut consecutive_failures = 0;
+        loop {
+            end_grace_portal_if_expired(
+                &mut grace_portal,
+                &mut controller,
+                &framework,
+                &ssid,
+                &password,
+                &options,
+            );
+            if is_connected {
+                wait_for_sta_disconnected(
+                    &mut controller,
+                    &framework,
+                    &mut grace_portal,
+                    &ssid,
+                    &password,
+                    &options,
+                )
+                .await;
+                is_connected = false;
+                framework.borrow_mut().wifi_rssi = None;
+                framework.borrow_mut().report_wifi(None, false, &ssid);
+                framework.borrow().notify_wifi_sta_disconnected();
+                term_error!(framework.borrow().text(FrameworkText::WifiDisconnected));
+            }
+
+            if !matches!(controller.is_started(), Ok(true)) {
+                let client_config = station_mode_config(&ssid, &password, &options);
+                controller.set_config(&client_config).unwrap();
+                trace!("Starting wifi");
+                controller.start_async().await.unwrap();
+                apply_configured_wifi_tx_power(&framework);
+                trace!("Wifi started!");
+            }
 
-                loop {
-                    info!("Waiting for link to be up");
-                    if sta_stack.is_link_up() {
-                        break;
+            match controller.connect_async().await {
+                Ok(_) => {
+                    term_info!(framework.borrow().text(FrameworkText::ConnectedToWifi));
+                    reconnect_backoff = RECONNECT_BACKOFF_MIN;
+                    consecutive_failures = 0;
+
+                    loop {
+                        info!("Waiting for link to be up");
+                        if sta_stack.is_link_up() {
+                            break;
+                        }
+                        Timer::after(Duration::from_millis(500)).await;
                     }
-                    Timer::after(Duration::from_millis(500)).await;
-                }
-                term_info!("Waiting for an IP");
-
-                let mut wait_counter = 24;
-                const SKIP_CHECKS: i32 = 0;
-                loop {
-                    if let Some(config) = sta_stack.config_v4() {
-                        term_info!("Received IP: {}", config.address);
-                        framework.borrow_mut().report_wifi(
-                            Some(config.address.address()),
-                            false,
-                            &ssid,
-                        );
-                        if improv_wifi_bootstrap {
-                            // ignore warning, it's wrong, there's a drop below
-                            let res = framework
-                                .borrow_mut()
-                                .set_wifi_credentials(&ssid, &password); // need to be on separate line (due to borrowing)
-                            match res {
-                                Ok(_) => {
-                                    #[cfg(any(
-                                        feature = "improv-jtag-serial",
-                                        feature = "improv-uart"
-                                    ))]
-                                    {
-                                        let response = ImprovWifiPacket::new_current_state(
-                                            CurrentStateOption::Provisioned,
-                                        );
-                                        send_packet(response, true).await;
-                                    }
+                    term_info!(framework.borrow().text(FrameworkText::WaitingForIp));
 
-                                    framework
-                                        .borrow_mut()
-                                        .start_web_app(sta_stack, WebConfigMode::STA);
-
-                                    #[cfg(any(
-                                        feature = "improv-jtag-serial",
-                                        feature = "improv-uart"
-                                    ))]
-                                    {
-                                        let response = ImprovWifiPacket::new_rpc_result(
-                                            RPCResultStruct::new_response_to_send_wifi_settings(
-                                                &format!("{prefix}://{}", config.address.address()),
-                                            ),
-                                        );
-                                        term_info!("Stored credentials in flash");
-                                        send_packet(response, true).await;
+                    let mut wait_counter = 24;
+                    const SKIP_CHECKS: i32 = 0;
+                    loop {
+                        if let Some(config) = sta_stack.config_v4() {
+                            term_info!(framework
+                                .borrow()
+                                .text_with(FrameworkText::ReceivedIp, &[&config.address]));
+                            framework.borrow_mut().wifi_rssi = controller.rssi().ok();
+                            framework.borrow_mut().report_wifi(
+                                Some(config.address.address()),
+                                false,
+                                &ssid,
+                            );
+                            if improv_wifi_bootstrap {
+                                // ignore warning, it's wrong, there's a drop below
+                                let res = framework
+                                    .borrow_mut()
+                                    .set_wifi_credentials(&ssid, &password); // need to be on separate line (due to borrowing)
+                                match res {
+                                    Ok(_) => {
+                                        #[cfg(any(
+                                            feature = "improv-jtag-serial",
+                                            feature = "improv-uart"
+                                        ))]
+                                        {
+                                            let response = ImprovWifiPacket::new_current_state(
+                                                CurrentStateOption::Provisioned,
+                                            );
+                                            send_packet(response, true).await;
+                                        }
+
+                                        framework
+                                            .borrow_mut()
+                                            .start_web_app(sta_stack, WebConfigMode::STA);
+
+                                        #[cfg(any(
+                                            feature = "improv-jtag-serial",
+                                            feature = "improv-uart"
+                                        ))]
+                                        {
+                                            let response = ImprovWifiPacket::new_rpc_result(
+                                                RPCResultStruct::new_response_to_send_wifi_settings(
+                                                    &format!(
+                                                        "{prefix}://{}",
+                                                        config.address.address()
+                                                    ),
+                                                ),
+                                            );
+                                            term_info!(framework
+                                                .borrow()
+                                                .text(FrameworkText::StoredWifiCredentials));
+                                            send_packet(response, true).await;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        term_error!(format!("Error storing credentials in flash, WiFi initialization halted: {e}"));
+                                        return;
                                     }
                                 }
-                                Err(e) => {
-                                    term_error!(format!("Error storing credentials in flash, WiFi initialization halted {e:?}"));
-                                    return;
+                            } else if web_wifi_bootstrap {
+                                // credentials were already tested and stored by the web config request
+                                framework
+                                    .borrow_mut()
+                                    .start_web_app(sta_stack, WebConfigMode::STA);
+                                web_wifi_bootstrap = false;
+                            }
+                            framework.borrow().notify_wifi_sta_connected();
+                            is_connected = true;
+                            break;
+                        } else {
+                            if matches!(controller.is_connected(), Ok(false)) {
+                                term_info!("");
+                                term_error!(framework
+                                    .borrow()
+                                    .text(FrameworkText::WifiDisconnected));
+                                break;
+                            }
+                            if wait_counter >= SKIP_CHECKS {
+                                if (wait_counter - SKIP_CHECKS) % 90 == 0 {
+                                    term_info!("");
                                 }
+                                term_info_same_line!(".");
                             }
+                            wait_counter += 1;
                         }
-                        framework.borrow().notify_wifi_sta_connected();
-                        first_connect = false;
-                        is_connected = true;
-                        break;
-                    } else {
-                        if wait_counter >= SKIP_CHECKS {
-                            if (wait_counter - SKIP_CHECKS) % 90 == 0 {
-                                term_info!("");
+                        Timer::after(Duration::from_millis(250)).await;
+                        info!("Still waiting for an IP address");
+                    }
+                }
+                Err(e) => {
+                    framework.borrow_mut().wifi_rssi = None;
+                    term_error!(framework
+                        .borrow()
+                        .text_with(FrameworkText::WifiConnectError, &[&format!("{e:?}")]));
+                    consecutive_failures += 1;
+                    if fallback_ap_after.is_some_and(|after| consecutive_failures >= after) {
+                        term_error!(framework.borrow().text_with(
+                            FrameworkText::WifiFallbackToAp,
+                            &[&ssid, &consecutive_failures]
+                        ));
+                        if let Some((ap_services, _)) = grace_portal.take() {
+                            if matches!(framework.borrow().web_config_mode, Some(WebConfigMode::AP))
+                            {
+                                framework.borrow_mut().stop_web_app();
                             }
-                            term_info_same_line!(".");
+                            ap_services.stop();
                         }
-                        wait_counter += 1;
+                        let _ = controller.disconnect_async().await;
+                        let _ = controller.stop_async().await;
+                        credentials_available = false;
+                        // The AP portal alone would never get back to the stored network, e.g. after a router outage
+                        stored_credentials_retry_at = (fallback_retry_interval.as_secs() > 0
+                            && !options.is_enterprise())
+                        .then(|| Instant::now() + fallback_retry_interval);
+                        continue 'provisioning;
                     }
-                    Timer::after(Duration::from_millis(250)).await;
-                    info!("Still waiting for an IP address");
+                    let delay = reconnect_delay_with_jitter(reconnect_backoff);
+                    term_info!(framework.borrow().text_with(
+                        FrameworkText::WifiReconnectIn,
+                        &[&format!("{:.1}", delay.as_millis() as f32 / 1000.0)]
+                    ));
+                    wait_serving_wifi_scans(&mut controller, &framework, delay).await;
+                    reconnect_backoff = (reconnect_backoff * 2).min(RECONNECT_BACKOFF_MAX);
                 }
             }
-            Err(e) => {
-                if is_connected && !first_connect {
-                    framework.borrow_mut().report_wifi(None, false, &ssid);
-                    framework.borrow().notify_wifi_sta_disconnected();
-                }
-                is_connected = false;
-                term_error!("Error while trying to connect to wifi: {:?}", e);
-                Timer::after(Duration::from_millis(1000)).await
+        }
+    }
+}
+
+fn unique_access_points(scan_results: Vec<AccessPointInfo>) -> Vec<AccessPointInfo> {
+    let mut seen = hashbrown::HashSet::new();
+    scan_results
+        .into_iter()
+        .filter(|item| seen.insert(item.ssid.clone()))
+        .collect()
+}
+
+// Scans on behalf of Framework::scan_wifi and signals it the results
+async fn serve_wifi_scan(
+    controller: &mut WifiController<'static>,
+    framework: &Rc<RefCell<Framework>>,
+) {
+    let (scan_max, scan_timeout, result) = {
+        let framework = framework.borrow();
+        (
+            framework.settings.improv_scan_max_results,
+            Duration::from_millis(framework.settings.improv_scan_timeout_ms),
+            framework.wifi_scan_result,
+        )
+    };
+    let cfg = ScanConfig::default().with_max(scan_max);
+    info!("Scanning for available WiFi networks");
+    let networks = match with_timeout(scan_timeout, controller.scan_with_config_async(cfg)).await {
+        Ok(Ok(scan_results)) => unique_access_points(scan_results)
+            .into_iter()
+            .map(|ap_info| WifiNetwork {
+                ssid: format!("{}", ap_info.ssid),
+                rssi: ap_info.signal_strength,
+                auth_required: ap_info.auth_method.is_some(),
+            })
+            .collect(),
+        scan_res => {
+            term_error!("Error scanning wifi networks {:?}", scan_res);
+            Vec::new()
+        }
+    };
+    result.signal(networks);
+}
+
+// Returns once the station lost its connection. Meanwhile keeps the RSSI up to date, closes the
+// grace portal when due and serves scan requests.
+async fn wait_for_sta_disconnected(
+    controller: &mut WifiController<'static>,
+    framework: &Rc<RefCell<Framework>>,
+    grace_portal: &mut Option<(ApServices, embassy_time::Instant)>,
+    ssid: &str,
+    password: &str,
+    options: &WifiCredentialOptions,
+) {
+    let scan_requests = framework.borrow().wifi_scan_requests;
+    loop {
+        let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+        if with_timeout(STA_CONNECTED_POLL_INTERVAL, disconnected)
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        // Covers a disconnect that happened before the wait started, its event is already consumed
+        if matches!(controller.is_connected(), Ok(false)) {
+            return;
+        }
+        framework.borrow_mut().wifi_rssi = controller.rssi().ok();
+        end_grace_portal_if_expired(grace_portal, controller, framework, ssid, password, options);
+        if scan_requests.try_receive().is_ok() {
+            serve_wifi_scan(controller, framework).await;
+        }
+    }
+}
+
+// Waits for the given duration, serving scan requests meanwhile
+async fn wait_serving_wifi_scans(
+    controller: &mut WifiController<'static>,
+    framework: &Rc<RefCell<Framework>>,
+    duration: Duration,
+) {
+    let scan_requests = framework.borrow().wifi_scan_requests;
+    let deadline = embassy_time::Instant::now() + duration;
+    while let Either::Second(_) = select(Timer::at(deadline), scan_requests.receive()).await {
+        serve_wifi_scan(controller, framework).await;
+    }
+}
+
+// State reported to improv hosts when no credentials attempt is in progress
+#[cfg(any(feature = "improv-jtag-serial", feature = "improv-uart"))]
+fn improv_current_state(framework: &Rc<RefCell<Framework>>) -> CurrentStateOption {
+    if framework.borrow().wifi_ok == Some(true)
+        || matches!(
+            esp_radio::wifi::sta_state(),
+            esp_radio::wifi::WifiStaState::Connected
+        )
+    {
+        CurrentStateOption::Provisioned
+    } else {
+        CurrentStateOption::Ready
+    }
+}
+
+// Answers RequestCurrentState packets with the given state while something else is in progress.
+// Never returns, meant to be raced against the operation. Data other than state requests is left in
+// pending, for the caller to process once the operation is done.
+#[cfg(any(feature = "improv-jtag-serial", feature = "improv-uart"))]
+async fn answer_current_state_requests(
+    rx: &mut impl embedded_io_async::Read,
+    pending: &mut Vec<u8>,
+    state: CurrentStateOption,
+    send_packet: &mut impl AsyncFnMut(ImprovWifiPacket, bool),
+) -> ! {
+    let mut temp_buf = [0u8; 40];
+    loop {
+        let len = match rx.read(&mut temp_buf).await {
+            Ok(0) => {
+                // Nothing more will arrive, the caller sees the end of input once the operation is done
+                core::future::pending::<()>().await;
+                continue;
+            }
+            Ok(len) => len,
+            Err(_) => {
+                // Don't spin on a failing port, the caller reports read errors
+                Timer::after_millis(100).await;
+                continue;
+            }
+        };
+        pending.extend_from_slice(&temp_buf[..len]);
+        while let Ok((rest, packet)) = ImprovWifiPacket::from_bytes((pending.as_ref(), 0)) {
+            if !matches!(
+                packet.data,
+                ImprovWifiPacketData::RPC(RPCCommandStruct {
+                    data: RPCCommand::RequestCurrentState,
+                    ..
+                })
+            ) {
+                break;
             }
+            let parsed_len = pending.len() - rest.0.len();
+            pending.drain(..parsed_len);
+            send_packet(ImprovWifiPacket::new_current_state(state), false).await;
         }
     }
 }
 
+fn client_config(ssid: &str, password: &str, options: &WifiCredentialOptions) -> ClientConfig {
+    let config = ClientConfig::default()
+        .with_ssid(ssid.into())
+        .with_password(password.into());
+    if options.hidden {
+        // Hidden networks don't answer the fast scan, probe every channel for the SSID
+        config.with_scan_method(ScanMethod::AllChannels)
+    } else {
+        config
+    }
+}
+
+// Station configuration for the stored credentials, WPA2-Enterprise when a username is set
+fn station_mode_config(ssid: &str, password: &str, options: &WifiCredentialOptions) -> ModeConfig {
+    if options.is_enterprise() {
+        let identity = options
+            .identity
+            .clone()
+            .or_else(|| options.username.clone());
+        ModeConfig::EapClient(
+            EapClientConfig::default()
+                .with_ssid(ssid.into())
+                .with_identity(identity)
+                .with_username(options.username.clone())
+                .with_password(Some(password.into())),
+        )
+    } else {
+        ModeConfig::Client(client_config(ssid, password, options))
+    }
+}
+
+// Attempts to connect with the given configuration, used to check credentials before committing to them.
+// Leaves the station disconnected, caller is responsible for restoring the previous mode if required.
+async fn try_wifi_credentials(
+    controller: &mut WifiController<'static>,
+    config: &ModeConfig,
+) -> Result<(), WifiError> {
+    controller.set_config(config)?;
+    if !matches!(controller.is_started(), Ok(true)) {
+        controller.start_async().await?;
+    }
+    let connect_res = controller.connect_async().await;
+    let _ = controller.disconnect_async().await;
+    connect_res
+}
+
+// Handles a credentials test requested by the web config while in access point mode.
+// The access point is kept up during the test (AP+STA) so the web response can reach the client.
+// Returns true if connection succeeded and the credentials got stored, in which case access point and web config are stopped.
+async fn web_wifi_credentials_test(
+    controller: &mut WifiController<'static>,
+    framework: &Rc<RefCell<Framework>>,
+    ap_config: &AccessPointConfig,
+    ap_services: &ApServices,
+    ssid: &str,
+    password: &str,
+    options: &WifiCredentialOptions,
+) -> bool {
+    term_info!(framework
+        .borrow()
+        .text_with(FrameworkText::WebCredentialsCheck, &[&ssid]));
+    let test_config = ModeConfig::ApSta(client_config(ssid, password, options), ap_config.clone());
+    let connect_res = try_wifi_credentials(controller, &test_config).await;
+    let (result_signal, stored_signal) = {
+        let framework = framework.borrow();
+        (
+            framework.wifi_credentials_test_result,
+            framework.wifi_credentials_test_stored,
+        )
+    };
+
+    match connect_res {
+        Ok(_) => {
+            term_info!(framework.borrow().text(FrameworkText::WebCredentialsOk));
+            framework.borrow_mut().wifi_credentials_test_available = false;
+            result_signal.signal(true);
+            // the access point goes down only once the web request stored the credentials
+            let stored =
+                embassy_time::with_timeout(WEB_CREDENTIALS_STORE_TIMEOUT, stored_signal.wait())
+                    .await
+                    .unwrap_or(false);
+            if !stored {
+                term_error!(framework
+                    .borrow()
+                    .text(FrameworkText::WebCredentialsNotStored));
+                framework.borrow_mut().wifi_credentials_test_available = true;
+                let _ = controller.set_config(&ModeConfig::AccessPoint(ap_config.clone()));
+                return false;
+            }
+            // give the web request time to respond before stopping the access point
+            Timer::after(WEB_CREDENTIALS_RESPONSE_DELAY).await;
+            term_info!(framework.borrow().text(FrameworkText::WebStoppingAp));
+            framework.borrow_mut().stop_web_app();
+            ap_services.stop();
+            let _ = controller.stop_async().await;
+            true
+        }
+        Err(e) => {
+            term_info!(framework
+                .borrow()
+                .text_with(FrameworkText::WebCredentialsIncorrect, &[&format!("{e:?}")]));
+            let _ = controller.set_config(&ModeConfig::AccessPoint(ap_config.clone()));
+            result_signal.signal(false);
+            false
+        }
+    }
+}
+
+// Completes when the stored credentials are due for a retry, never if they aren't retried
+async fn retry_due(retry_at: Option<Instant>) {
+    match retry_at {
+        Some(retry_at) => Timer::at(retry_at).await,
+        None => core::future::pending().await,
+    }
+}
+
+// Retries the stored credentials while the fallback access point is up, which is kept up during the attempt (AP+STA).
+// Returns true if connection succeeded, in which case access point and web config are stopped.
+async fn retry_stored_credentials(
+    controller: &mut WifiController<'static>,
+    framework: &Rc<RefCell<Framework>>,
+    ap_config: &AccessPointConfig,
+    ap_services: &ApServices,
+    ssid: &str,
+    password: &str,
+    options: &WifiCredentialOptions,
+) -> bool {
+    term_info!(framework
+        .borrow()
+        .text_with(FrameworkText::WifiFallbackRetry, &[&ssid]));
+    let test_config = ModeConfig::ApSta(client_config(ssid, password, options), ap_config.clone());
+    match try_wifi_credentials(controller, &test_config).await {
+        Ok(_) => {
+            term_info!(framework.borrow().text(FrameworkText::WifiFallbackRetryOk));
+            framework.borrow_mut().wifi_credentials_test_available = false;
+            framework.borrow_mut().stop_web_app();
+            ap_services.stop();
+            let _ = controller.stop_async().await;
+            true
+        }
+        Err(e) => {
+            info!("Stored WiFi credentials still failing: {:?}", e);
+            let _ = controller.set_config(&ModeConfig::AccessPoint(ap_config.clone()));
+            false
+        }
+    }
+}
+
+// Stop signals for the tasks serving the access point, so their sockets and buffers are released when leaving AP mode
+struct ApServices {
+    dhcp_stop: Rc<Signal<NoopRawMutex, ()>>,
+    captive_stop: Rc<Signal<NoopRawMutex, ()>>,
+}
+
+impl ApServices {
+    fn new() -> Self {
+        Self {
+            dhcp_stop: Rc::new(Signal::new()),
+            captive_stop: Rc::new(Signal::new()),
+        }
+    }
+
+    // DHCP server, and the DNS responder if captive or hostnames are configured
+    fn spawn(
+        spawner: Spawner,
+        ap_stack: Stack<'static>,
+        framework: &Rc<RefCell<Framework>>,
+    ) -> Self {
+        let ap_services = Self::new();
+        spawner
+            .spawn_heap(dhcp_server(
+                ap_stack,
+                framework.clone(),
+                ap_services.dhcp_stop.clone(),
+            ))
+            .ok();
+        if framework.borrow().settings.web_server_captive
+            || !framework.borrow().settings.ap_dns_hostnames.is_empty()
+        {
+            spawner
+                .spawn_heap(captive_portal(
+                    ap_stack,
+                    framework.clone(),
+                    ap_services.captive_stop.clone(),
+                ))
+                .ok();
+        }
+        ap_services
+    }
+
+    fn stop(&self) {
+        self.dhcp_stop.signal(());
+        self.captive_stop.signal(());
+    }
+}
+
+// Leaving AP mode on any path (provisioned, grace period over, fallback restarted) frees the sockets
+impl Drop for ApServices {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Closes the grace window config portal once its time is up, leaving only the station
+fn end_grace_portal_if_expired(
+    grace_portal: &mut Option<(ApServices, embassy_time::Instant)>,
+    controller: &mut WifiController<'static>,
+    framework: &Rc<RefCell<Framework>>,
+    ssid: &str,
+    password: &str,
+    options: &WifiCredentialOptions,
+) {
+    if !grace_portal
+        .as_ref()
+        .is_some_and(|(_, deadline)| embassy_time::Instant::now() >= *deadline)
+    {
+        return;
+    }
+    let Some((ap_services, _)) = grace_portal.take() else {
+        return;
+    };
+    // The app may have moved web config to the station meanwhile, keep it then
+    if matches!(framework.borrow().web_config_mode, Some(WebConfigMode::AP)) {
+        framework.borrow_mut().stop_web_app();
+    }
+    ap_services.stop();
+    let client_config = station_mode_config(ssid, password, options);
+    if let Err(e) = controller.set_config(&client_config) {
+        error!("Failed closing config portal access point: {e:?}");
+    }
+    info!("Config portal grace period ended");
+}
+
 // #[embassy_executor::task]
-async fn dhcp_server(stack: Stack<'static>, framework: Rc<RefCell<Framework>>) {
+async fn dhcp_server(
+    stack: Stack<'static>,
+    framework: Rc<RefCell<Framework>>,
+    stop: Rc<Signal<NoopRawMutex, ()>>,
+) {
     let ap_addr = framework.borrow().settings.ap_addr;
     let mut server: edge_dhcp::server::Server<fn() -> u64, 3> =
         edge_dhcp::server::Server::new_with_et(Ipv4Addr::new(
@@ -533,31 +1226,127 @@ async fn dhcp_server(stack: Stack<'static>, framework: Rc<RefCell<Framework>>) {
         edge_nal_embassy::UdpBuffers::new();
     let udp = edge_nal_embassy::Udp::new(stack, &udp_buffers);
     let addr = core::net::SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DEFAULT_SERVER_PORT);
-    let mut socket = udp.bind(core::net::SocketAddr::V4(addr)).await.unwrap();
-    io::server::server::run(&mut server, &server_options, &mut socket, &mut buf)
-        .await
-        .unwrap();
+    let mut socket = match udp.bind(core::net::SocketAddr::V4(addr)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("DHCP server failed to bind: {:?}", e);
+            return;
+        }
+    };
+    // Errors end the task rather than panic, the socket is released either way
+    match select(
+        io::server::server::run(&mut server, &server_options, &mut socket, &mut buf),
+        stop.wait(),
+    )
+    .await
+    {
+        Either::First(Err(e)) => error!("DHCP server failed: {:?}", e),
+        Either::First(Ok(_)) => (),
+        Either::Second(_) => debug!("DHCP server stopped"),
+    }
 }
 
 // #[embassy_executor::task]
-async fn captive_portal(stack: Stack<'static>, framework: Rc<RefCell<Framework>>) {
+async fn captive_portal(
+    stack: Stack<'static>,
+    framework: Rc<RefCell<Framework>>,
+    stop: Rc<Signal<NoopRawMutex, ()>>,
+) {
     let ap_addr = framework.borrow().settings.ap_addr;
+    let ttl_secs = framework.borrow().settings.web_server_captive_dns_ttl_secs;
     let udp_buffers: edge_nal_embassy::UdpBuffers<1, 512, 512, 1> =
         edge_nal_embassy::UdpBuffers::new();
     let udp = edge_nal_embassy::Udp::new(stack, &udp_buffers);
 
     let mut tx_buf = vec![0; 512];
     let mut rx_buf = vec![0; 512];
-    edge_captive::io::run(
-        &udp,
-        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 53),
-        &mut tx_buf,
-        &mut rx_buf,
-        Ipv4Addr::new(ap_addr.0, ap_addr.1, ap_addr.2, ap_addr.3),
-        core::time::Duration::from_secs(60),
-    )
-    .await
-    .unwrap();
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 53);
+    let ip = Ipv4Addr::new(ap_addr.0, ap_addr.1, ap_addr.2, ap_addr.3);
+    let ttl = core::time::Duration::from_secs(ttl_secs);
+
+    if framework.borrow().settings.web_server_captive {
+        // captive answers every name with the AP address, so configured hostnames resolve as well
+        match select(
+            edge_captive::io::run(&udp, addr, &mut tx_buf, &mut rx_buf, ip, ttl),
+            stop.wait(),
+        )
+        .await
+        {
+            Either::First(Err(e)) => error!("Captive portal DNS failed: {:?}", e),
+            Either::First(Ok(_)) => (),
+            Either::Second(_) => debug!("Captive portal DNS stopped"),
+        }
+    } else {
+        let hostnames = framework.borrow().settings.ap_dns_hostnames;
+        match select(
+            hostnames_dns(&udp, addr, &mut tx_buf, &mut rx_buf, ip, ttl, hostnames),
+            stop.wait(),
+        )
+        .await
+        {
+            Either::First(_) => (),
+            Either::Second(_) => debug!("AP hostnames DNS stopped"),
+        }
+    }
+}
+
+// Answers only queries for the given hostnames with the AP address, other queries are ignored
+async fn hostnames_dns(
+    udp: &edge_nal_embassy::Udp<'_, 1, 512, 512, 1>,
+    addr: SocketAddr,
+    tx_buf: &mut [u8],
+    rx_buf: &mut [u8],
+    ip: Ipv4Addr,
+    ttl: core::time::Duration,
+    hostnames: &[&str],
+) {
+    let Ok(mut socket) = udp.bind(addr).await else {
+        error!("AP hostnames DNS: failed to bind");
+        return;
+    };
+    loop {
+        let Ok((len, remote)) = socket.receive(rx_buf).await else {
+            continue;
+        };
+        let request = &rx_buf[..len];
+        let Some(name) = dns_query_name(request) else {
+            continue;
+        };
+        if !hostnames
+            .iter()
+            .any(|hostname| hostname.eq_ignore_ascii_case(name.trim_end_matches('.')))
+        {
+            continue;
+        }
+        match edge_captive::reply(request, &ip.octets(), ttl, tx_buf) {
+            Ok(len) => {
+                let _ = socket.send(remote, &tx_buf[..len]).await;
+            }
+            Err(e) => debug!("AP hostnames DNS: failed to reply {:?}", e),
+        }
+    }
+}
+
+// Name of the first question in a DNS query, only plain (uncompressed) names are supported
+fn dns_query_name(request: &[u8]) -> Option<String> {
+    const HEADER_LEN: usize = 12;
+    let mut name = String::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *request.get(pos)? as usize;
+        if len == 0 {
+            return Some(name);
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = request.get(pos + 1..pos + 1 + len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(core::str::from_utf8(label).ok()?);
+        pos += 1 + len;
+    }
 }
 
 // async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
@@ -571,3 +1360,52 @@ pub async fn sta_net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
 pub async fn ap_net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
+
+// Driver error when setting TX power before the radio is started (ESP_ERR_WIFI_NOT_STARTED)
+pub(crate) const ESP_ERR_WIFI_NOT_STARTED: i32 = 0x3007;
+
+// dbm is validated by the caller, the driver takes units of 0.25 dBm
+pub(crate) fn apply_wifi_tx_power(dbm: i8) -> Result<(), i32> {
+    let res = unsafe { esp_wifi_sys::include::esp_wifi_set_max_tx_power(dbm * 4) };
+    if res != 0 {
+        return Err(res as i32);
+    }
+    Ok(())
+}
+
+fn apply_configured_wifi_tx_power(framework: &Rc<RefCell<Framework>>) {
+    let Some(dbm) = framework.borrow().wifi_tx_power_dbm else {
+        return;
+    };
+    match apply_wifi_tx_power(dbm) {
+        Ok(()) => debug!("WiFi TX power set to {dbm} dBm"),
+        Err(e) => error!("Failed setting WiFi TX power to {dbm} dBm: {e}"),
+    }
+}
+
+fn apply_wifi_country(country: &str) {
+    if country.is_empty() {
+        return;
+    }
+    let [c0, c1] = country.as_bytes() else {
+        warn!("Ignoring invalid WiFi country code '{country}', expected 2 letters");
+        return;
+    };
+    if !c0.is_ascii_alphabetic() || !c1.is_ascii_alphabetic() {
+        warn!("Ignoring invalid WiFi country code '{country}', expected 2 letters");
+        return;
+    }
+    let code = [c0.to_ascii_uppercase(), c1.to_ascii_uppercase(), 0];
+    // 802.11d lets the AP's country information refine the limits
+    let res = unsafe {
+        esp_wifi_sys::include::esp_wifi_set_country_code(
+            code.as_ptr() as *const core::ffi::c_char,
+            true,
+        )
+    };
+    if res != 0 {
+        error!("Failed setting WiFi country code '{country}': {res}");
+    } else {
+        debug!("WiFi country code set to '{country}'");
+    }
+}
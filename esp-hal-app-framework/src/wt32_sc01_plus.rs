@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, rc::Rc, string::String};
+use alloc::{boxed::Box, format, rc::Rc, string::String};
 use core::{cell::RefCell, slice};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::Timer;
@@ -16,6 +16,7 @@ use esp_hal::{
     time::Rate,
 };
 use mipidsi::models::ST7796;
+use portable_atomic::{AtomicU64, Ordering};
 use slint::platform::software_renderer::Rgb565Pixel;
 
 use crate::{
@@ -25,14 +26,14 @@ use crate::{
     mk_static,
     sdcard_spi::create_sdcard_spi_device_dma,
     slint_ext::McuWindow,
-    touch::Touch,
+    touch::{Touch, TouchConfig},
     ui_loop::UiRenderBackend,
 };
 
 // For collecting stats on rendering time split
-static mut GRAPHICS_TOTAL: u64 = 0;
-static mut TOTAL_LINES: u64 = 0;
-static mut TOTAL_PIXELS: u64 = 0;
+static GRAPHICS_TOTAL: AtomicU64 = AtomicU64::new(0);
+static TOTAL_LINES: AtomicU64 = AtomicU64::new(0);
+static TOTAL_PIXELS: AtomicU64 = AtomicU64::new(0);
 
 // ===============================================================================================================
 // == WT32 Display Renderer Backend ===============================================================================
@@ -56,9 +57,7 @@ where
         renderer.render_by_line(&mut self.buffer_provider);
 
         let graphics_time = start_graphics_time.elapsed();
-        unsafe {
-            GRAPHICS_TOTAL += graphics_time.as_micros();
-        }
+        GRAPHICS_TOTAL.fetch_add(graphics_time.as_micros(), Ordering::Relaxed);
         true
     }
 }
@@ -130,6 +129,8 @@ where
     pub curr_buffer: usize,
     pub prev_range: core::ops::Range<usize>,
     pub prev_line: usize,
+    // Rows in the current orientation, bounds the row address window
+    pub height: usize,
     pub i8080: Option<esp_hal::lcd_cam::lcd::i8080::I8080<'a, DM>>,
 }
 
@@ -192,7 +193,7 @@ where
             let transfer = i8080.send(0x2au8, 0, dma_buf_cmd).unwrap();
 
             let line_start_b = line.to_be_bytes();
-            let num_lines_b = 479u16.to_be_bytes();
+            let num_lines_b = ((self.height - 1) as u16).to_be_bytes();
             let cmdbuffer_v = [
                 line_start_b[3],
                 line_start_b[2],
@@ -224,10 +225,8 @@ where
         //     self.dma_buf0 = Some(dma_buf);
         // }
 
-        unsafe {
-            TOTAL_LINES += 1;
-            TOTAL_PIXELS += pixels as u64;
-        }
+        TOTAL_LINES.fetch_add(1, Ordering::Relaxed);
+        TOTAL_PIXELS.fetch_add(pixels as u64, Ordering::Relaxed);
     }
 }
 
@@ -235,12 +234,32 @@ where
 // == WT32 Rendering Stats =======================================================================================
 // ===============================================================================================================
 
+// Totals since boot or the last reset_render_stats
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderStats {
+    pub graphics_total_us: u64,
+    pub total_lines: u64,
+    pub total_pixels: u64,
+}
+
+pub fn render_stats() -> RenderStats {
+    RenderStats {
+        graphics_total_us: GRAPHICS_TOTAL.load(Ordering::Relaxed),
+        total_lines: TOTAL_LINES.load(Ordering::Relaxed),
+        total_pixels: TOTAL_PIXELS.load(Ordering::Relaxed),
+    }
+}
+
+pub fn reset_render_stats() {
+    GRAPHICS_TOTAL.store(0, Ordering::Relaxed);
+    TOTAL_LINES.store(0, Ordering::Relaxed);
+    TOTAL_PIXELS.store(0, Ordering::Relaxed);
+}
+
 #[embassy_executor::task]
 async fn stats_task() {
     loop {
-        unsafe {
-            dbg!(GRAPHICS_TOTAL, TOTAL_LINES, TOTAL_PIXELS);
-        }
+        dbg!(render_stats());
         Timer::after_secs(5).await;
     }
 }
@@ -310,6 +329,7 @@ impl WT32SC01Plus {
         display_peripherals: WT32SC01PlusDisplayPeripherals<CHLCD, P>,
         sdcard_peripherals: WT32SC01PlusSDCardPeripherals<S, CHSD>,
         display_orientation: mipidsi::options::Orientation,
+        touch_config: TouchConfig,
         framework: Rc<RefCell<Framework>>,
     ) -> (
         Self,
@@ -334,6 +354,7 @@ impl WT32SC01Plus {
         let runner = WT32SC01PlusRunner {
             peripherals: Some(display_peripherals),
             display_orientation,
+            touch_config,
             framework,
             init_done,
         };
@@ -396,6 +417,7 @@ where
 {
     peripherals: Option<WT32SC01PlusDisplayPeripherals<C, P>>,
     display_orientation: mipidsi::options::Orientation,
+    touch_config: TouchConfig,
     framework: Rc<RefCell<Framework>>,
     init_done: &'static InitDone,
 }
@@ -436,8 +458,11 @@ where
             .orientation(self.display_orientation)
             .reset_pin(di_rst)
             // .init(&mut delay)
-            .init(&mut esp_hal::delay::Delay::new())
-            .unwrap();
+            .init(&mut esp_hal::delay::Delay::new());
+        let display = match display {
+            Ok(display) => display,
+            Err(e) => return self.init_failed(format!("Failed to initialize display: {e:?}")),
+        };
 
         let (di, _model, _rst) = display.release();
         let (_bus, _di_dc, _di_wr) = di.release();
@@ -452,22 +477,25 @@ where
         let i8080_config =
             esp_hal::lcd_cam::lcd::i8080::Config::default().with_frequency(Rate::from_mhz(40));
 
-        let mut i8080 = esp_hal::lcd_cam::lcd::i8080::I8080::new(
+        let i8080 = match esp_hal::lcd_cam::lcd::i8080::I8080::new(
             lcd_cam.lcd,
             peripherals.DMA_CHx,
             i8080_config,
-        )
-        .unwrap()
-        .with_dc(di_dc)
-        .with_wrx(di_wr)
-        .with_data0(peripherals.GPIO9)
-        .with_data1(peripherals.GPIO46)
-        .with_data2(peripherals.GPIO3)
-        .with_data3(peripherals.GPIO8)
-        .with_data4(peripherals.GPIO18)
-        .with_data5(peripherals.GPIO17)
-        .with_data6(peripherals.GPIO16)
-        .with_data7(peripherals.GPIO15);
+        ) {
+            Ok(i8080) => i8080,
+            Err(e) => return self.init_failed(format!("Failed to configure LCD bus: {e:?}")),
+        };
+        let mut i8080 = i8080
+            .with_dc(di_dc)
+            .with_wrx(di_wr)
+            .with_data0(peripherals.GPIO9)
+            .with_data1(peripherals.GPIO46)
+            .with_data2(peripherals.GPIO3)
+            .with_data3(peripherals.GPIO8)
+            .with_data4(peripherals.GPIO18)
+            .with_data5(peripherals.GPIO17)
+            .with_data6(peripherals.GPIO16)
+            .with_data7(peripherals.GPIO15);
 
         i8080.set_8bits_order(esp_hal::lcd_cam::ByteOrder::Inverted);
 
@@ -485,6 +513,15 @@ where
         let (_, _, tx_buffer_cmd, tx_descriptors_cmd) = dma_buffers!(0, 4);
         let dma_buf_cmd = DmaTxBuf::new(tx_descriptors_cmd, tx_buffer_cmd).unwrap();
 
+        let (width, height, ft6x36orientation) = match self.display_orientation.rotation {
+            mipidsi::options::Rotation::Deg0 => (320, 480, ft6x36::Orientation::Portrait), // ?? orientation not tested
+            mipidsi::options::Rotation::Deg180 => (320, 480, ft6x36::Orientation::InvertedPortrait), // ?? orientation not tested
+            mipidsi::options::Rotation::Deg90 => (480, 320, ft6x36::Orientation::Landscape),
+            mipidsi::options::Rotation::Deg270 => {
+                (480, 320, ft6x36::Orientation::InvertedLandscape)
+            }
+        };
+
         let buffer_provider = DrawBuffer {
             i8080: Some(i8080),
             dma_buf0: Some(dma_buf0),
@@ -497,6 +534,7 @@ where
                 end: 10000,
             },
             prev_line: 0,
+            height: height as usize,
         };
 
         // Initialize backlight pwm control
@@ -506,13 +544,13 @@ where
             esp_hal::ledc::timer::Timer<esp_hal::ledc::LowSpeed>,
             ledc.timer::<esp_hal::ledc::LowSpeed>(esp_hal::ledc::timer::Number::Timer0)
         );
-        lstimer0
-            .configure(esp_hal::ledc::timer::config::Config {
-                duty: esp_hal::ledc::timer::config::Duty::Duty5Bit,
-                clock_source: esp_hal::ledc::timer::LSClockSource::APBClk,
-                frequency: Rate::from_khz(24),
-            })
-            .unwrap();
+        if let Err(e) = lstimer0.configure(esp_hal::ledc::timer::config::Config {
+            duty: esp_hal::ledc::timer::config::Duty::Duty5Bit,
+            clock_source: esp_hal::ledc::timer::LSClockSource::APBClk,
+            frequency: Rate::from_khz(24),
+        }) {
+            return self.init_failed(format!("Failed to configure backlight timer: {e:?}"));
+        }
         let channel0 = ledc.channel(esp_hal::ledc::channel::Number::Channel0, di_bl);
 
         // ===============================================================================================================
@@ -530,33 +568,25 @@ where
         //     config
         // });
 
-        let ti_i2c = esp_hal::i2c::master::I2c::new(
+        let ti_i2c = match esp_hal::i2c::master::I2c::new(
             peripherals.I2Cx,
             esp_hal::i2c::master::Config::default().with_frequency(Rate::from_khz(400)),
-        )
-        .unwrap()
-        .with_sda(ti_sda)
-        .with_scl(ti_scl);
+        ) {
+            Ok(ti_i2c) => ti_i2c.with_sda(ti_sda).with_scl(ti_scl),
+            Err(e) => return self.init_failed(format!("Failed to configure touch I2C: {e:?}")),
+        };
 
-        esp_hal::interrupt::enable(
+        if let Err(e) = esp_hal::interrupt::enable(
             esp_hal::peripherals::Interrupt::GPIO,
             esp_hal::interrupt::Priority::Priority3,
-        )
-        .unwrap();
+        ) {
+            return self.init_failed(format!("Failed to enable touch interrupt: {e:?}"));
+        }
 
         // ===============================================================================================================
         // == WT32 Runner - Slint Backend ==============================================================================
         // ===============================================================================================================
 
-        let (width, height, ft6x36orientation) = match self.display_orientation.rotation {
-            mipidsi::options::Rotation::Deg0 => (320, 480, ft6x36::Orientation::Portrait), // ?? orientation not tested
-            mipidsi::options::Rotation::Deg180 => (320, 480, ft6x36::Orientation::InvertedPortrait), // ?? orientation not tested
-            mipidsi::options::Rotation::Deg90 => (480, 320, ft6x36::Orientation::Landscape),
-            mipidsi::options::Rotation::Deg270 => {
-                (480, 320, ft6x36::Orientation::InvertedLandscape)
-            }
-        };
-
         let size = slint::PhysicalSize::new(width, height);
         let window =
             McuWindow::new(slint::platform::software_renderer::RepaintBufferType::ReusedBuffer);
@@ -572,28 +602,34 @@ where
             ft6x36::Dimension((height - 1) as u16, (width - 1) as u16),
         );
         touch_inner.set_orientation(ft6x36orientation);
-        if touch_inner.init().is_err() {
-            panic!(
-                "Failed to initialize touch. Did you flash the correct device? (WT32-SC01 Plus)"
-            );
+        if let Err(e) = touch_inner.init() {
+            return self.init_failed(format!(
+                "Failed to initialize touch ({e:?}). Did you flash the correct device? (WT32-SC01 Plus)"
+            ));
         }
 
         let touch_adapter = Ft6x36TouchAdapter::new(touch_inner, ti_irq);
-        let touch = Touch::new(touch_adapter);
+        let touch = Touch::new(touch_adapter, self.touch_config);
 
         let render_backend = WT32RenderBackend { buffer_provider };
         let mut backlight = WT32Backlight::new(channel0, lstimer0);
 
         // Turn on display backlight
-        backlight
-            .set_percent(100)
-            .expect("Failed to set display backlight to 100%");
+        if let Err(e) = backlight.set_percent(100) {
+            return self.init_failed(format!("Failed to set display backlight to 100%: {e:?}"));
+        }
 
         self.init_done.signal(Ok(()));
 
         crate::ui_loop::event_loop(touch, window, render_backend, backlight, self.framework.clone())
             .await;
     }
+
+    // Reported through wait_init_done so the app can show a diagnostic instead of panicking
+    fn init_failed(&self, message: String) {
+        error!("{message}");
+        self.init_done.signal(Err(message));
+    }
 }
 
 // ===============================================================================================================
@@ -0,0 +1,24 @@
+// The framework itself only builds for the device, so the modules that are plain logic
+// (Improv parsing, settings file, OTA version checks, web API DTOs) are compiled here
+// for the host as well, to run their unit tests:
+//
+//     cargo test -p framework-host-tests
+//
+// Modules included here must only depend on alloc, serde, serde_json, toml and semver.
+#![no_std]
+#![allow(dead_code)]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+#[path = "../../esp-hal-app-framework/src/config_file.rs"]
+mod config_file;
+#[path = "../../esp-hal-app-framework/src/improv_wifi.rs"]
+// Names follow the Improv spec (RPC)
+#[allow(clippy::upper_case_acronyms)]
+mod improv_wifi;
+#[path = "../../esp-hal-app-framework/src/ota_checks.rs"]
+mod ota_checks;
+#[path = "../../esp-hal-app-framework/src/web_api.rs"]
+mod web_api;
@@ -1,8 +1,11 @@
 use cargo_util_schemas::manifest::{InheritableSemverVersion, TomlManifest, TomlPackage};
 #[allow(unused_imports)]
 use clap::{builder::PathBufValueParser as _, Args, Parser, Subcommand};
+use base64::{engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD}, Engine as _};
 use crc32fast::Hasher;
+use ed25519_compact::{KeyPair, PublicKey, SecretKey, Signature};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::{
     fs::{self, File},
     io::{self, BufReader, Read},
@@ -28,7 +31,30 @@ enum MainCommand {
     WebInstall(OtaAndFlasherCommand), 
     /// License commands
     #[command(subcommand)]
-    License(LicenseCommand)
+    License(LicenseCommand),
+    /// Generate a unique self-signed TLS certificate and key for a device (requires openssl)
+    GenCert(GenCertCommand),
+    /// Rebuild device-wasm and copy its bindings to the framework's static web files (requires wasm-pack)
+    BuildWasm,
+}
+
+#[derive(Args)]
+struct GenCertCommand {
+    /// Device hostname, e.g. the web app domain or <device-name>.local
+    #[arg(long)]
+    hostname: String,
+
+    /// Device IP address, added to the certificate if specified
+    #[arg(long)]
+    ip: Option<String>,
+
+    /// Certificate validity in days
+    #[arg(long, default_value_t = 3650)]
+    days: u32,
+
+    /// Folder to save <hostname>-cert.pem and <hostname>-key.pem (must exist)
+    #[arg(long, short, default_value = ".")]
+    output: PathBuf,
 }
 
 // order matters
@@ -38,7 +64,8 @@ struct OtaAndFlasherCommand {
     #[arg(value_enum)]
     build: Option<Build>,
 
-    /// Deploy binaries and metadata files (requires build outputs) - not yet implemented !
+    /// Deploy binaries and metadata files (requires build outputs), OTA only: uploads to the url in
+    /// the device project's deploy.toml or XTASK_DEPLOY_URL
     #[arg(value_enum, requires = "build")]
     deploy: Option<Deploy>,
 
@@ -53,6 +80,18 @@ struct OtaAndFlasherCommand {
     /// Use when building to a folder under target using cargo --target-dir
     #[arg(long)]
     subtarget: Option<String>,
+
+    /// Target chip, selects the build target, image format and web install chip family
+    #[arg(long, value_enum, default_value_t = Chip::Esp32s3)]
+    chip: Chip,
+
+    /// Oldest version this firmware can be OTA updated from (written to ota.toml), older devices refuse it
+    #[arg(long)]
+    min_from_version: Option<semver::Version>,
+
+    /// Only validate inputs (package info, release ELF present and up to date), don't generate anything
+    #[arg(long)]
+    dry_run: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -60,25 +99,31 @@ enum LicenseCommand {
     /// Generate private and public keys
     #[command(arg_required_else_help = true)]
     GenKeys {
-        /// base file name to store keys
+        /// base file name to store keys (<file>.priv and <file>.pub, the latter is given to the firmware)
         file: PathBuf,
     },
     /// Generate license binary
     GenBin {
+        /// device MAC address, e.g. aa:bb:cc:dd:ee:ff
         #[arg(long, short)]
         mac: String,
 
         /// file to store bin of license
         bin_file: PathBuf,
 
+        /// private key (<file>.priv) to sign with
         key_file: PathBuf,
 
+        /// up to 8 characters, must match the magic the firmware loads the license with (none if not specified)
         #[arg(long, short='g')]
         magic: Option<String>,
 
         #[arg(long, short)]
         version: semver::Version,
 
+        /// must match the obfuscate key the firmware loads the license with
+        #[arg(long, short='x')]
+        obfuscate_key: String,
     },
     /// Parse license binary
     ParseBin {
@@ -90,6 +135,9 @@ enum LicenseCommand {
 
         #[arg(long, short='g')]
         magic: Option<String>,
+
+        #[arg(long, short='x')]
+        obfuscate_key: String,
     },
 }
 
@@ -103,6 +151,57 @@ enum Deploy {
     Deploy,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum Chip {
+    Esp32,
+    Esp32s2,
+    Esp32s3,
+    Esp32c3,
+    Esp32c6,
+}
+
+impl Chip {
+    fn target_triple(self) -> &'static str {
+        match self {
+            Chip::Esp32 => "xtensa-esp32-none-elf",
+            Chip::Esp32s2 => "xtensa-esp32s2-none-elf",
+            Chip::Esp32s3 => "xtensa-esp32s3-none-elf",
+            Chip::Esp32c3 => "riscv32imc-unknown-none-elf",
+            Chip::Esp32c6 => "riscv32imac-unknown-none-elf",
+        }
+    }
+
+    // espflash --chip value
+    fn espflash_name(self) -> &'static str {
+        match self {
+            Chip::Esp32 => "esp32",
+            Chip::Esp32s2 => "esp32s2",
+            Chip::Esp32s3 => "esp32s3",
+            Chip::Esp32c3 => "esp32c3",
+            Chip::Esp32c6 => "esp32c6",
+        }
+    }
+
+    // ESP Web Tools manifest chipFamily
+    fn chip_family(self) -> &'static str {
+        match self {
+            Chip::Esp32 => "ESP32",
+            Chip::Esp32s2 => "ESP32-S2",
+            Chip::Esp32s3 => "ESP32-S3",
+            Chip::Esp32c3 => "ESP32-C3",
+            Chip::Esp32c6 => "ESP32-C6",
+        }
+    }
+
+    // Second stage bootloader flash offset, older chips keep it after the first 4K
+    fn bootloader_offset(self) -> u32 {
+        match self {
+            Chip::Esp32 | Chip::Esp32s2 => 0x1000,
+            Chip::Esp32s3 | Chip::Esp32c3 | Chip::Esp32c6 => 0,
+        }
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
     
@@ -125,6 +224,18 @@ fn main() {
                 std::process::exit(1);
             }
         }
+        MainCommand::GenCert(command) => {
+            if let Err(e) = handle_gen_cert(&command) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        MainCommand::BuildWasm => {
+            if let Err(e) = handle_build_wasm() {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
     }
 }
 
@@ -137,6 +248,9 @@ struct OtaToml {
     version: String,
     filesize: u64,
     crc32: String,
+    sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_from_version: Option<String>,
 }
 
 const MANIFEST_TEMPLATE_NEW: &str = r#"{
@@ -147,9 +261,9 @@ const MANIFEST_TEMPLATE_NEW: &str = r#"{
   "new_install_improv_wait_time": 30,
   "builds": [
     {
-      "chipFamily": "ESP32-S3",
+      "chipFamily": "{chip_family}",
       "parts": [
-        { "path": "boot-loader.bin", "offset": 0 },
+        { "path": "boot-loader.bin", "offset": {bootloader_offset} },
         { "path": "partition-table.bin", "offset": 32768 },
         { "path": "{bin_name}", "offset": 2097152 }
       ]
@@ -166,7 +280,7 @@ const MANIFEST_TEMPLATE_UPGRADE: &str = r#"{
   "new_install_improv_wait_time": 30,
   "builds": [
     {
-      "chipFamily": "ESP32-S3",
+      "chipFamily": "{chip_family}",
       "parts": [
         { "path": "clear-ota.bin", "offset": 36864 },
         { "path": "{bin_name}", "offset": 2097152 }
@@ -184,6 +298,10 @@ fn handle_web_install(command: &OtaAndFlasherCommand) -> Result<(), String> {
     let package_folder_path = command.input.canonicalize().map_err(|e| format!("Error in input path {e}"))?;
     let (package_name, version) = get_package_info(&package_folder_path)?;
 
+    if command.dry_run {
+        return dry_run(&package_folder_path, &package_name, &version, &command.subtarget, command.chip);
+    }
+
     if let Some(Build::Build) = command.build {
         let web_install_folder_path = {
             let result;
@@ -208,17 +326,17 @@ fn handle_web_install(command: &OtaAndFlasherCommand) -> Result<(), String> {
 
         let bin_name = format!("{package_name}-{version}.bin");
 
-        let (_bin_size, _crc32) = espflash_gen_bin(&package_folder_path, &package_name, &web_install_folder_path, &bin_name, &command.subtarget)?;
+        let (_bin_size, _crc32) = espflash_gen_bin(&package_folder_path, &package_name, &web_install_folder_path, &bin_name, &command.subtarget, command.chip)?;
 
-        let manifest_new = MANIFEST_TEMPLATE_NEW.replace("{package_name}", &package_name).replace("{version}", &version.to_string()).replace("{bin_name}", &bin_name);
+        let manifest_new = fill_manifest(MANIFEST_TEMPLATE_NEW, &package_name, &version, &bin_name, command.chip);
 
-        let web_install_manifest_new_path = web_install_folder_path.join(format!("manifest-new-{}.json", &version.to_string()));
+        let web_install_manifest_new_path = web_install_folder_path.join(format!("manifest-new-{}.json", version));
         std::fs::write(&web_install_manifest_new_path, manifest_new)
             .map_err(|e| format!("Failed writing {} : {e:?}", web_install_manifest_new_path.display()))?;
         println!("Saved new manifest file to {}", web_install_manifest_new_path.display());
 
-        let manifest_upgrade = MANIFEST_TEMPLATE_UPGRADE.replace("{package_name}", &package_name).replace("{version}", &version.to_string()).replace("{bin_name}", &bin_name);
-        let web_install_manifest_upgrade_path = web_install_folder_path.join(format!("manifest-upgrade-{}.json", &version.to_string()));
+        let manifest_upgrade = fill_manifest(MANIFEST_TEMPLATE_UPGRADE, &package_name, &version, &bin_name, command.chip);
+        let web_install_manifest_upgrade_path = web_install_folder_path.join(format!("manifest-upgrade-{}.json", version));
         std::fs::write(&web_install_manifest_upgrade_path, manifest_upgrade)
             .map_err(|e| format!("Failed writing {} : {e:?}", web_install_manifest_upgrade_path.display()))?;
         println!("Saved upgrade manifest file to {}", web_install_manifest_upgrade_path.display());
@@ -226,6 +344,15 @@ fn handle_web_install(command: &OtaAndFlasherCommand) -> Result<(), String> {
     Ok(())
 }
 
+fn fill_manifest(template: &str, package_name: &str, version: &semver::Version, bin_name: &str, chip: Chip) -> String {
+    template
+        .replace("{package_name}", package_name)
+        .replace("{version}", &version.to_string())
+        .replace("{bin_name}", bin_name)
+        .replace("{chip_family}", chip.chip_family())
+        .replace("{bootloader_offset}", &chip.bootloader_offset().to_string())
+}
+
 fn handle_ota(command: &OtaAndFlasherCommand) -> Result<(), String> {
     if command.build.is_none() && command.deploy.is_none() {
         return Err("At least one command (build or deploy) must be specified".to_string());
@@ -234,6 +361,17 @@ fn handle_ota(command: &OtaAndFlasherCommand) -> Result<(), String> {
     let package_folder_path = command.input.canonicalize().map_err(|e| format!("Error in input path '{}' {e}", command.input.display()))?;
     let (package_name, version) = get_package_info(&package_folder_path)?;
 
+    if command.dry_run {
+        return dry_run(&package_folder_path, &package_name, &version, &command.subtarget, command.chip);
+    }
+
+    if let Some(min_from_version) = &command.min_from_version {
+        if *min_from_version > version {
+            return Err(format!("Minimum from version {min_from_version} is newer than the package version {version}"));
+        }
+    }
+
+    let mut built = None;
     if let Some(Build::Build) = command.build {
         let ota_folder_path = {
             let result;
@@ -259,7 +397,9 @@ fn handle_ota(command: &OtaAndFlasherCommand) -> Result<(), String> {
         // let espflash_relative_ota_folder_path = Path::new(".").join("target").join("ota"); // espflash runs with current foder as device package
         let bin_name = format!("{package_name}-{version}.bin");
 
-        let (bin_size, crc32) = espflash_gen_bin(&package_folder_path, &package_name, &ota_folder_path, &bin_name, &command.subtarget)?;
+        let (bin_size, crc32) = espflash_gen_bin(&package_folder_path, &package_name, &ota_folder_path, &bin_name, &command.subtarget, command.chip)?;
+        let sha256 = compute_sha256(&ota_folder_path.join(&bin_name))
+            .map_err(|e| format!("Failed to calculate sha256: {e:?}"))?;
 
         // Create toml
         let ota_toml = OtaToml {
@@ -267,24 +407,83 @@ fn handle_ota(command: &OtaAndFlasherCommand) -> Result<(), String> {
             version: version.to_string(),
             filesize: bin_size,
             crc32: format!("{crc32:x}"),
+            sha256,
+            min_from_version: command.min_from_version.as_ref().map(|v| v.to_string()),
         };
 
         let ota_toml_path = ota_folder_path.join("ota.toml");
-        let ota_toml = toml::to_string(&ota_toml).expect("Unexpected: failed to serialize toml");
-        std::fs::write(&ota_toml_path, ota_toml)
+        let ota_toml_text = toml::to_string(&ota_toml).expect("Unexpected: failed to serialize toml");
+        std::fs::write(&ota_toml_path, ota_toml_text)
             .map_err(|e| format!("Failed writing {} : {e:?}", ota_toml_path.display()))?;
         println!("Saved metadata information to {}", ota_toml_path.display());
+        built = Some((ota_folder_path, ota_toml.filename));
     }
 
-    if let Some(Deploy::Deploy) = command.deploy {
-        // TODO: Implement deploy logic
-        println!("Deploying OTA update...");
+    if let (Some(Deploy::Deploy), Some((ota_folder_path, bin_name))) = (&command.deploy, &built) {
+        deploy_ota(&package_folder_path, ota_folder_path, bin_name)?;
     }
 
     Ok(())
 }
 
-fn espflash_gen_bin(package_folder_path: &std::path::PathBuf, package_name: &str, espflash_relative_ota_folder_path: &std::path::PathBuf, bin_name: &str, subtarget: &Option<String>) -> Result<(u64, u32), String> {
+fn dry_run(package_folder_path: &Path, package_name: &str, version: &semver::Version, subtarget: &Option<String>, chip: Chip) -> Result<(), String> {
+    let elf_path = check_release_elf(package_folder_path, package_name, subtarget, chip)?;
+    println!("Package {package_name} version {version}");
+    println!("Release ELF {} found", elf_path.display());
+    println!("Would generate {package_name}-{version}.bin");
+    Ok(())
+}
+
+fn release_elf_path(package_folder_path: &Path, package_name: &str, subtarget: &Option<String>, chip: Chip) -> PathBuf {
+    let mut path = package_folder_path.join("target");
+    if let Some(sub) = &subtarget {
+        path = path.join(sub);
+    }
+    path.join(chip.target_triple()).join("release").join(package_name)
+}
+
+// Fails with a clear message if the release ELF is missing, warns if sources changed after it was built
+fn check_release_elf(package_folder_path: &Path, package_name: &str, subtarget: &Option<String>, chip: Chip) -> Result<PathBuf, String> {
+    let elf_path = release_elf_path(package_folder_path, package_name, subtarget, chip);
+    let elf_modified = fs::metadata(&elf_path)
+        .and_then(|meta| meta.modified())
+        .map_err(|_| {
+            format!(
+                "Release ELF '{}' not found, build it first with `cargo build --release` in {}",
+                elf_path.display(),
+                package_folder_path.display()
+            )
+        })?;
+
+    let sources_modified = [package_folder_path.join("Cargo.toml"), package_folder_path.join("src")]
+        .iter()
+        .filter_map(|path| newest_modified(path))
+        .max();
+    if sources_modified.is_some_and(|sources_modified| sources_modified > elf_modified) {
+        eprintln!(
+            "Warning: sources changed after '{}' was built, run `cargo build --release` to include the changes",
+            elf_path.display()
+        );
+    }
+    Ok(elf_path)
+}
+
+fn newest_modified(path: &Path) -> Option<std::time::SystemTime> {
+    let meta = fs::metadata(path).ok()?;
+    if meta.is_dir() {
+        fs::read_dir(path)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| newest_modified(&entry.path()))
+            .max()
+    } else {
+        meta.modified().ok()
+    }
+}
+
+fn espflash_gen_bin(package_folder_path: &std::path::PathBuf, package_name: &str, espflash_relative_ota_folder_path: &Path, bin_name: &str, subtarget: &Option<String>, chip: Chip) -> Result<(u64, u32), String> {
+    check_release_elf(package_folder_path, package_name, subtarget, chip)?;
+
     let mut path = Path::new(".").join("target");
 
     if let Some(sub) = &subtarget {
@@ -292,18 +491,18 @@ fn espflash_gen_bin(package_folder_path: &std::path::PathBuf, package_name: &str
     }
 
     let espflash_relative_source_bin_folder_path = path
-        .join("xtensa-esp32s3-none-elf")
+        .join(chip.target_triple())
         .join("release");
     let espflash_relative_source_bin_file_path =
-        espflash_relative_source_bin_folder_path.join(&package_name);
+        espflash_relative_source_bin_folder_path.join(package_name);
     let esp_flash_relative_target_bin_file_path =
         espflash_relative_ota_folder_path.join(bin_name);
-    let espflash_cmdline = format!("save-image --partition-table ./partitions.csv --flash-mode dio --flash-freq 80mhz --flash-size 16mb --chip esp32s3 {} {}", espflash_relative_source_bin_file_path.display(), esp_flash_relative_target_bin_file_path.display());
+    let espflash_cmdline = format!("save-image --partition-table ./partitions.csv --flash-mode dio --flash-freq 80mhz --flash-size 16mb --chip {} {} {}", chip.espflash_name(), espflash_relative_source_bin_file_path.display(), esp_flash_relative_target_bin_file_path.display());
     println!("Executing: espflash {espflash_cmdline}");
     let args: Vec<&str> = espflash_cmdline.split(" ").collect();
     let status = std::process::Command::new("espflash")
         .args(&args)
-        .current_dir(&package_folder_path)
+        .current_dir(package_folder_path)
         .status()
         .map_err(|e| format!("Failed to execute espflash : {e}"))?;
     if !status.success() {
@@ -321,23 +520,77 @@ fn espflash_gen_bin(package_folder_path: &std::path::PathBuf, package_name: &str
         )
     })?;
     let bin_size = target_bin_meta.size();
+    check_partition_fit(package_folder_path, bin_size)?;
     let crc32 = compute_crc32(espflash_target_bin_file_path.as_path())
         .map_err(|e| format!("Failed to calculate crc32: {e:?}"))?;
     Ok((bin_size, crc32))
 }
 
+// The app must fit in the smallest app partition, OTA alternates between them
+fn check_partition_fit(package_folder_path: &Path, bin_size: u64) -> Result<(), String> {
+    let partitions_path = package_folder_path.join("partitions.csv");
+    let partitions = fs::read_to_string(&partitions_path)
+        .map_err(|e| format!("Can't read '{}' : {e:?}", partitions_path.display()))?;
+    let partition_size = app_partition_size(&partitions)?;
+    println!(
+        "Firmware size {bin_size} bytes of {partition_size} available in app partition ({}%)",
+        bin_size * 100 / partition_size
+    );
+    if bin_size > partition_size {
+        return Err(format!(
+            "Firmware size {bin_size} bytes exceeds the app partition size {partition_size} bytes by {} bytes",
+            bin_size - partition_size
+        ));
+    }
+    Ok(())
+}
+
+// Smallest size among app partitions in an esp-idf partition table csv (name, type, subtype, offset, size, flags)
+fn app_partition_size(partitions_csv: &str) -> Result<u64, String> {
+    partitions_csv
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            (fields.get(1) == Some(&"app")).then(|| {
+                let size = fields.get(4).copied().unwrap_or_default();
+                parse_partition_size(size)
+                    .ok_or_else(|| format!("Invalid size '{size}' of partition '{}'", fields[0]))
+            })
+        })
+        .collect::<Result<Vec<u64>, String>>()?
+        .into_iter()
+        .min()
+        .ok_or_else(|| "No app partition found in partition table".to_string())
+}
+
+// Sizes are decimal or hex (0x), optionally with a K or M suffix
+fn parse_partition_size(size: &str) -> Option<u64> {
+    let (number, multiplier) = match size.chars().last()? {
+        'K' | 'k' => (&size[..size.len() - 1], 1024),
+        'M' | 'm' => (&size[..size.len() - 1], 1024 * 1024),
+        _ => (size, 1),
+    };
+    let value = match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => number.parse::<u64>().ok()?,
+    };
+    Some(value * multiplier)
+}
+
 fn get_package_info(
-    package_folder_path: &std::path::PathBuf,
+    package_folder_path: &Path,
 ) -> Result<(String, semver::Version), String> {
     let toml_path = package_folder_path.join("Cargo.toml");
     let cargo_toml = fs::read_to_string(&toml_path)
-        .map_err(|e| format!("Can't read '{}' : {e:?}", &toml_path.display()))?;
+        .map_err(|e| format!("Can't read '{}' : {e:?}", toml_path.display()))?;
     let cargo_toml: TomlManifest = toml::from_str(&cargo_toml)
-        .map_err(|e| format!("Can't parse '{}' : {e:?}", &toml_path.display()))?;
+        .map_err(|e| format!("Can't parse '{}' : {e:?}", toml_path.display()))?;
     let package_name = if let Some(TomlPackage { name, .. }) = cargo_toml.package.as_deref() {
         name.as_ref()
     } else {
-        return Err("Package name not fount in Cargo.toml".to_string());
+        return Err("Package name not found in Cargo.toml".to_string());
     };
     let version = if let Some(TomlPackage {
         version: Some(InheritableSemverVersion::Value(version)),
@@ -346,47 +599,566 @@ fn get_package_info(
     {
         version
     } else {
-        return Err("Package version not fount in Cargo.toml".to_string());
+        return Err("Package version not found in Cargo.toml".to_string());
     };
     Ok((package_name.to_string(), version.clone()))
 }
 
 fn compute_crc32(path: &Path) -> Result<u32, io::Error> {
+    let mut hasher = Hasher::new();
+    read_chunks(path, |chunk| hasher.update(chunk))?;
+    Ok(hasher.finalize())
+}
+
+// Lowercase hex, as expected in ota.toml
+fn compute_sha256(path: &Path) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    read_chunks(path, |chunk| hasher.update(chunk))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn read_chunks(path: &Path, mut on_chunk: impl FnMut(&[u8])) -> Result<(), io::Error> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
-    let mut hasher = Hasher::new();
     let mut buffer = [0u8; 4096]; // 4 KB buffer
 
-    while let Ok(n) = reader.read(&mut buffer) {
-        if n == 0 {
-            break; // EOF
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break, // EOF
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        on_chunk(&buffer[..n]);
+    }
+
+    Ok(())
+}
+
+// Where `ota build deploy` uploads to, read from deploy.toml in the device project folder:
+//   url = "s3://my-bucket/firmware/"         # aws cli, with endpoint_url for S3 compatible storage
+//   url = "sftp://user@host/var/www/fw/"     # curl
+//   url = "https://fw.example.com/upload/"   # curl, HTTP PUT, with any headers (e.g. auth) given
+// The url is the folder the devices' ota_domain + ota_path serve. XTASK_DEPLOY_URL overrides it,
+// so deploy.toml is only needed for the extra settings.
+#[derive(Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DeployConfig {
+    url: String,
+    endpoint_url: Option<String>,
+    headers: Vec<String>,
+}
+
+const DEPLOY_CONFIG_FILE: &str = "deploy.toml";
+const DEPLOY_URL_ENV: &str = "XTASK_DEPLOY_URL";
+
+fn deploy_ota(package_folder_path: &Path, ota_folder_path: &Path, bin_name: &str) -> Result<(), String> {
+    let config = load_deploy_config(package_folder_path, std::env::var(DEPLOY_URL_ENV).ok())?;
+    // Firmware first, so a device that already sees the new ota.toml finds the bin it names
+    for file_name in [bin_name, "ota.toml"] {
+        let mut command = upload_command(&config, &ota_folder_path.join(file_name), file_name)?;
+        println!("Uploading {file_name} to {}", config.url);
+        let status = command
+            .status()
+            .map_err(|e| format!("Failed to execute {} : {e}", command.get_program().to_string_lossy()))?;
+        if !status.success() {
+            return Err(format!("Uploading {file_name} failed"));
         }
-        hasher.update(&buffer[..n]);
     }
+    println!("Deployed OTA update to {}", config.url);
+    Ok(())
+}
 
-    Ok(hasher.finalize())
+fn load_deploy_config(package_folder_path: &Path, env_url: Option<String>) -> Result<DeployConfig, String> {
+    let config_path = package_folder_path.join(DEPLOY_CONFIG_FILE);
+    let mut config = match fs::read_to_string(&config_path) {
+        Ok(config) => toml::from_str::<DeployConfig>(&config)
+            .map_err(|e| format!("Can't parse '{}' : {e}", config_path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => DeployConfig::default(),
+        Err(e) => return Err(format!("Can't read '{}' : {e:?}", config_path.display())),
+    };
+    if let Some(env_url) = env_url {
+        config.url = env_url;
+    }
+    if config.url.is_empty() {
+        return Err(format!("No deploy destination, set url in '{}' or {DEPLOY_URL_ENV}", config_path.display()));
+    }
+    Ok(config)
+}
+
+// Uploads file_path into the destination folder as file_name, which keeps the names devices fetch
+fn upload_command(config: &DeployConfig, file_path: &Path, file_name: &str) -> Result<std::process::Command, String> {
+    let url = format!("{}/{file_name}", config.url.trim_end_matches('/'));
+    if url.starts_with("s3://") {
+        let mut command = std::process::Command::new("aws");
+        if let Some(endpoint_url) = &config.endpoint_url {
+            command.args(["--endpoint-url", endpoint_url]);
+        }
+        command.args(["s3", "cp"]).arg(file_path).arg(url);
+        Ok(command)
+    } else if ["http://", "https://", "sftp://"].iter().any(|scheme| url.starts_with(scheme)) {
+        let mut command = std::process::Command::new("curl");
+        command.args(["--fail", "--silent", "--show-error"]);
+        for header in &config.headers {
+            command.args(["-H", header]);
+        }
+        command.arg("-T").arg(file_path).arg(url);
+        Ok(command)
+    } else {
+        Err(format!("Unsupported deploy url '{}', expected s3://, sftp://, http:// or https://", config.url))
+    }
 }
 
 // WEB Install and OTA ////////////////////////////////////////////////////////////////////////////////
 
+// TLS Certificates //////////////////////////////////////////////////////////////////////////////////
+
+fn handle_gen_cert(command: &GenCertCommand) -> Result<(), String> {
+    let cert_path = command.output.join(format!("{}-cert.pem", command.hostname));
+    let key_path = command.output.join(format!("{}-key.pem", command.hostname));
+
+    let mut subject_alt_name = format!("subjectAltName=DNS:{}", command.hostname);
+    if let Some(ip) = &command.ip {
+        subject_alt_name.push_str(&format!(",IP:{ip}"));
+    }
+
+    // EC P-256 keeps the TLS handshake fast on the device
+    let status = std::process::Command::new("openssl")
+        .args(["req", "-x509", "-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:prime256v1", "-nodes"])
+        .args(["-days", &command.days.to_string()])
+        .args(["-subj", &format!("/CN={}", command.hostname)])
+        .args(["-addext", &subject_alt_name])
+        .arg("-keyout")
+        .arg(&key_path)
+        .arg("-out")
+        .arg(&cert_path)
+        .status()
+        .map_err(|e| format!("Failed to execute openssl : {e}"))?;
+    if !status.success() {
+        return Err("openssl run failed".to_string());
+    }
+
+    println!("Saved certificate to {}", cert_path.display());
+    println!("Saved private key to {}", key_path.display());
+    println!("Install on the device with Framework::set_tls_credentials or POST /api/tls-config (stored in flash, applied after restart)");
+    Ok(())
+}
+
+// Web App WASM //////////////////////////////////////////////////////////////////////////////////////
+
+// The web pages load these two files from the firmware, they must be regenerated whenever device-wasm changes
+const WASM_BINDINGS: [&str; 2] = ["device_wasm.js", "device_wasm_bg.wasm"];
+
+fn handle_build_wasm() -> Result<(), String> {
+    let repo_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let wasm_crate_path = repo_path.join("device-wasm");
+    let static_path = repo_path.join("esp-hal-app-framework").join("src").join("static");
+
+    let status = std::process::Command::new("wasm-pack")
+        .args(["build", "--release", "--target", "web", "--no-typescript"])
+        .current_dir(&wasm_crate_path)
+        .status()
+        .map_err(|e| format!("Failed to execute wasm-pack : {e}"))?;
+    if !status.success() {
+        return Err("wasm-pack run failed".to_string());
+    }
+
+    for file in WASM_BINDINGS {
+        let from = wasm_crate_path.join("pkg").join(file);
+        let to = static_path.join(file);
+        fs::copy(&from, &to).map_err(|e| format!("Failed copying {} to {} : {e:?}", from.display(), to.display()))?;
+        println!("Updated {}", to.display());
+    }
+    Ok(())
+}
+
+// Licenses //////////////////////////////////////////////////////////////////////////////////////////
+
 fn handle_license(command: &LicenseCommand) -> Result<(), String> {
     match command {
         LicenseCommand::GenKeys { file } => handle_license_genkeys(file),
-        LicenseCommand::GenBin { version, mac, magic, bin_file, key_file } => handle_gen_bin(version, mac, magic, bin_file, key_file),
-        LicenseCommand::ParseBin { magic, bin_file, key_file } => handle_parse_bin(magic, bin_file, key_file),
+        LicenseCommand::GenBin { version, mac, magic, bin_file, key_file, obfuscate_key } => handle_gen_bin(version, mac, magic, bin_file, key_file, obfuscate_key),
+        LicenseCommand::ParseBin { magic, bin_file, key_file, obfuscate_key } => handle_parse_bin(magic, bin_file, key_file, obfuscate_key),
     }
 }
 
-fn handle_parse_bin(_magic: &Option<String>, _bin_file: &PathBuf, _key_file: &PathBuf) -> Result<(), String> {
-    todo!()
+// Format expected by the firmware's license.rs (flashed to the 'lic' partition):
+// 8 bytes magic (zero padded) | u16 LE token length | token
+// token is a PASETO v4.public token (Ed25519), XORed with the obfuscate key and base64 (url safe) encoded.
+// Its payload is {"license": "<License json>"}, License being {"version": .., "mac_addr": <base64 of the MAC bytes>}.
+
+const LICENSE_MAGIC_LEN: usize = 8;
+const PASETO_V4_PUBLIC_HEADER: &str = "v4.public.";
+
+#[derive(Serialize, serde::Deserialize)]
+struct License {
+    version: String,
+    mac_addr: String,
+}
+
+fn handle_license_genkeys(file: &Path) -> Result<(), String> {
+    let key_pair = KeyPair::generate();
+    let priv_path = file.with_extension("priv");
+    let pub_path = file.with_extension("pub");
+    fs::write(&priv_path, URL_SAFE.encode(key_pair.sk.as_slice()))
+        .map_err(|e| format!("Failed writing {} : {e:?}", priv_path.display()))?;
+    fs::write(&pub_path, URL_SAFE.encode(key_pair.pk.as_slice()))
+        .map_err(|e| format!("Failed writing {} : {e:?}", pub_path.display()))?;
+    println!("Saved private key to {} (keep it secret)", priv_path.display());
+    println!("Saved public key to {}", pub_path.display());
+    Ok(())
+}
+
+fn handle_gen_bin(version: &semver::Version, mac: &str, magic: &Option<String>, bin_file: &Path, key_file: &Path, obfuscate_key: &str) -> Result<(), String> {
+    let sk = SecretKey::from_slice(&read_key_file(key_file)?)
+        .map_err(|_| format!("'{}' is not a private key", key_file.display()))?;
+    let bin = gen_license_bin(&sk, version, &parse_mac(mac)?, magic.as_deref().unwrap_or_default(), obfuscate_key)?;
+    fs::write(bin_file, bin).map_err(|e| format!("Failed writing {} : {e:?}", bin_file.display()))?;
+    println!("Saved license for {mac} version {version} to {}", bin_file.display());
+    Ok(())
 }
 
-fn handle_gen_bin(_version: &semver::Version, _mac: &str, _magic: &Option<String>, _bin_file: &PathBuf, _key_file: &PathBuf) -> Result<(), String> {
-    todo!()
+fn handle_parse_bin(magic: &Option<String>, bin_file: &Path, key_file: &Path, obfuscate_key: &str) -> Result<(), String> {
+    let pk = PublicKey::from_slice(&read_key_file(key_file)?)
+        .map_err(|_| format!("'{}' is not a public key", key_file.display()))?;
+    let bin = fs::read(bin_file).map_err(|e| format!("Can't read {} : {e:?}", bin_file.display()))?;
+    let license = parse_license_bin(&bin, &pk, magic.as_deref().unwrap_or_default(), obfuscate_key)?;
+    let mac = URL_SAFE
+        .decode(&license.mac_addr)
+        .map_err(|_| "License MAC address is not valid base64".to_string())?;
+    println!("Signature verified");
+    println!("Version: {}", license.version);
+    println!("MAC: {}", mac.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":"));
+    Ok(())
 }
 
-fn handle_license_genkeys(_file: &PathBuf) -> Result<(), String> {
-    todo!()
+fn read_key_file(key_file: &Path) -> Result<Vec<u8>, String> {
+    let key = fs::read_to_string(key_file).map_err(|e| format!("Can't read {} : {e:?}", key_file.display()))?;
+    URL_SAFE
+        .decode(key.trim())
+        .map_err(|_| format!("'{}' is not a base64 key", key_file.display()))
 }
 
+fn parse_mac(mac: &str) -> Result<[u8; 6], String> {
+    let hex: String = mac.chars().filter(|c| !matches!(c, ':' | '-')).collect();
+    if hex.len() != 12 || !hex.is_ascii() {
+        return Err(format!("Invalid MAC address '{mac}'"));
+    }
+    let mut bytes = [0u8; 6];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| format!("Invalid MAC address '{mac}'"))?;
+    }
+    Ok(bytes)
+}
+
+fn gen_license_bin(sk: &SecretKey, version: &semver::Version, mac: &[u8; 6], magic: &str, obfuscate_key: &str) -> Result<Vec<u8>, String> {
+    if magic.len() > LICENSE_MAGIC_LEN {
+        return Err(format!("Magic must be up to {LICENSE_MAGIC_LEN} bytes"));
+    }
+    if obfuscate_key.is_empty() {
+        return Err("Obfuscate key must not be empty".to_string());
+    }
+    let license = License { version: version.to_string(), mac_addr: URL_SAFE.encode(mac) };
+    let payload = serde_json::json!({ "license": serde_json::to_string(&license).unwrap() }).to_string();
+    let token = paseto_v4_public_sign(sk, payload.as_bytes());
+    let token = URL_SAFE.encode(xor(token.as_bytes(), obfuscate_key.as_bytes()));
+    let token_len = u16::try_from(token.len()).map_err(|_| "License token too long".to_string())?;
+
+    let mut bin = vec![0u8; LICENSE_MAGIC_LEN];
+    bin[..magic.len()].copy_from_slice(magic.as_bytes());
+    bin.extend_from_slice(&token_len.to_le_bytes());
+    bin.extend_from_slice(token.as_bytes());
+    Ok(bin)
+}
+
+fn parse_license_bin(bin: &[u8], pk: &PublicKey, magic: &str, obfuscate_key: &str) -> Result<License, String> {
+    if bin.len() < LICENSE_MAGIC_LEN + 2 || bin[..magic.len()] != *magic.as_bytes() {
+        return Err("No license available (magic doesn't match)".to_string());
+    }
+    let token_len = u16::from_le_bytes([bin[LICENSE_MAGIC_LEN], bin[LICENSE_MAGIC_LEN + 1]]) as usize;
+    let token = bin
+        .get(LICENSE_MAGIC_LEN + 2..LICENSE_MAGIC_LEN + 2 + token_len)
+        .ok_or("License truncated".to_string())?;
+    let token = URL_SAFE.decode(token).map_err(|_| "License token is not valid base64".to_string())?;
+    let token = String::from_utf8(xor(&token, obfuscate_key.as_bytes()))
+        .map_err(|_| "License token can't be decoded, wrong obfuscate key?".to_string())?;
+    let payload = paseto_v4_public_verify(pk, &token)?;
+
+    let claims: serde_json::Value = serde_json::from_slice(&payload).map_err(|_| "License payload is not json".to_string())?;
+    let license = claims["license"].as_str().ok_or("License payload is missing the license".to_string())?;
+    serde_json::from_str(license).map_err(|e| format!("License is invalid: {e}"))
+}
+
+fn xor(data: &[u8], key: &[u8]) -> Vec<u8> {
+    data.iter().enumerate().map(|(i, &byte)| byte ^ key[i % key.len()]).collect()
+}
+
+// PASETO pre-authentication encoding
+fn paseto_pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let mut pae = (pieces.len() as u64).to_le_bytes().to_vec();
+    for piece in pieces {
+        pae.extend_from_slice(&(piece.len() as u64).to_le_bytes());
+        pae.extend_from_slice(piece);
+    }
+    pae
+}
+
+// No footer and no implicit assertion, as verified by the firmware
+fn paseto_v4_public_sign(sk: &SecretKey, message: &[u8]) -> String {
+    let signature = sk.sign(paseto_pae(&[PASETO_V4_PUBLIC_HEADER.as_bytes(), message, b"", b""]), None);
+    let mut body = message.to_vec();
+    body.extend_from_slice(signature.as_slice());
+    format!("{PASETO_V4_PUBLIC_HEADER}{}", URL_SAFE_NO_PAD.encode(body))
+}
+
+fn paseto_v4_public_verify(pk: &PublicKey, token: &str) -> Result<Vec<u8>, String> {
+    let body = token
+        .strip_prefix(PASETO_V4_PUBLIC_HEADER)
+        .filter(|body| !body.contains('.'))
+        .ok_or("License token is not a v4.public token without footer".to_string())?;
+    let body = URL_SAFE_NO_PAD.decode(body).map_err(|_| "License token is not valid base64".to_string())?;
+    if body.len() < Signature::BYTES {
+        return Err("License token is too short".to_string());
+    }
+    let (message, signature) = body.split_at(body.len() - Signature::BYTES);
+    let signature = Signature::from_slice(signature).map_err(|_| "License signature is invalid".to_string())?;
+    pk.verify(paseto_pae(&[PASETO_V4_PUBLIC_HEADER.as_bytes(), message, b"", b""]), &signature)
+        .map_err(|_| "License signature verification failed".to_string())?;
+    Ok(message.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn package_info_with_version() {
+        let (name, version) = get_package_info(&fixture("with_version")).unwrap();
+        assert_eq!(name, "fixture-app");
+        assert_eq!(version, semver::Version::new(1, 2, 3));
+    }
+
+    #[test]
+    fn package_info_without_version() {
+        let err = get_package_info(&fixture("without_version")).unwrap_err();
+        assert_eq!(err, "Package version not found in Cargo.toml");
+    }
+
+    #[test]
+    fn package_info_with_workspace_version() {
+        // inherited versions aren't resolved, the device project must set its own
+        let err = get_package_info(&fixture("workspace_version")).unwrap_err();
+        assert_eq!(err, "Package version not found in Cargo.toml");
+    }
+
+    #[test]
+    fn package_info_missing_manifest() {
+        let err = get_package_info(&fixture("does_not_exist")).unwrap_err();
+        assert!(err.starts_with("Can't read"), "{err}");
+    }
+
+    #[test]
+    fn release_elf_missing() {
+        let err = check_release_elf(&fixture("with_version"), "fixture-app", &None, Chip::Esp32s3).unwrap_err();
+        assert!(err.contains("build it first"), "{err}");
+    }
+
+    #[test]
+    fn release_elf_path_with_subtarget() {
+        let path = release_elf_path(Path::new("/app"), "app", &Some("sub".to_string()), Chip::Esp32s3);
+        assert_eq!(path, Path::new("/app/target/sub/xtensa-esp32s3-none-elf/release/app"));
+    }
+
+    #[test]
+    fn release_elf_path_for_riscv_chip() {
+        let path = release_elf_path(Path::new("/app"), "app", &None, Chip::Esp32c6);
+        assert_eq!(path, Path::new("/app/target/riscv32imac-unknown-none-elf/release/app"));
+    }
+
+    #[test]
+    fn manifest_follows_chip() {
+        let version = semver::Version::new(1, 2, 3);
+        let manifest = fill_manifest(MANIFEST_TEMPLATE_NEW, "app", &version, "app-1.2.3.bin", Chip::Esp32c6);
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["builds"][0]["chipFamily"], "ESP32-C6");
+        assert_eq!(manifest["builds"][0]["parts"][0]["offset"], 0);
+
+        let manifest = fill_manifest(MANIFEST_TEMPLATE_UPGRADE, "app", &version, "app-1.2.3.bin", Chip::Esp32);
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["builds"][0]["chipFamily"], "ESP32");
+        assert_eq!(manifest["builds"][0]["parts"][1]["path"], "app-1.2.3.bin");
+
+        let manifest = fill_manifest(MANIFEST_TEMPLATE_NEW, "app", &version, "app-1.2.3.bin", Chip::Esp32);
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["builds"][0]["parts"][0]["offset"], 4096);
+    }
+
+    #[test]
+    fn partition_sizes() {
+        assert_eq!(parse_partition_size("0x1000"), Some(0x1000));
+        assert_eq!(parse_partition_size("4096"), Some(4096));
+        assert_eq!(parse_partition_size("64K"), Some(64 * 1024));
+        assert_eq!(parse_partition_size("6M"), Some(6 * 1024 * 1024));
+        assert_eq!(parse_partition_size("abc"), None);
+    }
+
+    #[test]
+    fn smallest_app_partition() {
+        let csv = "# Name, Type, SubType, Offset, Size, Flags\n\
+                   nvs, data, nvs, 0x9000, 0x4000,\n\
+                   otadata, data, ota, 0xd000, 0x2000,\n\
+                   ota_0, app, ota_0, 0x200000, 0x700000,\n\
+                   ota_1, app, ota_1, 0x900000, 6M,\n";
+        assert_eq!(app_partition_size(csv), Ok(6 * 1024 * 1024));
+        assert!(app_partition_size("nvs, data, nvs, 0x9000, 0x4000,").is_err());
+    }
+
+    #[test]
+    fn crc32_of_known_file() {
+        // "123456789" is the standard CRC-32 (IEEE) check input, same algorithm the device verifies
+        let crc32 = compute_crc32(&fixture("crc32_check.bin")).unwrap();
+        assert_eq!(crc32, 0xCBF4_3926);
+        // ota.toml format
+        assert_eq!(format!("{crc32:08x}"), "cbf43926");
+    }
+
+    #[test]
+    fn crc32_larger_than_read_buffer() {
+        let path = std::env::temp_dir().join(format!("xtask-crc32-{}.bin", std::process::id()));
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+        let crc32 = compute_crc32(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(crc32.unwrap(), crc32fast::hash(&data));
+    }
+
+    #[test]
+    fn crc32_read_error_is_not_eof() {
+        // opening a directory succeeds on unix but reading it fails
+        assert!(compute_crc32(&fixture("with_version")).is_err());
+    }
+
+    #[test]
+    fn sha256_of_known_file() {
+        let sha256 = compute_sha256(&fixture("crc32_check.bin")).unwrap();
+        assert_eq!(sha256, "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225");
+    }
+
+    #[test]
+    fn paseto_v4_public_test_vector() {
+        // 4-S-1 from the PASETO test vectors, what the firmware's pasetors verifies
+        let pk = PublicKey::from_slice(&hex("1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2")).unwrap();
+        let token = "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9bg_XBBzds8lTZShVlwwKSgeKpLT3yukTw6JUz3W4h_ExsQV-P0V54zemZDcAxFaSeef1QlXEFtkqxT1ciiQEDA";
+        let payload = paseto_v4_public_verify(&pk, token).unwrap();
+        assert_eq!(payload, br#"{"data":"this is a signed message","exp":"2022-01-01T00:00:00+00:00"}"#);
+    }
+
+    #[test]
+    fn license_bin_round_trip() {
+        let key_pair = KeyPair::generate();
+        let version = semver::Version::new(1, 0, 0);
+        let mac = parse_mac("aa:bb:cc:00:11:22").unwrap();
+        let bin = gen_license_bin(&key_pair.sk, &version, &mac, "SPOOL", "obfuscate").unwrap();
+        assert_eq!(&bin[..8], b"SPOOL\0\0\0");
+        assert_eq!(u16::from_le_bytes([bin[8], bin[9]]) as usize, bin.len() - 10);
 
+        let license = parse_license_bin(&bin, &key_pair.pk, "SPOOL", "obfuscate").unwrap();
+        assert_eq!(license.version, "1.0.0");
+        assert_eq!(URL_SAFE.decode(license.mac_addr).unwrap(), mac);
+
+        assert!(parse_license_bin(&bin, &key_pair.pk, "OTHER", "obfuscate").is_err());
+        assert!(parse_license_bin(&bin, &KeyPair::generate().pk, "SPOOL", "obfuscate").is_err());
+        assert!(parse_license_bin(&bin, &key_pair.pk, "SPOOL", "wrong").is_err());
+    }
+
+    #[test]
+    fn license_magic_too_long() {
+        let key_pair = KeyPair::generate();
+        let version = semver::Version::new(1, 0, 0);
+        assert!(gen_license_bin(&key_pair.sk, &version, &[0; 6], "TOOLONGMAGIC", "x").is_err());
+    }
+
+    #[test]
+    fn mac_formats() {
+        assert_eq!(parse_mac("aa:bb:cc:dd:ee:ff").unwrap(), [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert_eq!(parse_mac("AA-BB-CC-DD-EE-FF").unwrap(), [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert_eq!(parse_mac("aabbccddeeff").unwrap(), [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert!(parse_mac("aa:bb:cc").is_err());
+        assert!(parse_mac("zz:bb:cc:dd:ee:ff").is_err());
+    }
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn command_args(command: &std::process::Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn deploy_config_from_file() {
+        let config = load_deploy_config(&fixture("deploy"), None).unwrap();
+        assert_eq!(config.url, "s3://fw-bucket/app/");
+        assert_eq!(config.endpoint_url.as_deref(), Some("https://storage.example.com"));
+    }
+
+    #[test]
+    fn deploy_url_from_env() {
+        let config = load_deploy_config(&fixture("deploy"), Some("https://fw.example.com/".to_string())).unwrap();
+        assert_eq!(config.url, "https://fw.example.com/");
+        // the other settings still come from the file
+        assert_eq!(config.endpoint_url.as_deref(), Some("https://storage.example.com"));
+
+        let config = load_deploy_config(&fixture("with_version"), Some("sftp://host/fw".to_string())).unwrap();
+        assert_eq!(config.url, "sftp://host/fw");
+    }
+
+    #[test]
+    fn deploy_without_destination() {
+        let err = load_deploy_config(&fixture("with_version"), None).unwrap_err();
+        assert!(err.contains(DEPLOY_URL_ENV), "{err}");
+    }
+
+    #[test]
+    fn upload_to_s3() {
+        let config = load_deploy_config(&fixture("deploy"), None).unwrap();
+        let command = upload_command(&config, Path::new("/out/ota.toml"), "ota.toml").unwrap();
+        assert_eq!(command.get_program(), "aws");
+        assert_eq!(
+            command_args(&command),
+            ["--endpoint-url", "https://storage.example.com", "s3", "cp", "/out/ota.toml", "s3://fw-bucket/app/ota.toml"]
+        );
+    }
+
+    #[test]
+    fn upload_with_http_put() {
+        let config = DeployConfig {
+            url: "https://fw.example.com/upload".to_string(),
+            headers: vec!["Authorization: Bearer x".to_string()],
+            ..Default::default()
+        };
+        let command = upload_command(&config, Path::new("/out/app-1.2.3.bin"), "app-1.2.3.bin").unwrap();
+        assert_eq!(command.get_program(), "curl");
+        let args = command_args(&command);
+        assert!(args.windows(2).any(|arg| arg == ["-H", "Authorization: Bearer x"]), "{args:?}");
+        assert!(args.ends_with(&["-T".to_string(), "/out/app-1.2.3.bin".to_string(), "https://fw.example.com/upload/app-1.2.3.bin".to_string()]), "{args:?}");
+    }
+
+    #[test]
+    fn upload_unsupported_scheme() {
+        let config = DeployConfig { url: "ftp://host/fw".to_string(), ..Default::default() };
+        assert!(upload_command(&config, Path::new("/out/ota.toml"), "ota.toml").is_err());
+    }
+
+    #[test]
+    fn crc32_missing_file() {
+        assert!(compute_crc32(&fixture("does_not_exist.bin")).is_err());
+    }
+}